@@ -0,0 +1,23 @@
+//! Records the git commit and target triple of a build for `revtag --version-json`.
+use std::env;
+use std::process::Command;
+
+fn main() {
+    let hash = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=REVTAG_GIT_HASH={hash}");
+    }
+    println!(
+        "cargo:rustc-env=REVTAG_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
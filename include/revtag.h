@@ -0,0 +1,30 @@
+#ifndef REVTAG_H
+#define REVTAG_H
+
+/* Generated by cbindgen from src/lib/ffi.rs; do not edit by hand. */
+
+#include <stddef.h>
+#include <htslib/sam.h>
+
+typedef struct RevtagPlan RevtagPlan;
+
+const char *revtag_last_error(void);
+
+RevtagPlan *revtag_plan_new(const char *const *rev,
+                            size_t n_rev,
+                            const char *const *revcomp,
+                            size_t n_revcomp);
+
+void revtag_plan_free(RevtagPlan *plan);
+
+int revtag_transform_bam1(const RevtagPlan *plan, bam1_t *b);
+
+int revtag_run(const char *input,
+               const char *output,
+               const char *const *rev,
+               size_t n_rev,
+               const char *const *revcomp,
+               size_t n_revcomp,
+               int threads);
+
+#endif /* REVTAG_H */
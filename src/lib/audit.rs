@@ -0,0 +1,197 @@
+//! An audit table of modified records, listing where each record lies and which tags changed,
+//! so auditors can spot-check a sample against the source data without diffing whole files.
+use log::*;
+use rust_htslib::bam::{HeaderView, Record};
+use rust_htslib::bgzf;
+use std::error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::aux;
+use crate::plan::{Operation, Plan, Tag};
+
+/// The header line of the audit table.
+const HEADER: &[u8] = b"qname\tcontig\tposition\tflag\ttags\n";
+
+/// Returns the tags a plan may modify: its planned tags, the tags they are renamed to, and any
+/// strand annotation tag.
+///
+/// # Arguments
+///
+/// * `plan` - The tag specifications applied to a record
+/// * `annotation` - The tag stamped with each transformed record's orientation, if any
+///
+/// # Returns
+///
+/// Returns the tags, without repeats, in the order the plan names them.
+///
+pub fn candidate_tags(plan: &Plan, annotation: Option<&Tag>) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    let renamed = plan.specs().iter().filter_map(|spec| match spec.operation {
+        Operation::Rename(tag) => Some(tag),
+        _ => None,
+    });
+    for tag in plan
+        .specs()
+        .iter()
+        .map(|spec| spec.tag)
+        .chain(renamed)
+        .chain(annotation.copied())
+    {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+/// Returns the tags among `candidates` whose presence or raw value differs between two copies
+/// of a record's auxiliary data.
+///
+/// # Arguments
+///
+/// * `before` - The auxiliary data before the record was transformed
+/// * `after` - The auxiliary data after the record was transformed
+/// * `candidates` - The tags to compare, per [`candidate_tags`]
+///
+/// # Returns
+///
+/// Returns the changed tags in the order of `candidates`, or an error if either copy of the
+/// data is malformed.
+///
+pub fn modified_tags(
+    before: &[u8],
+    after: &[u8],
+    candidates: &[Tag],
+) -> Result<Vec<Tag>, Box<dyn error::Error>> {
+    let mut modified = Vec::new();
+    for tag in candidates {
+        let old = aux::find(before, tag)?.map(|field| (field.value_type, &before[field.value]));
+        let new = aux::find(after, tag)?.map(|field| (field.value_type, &after[field.value]));
+        if old != new {
+            modified.push(*tag);
+        }
+    }
+    Ok(modified)
+}
+
+/// Writes a tab-separated row for each modified record: its name, contig, 1-based position,
+/// flag, and the comma-separated tags that changed, or `*` for none.
+pub struct AuditWriter {
+    writer: Box<dyn Write>,
+    contigs: Vec<String>,
+    written: u64,
+}
+
+impl AuditWriter {
+    /// Creates an audit table with its header line, BGZF compressed if the path ends in `.gz`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The table to create
+    /// * `header` - The header naming the contigs records are placed on
+    ///
+    /// # Returns
+    ///
+    /// Returns the writer, or an error if the file cannot be created.
+    ///
+    pub fn create(path: &Path, header: &HeaderView) -> Result<Self, Box<dyn error::Error>> {
+        info!("Audit table: {path:?}");
+        let mut writer: Box<dyn Write> = if path.extension().is_some_and(|ext| ext == "gz") {
+            Box::new(bgzf::Writer::from_path(path)?)
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        writer.write_all(HEADER)?;
+        let contigs = header
+            .target_names()
+            .into_iter()
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        Ok(Self {
+            writer,
+            contigs,
+            written: 0,
+        })
+    }
+
+    /// Writes a row for a modified record.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The record, after it was transformed
+    /// * `tags` - The tags the transform modified, per [`modified_tags`]
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if writing fails.
+    ///
+    pub fn write(&mut self, record: &Record, tags: &[Tag]) -> Result<(), Box<dyn error::Error>> {
+        let contig = usize::try_from(record.tid())
+            .ok()
+            .and_then(|tid| self.contigs.get(tid))
+            .map_or("*", String::as_str);
+        self.writer.write_all(record.qname())?;
+        write!(
+            self.writer,
+            "\t{contig}\t{}\t{}\t",
+            record.pos() + 1,
+            record.flags()
+        )?;
+        if tags.is_empty() {
+            self.writer.write_all(b"*")?;
+        }
+        for (i, tag) in tags.iter().enumerate() {
+            if i > 0 {
+                self.writer.write_all(b",")?;
+            }
+            self.writer.write_all(tag)?;
+        }
+        self.writer.write_all(b"\n")?;
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Flushes the table and reports how many records were listed.
+    pub fn finish(mut self) -> Result<(), Box<dyn error::Error>> {
+        self.writer.flush()?;
+        info!(
+            "Listed {} modified records in the audit table",
+            self.written
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidate_tags() {
+        let plan = Plan::parse(
+            &["QT".to_string()],
+            &[],
+            &["XB:comp,rev,rename=BC".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            candidate_tags(&plan, Some(b"os")),
+            vec![*b"QT", *b"XB", *b"BC", *b"os"]
+        );
+        assert_eq!(candidate_tags(&Plan::default(), None), Vec::<Tag>::new());
+    }
+
+    #[test]
+    fn test_modified_tags() {
+        let before = b"QTZABC\0BCZAAC\0NMC\x03";
+        let after = b"QTZCBA\0BCZAAC\0NMC\x03osA-";
+        let modified = modified_tags(before, after, &[*b"QT", *b"BC", *b"os"]).unwrap();
+        assert_eq!(modified, vec![*b"QT", *b"os"]);
+
+        let renamed = b"XBZCGTT\0";
+        let modified = modified_tags(b"BCZAACG\0", renamed, &[*b"BC", *b"XB"]).unwrap();
+        assert_eq!(modified, vec![*b"BC", *b"XB"]);
+    }
+}
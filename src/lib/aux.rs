@@ -0,0 +1,1540 @@
+//! Parsing and in-place edits of raw BAM auxiliary data.
+//!
+//! BAM stores the optional fields of a record as a contiguous block of `TAG TYPE VALUE`
+//! entries. Working on this block directly lets values be rewritten without removing and
+//! re-appending fields, which rebuilds the record's data on every call.
+use log::*;
+use std::error;
+use std::fmt;
+use std::ops::Range;
+
+use crate::dna;
+use crate::plan::{
+    Columns, DuplicateTags, Encoding, MatrixShape, NonSequence, Operation, PairMismatch, Plan, Tag,
+    TagPair, TagSpec,
+};
+
+/// An error for a `Z` value holding a non-ASCII byte, which cannot be reverse complemented
+/// without corrupting the string.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NonAsciiError {
+    /// The SAM tag holding the value.
+    pub tag: Tag,
+    /// The offset of the first non-ASCII byte within the value.
+    pub position: usize,
+    /// The offending byte.
+    pub byte: u8,
+}
+
+impl fmt::Display for NonAsciiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Tag {} holds non-ASCII byte 0x{:02X} at position {}; Z values must be ASCII",
+            show(&self.tag),
+            self.byte,
+            self.position
+        )
+    }
+}
+
+impl error::Error for NonAsciiError {}
+
+/// An error for a `B:C` array to convert to a `Z` string holding a byte a string cannot, as `Z`
+/// values are limited to printable characters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnprintableError {
+    /// The SAM tag holding the value.
+    pub tag: Tag,
+    /// The offset of the first unprintable byte within the value.
+    pub position: usize,
+    /// The offending byte.
+    pub byte: u8,
+}
+
+impl fmt::Display for UnprintableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Tag {} holds unprintable byte 0x{:02X} at position {} and cannot be converted to a \
+             Z string",
+            show(&self.tag),
+            self.byte,
+            self.position
+        )
+    }
+}
+
+impl error::Error for UnprintableError {}
+
+/// Fails if a value to store as a `Z` string holds a byte outside the printable characters
+/// `[ !-~]` the SAM specification allows.
+///
+/// # Arguments
+///
+/// * `tag` - The SAM tag of the value, named in the error
+/// * `value` - The bytes of the value
+///
+/// # Returns
+///
+/// Returns an [`UnprintableError`] naming the first byte at fault.
+///
+pub fn check_printable(tag: &Tag, value: &[u8]) -> Result<(), UnprintableError> {
+    match value.iter().position(|byte| !(b' '..=b'~').contains(byte)) {
+        Some(position) => Err(UnprintableError {
+            tag: *tag,
+            position,
+            byte: value[position],
+        }),
+        None => Ok(()),
+    }
+}
+
+/// An error for a record carrying a planned tag more than once under [`DuplicateTags::Error`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DuplicateTagError {
+    /// The repeated SAM tag.
+    pub tag: Tag,
+}
+
+impl fmt::Display for DuplicateTagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Tag {} occurs more than once on a record",
+            show(&self.tag)
+        )
+    }
+}
+
+impl error::Error for DuplicateTagError {}
+
+/// An error for a record lacking a tag it is required to carry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingTagError {
+    /// The name of the record.
+    pub qname: String,
+    /// The missing SAM tag.
+    pub tag: Tag,
+}
+
+impl fmt::Display for MissingTagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Record {} is missing required tag {}",
+            self.qname,
+            show(&self.tag)
+        )
+    }
+}
+
+impl error::Error for MissingTagError {}
+
+/// An error for a record carrying one tag of a companion pair without the other, so only one
+/// of them would be transformed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnpairedTagError {
+    /// The name of the record.
+    pub qname: String,
+    /// The SAM tag the record carries.
+    pub tag: Tag,
+    /// The companion SAM tag the record lacks.
+    pub companion: Tag,
+}
+
+impl fmt::Display for UnpairedTagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Record {} has tag {} without its companion tag {}",
+            self.qname,
+            show(&self.tag),
+            show(&self.companion)
+        )
+    }
+}
+
+impl error::Error for UnpairedTagError {}
+
+/// An error for a record whose companion pair holds values of different lengths.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PairLengthError {
+    /// The name of the record.
+    pub qname: String,
+    /// The companion pair.
+    pub pair: TagPair,
+    /// The length of the sequence tag's value.
+    pub sequence: usize,
+    /// The length of the quality tag's value.
+    pub quality: usize,
+}
+
+impl fmt::Display for PairLengthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Record {} has tag {} of length {} but companion tag {} of length {}",
+            self.qname,
+            show(&self.pair.sequence),
+            self.sequence,
+            show(&self.pair.quality),
+            self.quality
+        )
+    }
+}
+
+impl error::Error for PairLengthError {}
+
+/// An error for a matrix tag whose array does not fit its declared shape.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MatrixShapeError {
+    /// The matrix SAM tag.
+    pub tag: Tag,
+    /// The number of elements of the array.
+    pub elements: usize,
+    /// The declared number of rows, or None if any number is allowed.
+    pub rows: Option<usize>,
+    /// The number of columns, or None if the tag giving it is missing or not a positive
+    /// integer.
+    pub columns: Option<usize>,
+}
+
+impl fmt::Display for MatrixShapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tag = show(&self.tag);
+        match (self.rows, self.columns) {
+            (_, None) => write!(
+                f,
+                "Matrix tag {tag} has no number of columns: its column tag is missing or not a \
+                 positive integer"
+            ),
+            (Some(rows), Some(columns)) => write!(
+                f,
+                "Matrix tag {tag} has {} elements, not {rows} rows of {columns} columns",
+                self.elements
+            ),
+            (None, Some(columns)) => write!(
+                f,
+                "Matrix tag {tag} has {} elements, not a whole number of rows of {columns} columns",
+                self.elements
+            ),
+        }
+    }
+}
+
+impl error::Error for MatrixShapeError {}
+
+/// An error for a record holding a value that a validated tag may not hold, such as a Bismark
+/// `XR` tag other than `CT` or `GA`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnexpectedValueError {
+    /// The name of the record.
+    pub qname: String,
+    /// The validated SAM tag.
+    pub tag: Tag,
+    /// The value the record holds, lossily decoded as UTF-8.
+    pub value: String,
+    /// The values the tag may hold.
+    pub expected: &'static [&'static str],
+}
+
+impl fmt::Display for UnexpectedValueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Record {} has tag {} with value {:?}; expected one of {}",
+            self.qname,
+            show(&self.tag),
+            self.value,
+            self.expected.join(", ")
+        )
+    }
+}
+
+impl error::Error for UnexpectedValueError {}
+
+/// The number of planned values of one type left untouched because the type does not support
+/// the planned operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnsupportedType {
+    /// The tag specification that could not be applied.
+    pub spec: TagSpec,
+    /// The BAM type character of the values.
+    pub value_type: u8,
+    /// The element type character of `B` arrays, or None for other types.
+    pub subtype: Option<u8>,
+    /// The number of values left untouched.
+    pub count: u64,
+}
+
+impl fmt::Display for UnsupportedType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operation = match self.spec.operation {
+            Operation::Reverse => "reversed",
+            Operation::ReverseComplement => "reverse complemented",
+            Operation::ReverseColorspace => "reversed as colorspace",
+            Operation::Complement => "complemented",
+            Operation::Rename(_) => "renamed",
+            Operation::Convert(Encoding::String) => "converted to Z",
+            Operation::Convert(Encoding::ByteArray) => "converted to B:C",
+        };
+        write!(
+            f,
+            "Left {} values of tag {} unchanged: type {} cannot be {operation}",
+            self.count,
+            show(&self.spec.tag),
+            type_name(self.value_type, self.subtype)
+        )
+    }
+}
+
+/// Returns a value type as written in SAM, e.g. `i` or `B:s`.
+fn type_name(value_type: u8, subtype: Option<u8>) -> String {
+    match subtype {
+        Some(subtype) => format!("{}:{}", value_type as char, subtype as char),
+        None => (value_type as char).to_string(),
+    }
+}
+
+/// Counts of planned values left untouched because of their type, in the order first seen.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Unsupported {
+    types: Vec<UnsupportedType>,
+}
+
+impl Unsupported {
+    /// Counts `count` values of a field's type that `spec` could not be applied to.
+    fn add(&mut self, spec: TagSpec, value_type: u8, subtype: Option<u8>, count: u64) {
+        let existing = self.types.iter_mut().find(|entry| {
+            entry.spec == spec && entry.value_type == value_type && entry.subtype == subtype
+        });
+        match existing {
+            Some(entry) => entry.count += count,
+            None => self.types.push(UnsupportedType {
+                spec,
+                value_type,
+                subtype,
+                count,
+            }),
+        }
+    }
+
+    /// Adds every count from `other` to these counts.
+    pub fn merge(&mut self, other: &Unsupported) {
+        for entry in &other.types {
+            self.add(entry.spec, entry.value_type, entry.subtype, entry.count);
+        }
+    }
+
+    /// Returns the counts for each tag specification and type.
+    pub fn types(&self) -> &[UnsupportedType] {
+        &self.types
+    }
+
+    /// Returns true if no values were left untouched.
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+}
+
+/// A single field located within a raw auxiliary data block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    /// The SAM tag of the field.
+    pub tag: Tag,
+    /// The BAM type character of the value, e.g. `b'Z'` or `b'B'`.
+    pub value_type: u8,
+    /// The element type character of a `B` array, or None for other types.
+    pub subtype: Option<u8>,
+    /// The byte range of the value: array elements only for `B`, and without the trailing NUL
+    /// for `Z` and `H`.
+    pub value: Range<usize>,
+}
+
+/// Returns the width in bytes of a fixed-size BAM value or array element type.
+///
+/// # Arguments
+///
+/// * `value_type` - The BAM type character
+///
+/// # Returns
+///
+/// Returns the width, or None if the type is not fixed-size.
+///
+pub fn width(value_type: u8) -> Option<usize> {
+    match value_type {
+        b'A' | b'c' | b'C' => Some(1),
+        b's' | b'S' => Some(2),
+        b'i' | b'I' | b'f' => Some(4),
+        b'd' => Some(8),
+        _ => None,
+    }
+}
+
+/// An iterator over the fields of a raw auxiliary data block.
+///
+/// Iteration stops after the first malformed field is reported.
+pub struct Fields<'a> {
+    aux: &'a [u8],
+    offset: usize,
+}
+
+/// Parses the field starting at byte `start`, returning it with the offset of the next field.
+fn parse_field(aux: &[u8], start: usize) -> Result<(Field, usize), Box<dyn error::Error>> {
+    let truncated =
+        || -> Box<dyn error::Error> { format!("Truncated auxiliary data at byte {start}").into() };
+
+    let header = aux.get(start..start + 3).ok_or_else(truncated)?;
+    let tag = [header[0], header[1]];
+    let value_type = header[2];
+    let begin = start + 3;
+
+    let (subtype, value, end) = match value_type {
+        b'Z' | b'H' => {
+            let len = aux[begin..]
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or_else(truncated)?;
+            (None, begin..begin + len, begin + len + 1)
+        }
+        b'B' => {
+            let header = aux.get(begin..begin + 5).ok_or_else(truncated)?;
+            let subtype = header[0];
+            let width = width(subtype).ok_or_else(|| {
+                format!(
+                    "Invalid array type {:?} for tag {}",
+                    subtype as char,
+                    show(&tag)
+                )
+            })?;
+            let count = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+            let first = begin + 5;
+            let end = first + count as usize * width;
+            (Some(subtype), first..end, end)
+        }
+        _ => {
+            let width = width(value_type).ok_or_else(|| {
+                format!(
+                    "Invalid type {:?} for tag {}",
+                    value_type as char,
+                    show(&tag)
+                )
+            })?;
+            (None, begin..begin + width, begin + width)
+        }
+    };
+
+    if end > aux.len() {
+        return Err(truncated());
+    }
+    let field = Field {
+        tag,
+        value_type,
+        subtype,
+        value,
+    };
+    Ok((field, end))
+}
+
+impl Iterator for Fields<'_> {
+    type Item = Result<Field, Box<dyn error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.aux.len() {
+            return None;
+        }
+        match parse_field(self.aux, self.offset) {
+            Ok((field, end)) => {
+                self.offset = end;
+                Some(Ok(field))
+            }
+            Err(e) => {
+                self.offset = self.aux.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Returns a tag as a printable string for error messages.
+fn show(tag: &Tag) -> String {
+    String::from_utf8_lossy(tag).into_owned()
+}
+
+/// Iterates over the fields of a raw auxiliary data block.
+///
+/// # Arguments
+///
+/// * `aux` - The raw auxiliary data of a BAM record
+///
+/// # Returns
+///
+/// Returns an iterator yielding each field, or an error for malformed data.
+///
+pub fn fields(aux: &[u8]) -> Fields<'_> {
+    Fields { aux, offset: 0 }
+}
+
+/// Finds the field stored under `tag`.
+///
+/// # Arguments
+///
+/// * `aux` - The raw auxiliary data of a BAM record
+/// * `tag` - The SAM tag to find
+///
+/// # Returns
+///
+/// Returns the field if present, None if absent, or an error if the data is malformed.
+///
+pub fn find(aux: &[u8], tag: &Tag) -> Result<Option<Field>, Box<dyn error::Error>> {
+    for field in fields(aux) {
+        let field = field?;
+        if field.tag == *tag {
+            return Ok(Some(field));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns true if any field's tag satisfies `predicate`, scanning the block once.
+///
+/// # Arguments
+///
+/// * `aux` - The raw auxiliary data of a BAM record
+/// * `predicate` - Returns true for the tags of interest
+///
+/// # Returns
+///
+/// Returns whether a matching field exists, or an error if the data is malformed.
+///
+pub fn any_tag<F>(aux: &[u8], mut predicate: F) -> Result<bool, Box<dyn error::Error>>
+where
+    F: FnMut(&Tag) -> bool,
+{
+    for field in fields(aux) {
+        if predicate(&field?.tag) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns true if a strand tag in a raw auxiliary data block marks the record as reverse strand.
+///
+/// The tag marks the reverse strand when it holds `-` as an `A` character or a `Z` string, as in
+/// the `XS:A` convention of spliced aligners. Any other value, or a missing tag, marks the
+/// forward strand.
+///
+/// # Arguments
+///
+/// * `aux` - The raw auxiliary data of a BAM record
+/// * `tag` - The SAM tag holding the strand
+///
+/// # Returns
+///
+/// Returns whether the record is reverse strand, or an error if the data is malformed.
+///
+pub fn minus_strand(aux: &[u8], tag: &Tag) -> Result<bool, Box<dyn error::Error>> {
+    Ok(match find(aux, tag)? {
+        Some(field) => matches!(field.value_type, b'A' | b'Z') && &aux[field.value] == b"-",
+        None => false,
+    })
+}
+
+/// Returns true if the `MI` tag in a raw auxiliary data block marks the record as the B strand
+/// of a duplex molecule.
+///
+/// Duplex UMI grouping, as by fgbio's `GroupReadsByUmi --strategy paired`, suffixes the
+/// molecular identifier with `/A` or `/B` for the two strands of the source molecule, and the B
+/// strand is the one whose consensus tags are reverse strand. A `Z` string ending in `/B` marks
+/// the B strand; any other value, or a missing tag, marks the A strand.
+///
+/// # Arguments
+///
+/// * `aux` - The raw auxiliary data of a BAM record
+///
+/// # Returns
+///
+/// Returns whether the record is from the B strand, or an error if the data is malformed.
+///
+pub fn duplex_b_strand(aux: &[u8]) -> Result<bool, Box<dyn error::Error>> {
+    Ok(match find(aux, b"MI")? {
+        Some(field) => field.value_type == b'Z' && aux[field.value].ends_with(b"/B"),
+        None => false,
+    })
+}
+
+/// Returns the first of `tags` absent from a raw auxiliary data block.
+///
+/// # Arguments
+///
+/// * `aux` - The raw auxiliary data of a BAM record
+/// * `tags` - The SAM tags that must be present
+///
+/// # Returns
+///
+/// Returns the first missing tag, None if every tag is present, or an error if the data is
+/// malformed.
+///
+pub fn missing_tag<'a, I>(aux: &[u8], tags: I) -> Result<Option<Tag>, Box<dyn error::Error>>
+where
+    I: IntoIterator<Item = &'a Tag>,
+{
+    for tag in tags {
+        if find(aux, tag)?.is_none() {
+            return Ok(Some(*tag));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the number of elements of a field's value: characters of `Z` and `H` strings,
+/// elements of `B` arrays, and one for scalars.
+pub fn value_length(field: &Field) -> usize {
+    match (field.value_type, field.subtype.and_then(width)) {
+        (b'B', Some(width)) => field.value.len() / width,
+        (b'Z' | b'H', _) => field.value.len(),
+        _ => 1,
+    }
+}
+
+/// Checks the companion pairs of a reverse strand record before they are transformed.
+///
+/// A record carrying only one tag of a pair fails, since only that tag would be transformed.
+/// A pair whose values differ in length fails, or under [`PairMismatch::Warn`] is logged and
+/// transformed anyway.
+///
+/// # Arguments
+///
+/// * `plan` - The plan declaring the companion pairs
+/// * `qname` - Returns the record name for messages
+/// * `length` - Returns the length of a tag's value, or None if the record lacks the tag
+///
+/// # Returns
+///
+/// Returns an [`UnpairedTagError`] or [`PairLengthError`] for the first offending pair, or the
+/// first error from `length`.
+///
+pub fn check_pairs<N, F>(plan: &Plan, qname: N, mut length: F) -> Result<(), Box<dyn error::Error>>
+where
+    N: Fn() -> String,
+    F: FnMut(&Tag) -> Result<Option<usize>, Box<dyn error::Error>>,
+{
+    for pair in plan.pairs() {
+        let (sequence, quality) = match (length(&pair.sequence)?, length(&pair.quality)?) {
+            (Some(sequence), Some(quality)) => (sequence, quality),
+            (None, None) => continue,
+            (sequence, _) => {
+                let (tag, companion) = match sequence {
+                    Some(_) => (pair.sequence, pair.quality),
+                    None => (pair.quality, pair.sequence),
+                };
+                return Err(Box::new(UnpairedTagError {
+                    qname: qname(),
+                    tag,
+                    companion,
+                }));
+            }
+        };
+        if sequence != quality {
+            let error = PairLengthError {
+                qname: qname(),
+                pair: *pair,
+                sequence,
+                quality,
+            };
+            match plan.pair_mismatch() {
+                PairMismatch::Error => return Err(Box::new(error)),
+                PairMismatch::Warn => warn!("{error}"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the validated tags of a reverse strand record, which are not transformed.
+///
+/// A record lacking a validated tag passes, since nothing depends on it.
+///
+/// # Arguments
+///
+/// * `plan` - The plan declaring the validated tags
+/// * `qname` - Returns the record name for messages
+/// * `value` - Returns the `Z` value of a tag, or None if the record lacks the tag
+///
+/// # Returns
+///
+/// Returns an [`UnexpectedValueError`] for the first tag holding a value it may not hold, or
+/// the first error from `value`.
+///
+pub fn check_values<N, F>(plan: &Plan, qname: N, mut value: F) -> Result<(), Box<dyn error::Error>>
+where
+    N: Fn() -> String,
+    F: FnMut(&Tag) -> Result<Option<Vec<u8>>, Box<dyn error::Error>>,
+{
+    for (tag, expected) in plan.expected_values() {
+        let Some(value) = value(tag)? else {
+            continue;
+        };
+        if !expected.iter().any(|allowed| allowed.as_bytes() == value) {
+            return Err(Box::new(UnexpectedValueError {
+                qname: qname(),
+                tag: *tag,
+                value: String::from_utf8_lossy(&value).into_owned(),
+                expected,
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Reverses a `B` array or `Z` string stored under `tag`, in place.
+///
+/// Array elements are reversed respecting their width. Strings are reversed byte-wise, since
+/// SAM restricts `Z` values to printable ASCII.
+///
+/// # Arguments
+///
+/// * `aux` - The raw auxiliary data of a BAM record
+/// * `tag` - The SAM tag to reverse
+///
+/// # Returns
+///
+/// Returns true if a value was reversed, false if the tag is missing or of another type, or an
+/// error if the data is malformed.
+///
+pub fn reverse(aux: &mut [u8], tag: &Tag) -> Result<bool, Box<dyn error::Error>> {
+    match find(aux, tag)? {
+        Some(field) => Ok(reverse_field(aux, field)),
+        None => Ok(false),
+    }
+}
+
+/// Returns true if an operation applies to a field's value type.
+///
+/// Reversal applies to `Z` strings and `B` arrays of any element type; reverse complementing
+/// and complementing apply to `Z` strings and `B:C` byte arrays; colorspace reversal applies to
+/// `Z` strings; renaming applies to every type; conversion applies to `Z` strings and `B:C`
+/// byte arrays. Fields of other types are left untouched.
+///
+/// # Arguments
+///
+/// * `operation` - The operation to check
+/// * `field` - The located field
+///
+/// # Returns
+///
+/// Returns whether applying `operation` would transform the field's value.
+///
+pub fn supports(operation: Operation, field: &Field) -> bool {
+    match operation {
+        Operation::Reverse => matches!(field.value_type, b'Z' | b'B'),
+        Operation::ReverseComplement | Operation::Complement => {
+            matches!(
+                (field.value_type, field.subtype),
+                (b'Z', _) | (b'B', Some(b'C'))
+            )
+        }
+        Operation::ReverseColorspace => field.value_type == b'Z',
+        Operation::Rename(_) => true,
+        Operation::Convert(_) => matches!(
+            (field.value_type, field.subtype),
+            (b'Z', _) | (b'B', Some(b'C'))
+        ),
+    }
+}
+
+/// Returns true if a located field already holds its value in `encoding`.
+fn encoded_as(field: &Field, encoding: Encoding) -> bool {
+    match encoding {
+        Encoding::String => field.value_type == b'Z',
+        Encoding::ByteArray => (field.value_type, field.subtype) == (b'B', Some(b'C')),
+    }
+}
+
+/// Returns the type and value of a located `Z` string or `B:C` array field re-encoded as
+/// `encoding`, or None if the field already has that encoding or is of another type.
+fn reencode_field(
+    aux: &[u8],
+    field: &Field,
+    encoding: Encoding,
+) -> Result<Option<Vec<u8>>, Box<dyn error::Error>> {
+    if encoded_as(field, encoding) || !supports(Operation::Convert(encoding), field) {
+        return Ok(None);
+    }
+    let value = &aux[field.value.clone()];
+    let mut encoded = Vec::with_capacity(value.len() + 6);
+    match encoding {
+        Encoding::String => {
+            check_printable(&field.tag, value)?;
+            encoded.push(b'Z');
+            encoded.extend_from_slice(value);
+            encoded.push(0);
+        }
+        Encoding::ByteArray => {
+            encoded.extend_from_slice(b"BC");
+            encoded.extend_from_slice(&u32::try_from(value.len())?.to_le_bytes());
+            encoded.extend_from_slice(value);
+        }
+    }
+    Ok(Some(encoded))
+}
+
+/// Re-encodes the fields a plan converts between `Z` strings and `B:C` byte arrays.
+///
+/// Unlike the other operations, conversion changes a field's length, so it rebuilds the block
+/// rather than editing it in place; callers replace the record's auxiliary data with the result
+/// before applying the rest of the plan with [`apply_tracked`]. Only the first occurrence of a
+/// tag is converted unless the plan's [`DuplicateTags`] policy is `All`.
+///
+/// # Arguments
+///
+/// * `aux` - The raw auxiliary data of a BAM record
+/// * `plan` - The tag specifications, of which only conversions are applied
+///
+/// # Returns
+///
+/// Returns the rebuilt auxiliary data, or None if no field changes, or an error if the data is
+/// malformed or a byte array to convert to a string holds an unprintable byte.
+///
+pub fn convert(aux: &[u8], plan: &Plan) -> Result<Option<Vec<u8>>, Box<dyn error::Error>> {
+    let conversions: Vec<(Tag, Encoding)> = plan
+        .specs()
+        .iter()
+        .filter_map(|spec| match spec.operation {
+            Operation::Convert(encoding) => Some((spec.tag, encoding)),
+            _ => None,
+        })
+        .collect();
+    let mut converted = Vec::new();
+    let mut seen: Vec<Tag> = Vec::new();
+    let mut changed = false;
+    let mut offset = 0;
+    while offset < aux.len() && !conversions.is_empty() {
+        let (field, end) = parse_field(aux, offset)?;
+        let encoding = conversions
+            .iter()
+            .find(|(tag, _)| *tag == field.tag)
+            .map(|(_, encoding)| *encoding)
+            .filter(|_| plan.duplicate_tags() == DuplicateTags::All || !seen.contains(&field.tag));
+        let encoded = match encoding {
+            Some(encoding) => {
+                seen.push(field.tag);
+                reencode_field(aux, &field, encoding)?
+            }
+            None => None,
+        };
+        match encoded {
+            Some(encoded) => {
+                converted.extend_from_slice(&field.tag);
+                converted.extend_from_slice(&encoded);
+                changed = true;
+            }
+            None => converted.extend_from_slice(&aux[offset..end]),
+        }
+        offset = end;
+    }
+    Ok(changed.then_some(converted))
+}
+
+/// Reverses a located `B` array or `Z` string field in place, returning false for other types.
+fn reverse_field(aux: &mut [u8], field: Field) -> bool {
+    let width = match (field.value_type, field.subtype) {
+        (b'Z', _) => 1,
+        // The subtype was validated while parsing, so it always has a width.
+        (b'B', Some(subtype)) => width(subtype).unwrap_or(1),
+        _ => return false,
+    };
+    let elements = &mut aux[field.value];
+    // Reversing all bytes reverses the element order and each element's byte order, so
+    // reversing every element again restores the little-endian values.
+    elements.reverse();
+    if width > 1 {
+        elements
+            .chunks_exact_mut(width)
+            .for_each(|element| element.reverse());
+    }
+    true
+}
+
+/// Returns the value of an integer field of any width, or None for other types.
+fn integer_value(aux: &[u8], field: &Field) -> Option<i64> {
+    let bytes = &aux[field.value.clone()];
+    Some(match field.value_type {
+        b'c' => i8::from_le_bytes(bytes.try_into().ok()?) as i64,
+        b'C' => u8::from_le_bytes(bytes.try_into().ok()?) as i64,
+        b's' => i16::from_le_bytes(bytes.try_into().ok()?) as i64,
+        b'S' => u16::from_le_bytes(bytes.try_into().ok()?) as i64,
+        b'i' => i32::from_le_bytes(bytes.try_into().ok()?) as i64,
+        b'I' => u32::from_le_bytes(bytes.try_into().ok()?) as i64,
+        _ => return None,
+    })
+}
+
+/// Reverses the rows of a located `B` array field holding a row-major matrix in place, keeping
+/// each row's internal order, and returns false for other types.
+///
+/// The number of columns is fixed by the shape or read from another integer field of `aux`.
+/// The array must hold a whole number of rows, and exactly the declared number if there is one.
+fn reverse_rows(
+    aux: &mut [u8],
+    field: Field,
+    shape: MatrixShape,
+) -> Result<bool, Box<dyn error::Error>> {
+    let Some(width) = field
+        .subtype
+        .filter(|_| field.value_type == b'B')
+        .and_then(width)
+    else {
+        return Ok(false);
+    };
+    let columns = match shape.columns {
+        Columns::Fixed(columns) => Some(columns),
+        Columns::Tag(tag) => find(aux, &tag)?
+            .and_then(|field| integer_value(aux, &field))
+            .and_then(|columns| usize::try_from(columns).ok())
+            .filter(|&columns| columns > 0),
+    };
+    let elements = field.value.len() / width;
+    let fits = columns.is_some_and(|columns| {
+        elements % columns == 0 && shape.rows.is_none_or(|rows| rows * columns == elements)
+    });
+    let Some(columns) = columns.filter(|_| fits) else {
+        return Err(Box::new(MatrixShapeError {
+            tag: field.tag,
+            elements,
+            rows: shape.rows,
+            columns,
+        }));
+    };
+    // Reversing all bytes reverses the row order and each row's bytes, so reversing every row
+    // again restores its elements and their little-endian values.
+    let values = &mut aux[field.value];
+    values.reverse();
+    values
+        .chunks_exact_mut(columns * width)
+        .for_each(|row| row.reverse());
+    Ok(true)
+}
+
+/// Reverse complements a `Z` string or `B:C` byte array stored under `tag`, in place.
+///
+/// Strings are validated as ASCII before they are modified, so malformed data fails rather than
+/// producing a corrupted string.
+///
+/// # Arguments
+///
+/// * `aux` - The raw auxiliary data of a BAM record
+/// * `tag` - The SAM tag to reverse complement
+///
+/// # Returns
+///
+/// Returns true if a value was reverse complemented, false if the tag is missing or of another
+/// type, or an error if the data is malformed or the value holds a non-ASCII byte.
+///
+pub fn reverse_complement(aux: &mut [u8], tag: &Tag) -> Result<bool, Box<dyn error::Error>> {
+    match find(aux, tag)? {
+        Some(field) => reverse_complement_field(aux, field),
+        None => Ok(false),
+    }
+}
+
+/// Reverses a located `Z` string field holding a colorspace read in place, returning false for
+/// other types and for strings that are not colorspace reads.
+fn reverse_colorspace_field(aux: &mut [u8], field: Field) -> bool {
+    field.value_type == b'Z' && dna::reverse_colorspace(&mut aux[field.value])
+}
+
+/// Reverse complements a located `Z` string or `B:C` array field in place, returning false for
+/// other types.
+fn reverse_complement_field(aux: &mut [u8], field: Field) -> Result<bool, Box<dyn error::Error>> {
+    match sequence_value(aux, field)? {
+        Some(value) => {
+            dna::revcomp_in_place(value);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Complements a located `Z` string or `B:C` array field in place without reversing it,
+/// returning false for other types.
+fn complement_field(aux: &mut [u8], field: Field) -> Result<bool, Box<dyn error::Error>> {
+    match sequence_value(aux, field)? {
+        Some(value) => {
+            value
+                .iter_mut()
+                .for_each(|base| *base = dna::complement(*base));
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Returns the bases of a located `Z` string or `B:C` array field, or None for other types.
+///
+/// Strings are validated as ASCII before they are returned, so malformed data fails rather than
+/// producing a corrupted string.
+fn sequence_value(
+    aux: &mut [u8],
+    field: Field,
+) -> Result<Option<&mut [u8]>, Box<dyn error::Error>> {
+    let is_string = match (field.value_type, field.subtype) {
+        (b'Z', _) => true,
+        (b'B', Some(b'C')) => false,
+        _ => return Ok(None),
+    };
+    let value = &mut aux[field.value];
+    let non_ascii = if is_string {
+        value.iter().position(|b| !b.is_ascii())
+    } else {
+        None
+    };
+    if let Some(position) = non_ascii {
+        return Err(Box::new(NonAsciiError {
+            tag: field.tag,
+            position,
+            byte: value[position],
+        }));
+    }
+    Ok(Some(value))
+}
+
+/// Handles a located field to reverse complement that does not hold a sequence as directed by
+/// `policy`, returning whether the value was changed.
+fn apply_nonsequence(
+    aux: &mut [u8],
+    field: Field,
+    policy: NonSequence,
+) -> Result<bool, Box<dyn error::Error>> {
+    match policy {
+        NonSequence::Error => Err(format!(
+            "Tag {} holds a value of type {} that is not a sequence and cannot be reverse \
+             complemented",
+            show(&field.tag),
+            type_name(field.value_type, field.subtype)
+        )
+        .into()),
+        NonSequence::ReverseOnly => Ok(reverse_field(aux, field)),
+        NonSequence::Skip => Ok(false),
+    }
+}
+
+/// Applies every tag specification in a plan to the fields of a raw auxiliary data block.
+///
+/// The block is walked once and each field is transformed in place as it is reached. In-place
+/// edits never change a field's length, so the walk stays valid while values are rewritten.
+///
+/// # Arguments
+///
+/// * `aux` - The raw auxiliary data of a BAM record
+/// * `plan` - The tag specifications to apply
+///
+/// # Returns
+///
+/// Returns the number of fields transformed, or an error if the data is malformed, a string to
+/// reverse complement holds a non-ASCII byte, a value to reverse complement is not a sequence
+/// under [`NonSequence::Error`], a matrix does not fit its shape, or a planned tag occurs more
+/// than once under [`DuplicateTags::Error`].
+///
+pub fn apply(aux: &mut [u8], plan: &Plan) -> Result<usize, Box<dyn error::Error>> {
+    apply_tracked(aux, plan, &mut Unsupported::default())
+}
+
+/// Applies a plan as [`apply`] does, counting planned fields whose type does not support their
+/// operation in `unsupported`.
+///
+/// Values to reverse complement that are not sequences are handled as directed by the plan's
+/// [`NonSequence`] policy, and planned tags occurring more than once by its [`DuplicateTags`]
+/// policy.
+///
+/// # Arguments
+///
+/// * `aux` - The raw auxiliary data of a BAM record
+/// * `plan` - The tag specifications to apply
+/// * `unsupported` - Counts of planned fields left untouched, updated in place
+///
+/// # Returns
+///
+/// Returns the number of fields transformed, or an error if the data is malformed, a string to
+/// reverse complement holds a non-ASCII byte, a value to reverse complement is not a sequence
+/// under [`NonSequence::Error`], a matrix does not fit its shape, or a planned tag occurs more
+/// than once under [`DuplicateTags::Error`].
+///
+pub fn apply_tracked(
+    aux: &mut [u8],
+    plan: &Plan,
+    unsupported: &mut Unsupported,
+) -> Result<usize, Box<dyn error::Error>> {
+    let policy = plan.duplicate_tags();
+    let mut applied = vec![false; plan.specs().len()];
+    let mut transformed = 0;
+    let mut seen = 0;
+    let mut offset = 0;
+    // Under DuplicateTags::First the walk stops once every planned tag was seen; otherwise it
+    // continues to find later occurrences.
+    while offset < aux.len() && (seen < applied.len() || policy != DuplicateTags::First) {
+        let (field, end) = parse_field(aux, offset)?;
+        let specs = plan.specs().iter().enumerate();
+        for (index, spec) in specs.filter(|(_, spec)| spec.tag == field.tag) {
+            if applied[index] {
+                match policy {
+                    DuplicateTags::First => continue,
+                    DuplicateTags::All => {}
+                    DuplicateTags::Error => {
+                        return Err(Box::new(DuplicateTagError { tag: field.tag }));
+                    }
+                }
+            } else {
+                applied[index] = true;
+                seen += 1;
+            }
+            let changed = match spec.operation {
+                Operation::Reverse => match plan.matrix_shape(&field.tag) {
+                    Some(shape) => reverse_rows(aux, field.clone(), shape)?,
+                    None => reverse_field(aux, field.clone()),
+                },
+                Operation::ReverseComplement if !supports(Operation::ReverseComplement, &field) => {
+                    apply_nonsequence(aux, field.clone(), plan.nonsequence())?
+                }
+                Operation::ReverseComplement => reverse_complement_field(aux, field.clone())?,
+                Operation::ReverseColorspace => reverse_colorspace_field(aux, field.clone()),
+                Operation::Complement => complement_field(aux, field.clone())?,
+                Operation::Rename(tag) => {
+                    aux[offset..offset + 2].copy_from_slice(&tag);
+                    true
+                }
+                // The field was re-encoded by `convert` before this in-place pass.
+                Operation::Convert(encoding) => encoded_as(&field, encoding),
+            };
+            if changed {
+                transformed += 1;
+            } else {
+                unsupported.add(*spec, field.value_type, field.subtype, 1);
+            }
+        }
+        offset = end;
+    }
+    Ok(transformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::Preset;
+
+    /// Builds a `B` array field from a subtype and little-endian element bytes.
+    fn array(tag: &Tag, subtype: u8, count: u32, elements: &[u8]) -> Vec<u8> {
+        let mut field = vec![tag[0], tag[1], b'B', subtype];
+        field.extend_from_slice(&count.to_le_bytes());
+        field.extend_from_slice(elements);
+        field
+    }
+
+    #[test]
+    fn test_fields() {
+        let mut aux = b"NMC\x03XYZHI\0".to_vec();
+        aux.extend(array(b"QT", b'S', 2, &[1, 0, 2, 0]));
+        aux.extend_from_slice(b"ASi\x01\x00\x00\x00");
+
+        let fields: Vec<Field> = fields(&aux).collect::<Result<_, _>>().unwrap();
+        let tags: Vec<&Tag> = fields.iter().map(|f| &f.tag).collect();
+        assert_eq!(tags, vec![b"NM", b"XY", b"QT", b"AS"]);
+        assert_eq!(&aux[fields[1].value.clone()], b"HI");
+        assert_eq!(fields[2].subtype, Some(b'S'));
+        assert_eq!(&aux[fields[2].value.clone()], &[1, 0, 2, 0]);
+    }
+
+    #[test]
+    fn test_find_missing() {
+        let aux = b"NMC\x03".to_vec();
+        assert_eq!(find(&aux, b"QT").unwrap(), None);
+    }
+
+    #[test]
+    fn test_fields_truncated() {
+        let aux = array(b"QT", b'I', 3, &[1, 0, 0, 0]);
+        assert!(find(&aux, b"ZZ").is_err());
+        assert!(find(b"XYZHI", b"ZZ").is_err());
+    }
+
+    #[test]
+    fn test_fields_invalid_type() {
+        assert!(find(b"XYQ\x01", b"ZZ").is_err());
+        assert!(find(&array(b"QT", b'Z', 0, &[]), b"ZZ").is_err());
+    }
+
+    #[test]
+    fn test_any_tag_ignores_tag_bytes_inside_values() {
+        let aux = b"XYZQTBC\0NMC\x03".to_vec();
+        assert!(!any_tag(&aux, |tag| tag == b"QT" || tag == b"BC").unwrap());
+        assert!(any_tag(&aux, |tag| tag == b"NM").unwrap());
+        assert!(!any_tag(&[], |_| true).unwrap());
+    }
+
+    #[test]
+    fn test_minus_strand() {
+        assert!(minus_strand(b"XSA-", b"XS").unwrap());
+        assert!(minus_strand(b"NMC\x03XSZ-\0", b"XS").unwrap());
+        assert!(!minus_strand(b"XSA+", b"XS").unwrap());
+        assert!(!minus_strand(b"XSZ--\0", b"XS").unwrap());
+        assert!(!minus_strand(b"XSc-", b"XS").unwrap());
+        assert!(!minus_strand(b"NMC\x03", b"XS").unwrap());
+    }
+
+    #[test]
+    fn test_duplex_b_strand() {
+        assert!(duplex_b_strand(b"MIZ17/B\0").unwrap());
+        assert!(duplex_b_strand(b"NMC\x03MIZ17/B\0").unwrap());
+        assert!(!duplex_b_strand(b"MIZ17/A\0").unwrap());
+        assert!(!duplex_b_strand(b"MIZ17\0").unwrap());
+        assert!(!duplex_b_strand(b"MIC\x11").unwrap());
+        assert!(!duplex_b_strand(b"NMC\x03").unwrap());
+    }
+
+    #[test]
+    fn test_missing_tag() {
+        let aux = b"QTZAB\0NMC\x03".to_vec();
+        assert_eq!(missing_tag(&aux, [b"QT", b"NM"]).unwrap(), None);
+        assert_eq!(
+            missing_tag(&aux, [b"QT", b"BC", b"XX"]).unwrap(),
+            Some(*b"BC")
+        );
+        assert_eq!(missing_tag(&[], [b"QT"]).unwrap(), Some(*b"QT"));
+
+        let error = MissingTagError {
+            qname: "read1".into(),
+            tag: *b"BC",
+        };
+        assert_eq!(error.to_string(), "Record read1 is missing required tag BC");
+    }
+
+    #[test]
+    fn test_value_length() {
+        let mut aux = b"BCZACGT\0NMC\x03".to_vec();
+        aux.extend(array(b"QT", b's', 3, &[1, 0, 2, 0, 3, 0]));
+        let lengths: Vec<usize> = fields(&aux).map(|f| value_length(&f.unwrap())).collect();
+        assert_eq!(lengths, vec![4, 1, 3]);
+    }
+
+    #[test]
+    fn test_check_pairs() {
+        let plan = Plan::default().with_pairs(&["BC:QT".to_string()]).unwrap();
+        let qname = || "read1".to_string();
+        let check = |plan: &Plan, aux: &[u8]| {
+            check_pairs(plan, qname, |tag| {
+                Ok(find(aux, tag)?.map(|field| value_length(&field)))
+            })
+        };
+
+        assert!(check(&plan, b"BCZACGT\0QTZABCD\0").is_ok());
+        assert!(check(&plan, b"NMC\x03").is_ok());
+        assert_eq!(
+            check(&plan, b"QTZABCD\0").unwrap_err().to_string(),
+            "Record read1 has tag QT without its companion tag BC"
+        );
+        let mismatched = b"BCZACGT\0QTZABC\0";
+        assert_eq!(
+            check(&plan, mismatched).unwrap_err().to_string(),
+            "Record read1 has tag BC of length 4 but companion tag QT of length 3"
+        );
+        let plan = plan.with_pair_mismatch(PairMismatch::Warn);
+        assert!(check(&plan, mismatched).is_ok());
+    }
+
+    #[test]
+    fn test_check_values() {
+        let plan = Plan::default().with_preset(Preset::Bismark).unwrap();
+        let check = |aux: &[u8]| {
+            check_values(
+                &plan,
+                || "read1".to_string(),
+                |tag| Ok(find(aux, tag)?.map(|field| aux[field.value].to_vec())),
+            )
+        };
+
+        assert!(check(b"XRZCT\0XGZGA\0").is_ok());
+        assert!(check(b"NMC\x03").is_ok());
+        assert_eq!(
+            check(b"XRZCT\0XGZAG\0").unwrap_err().to_string(),
+            "Record read1 has tag XG with value \"AG\"; expected one of CT, GA"
+        );
+    }
+
+    #[test]
+    fn test_reverse_array_respects_width() {
+        let mut aux = b"NMC\x03".to_vec();
+        let values: Vec<u8> = [1u16, 2, 0x0304]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        aux.extend(array(b"QT", b'S', 3, &values));
+        aux.extend_from_slice(b"XYZHI\0");
+
+        assert!(reverse(&mut aux, b"QT").unwrap());
+
+        let field = find(&aux, b"QT").unwrap().unwrap();
+        let reversed: Vec<u16> = aux[field.value]
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(reversed, vec![0x0304, 2, 1]);
+        assert!(aux.starts_with(b"NMC\x03") && aux.ends_with(b"XYZHI\0"));
+    }
+
+    #[test]
+    fn test_reverse_matrix_rows() {
+        let values: Vec<u8> = [1u16, 2, 3, 4, 5, 6]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let mut aux = array(b"SG", b'S', 6, &values);
+        aux.extend_from_slice(b"NCC\x02");
+        let rows = |aux: &[u8]| -> Vec<u16> {
+            let field = find(aux, b"SG").unwrap().unwrap();
+            aux[field.value]
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                .collect()
+        };
+
+        let plan = Plan::default()
+            .with_matrices(&["SG:*xNC".to_string()])
+            .unwrap();
+        assert_eq!(apply(&mut aux, &plan).unwrap(), 1);
+        assert_eq!(rows(&aux), vec![5, 6, 3, 4, 1, 2]);
+
+        let plan = Plan::default()
+            .with_matrices(&["SG:2x3".to_string()])
+            .unwrap();
+        assert_eq!(apply(&mut aux, &plan).unwrap(), 1);
+        assert_eq!(rows(&aux), vec![4, 1, 2, 5, 6, 3]);
+
+        for (token, message) in [
+            (
+                "SG:*x4",
+                "Matrix tag SG has 6 elements, not a whole number of rows of 4 columns",
+            ),
+            (
+                "SG:3x3",
+                "Matrix tag SG has 6 elements, not 3 rows of 3 columns",
+            ),
+            ("SG:*xXX", "Matrix tag SG has no number of columns"),
+        ] {
+            let plan = Plan::default().with_matrices(&[token.to_string()]).unwrap();
+            let err = apply(&mut aux, &plan).unwrap_err().to_string();
+            assert!(err.starts_with(message), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_reverse_string_bytewise() {
+        let mut aux = b"XYZHELLO\0NMC\x03".to_vec();
+        assert!(reverse(&mut aux, b"XY").unwrap());
+        assert_eq!(aux, b"XYZOLLEH\0NMC\x03");
+    }
+
+    #[test]
+    fn test_reverse_complement_string() {
+        let mut aux = b"BCZAtCgN\0NMC\x03".to_vec();
+        assert!(reverse_complement(&mut aux, b"BC").unwrap());
+        assert_eq!(aux, b"BCZNcGaT\0NMC\x03");
+        assert!(!reverse_complement(&mut aux, b"NM").unwrap());
+    }
+
+    #[test]
+    fn test_reverse_colorspace_string() {
+        let mut aux = b"CSZT3021\0CQZ#+5?\0".to_vec();
+        let plan = Plan::parse(&[], &[], &["CS:revcs".to_string(), "CQ:rev".to_string()]).unwrap();
+        assert_eq!(apply(&mut aux, &plan).unwrap(), 2);
+        assert_eq!(aux, b"CSZA1203\0CQZ?5+#\0");
+    }
+
+    #[test]
+    fn test_reverse_complement_byte_array() {
+        let mut aux = array(b"BC", b'C', 4, b"ATCG");
+        aux.extend(array(b"QT", b'S', 1, &[b'A', 0]));
+        assert!(reverse_complement(&mut aux, b"BC").unwrap());
+        assert!(!reverse_complement(&mut aux, b"QT").unwrap());
+
+        let mut expected = array(b"BC", b'C', 4, b"CGAT");
+        expected.extend(array(b"QT", b'S', 1, &[b'A', 0]));
+        assert_eq!(aux, expected);
+    }
+
+    #[test]
+    fn test_reverse_complement_rejects_non_ascii() {
+        let mut aux = "BCZAC\u{e9}\0".as_bytes().to_vec();
+        let original = aux.clone();
+        let err = reverse_complement(&mut aux, b"BC").unwrap_err();
+        let err = err.downcast_ref::<NonAsciiError>().unwrap();
+        assert_eq!((err.tag, err.position, err.byte), (*b"BC", 2, 0xC3));
+        assert_eq!(aux, original);
+    }
+
+    #[test]
+    fn test_apply_plan_in_one_pass() {
+        let mut aux = b"BCZGATT\0NMC\x03MNZHELLO\0".to_vec();
+        aux.extend(array(b"QT", b'C', 3, &[1, 2, 3]));
+        let plan = Plan::new(
+            &["QT".to_string(), "MN".to_string(), "XX".to_string()],
+            &["BC".to_string()],
+        )
+        .unwrap();
+
+        assert_eq!(apply(&mut aux, &plan).unwrap(), 3);
+
+        let mut expected = b"BCZAATC\0NMC\x03MNZOLLEH\0".to_vec();
+        expected.extend(array(b"QT", b'C', 3, &[3, 2, 1]));
+        assert_eq!(aux, expected);
+    }
+
+    #[test]
+    fn test_apply_chain() {
+        let mut aux = b"XBZAACG\0NMC\x03".to_vec();
+        let plan = Plan::parse(&[], &[], &["XB:comp,rev,rename=BC".to_string()]).unwrap();
+        assert_eq!(apply(&mut aux, &plan).unwrap(), 3);
+        assert_eq!(aux, b"BCZCGTT\0NMC\x03");
+
+        let mut aux = b"XBZAACG\0".to_vec();
+        let plan = Plan::parse(&[], &[], &["XB:comp".to_string()]).unwrap();
+        apply(&mut aux, &plan).unwrap();
+        assert_eq!(aux, b"XBZTTGC\0");
+    }
+
+    #[test]
+    fn test_convert() {
+        let plan = Plan::parse(&[], &[], &["XB:as=B:C".to_string()]).unwrap();
+        let converted = convert(b"NMC\x03XBZACG\0", &plan).unwrap().unwrap();
+        assert_eq!(converted, b"NMC\x03XBBC\x03\0\0\0ACG");
+        assert_eq!(convert(&converted, &plan).unwrap(), None);
+        assert_eq!(convert(b"NMC\x03", &plan).unwrap(), None);
+
+        let plan = Plan::parse(&[], &[], &["XB:revcomp,as=Z".to_string()]).unwrap();
+        let mut aux = convert(&converted, &plan).unwrap().unwrap();
+        assert_eq!(aux, b"NMC\x03XBZACG\0");
+        assert_eq!(apply(&mut aux, &plan).unwrap(), 2);
+        assert_eq!(aux, b"NMC\x03XBZCGT\0");
+
+        let error = convert(&array(b"XB", b'C', 2, &[b'A', 0]), &plan).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Tag XB holds unprintable byte 0x00 at position 1 and cannot be converted to a Z string"
+        );
+
+        let mut unconverted = b"XBZACG\0".to_vec();
+        let plan = Plan::parse(&[], &[], &["XB:as=B:C".to_string()]).unwrap();
+        let mut unsupported = Unsupported::default();
+        assert_eq!(
+            apply_tracked(&mut unconverted, &plan, &mut unsupported).unwrap(),
+            0
+        );
+        assert_eq!(unsupported.types()[0].count, 1);
+    }
+
+    #[test]
+    fn test_apply_duplicate_tags() {
+        let aux = b"QTZABC\0QTZDEF\0BCZAAC\0".to_vec();
+        let plan = Plan::new(&["QT".to_string()], &["BC".to_string()]).unwrap();
+
+        let mut first = aux.clone();
+        assert_eq!(apply(&mut first, &plan).unwrap(), 2);
+        assert_eq!(first, b"QTZCBA\0QTZDEF\0BCZGTT\0");
+
+        let mut all = aux.clone();
+        let plan = plan.with_duplicate_tags(DuplicateTags::All);
+        assert_eq!(apply(&mut all, &plan).unwrap(), 3);
+        assert_eq!(all, b"QTZCBA\0QTZFED\0BCZGTT\0");
+
+        let mut error = aux.clone();
+        let plan = plan.with_duplicate_tags(DuplicateTags::Error);
+        assert_eq!(
+            apply(&mut error, &plan).unwrap_err().to_string(),
+            "Tag QT occurs more than once on a record"
+        );
+    }
+
+    #[test]
+    fn test_apply_tracked_counts_unsupported_types() {
+        let mut aux = b"QTi\x01\0\0\0".to_vec();
+        aux.extend(array(b"BC", b's', 1, &[1, 0]));
+        let plan = Plan::new(&["QT".to_string()], &["BC".to_string()]).unwrap();
+
+        let mut unsupported = Unsupported::default();
+        for _ in 0..2 {
+            let original = aux.clone();
+            assert_eq!(apply_tracked(&mut aux, &plan, &mut unsupported).unwrap(), 0);
+            assert_eq!(aux, original);
+        }
+
+        let counts: Vec<(Tag, u8, Option<u8>, u64)> = unsupported
+            .types()
+            .iter()
+            .map(|t| (t.spec.tag, t.value_type, t.subtype, t.count))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![(*b"QT", b'i', None, 2), (*b"BC", b'B', Some(b's'), 2)]
+        );
+        assert_eq!(
+            unsupported.types()[1].to_string(),
+            "Left 2 values of tag BC unchanged: type B:s cannot be reverse complemented"
+        );
+
+        let mut merged = unsupported.clone();
+        merged.merge(&unsupported);
+        assert_eq!(merged.types()[0].count, 4);
+        assert!(Unsupported::default().is_empty());
+    }
+
+    #[test]
+    fn test_apply_nonsequence_policy() {
+        let field = array(b"XS", b's', 2, &[1, 0, 2, 0]);
+        let plan = Plan::new(&[], &["XS".to_string()]).unwrap();
+
+        let mut aux = field.clone();
+        assert_eq!(apply(&mut aux, &plan).unwrap(), 0);
+        assert_eq!(aux, field);
+
+        let mut aux = field.clone();
+        let reverse_only = plan.clone().with_nonsequence(NonSequence::ReverseOnly);
+        assert_eq!(apply(&mut aux, &reverse_only).unwrap(), 1);
+        assert_eq!(aux, array(b"XS", b's', 2, &[2, 0, 1, 0]));
+
+        let mut aux = field.clone();
+        let error = plan.with_nonsequence(NonSequence::Error);
+        let err = apply(&mut aux, &error).unwrap_err();
+        assert!(err.to_string().contains("Tag XS holds a value of type B:s"));
+        assert_eq!(aux, field);
+    }
+
+    #[test]
+    fn test_supports() {
+        let mut aux = b"QTZAB\0".to_vec();
+        aux.extend(array(b"XS", b's', 1, &[1, 0]));
+        aux.extend(array(b"XC", b'C', 1, &[65]));
+        aux.extend_from_slice(b"NMi\x01\0\0\0");
+        let fields: Vec<Field> = fields(&aux).collect::<Result<_, _>>().unwrap();
+
+        let reversible: Vec<bool> = fields
+            .iter()
+            .map(|f| supports(Operation::Reverse, f))
+            .collect();
+        assert_eq!(reversible, vec![true, true, true, false]);
+        let complementable: Vec<bool> = fields
+            .iter()
+            .map(|f| supports(Operation::ReverseComplement, f))
+            .collect();
+        assert_eq!(complementable, vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn test_reverse_skips_other_types() {
+        let mut aux = b"XYH1AFF\0NMC\x03".to_vec();
+        assert!(!reverse(&mut aux, b"XY").unwrap());
+        assert!(!reverse(&mut aux, b"NM").unwrap());
+        assert!(!reverse(&mut aux, b"QT").unwrap());
+        assert_eq!(aux, b"XYH1AFF\0NMC\x03");
+    }
+}
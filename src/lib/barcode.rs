@@ -0,0 +1,141 @@
+//! A FASTQ sidecar of barcodes and their qualities, for demultiplexers that only read FASTQ.
+use log::*;
+use rust_htslib::bam::Record;
+use rust_htslib::bgzf;
+use std::error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::aux;
+use crate::ops::aux_data;
+use crate::plan::Tag;
+
+/// Writes the barcode and barcode quality tags of records as FASTQ named by read name.
+pub struct BarcodeWriter {
+    writer: Box<dyn Write>,
+    barcode_tag: Tag,
+    quality_tag: Tag,
+    written: u64,
+}
+
+impl BarcodeWriter {
+    /// Creates a barcode FASTQ, BGZF compressed if the path ends in `.gz`.
+    ///
+    /// BGZF is gzip compatible, so the output can be read by any gzip-aware FASTQ reader.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The FASTQ file to create
+    /// * `barcode_tag` - The `Z` tag holding each record's barcode
+    /// * `quality_tag` - The `Z` tag holding each barcode's qualities
+    ///
+    /// # Returns
+    ///
+    /// Returns the writer, or an error if the file cannot be created.
+    ///
+    pub fn create(
+        path: &Path,
+        barcode_tag: Tag,
+        quality_tag: Tag,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        info!("Barcode FASTQ: {path:?}");
+        let writer: Box<dyn Write> = if path.extension().is_some_and(|ext| ext == "gz") {
+            Box::new(bgzf::Writer::from_path(path)?)
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        Ok(Self {
+            writer,
+            barcode_tag,
+            quality_tag,
+            written: 0,
+        })
+    }
+
+    /// Writes a record's barcode and its qualities as a FASTQ record, if it carries a barcode.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The record, after its tags have been transformed
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the record has a barcode without qualities, either tag is not a `Z`
+    /// string, or writing fails.
+    ///
+    pub fn write(&mut self, record: &Record) -> Result<(), Box<dyn error::Error>> {
+        let data = aux_data(record);
+        let Some(barcode) = aux::find(data, &self.barcode_tag)? else {
+            return Ok(());
+        };
+        let qname = String::from_utf8_lossy(record.qname());
+        let Some(quality) = aux::find(data, &self.quality_tag)? else {
+            return Err(Box::new(aux::MissingTagError {
+                qname: qname.into_owned(),
+                tag: self.quality_tag,
+            }));
+        };
+        for field in [&barcode, &quality] {
+            if field.value_type != b'Z' {
+                return Err(format!(
+                    "Record {qname} holds tag {} as type {}, not a Z string",
+                    String::from_utf8_lossy(&field.tag),
+                    field.value_type as char
+                )
+                .into());
+            }
+        }
+
+        self.writer.write_all(b"@")?;
+        self.writer.write_all(record.qname())?;
+        self.writer.write_all(b"\n")?;
+        self.writer.write_all(&data[barcode.value])?;
+        self.writer.write_all(b"\n+\n")?;
+        self.writer.write_all(&data[quality.value])?;
+        self.writer.write_all(b"\n")?;
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Flushes the FASTQ and reports how many barcodes were written.
+    pub fn finish(mut self) -> Result<(), Box<dyn error::Error>> {
+        self.writer.flush()?;
+        info!("Wrote {} barcodes", self.written);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::Aux;
+
+    fn record(qname: &[u8], tags: &[(&[u8], &str)]) -> Record {
+        let mut record = Record::new();
+        record.set(qname, None, b"ACGT", &[30, 30, 30, 30]);
+        for (tag, value) in tags {
+            record.push_aux(tag, Aux::String(value)).unwrap();
+        }
+        record
+    }
+
+    #[test]
+    fn test_write_barcodes() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("barcodes.fq");
+
+        let mut writer = BarcodeWriter::create(&path, *b"BC", *b"QT").unwrap();
+        writer
+            .write(&record(b"r1", &[(b"BC", "AATC"), (b"QT", "DCBA")]))
+            .unwrap();
+        writer.write(&record(b"r2", &[(b"NM", "0")])).unwrap();
+        assert!(writer.write(&record(b"r3", &[(b"BC", "ACGT")])).is_err());
+        writer.finish().unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            "@r1\nAATC\n+\nDCBA\n"
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Batch runs over many inputs listed in a manifest, several files at a time.
+use log::*;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+
+/// One input of a batch and the output it is written to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The input SAM/BAM/CRAM file.
+    pub input: PathBuf,
+    /// The output SAM/BAM/CRAM file.
+    pub output: PathBuf,
+}
+
+/// Parses a manifest: one input and its output per line, separated by a tab. Blank lines and
+/// lines starting with `#` are skipped.
+///
+/// # Arguments
+///
+/// * `text` - The contents of the manifest
+///
+/// # Returns
+///
+/// Returns the entries in order, or an error naming the first malformed line.
+///
+pub fn parse_manifest(text: &str) -> Result<Vec<ManifestEntry>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| match line.split('\t').collect::<Vec<_>>()[..] {
+            [input, output] if !input.is_empty() && !output.is_empty() => Ok(ManifestEntry {
+                input: PathBuf::from(input),
+                output: PathBuf::from(output),
+            }),
+            _ => Err(format!(
+                "Line {} of the manifest is not an input and output separated by a tab: {line:?}",
+                i + 1
+            )),
+        })
+        .collect()
+}
+
+/// Reads and parses a manifest file; see [`parse_manifest`].
+///
+/// # Arguments
+///
+/// * `path` - The manifest file
+///
+/// # Returns
+///
+/// Returns the entries in order, or an error if the file cannot be read or is malformed.
+///
+pub fn read_manifest(path: &Path) -> Result<Vec<ManifestEntry>, Box<dyn error::Error>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read manifest {}: {e}", path.display()))?;
+    let entries = parse_manifest(&text)?;
+    if entries.is_empty() {
+        return Err(format!("The manifest {} lists no inputs", path.display()).into());
+    }
+    Ok(entries)
+}
+
+/// The outcome of a batch run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BatchSummary {
+    /// The number of inputs processed successfully.
+    pub succeeded: usize,
+    /// Each input that failed and its error, in manifest order.
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl BatchSummary {
+    /// Returns true if every input was processed successfully.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl fmt::Display for BatchSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Processed {} files: {} succeeded, {} failed",
+            self.succeeded + self.failed.len(),
+            self.succeeded,
+            self.failed.len()
+        )?;
+        for (input, error) in &self.failed {
+            write!(f, "\n  {}: {error}", input.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs every entry of a manifest, up to `jobs` at a time, each on its own thread with its own
+/// reader and writer. A failed entry does not stop the others; each is logged as it finishes
+/// with the count of entries done so far.
+///
+/// # Arguments
+///
+/// * `entries` - The inputs and outputs to process
+/// * `jobs` - The most entries processed at once
+/// * `run` - Processes a single entry, e.g. by calling [`crate::run::run`]
+///
+/// # Returns
+///
+/// Returns the combined summary of every entry.
+///
+pub fn run_batch<F>(entries: &[ManifestEntry], jobs: usize, run: F) -> BatchSummary
+where
+    F: Fn(&ManifestEntry) -> Result<(), Box<dyn error::Error>> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<(), String>>>> = Mutex::new(vec![None; entries.len()]);
+    let workers = jobs.clamp(1, entries.len().max(1));
+    info!("Processing {} files, {workers} at a time", entries.len());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(entry) = entries.get(i) else {
+                        break;
+                    };
+                    let start = Instant::now();
+                    let result = run(entry).map_err(|e| e.to_string());
+                    let finished = done.fetch_add(1, Ordering::Relaxed) + 1;
+                    match &result {
+                        Ok(()) => info!(
+                            "[{finished}/{}] Finished {:?} in {:.1?}",
+                            entries.len(),
+                            entry.input,
+                            start.elapsed()
+                        ),
+                        Err(e) => error!(
+                            "[{finished}/{}] Failed to process {:?}: {e}",
+                            entries.len(),
+                            entry.input
+                        ),
+                    }
+                    results.lock().expect("batch results lock poisoned")[i] = Some(result);
+                }
+            });
+        }
+    });
+
+    let mut summary = BatchSummary::default();
+    let results = results.into_inner().expect("batch results lock poisoned");
+    for (entry, result) in entries.iter().zip(results) {
+        match result {
+            Some(Ok(())) => summary.succeeded += 1,
+            Some(Err(e)) => summary.failed.push((entry.input.clone(), e)),
+            None => summary
+                .failed
+                .push((entry.input.clone(), "Not processed".to_string())),
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest() {
+        let entries = parse_manifest("# samples\na.bam\tout/a.bam\n\nb.bam\tout/b.bam\n").unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ManifestEntry {
+                    input: PathBuf::from("a.bam"),
+                    output: PathBuf::from("out/a.bam"),
+                },
+                ManifestEntry {
+                    input: PathBuf::from("b.bam"),
+                    output: PathBuf::from("out/b.bam"),
+                },
+            ]
+        );
+        let error = parse_manifest("a.bam\tout/a.bam\nb.bam\n").unwrap_err();
+        assert!(error.starts_with("Line 2 of the manifest"));
+    }
+
+    #[test]
+    fn test_run_batch() {
+        let entries: Vec<ManifestEntry> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|name| ManifestEntry {
+                input: PathBuf::from(format!("{name}.bam")),
+                output: PathBuf::from(format!("out/{name}.bam")),
+            })
+            .collect();
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let summary = run_batch(&entries, 2, |entry| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(std::time::Duration::from_millis(20));
+            running.fetch_sub(1, Ordering::SeqCst);
+            if entry.input == Path::new("c.bam") {
+                return Err("truncated file".into());
+            }
+            Ok(())
+        });
+
+        assert!(most.load(Ordering::SeqCst) <= 2);
+        assert_eq!(summary.succeeded, 3);
+        assert_eq!(
+            summary.failed,
+            vec![(PathBuf::from("c.bam"), "truncated file".to_string())]
+        );
+        assert!(!summary.is_success());
+        assert_eq!(
+            summary.to_string(),
+            "Processed 4 files: 3 succeeded, 1 failed\n  c.bam: truncated file"
+        );
+    }
+}
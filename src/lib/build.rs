@@ -0,0 +1,119 @@
+//! Metadata describing how this build of revtag was compiled.
+use crate::config::Json;
+
+/// Returns the git commit this build was compiled from, if it was built from a git checkout.
+pub fn git_hash() -> Option<&'static str> {
+    option_env!("REVTAG_GIT_HASH")
+}
+
+/// Returns the target triple this build was compiled for, e.g. `x86_64-unknown-linux-gnu`.
+pub fn target() -> &'static str {
+    env!("REVTAG_TARGET")
+}
+
+/// Returns the version of the htslib linked into this build, if built with the `htslib`
+/// feature.
+pub fn htslib_version() -> Option<String> {
+    #[cfg(feature = "htslib")]
+    {
+        // SAFETY: hts_version returns a pointer to a static NUL-terminated string.
+        let version = unsafe { std::ffi::CStr::from_ptr(rust_htslib::htslib::hts_version()) };
+        Some(version.to_string_lossy().into_owned())
+    }
+    #[cfg(not(feature = "htslib"))]
+    None
+}
+
+/// Returns the names of the optional cargo features enabled in this build.
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "ffi") {
+        features.push("ffi");
+    }
+    if cfg!(feature = "htslib") {
+        features.push("htslib");
+    }
+    if cfg!(feature = "libdeflate") {
+        features.push("libdeflate");
+    }
+    if cfg!(feature = "noodles") {
+        features.push("noodles");
+    }
+    if cfg!(feature = "plugins") {
+        features.push("plugins");
+    }
+    if cfg!(feature = "remote") {
+        features.push("remote");
+    }
+    if cfg!(feature = "scripting") {
+        features.push("scripting");
+    }
+    if cfg!(feature = "test-utils") {
+        features.push("test-utils");
+    }
+    if cfg!(feature = "tracing") {
+        features.push("tracing");
+    }
+    features
+}
+
+/// Returns the version string shown by `--version`, including the enabled features.
+pub fn long_version() -> String {
+    format!(
+        "{}\nfeatures: {}",
+        env!("CARGO_PKG_VERSION"),
+        features().join(", ")
+    )
+}
+
+/// Returns the build metadata shown by `--version-json`: the crate version, git commit,
+/// linked htslib version, enabled features, and target triple, for provenance tracking.
+pub fn version_json() -> String {
+    let text = |value: &str| Json::String(value.to_string());
+    let json = Json::Object(vec![
+        ("name", text(env!("CARGO_PKG_NAME"))),
+        ("version", text(env!("CARGO_PKG_VERSION"))),
+        ("git_hash", Json::optional(git_hash(), text)),
+        (
+            "htslib_version",
+            Json::optional(htslib_version(), |v| text(&v)),
+        ),
+        (
+            "features",
+            Json::Array(features().into_iter().map(text).collect()),
+        ),
+        ("target", text(target())),
+    ]);
+    let mut out = String::new();
+    json.write(&mut out, 0);
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_version_lists_features() {
+        let version = long_version();
+        assert!(version.starts_with(env!("CARGO_PKG_VERSION")));
+        for feature in features() {
+            assert!(version.contains(feature));
+        }
+    }
+
+    #[test]
+    fn test_version_json() {
+        let json = version_json();
+        assert!(json.contains(&format!("\"version\": \"{}\"", env!("CARGO_PKG_VERSION"))));
+        assert!(json.contains(&format!("\"target\": \"{}\"", target())));
+        assert_eq!(
+            json.contains("\"htslib_version\": null"),
+            cfg!(not(feature = "htslib"))
+        );
+        for feature in features() {
+            assert!(json.contains(&format!("\"{feature}\"")));
+        }
+    }
+}
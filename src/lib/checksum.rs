@@ -0,0 +1,186 @@
+//! MD5 checksums of outputs, computed as they are written and saved as `.md5` sidecar files.
+//!
+//! `ChecksumPipe`, which feeds htslib writers through a `/dev/fd` pipe, is enabled by the
+//! `htslib` feature; the rest of the module is portable.
+use md5::{Digest, Md5};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Write};
+#[cfg(feature = "htslib")]
+use std::io::{BufWriter, PipeWriter};
+#[cfg(feature = "htslib")]
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "htslib")]
+use std::thread::{self, JoinHandle};
+
+/// A writer that computes the MD5 checksum of every byte written through it.
+#[derive(Debug)]
+pub struct Md5Writer<W> {
+    inner: W,
+    hasher: Md5,
+}
+
+impl<W: Write> Md5Writer<W> {
+    /// Wraps a writer.
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Md5::new(),
+        }
+    }
+
+    /// Returns the wrapped writer and the lowercase hex MD5 checksum of all bytes written.
+    pub fn finish(self) -> (W, String) {
+        (self.inner, format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for Md5Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Returns the path of the checksum sidecar of an output: the output path with `.md5` appended.
+pub fn sidecar_path(output: &Path) -> PathBuf {
+    let mut path = OsString::from(output.as_os_str());
+    path.push(".md5");
+    PathBuf::from(path)
+}
+
+/// Writes the checksum sidecar of an output in the format of `md5sum`, so `md5sum -c` verifies
+/// the output from its directory.
+///
+/// # Arguments
+///
+/// * `output` - The output the checksum is of
+/// * `digest` - The lowercase hex MD5 checksum of the output
+///
+/// # Returns
+///
+/// Returns an error if the sidecar cannot be written.
+///
+pub fn write_sidecar(output: &Path, digest: &str) -> io::Result<()> {
+    let name = output.file_name().unwrap_or(output.as_os_str());
+    let mut sidecar = File::create(sidecar_path(output))?;
+    writeln!(sidecar, "{digest}  {}", name.to_string_lossy())
+}
+
+/// An output file fed through a pipe, whose bytes are checksummed on a separate thread as they
+/// are written, so writers that only accept a path, such as htslib's, get a checksum without a
+/// second read of the output.
+///
+/// Open the writer on [`ChecksumPipe::path`], close it, then call [`ChecksumPipe::finish`] to
+/// wait for the output and its `.md5` sidecar to be complete.
+#[cfg(feature = "htslib")]
+#[derive(Debug)]
+pub struct ChecksumPipe {
+    path: PathBuf,
+    writer: PipeWriter,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+#[cfg(feature = "htslib")]
+impl ChecksumPipe {
+    /// Creates an output file and the pipe feeding it.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - The output file to create
+    ///
+    /// # Returns
+    ///
+    /// Returns the pipe, or an error if the output or pipe cannot be created.
+    ///
+    pub fn create(output: &Path) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(output)?);
+        let (mut reader, writer) = io::pipe()?;
+        let path = PathBuf::from(format!("/dev/fd/{}", writer.as_raw_fd()));
+        let output = output.to_path_buf();
+        let thread = thread::spawn(move || {
+            let mut sink = Md5Writer::new(file);
+            io::copy(&mut reader, &mut sink)?;
+            let (mut file, digest) = sink.finish();
+            file.flush()?;
+            write_sidecar(&output, &digest)
+        });
+        Ok(Self {
+            path,
+            writer,
+            thread,
+        })
+    }
+
+    /// Returns the path to open the writer on, a `/dev/fd` path for the write end of the pipe.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Closes the pipe and waits for the output and its sidecar to be written; every writer
+    /// opened on [`ChecksumPipe::path`] must be closed first.
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the output or its sidecar could not be written.
+    ///
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.writer);
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("The checksum thread panicked")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_md5_writer() {
+        let mut writer = Md5Writer::new(Vec::new());
+        writer.write_all(b"The quick brown fox ").unwrap();
+        writer.write_all(b"jumps over the lazy dog").unwrap();
+        let (bytes, digest) = writer.finish();
+        assert_eq!(bytes, b"The quick brown fox jumps over the lazy dog");
+        assert_eq!(digest, "9e107d9d372bb6826bd81d3542a419d6");
+    }
+
+    #[test]
+    fn test_sidecar_path() {
+        assert_eq!(
+            sidecar_path(Path::new("dir/out.bam")),
+            PathBuf::from("dir/out.bam.md5")
+        );
+    }
+
+    #[cfg(feature = "htslib")]
+    #[test]
+    fn test_checksum_pipe() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let output = tmpdir.path().join("out.sam");
+        let pipe = ChecksumPipe::create(&output).unwrap();
+        let mut writer = File::create(pipe.path()).unwrap();
+        writer
+            .write_all(b"The quick brown fox jumps over the lazy dog")
+            .unwrap();
+        drop(writer);
+        pipe.finish().unwrap();
+
+        assert_eq!(
+            fs::read(&output).unwrap(),
+            b"The quick brown fox jumps over the lazy dog"
+        );
+        assert_eq!(
+            fs::read_to_string(sidecar_path(&output)).unwrap(),
+            "9e107d9d372bb6826bd81d3542a419d6  out.sam\n"
+        );
+    }
+}
@@ -0,0 +1,652 @@
+//! Per-record conditions, as given to `--if`, deciding whether the plan applies to a record.
+//!
+//! A condition combines comparisons and tests with `&&`, `||`, `!`, and parentheses:
+//!
+//! ```text
+//! mapq >= 20 && !flag.secondary && has(BC)
+//! tag(XS) == "-" || (flag.read2 && tlen < 0)
+//! ```
+//!
+//! `mapq`, `pos` (1-based, 0 when unplaced), `tlen`, and `flag` are integers, compared with
+//! `==`, `!=`, `<`, `<=`, `>`, or `>=` against integers or each other. `flag.NAME` tests one
+//! bit of the flag, named as in [`FLAGS`]. `has(TAG)` tests whether a record carries a tag, and
+//! `tag(TAG)` is the value of an integer, character, or string tag; strings are quoted with `"`
+//! or `'`. Comparisons involving a missing tag, a tag of another type, or an integer and a
+//! string are false.
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::aux::Field;
+use crate::plan::{Tag, parse_tag};
+
+/// The names `flag.NAME` accepts, with the flag bit each tests.
+pub const FLAGS: [(&str, u16); 12] = [
+    ("paired", 0x1),
+    ("proper_pair", 0x2),
+    ("unmapped", 0x4),
+    ("mate_unmapped", 0x8),
+    ("reverse", 0x10),
+    ("mate_reverse", 0x20),
+    ("read1", 0x40),
+    ("read2", 0x80),
+    ("secondary", 0x100),
+    ("qcfail", 0x200),
+    ("duplicate", 0x400),
+    ("supplementary", 0x800),
+];
+
+/// The value of a tag as seen by a condition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TagValue {
+    /// An integer of any width.
+    Int(i64),
+    /// A character or string.
+    Str(String),
+    /// A value of any other type, such as a float or an array, which no comparison holds for.
+    Other,
+}
+
+impl TagValue {
+    /// Reads the value of a field located in raw auxiliary data.
+    ///
+    /// # Arguments
+    ///
+    /// * `aux` - The raw auxiliary data of a BAM record
+    /// * `field` - A field of `aux`, as returned by [`crate::aux::find`]
+    ///
+    /// # Returns
+    ///
+    /// Returns the value, with integers of every width widened to `i64`.
+    ///
+    pub fn from_field(aux: &[u8], field: &Field) -> Self {
+        let bytes = &aux[field.value.clone()];
+        match field.value_type {
+            b'c' => TagValue::Int(i64::from(bytes[0] as i8)),
+            b'C' => TagValue::Int(i64::from(bytes[0])),
+            b's' => TagValue::Int(i64::from(i16::from_le_bytes([bytes[0], bytes[1]]))),
+            b'S' => TagValue::Int(i64::from(u16::from_le_bytes([bytes[0], bytes[1]]))),
+            b'i' => TagValue::Int(i64::from(i32::from_le_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3],
+            ]))),
+            b'I' => TagValue::Int(i64::from(u32::from_le_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3],
+            ]))),
+            b'A' | b'Z' => TagValue::Str(String::from_utf8_lossy(bytes).into_owned()),
+            _ => TagValue::Other,
+        }
+    }
+}
+
+/// The fields of an alignment record a condition can test.
+pub trait RecordFields {
+    /// Returns the SAM flag.
+    fn flag(&self) -> u16;
+    /// Returns the mapping quality, 255 when unavailable.
+    fn mapq(&self) -> u8;
+    /// Returns the 1-based leftmost position, or 0 when unplaced.
+    fn pos(&self) -> i64;
+    /// Returns the observed template length.
+    fn tlen(&self) -> i64;
+    /// Returns the length of SEQ, 0 when it is `*`.
+    fn seq_len(&self) -> usize;
+    /// Returns the value of a tag, None if the record lacks it, or an error if the record's
+    /// auxiliary data is malformed.
+    fn tag(&self, tag: &Tag) -> Result<Option<TagValue>, Box<dyn error::Error>>;
+}
+
+/// An error from parsing a condition, locating where in the condition it was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConditionError {
+    /// The character position in the condition, from 1, at which parsing failed.
+    pub column: usize,
+    /// The reason the condition was rejected.
+    pub message: String,
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid condition at column {}: {}",
+            self.column, self.message
+        )
+    }
+}
+
+impl error::Error for ConditionError {}
+
+/// A comparison between two operands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    /// Returns true if the comparison holds for operands ordered as `ordering`.
+    pub(crate) fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Eq => ordering.is_eq(),
+            Comparison::Ne => ordering.is_ne(),
+            Comparison::Lt => ordering.is_lt(),
+            Comparison::Le => ordering.is_le(),
+            Comparison::Gt => ordering.is_gt(),
+            Comparison::Ge => ordering.is_ge(),
+        }
+    }
+}
+
+/// An integer or string compared by a condition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Operand {
+    Int(i64),
+    Str(String),
+    Mapq,
+    Pos,
+    Tlen,
+    Flag,
+    Tag(Tag),
+}
+
+/// An operand's value for one record.
+enum Value<'a> {
+    Int(i64),
+    Str(Cow<'a, str>),
+}
+
+impl Operand {
+    /// Returns the operand's value for a record, or None for a missing or incomparable tag.
+    fn value<R: RecordFields + ?Sized>(
+        &self,
+        record: &R,
+    ) -> Result<Option<Value<'_>>, Box<dyn error::Error>> {
+        Ok(Some(match self {
+            Operand::Int(value) => Value::Int(*value),
+            Operand::Str(value) => Value::Str(Cow::Borrowed(value)),
+            Operand::Mapq => Value::Int(i64::from(record.mapq())),
+            Operand::Pos => Value::Int(record.pos()),
+            Operand::Tlen => Value::Int(record.tlen()),
+            Operand::Flag => Value::Int(i64::from(record.flag())),
+            Operand::Tag(tag) => match record.tag(tag)? {
+                Some(TagValue::Int(value)) => Value::Int(value),
+                Some(TagValue::Str(value)) => Value::Str(Cow::Owned(value)),
+                Some(TagValue::Other) | None => return Ok(None),
+            },
+        }))
+    }
+}
+
+/// A parsed condition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Comparison, Operand, Operand),
+    Flag(u16),
+    Has(Tag),
+}
+
+impl Expr {
+    /// Evaluates the condition for a record, skipping operands that cannot change the result.
+    fn eval<R: RecordFields + ?Sized>(&self, record: &R) -> Result<bool, Box<dyn error::Error>> {
+        Ok(match self {
+            Expr::Or(left, right) => left.eval(record)? || right.eval(record)?,
+            Expr::And(left, right) => left.eval(record)? && right.eval(record)?,
+            Expr::Not(expr) => !expr.eval(record)?,
+            Expr::Compare(comparison, left, right) => {
+                match (left.value(record)?, right.value(record)?) {
+                    (Some(Value::Int(left)), Some(Value::Int(right))) => {
+                        comparison.holds(left.cmp(&right))
+                    }
+                    (Some(Value::Str(left)), Some(Value::Str(right))) => {
+                        comparison.holds(left.cmp(&right))
+                    }
+                    _ => false,
+                }
+            }
+            Expr::Flag(bit) => record.flag() & bit != 0,
+            Expr::Has(tag) => record.tag(tag)?.is_some(),
+        })
+    }
+}
+
+/// A lexical token of a condition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Int(i64),
+    Str(String),
+    Name(String),
+    And,
+    Or,
+    Not,
+    Compare(Comparison),
+    Open,
+    Close,
+}
+
+/// Splits a condition into tokens, each with the byte offset where it starts.
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ConditionError> {
+    let error = |offset: usize, message: String| ConditionError {
+        column: source[..offset].chars().count() + 1,
+        message,
+    };
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let start = offset;
+        let rest = &source[start..];
+        let two = rest.get(..2).unwrap_or(rest);
+        let (token, len) = match (bytes[start], two) {
+            (b, _) if b.is_ascii_whitespace() => {
+                offset += 1;
+                continue;
+            }
+            (_, "&&") => (Token::And, 2),
+            (_, "||") => (Token::Or, 2),
+            (_, "==") => (Token::Compare(Comparison::Eq), 2),
+            (_, "!=") => (Token::Compare(Comparison::Ne), 2),
+            (_, "<=") => (Token::Compare(Comparison::Le), 2),
+            (_, ">=") => (Token::Compare(Comparison::Ge), 2),
+            (b'<', _) => (Token::Compare(Comparison::Lt), 1),
+            (b'>', _) => (Token::Compare(Comparison::Gt), 1),
+            (b'!', _) => (Token::Not, 1),
+            (b'(', _) => (Token::Open, 1),
+            (b')', _) => (Token::Close, 1),
+            (quote @ (b'"' | b'\''), _) => {
+                let Some(len) = rest[1..].find(quote as char) else {
+                    return Err(error(start, "unterminated string".to_string()));
+                };
+                (Token::Str(rest[1..1 + len].to_string()), len + 2)
+            }
+            (b, _)
+                if b.is_ascii_digit() || (b == b'-' && rest[1..].starts_with(char::is_numeric)) =>
+            {
+                let len = 1 + rest[1..]
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len() - 1);
+                let value = rest[..len]
+                    .parse()
+                    .map_err(|_| error(start, format!("invalid integer `{}`", &rest[..len])))?;
+                (Token::Int(value), len)
+            }
+            (b, _) if b.is_ascii_alphabetic() || b == b'_' => {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                    .unwrap_or(rest.len());
+                (Token::Name(rest[..len].to_string()), len)
+            }
+            _ => {
+                let c = rest.chars().next().unwrap_or_default();
+                return Err(error(start, format!("unexpected character {c:?}")));
+            }
+        };
+        tokens.push((start, token));
+        offset += len;
+    }
+    Ok(tokens)
+}
+
+/// Either side of a comparison, or a complete condition.
+enum Term {
+    Condition(Expr),
+    Operand(Operand),
+}
+
+/// A recursive descent parser over the tokens of a condition.
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+}
+
+impl Parser<'_> {
+    /// Returns the next token without consuming it.
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    /// Consumes and returns the next token.
+    fn bump(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.next).map(|(_, token)| token.clone());
+        self.next += 1;
+        token
+    }
+
+    /// Returns an error located at the next token, or at the end of the condition.
+    fn error(&self, message: impl Into<String>) -> ConditionError {
+        let offset = self
+            .tokens
+            .get(self.next)
+            .map_or(self.source.len(), |(offset, _)| *offset);
+        ConditionError {
+            column: self.source[..offset].chars().count() + 1,
+            message: message.into(),
+        }
+    }
+
+    /// Consumes the next token if it is `token`, failing with `message` otherwise.
+    fn expect(&mut self, token: Token, message: &str) -> Result<(), ConditionError> {
+        if self.peek() != Some(&token) {
+            return Err(self.error(message));
+        }
+        self.next += 1;
+        Ok(())
+    }
+
+    /// Parses conditions joined by `||`.
+    fn or(&mut self) -> Result<Expr, ConditionError> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    /// Parses conditions joined by `&&`.
+    fn and(&mut self) -> Result<Expr, ConditionError> {
+        let mut expr = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    /// Parses a condition, negated by any leading `!`.
+    fn not(&mut self) -> Result<Expr, ConditionError> {
+        if self.peek() == Some(&Token::Not) {
+            self.next += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    /// Parses a comparison between two operands, or a test such as `has(BC)`.
+    fn comparison(&mut self) -> Result<Expr, ConditionError> {
+        let start = self.next;
+        let left = self.term()?;
+        let Some(&Token::Compare(comparison)) = self.peek() else {
+            return match left {
+                Term::Condition(expr) => Ok(expr),
+                Term::Operand(_) => {
+                    self.next = start;
+                    Err(self.error("expected a condition, e.g. `mapq >= 20`, not a bare value"))
+                }
+            };
+        };
+        let Term::Operand(left) = left else {
+            self.next = start;
+            return Err(self.error("expected a value to compare"));
+        };
+        self.next += 1;
+        let right_start = self.next;
+        let Term::Operand(right) = self.term()? else {
+            self.next = right_start;
+            return Err(self.error("expected a value to compare with"));
+        };
+        Ok(Expr::Compare(comparison, left, right))
+    }
+
+    /// Parses a parenthesized condition, a test, or an operand.
+    fn term(&mut self) -> Result<Term, ConditionError> {
+        let start = self.next;
+        let term = match self.bump() {
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                self.expect(Token::Close, "expected `)`")?;
+                Term::Condition(expr)
+            }
+            Some(Token::Int(value)) => Term::Operand(Operand::Int(value)),
+            Some(Token::Str(value)) => Term::Operand(Operand::Str(value)),
+            Some(Token::Name(name)) => match name.as_str() {
+                "mapq" => Term::Operand(Operand::Mapq),
+                "pos" => Term::Operand(Operand::Pos),
+                "tlen" => Term::Operand(Operand::Tlen),
+                "flag" => Term::Operand(Operand::Flag),
+                "has" => Term::Condition(Expr::Has(self.tag_argument()?)),
+                "tag" => Term::Operand(Operand::Tag(self.tag_argument()?)),
+                _ => {
+                    let bit = name.strip_prefix("flag.").and_then(|flag| {
+                        FLAGS
+                            .iter()
+                            .find(|(known, _)| *known == flag)
+                            .map(|(_, bit)| *bit)
+                    });
+                    let Some(bit) = bit else {
+                        self.next = start;
+                        return Err(self.error(format!(
+                            "unknown name `{name}`; expected mapq, pos, tlen, flag, flag.NAME, \
+                             has(TAG), or tag(TAG)"
+                        )));
+                    };
+                    Term::Condition(Expr::Flag(bit))
+                }
+            },
+            _ => {
+                self.next = start;
+                return Err(self.error("expected a value or condition"));
+            }
+        };
+        Ok(term)
+    }
+
+    /// Parses the parenthesized tag name of `has(TAG)` or `tag(TAG)`.
+    fn tag_argument(&mut self) -> Result<Tag, ConditionError> {
+        self.expect(Token::Open, "expected `(` and a tag name")?;
+        let tag = match self.peek() {
+            Some(Token::Name(name) | Token::Str(name)) => {
+                parse_tag(name).map_err(|e| self.error(e.to_string()))?
+            }
+            _ => return Err(self.error("expected a tag name")),
+        };
+        self.next += 1;
+        self.expect(Token::Close, "expected `)`")?;
+        Ok(tag)
+    }
+}
+
+/// A condition deciding, record by record, whether the plan applies.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl Condition {
+    /// Parses a condition such as `mapq >= 20 && !flag.secondary && has(BC)`.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The condition to parse
+    ///
+    /// # Returns
+    ///
+    /// Returns the condition, or a [`ConditionError`] locating why it is invalid.
+    ///
+    pub fn parse(source: &str) -> Result<Self, ConditionError> {
+        let mut parser = Parser {
+            source,
+            tokens: tokenize(source)?,
+            next: 0,
+        };
+        let expr = parser.or()?;
+        if parser.peek().is_some() {
+            return Err(parser.error("expected `&&`, `||`, or the end of the condition"));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            expr,
+        })
+    }
+
+    /// Returns the condition as it was given.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns true if a record meets the condition.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The record to test
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the condition holds, or an error if the record's tags cannot be read.
+    ///
+    pub fn matches<R: RecordFields + ?Sized>(
+        &self,
+        record: &R,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        self.expr.eval(record)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = ConditionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestRecord {
+        flag: u16,
+        mapq: u8,
+        tlen: i64,
+        tags: Vec<(Tag, TagValue)>,
+    }
+
+    impl RecordFields for TestRecord {
+        fn flag(&self) -> u16 {
+            self.flag
+        }
+
+        fn mapq(&self) -> u8 {
+            self.mapq
+        }
+
+        fn pos(&self) -> i64 {
+            100
+        }
+
+        fn tlen(&self) -> i64 {
+            self.tlen
+        }
+
+        fn seq_len(&self) -> usize {
+            4
+        }
+
+        fn tag(&self, tag: &Tag) -> Result<Option<TagValue>, Box<dyn error::Error>> {
+            Ok(self
+                .tags
+                .iter()
+                .find(|(name, _)| name == tag)
+                .map(|(_, value)| value.clone()))
+        }
+    }
+
+    fn record() -> TestRecord {
+        TestRecord {
+            flag: 0x10 | 0x80,
+            mapq: 30,
+            tlen: -250,
+            tags: vec![
+                (*b"BC", TagValue::Str("ACGT".to_string())),
+                (*b"XS", TagValue::Str("-".to_string())),
+                (*b"NM", TagValue::Int(2)),
+                (*b"XF", TagValue::Other),
+            ],
+        }
+    }
+
+    fn holds(condition: &str) -> bool {
+        Condition::parse(condition)
+            .unwrap()
+            .matches(&record())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_conditions() {
+        assert!(holds("mapq >= 20 && !flag.secondary && has(BC)"));
+        assert!(!holds("mapq > 30"));
+        assert!(holds("tag(XS) == \"-\" && tag(NM) < 3"));
+        assert!(holds("tag('BC') != 'GGGG'"));
+        assert!(holds("flag.read2 && tlen < -100 && pos == 100"));
+        assert!(holds("flag == 144"));
+        assert!(holds("!has(QT) || has(BC)"));
+        assert!(holds("(flag.read1 || flag.read2) && !(mapq < 20)"));
+        assert!(!holds("flag.read1 || flag.secondary && has(BC)"));
+    }
+
+    #[test]
+    fn test_comparisons_with_missing_or_mismatched_values_are_false() {
+        assert!(!holds("tag(QT) == 'A'"));
+        assert!(!holds("tag(QT) != 'A'"));
+        assert!(!holds("tag(NM) == '2'"));
+        assert!(!holds("tag(XF) == 0"));
+        assert!(holds("has(XF)"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |condition| Condition::parse(condition).unwrap_err();
+        assert_eq!(error("mapq").column, 1);
+        assert_eq!(error("mapq >= 20 &&").column, 14);
+        assert_eq!(error("flag.mystery").column, 1);
+        assert!(error("has(BCD)").message.contains("exactly 2 characters"));
+        assert!(
+            error("tag(XS) == 'unterminated")
+                .message
+                .contains("unterminated")
+        );
+        assert!(
+            error("has(BC) >= 2")
+                .message
+                .contains("expected a value to compare")
+        );
+        assert!(error("(mapq > 1").message.contains("`)`"));
+        assert!(
+            error("mapq > 1 mapq")
+                .message
+                .contains("end of the condition")
+        );
+        assert_eq!(
+            error("mapq ~ 1").to_string(),
+            "Invalid condition at column 6: unexpected character '~'"
+        );
+    }
+
+    #[test]
+    fn test_tag_value_from_field() {
+        let aux = b"NMc\xffXIS\x01\x01BCZAC\0XFf\0\0\0\0";
+        let values: Vec<TagValue> = crate::aux::fields(aux)
+            .map(|field| TagValue::from_field(aux, &field.unwrap()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                TagValue::Int(-1),
+                TagValue::Int(257),
+                TagValue::Str("AC".to_string()),
+                TagValue::Other
+            ]
+        );
+    }
+}
@@ -0,0 +1,405 @@
+//! The effective configuration of a run, as printed by `--print-config`.
+//!
+//! Command-line flags are resolved, with their defaults, into a [`Plan`] and [`Options`] before
+//! anything is read. Printing those as JSON shows exactly what a run would do, so users can
+//! confirm it before launching a long job. The JSON is small and flat, so it is written here by
+//! hand rather than through a serialization framework.
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use crate::options::Options;
+use crate::plan::{Plan, StrandSource, Tag};
+
+/// A JSON value, with object keys kept in insertion order.
+#[derive(Clone, Debug)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    /// Returns a JSON string holding a tag name.
+    pub(crate) fn tag(tag: &Tag) -> Self {
+        Json::String(String::from_utf8_lossy(tag).into_owned())
+    }
+
+    /// Returns a JSON string holding a path, or `-` for None, meaning stdin or stdout.
+    fn path(path: Option<&Path>) -> Self {
+        Json::String(path.map_or("-".to_string(), |path| path.to_string_lossy().into_owned()))
+    }
+
+    /// Returns a JSON value holding an optional value, or null.
+    pub(crate) fn optional<T>(value: Option<T>, to_json: impl FnOnce(T) -> Json) -> Self {
+        value.map_or(Json::Null, to_json)
+    }
+
+    /// Returns a JSON array of paths.
+    fn paths(paths: &[PathBuf]) -> Self {
+        Json::Array(paths.iter().map(|path| Json::path(Some(path))).collect())
+    }
+
+    /// Appends the value to `out`, indenting nested values by two spaces per level.
+    pub(crate) fn write(&self, out: &mut String, indent: usize) {
+        let pad = |out: &mut String, level: usize| out.push_str(&"  ".repeat(level));
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+            Json::Number(value) => out.push_str(&value.to_string()),
+            Json::String(value) => write_string(out, value),
+            Json::Array(values) if values.is_empty() => out.push_str("[]"),
+            Json::Array(values) => {
+                out.push_str("[\n");
+                for (i, value) in values.iter().enumerate() {
+                    pad(out, indent + 1);
+                    value.write(out, indent + 1);
+                    out.push_str(if i + 1 < values.len() { ",\n" } else { "\n" });
+                }
+                pad(out, indent);
+                out.push(']');
+            }
+            Json::Object(fields) if fields.is_empty() => out.push_str("{}"),
+            Json::Object(fields) => {
+                out.push_str("{\n");
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    pad(out, indent + 1);
+                    write_string(out, key);
+                    out.push_str(": ");
+                    value.write(out, indent + 1);
+                    out.push_str(if i + 1 < fields.len() { ",\n" } else { "\n" });
+                }
+                pad(out, indent);
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// Appends `value` to `out` as a JSON string, escaping quotes, backslashes, and control
+/// characters.
+fn write_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Returns the name of a strand source as accepted by `--tag TAG:OP:strand=SOURCE`.
+fn strand_name(source: StrandSource) -> Json {
+    match source {
+        StrandSource::Read => Json::String("read".to_string()),
+        StrandSource::Mate => Json::String("mate".to_string()),
+        StrandSource::Tag(tag) => Json::tag(&tag),
+        StrandSource::Always => Json::String("always".to_string()),
+    }
+}
+
+/// Returns the plan as JSON: its tags in the order they are applied, its companion pairs, its
+/// matrix tags, and its policies.
+fn plan_json(plan: &Plan) -> Json {
+    let tags = plan
+        .specs()
+        .iter()
+        .map(|spec| {
+            Json::Object(vec![
+                ("tag", Json::tag(&spec.tag)),
+                ("operation", Json::String(spec.operation.to_string())),
+                ("strand", strand_name(plan.strand_source(&spec.tag))),
+            ])
+        })
+        .collect();
+    let pairs = plan
+        .pairs()
+        .iter()
+        .map(|pair| {
+            Json::Object(vec![
+                ("sequence", Json::tag(&pair.sequence)),
+                ("quality", Json::tag(&pair.quality)),
+            ])
+        })
+        .collect();
+    let matrices = plan
+        .matrices()
+        .iter()
+        .map(|(tag, shape)| {
+            Json::Object(vec![
+                ("tag", Json::tag(tag)),
+                ("shape", Json::String(shape.name())),
+            ])
+        })
+        .collect();
+    Json::Object(vec![
+        ("tags", Json::Array(tags)),
+        ("pairs", Json::Array(pairs)),
+        ("matrices", Json::Array(matrices)),
+        (
+            "on_pair_length_mismatch",
+            Json::String(plan.pair_mismatch().name().to_string()),
+        ),
+        (
+            "on_revcomp_nonsequence",
+            Json::String(plan.nonsequence().name().to_string()),
+        ),
+        (
+            "on_duplicate_tag",
+            Json::String(plan.duplicate_tags().name().to_string()),
+        ),
+    ])
+}
+
+/// Returns the options as JSON, one field for each option.
+fn options_json(options: &Options) -> Json {
+    let text = |value: &str| Json::String(value.to_string());
+    let fd = |fd: i32| Json::Number(fd.max(0) as u64);
+    Json::Object(vec![
+        ("threads", Json::Number(options.threads as u64)),
+        ("batch_size", Json::Number(options.batch_size as u64)),
+        (
+            "max_memory",
+            Json::optional(options.max_memory, |bytes| Json::Number(bytes as u64)),
+        ),
+        (
+            "strand_tag",
+            Json::optional(options.strand_tag, |tag| Json::tag(&tag)),
+        ),
+        ("strand_from_mi", Json::Bool(options.strand_from_mi)),
+        (
+            "condition",
+            Json::optional(options.condition.as_ref(), |condition| {
+                text(condition.source())
+            }),
+        ),
+        (
+            "tag_filters",
+            Json::Array(
+                options
+                    .tag_filters
+                    .iter()
+                    .map(|filter| text(filter.source()))
+                    .collect(),
+            ),
+        ),
+        (
+            "min_mapq",
+            Json::optional(options.min_mapq, |mapq| Json::Number(mapq.into())),
+        ),
+        (
+            "read",
+            Json::optional(options.read, |read| text(read.name())),
+        ),
+        (
+            "pair_fix",
+            Json::Array(
+                options
+                    .pair_fix
+                    .iter()
+                    .map(|tag| text(&tag.to_string()))
+                    .collect(),
+            ),
+        ),
+        ("pair_fix_source", text(options.pair_fix_source.name())),
+        ("unmapped_policy", text(options.unmapped.name())),
+        ("missing_seq_policy", text(options.missing_seq.name())),
+        (
+            "qcfail",
+            Json::optional(options.qcfail, |filter| text(filter.name())),
+        ),
+        ("qcfail_action", text(options.qcfail_action.name())),
+        (
+            "restore_orientation",
+            Json::Bool(options.restore_orientation),
+        ),
+        (
+            "strand_annotation",
+            Json::optional(options.strand_annotation, |tag| Json::tag(&tag)),
+        ),
+        (
+            "qname_umi_pattern",
+            Json::optional(options.qname_umi.as_ref(), |umi| text(umi.pattern())),
+        ),
+        ("preserve_header", Json::Bool(options.preserve_header)),
+        ("strict", Json::Bool(options.strict)),
+        ("max_errors", Json::Number(options.max_errors as u64)),
+        ("retries", Json::Number(u64::from(options.retries))),
+        (
+            "retry_delay_ms",
+            Json::Number(options.retry_delay.as_millis() as u64),
+        ),
+        ("sanitize", Json::Bool(options.sanitize)),
+        ("reproducible", Json::Bool(options.reproducible)),
+        ("pg_id", Json::optional(options.pg_id.as_deref(), text)),
+        ("pg_program_name", text(options.program_name())),
+        (
+            "preflight",
+            Json::optional(options.preflight, |records| Json::Number(records as u64)),
+        ),
+        (
+            "expected_sort_order",
+            Json::optional(options.expected_sort_order, |order| text(order.name())),
+        ),
+        ("progress_fd", Json::optional(options.progress_fd, fd)),
+        ("profile", Json::Bool(options.profile)),
+        (
+            "required_tags",
+            Json::Array(options.required_tags.iter().map(Json::tag).collect()),
+        ),
+        (
+            "reference",
+            Json::optional(options.reference.as_deref(), |path| Json::path(Some(path))),
+        ),
+        (
+            "reference_index",
+            Json::optional(options.reference_index.as_deref(), |path| {
+                Json::path(Some(path))
+            }),
+        ),
+        (
+            "output_format",
+            Json::optional(options.output_format, |format| text(format.name())),
+        ),
+        ("cram_lossy_names", Json::Bool(options.cram_lossy_names)),
+        (
+            "cram_quality_bins",
+            Json::optional(options.cram_quality_bins.as_ref(), |bins| {
+                text(bins.source())
+            }),
+        ),
+        ("tee", Json::paths(&options.tee)),
+        ("write_md5", Json::Bool(options.write_md5)),
+        ("fsync", Json::Bool(options.fsync)),
+        (
+            "barcode_fastq",
+            Json::optional(options.barcode_fastq.as_deref(), |path| {
+                Json::path(Some(path))
+            }),
+        ),
+        ("barcode_tag", Json::tag(&options.barcode_tag)),
+        (
+            "barcode_quality_tag",
+            Json::tag(&options.barcode_quality_tag),
+        ),
+        (
+            "whitelist",
+            Json::optional(options.whitelist.as_deref(), |path| Json::path(Some(path))),
+        ),
+        ("whitelist_tag", Json::tag(&options.whitelist_tag)),
+        (
+            "auto_orient",
+            Json::optional(options.auto_orient, |records| Json::Number(records as u64)),
+        ),
+        (
+            "audit_tsv",
+            Json::optional(options.audit_tsv.as_deref(), |path| Json::path(Some(path))),
+        ),
+        (
+            "contig_tsv",
+            Json::optional(options.contig_tsv.as_deref(), |path| Json::path(Some(path))),
+        ),
+        (
+            "tmpdir",
+            Json::optional(options.tmpdir.as_deref(), |path| Json::path(Some(path))),
+        ),
+        ("plugins", Json::paths(&options.plugins)),
+        (
+            "script",
+            Json::optional(options.script.as_deref(), |path| Json::path(Some(path))),
+        ),
+    ])
+}
+
+/// Returns the effective configuration of a run as pretty-printed JSON.
+///
+/// # Arguments
+///
+/// * `input` - The input file path, or None for stdin, shown as `-`
+/// * `output` - The output file path, or None for stdout, shown as `-`
+/// * `plan` - The tag specifications the run would apply
+/// * `options` - Options controlling the run
+///
+/// # Returns
+///
+/// Returns a JSON object with the `input`, `output`, `plan`, and `options` of the run, ending
+/// in a newline.
+///
+pub fn effective_config(
+    input: Option<&Path>,
+    output: Option<&Path>,
+    plan: &Plan,
+    options: &Options,
+) -> String {
+    let config = Json::Object(vec![
+        ("input", Json::path(input)),
+        ("output", Json::path(output)),
+        ("plan", plan_json(plan)),
+        ("options", options_json(options)),
+    ]);
+    let mut out = String::new();
+    config.write(&mut out, 0);
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_string_escapes() {
+        let mut out = String::new();
+        write_string(&mut out, "a\"b\\c\nd\u{1}");
+        assert_eq!(out, r#""a\"b\\c\nd\u0001""#);
+    }
+
+    #[test]
+    fn test_write_nested() {
+        let json = Json::Object(vec![
+            ("a", Json::Array(vec![Json::Number(1), Json::Null])),
+            ("b", Json::Array(Vec::new())),
+            ("c", Json::Bool(true)),
+        ]);
+        let mut out = String::new();
+        json.write(&mut out, 0);
+        assert_eq!(
+            out,
+            "{\n  \"a\": [\n    1,\n    null\n  ],\n  \"b\": [],\n  \"c\": true\n}"
+        );
+    }
+
+    #[test]
+    fn test_effective_config() {
+        let plan = Plan::parse(
+            &["QT".to_string()],
+            &[],
+            &["MB:revcomp:strand=mate".to_string()],
+        )
+        .unwrap();
+        let options = Options {
+            min_mapq: Some(20),
+            tee: vec![PathBuf::from("copy.bam")],
+            ..Options::default()
+        };
+        let config = effective_config(Some(Path::new("in.bam")), None, &plan, &options);
+        assert!(config.starts_with("{\n  \"input\": \"in.bam\",\n  \"output\": \"-\",\n"));
+        assert!(config.contains(
+            "{\n        \"tag\": \"MB\",\n        \"operation\": \"revcomp\",\n        \
+             \"strand\": \"mate\"\n      }"
+        ));
+        assert!(config.contains("\"min_mapq\": 20,"));
+        assert!(config.contains("\"tee\": [\n      \"copy.bam\"\n    ],"));
+        assert!(config.contains("\"script\": null\n  }\n}\n"));
+    }
+}
@@ -0,0 +1,205 @@
+//! Counts of records and transforms per reference sequence, written as a table at the end of a
+//! run to spot region-specific anomalies, such as a contig whose records stand apart from the
+//! rest because an earlier partial run already fixed them.
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The header line of the table.
+const HEADER: &[u8] = b"contig\trecords\treverse\ttransformed\n";
+
+/// The counts of one reference sequence.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContigCount {
+    /// Records placed on the contig, other than those left out of the output.
+    pub records: u64,
+    /// Those records that are reverse strand.
+    pub reverse: u64,
+    /// Those records the plan was applied to.
+    pub transformed: u64,
+}
+
+impl ContigCount {
+    fn merge(&mut self, other: &ContigCount) {
+        self.records += other.records;
+        self.reverse += other.reverse;
+        self.transformed += other.transformed;
+    }
+}
+
+/// Counts of records and transforms for every contig of a header, and for unplaced records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContigCounts {
+    names: Vec<String>,
+    counts: Vec<ContigCount>,
+    unplaced: ContigCount,
+}
+
+impl ContigCounts {
+    /// Creates empty counts for the contigs of a header.
+    ///
+    /// # Arguments
+    ///
+    /// * `names` - The contig names, in the order of the header's `@SQ` lines
+    ///
+    /// # Returns
+    ///
+    /// Returns the counts, all zero.
+    ///
+    pub fn new(names: Vec<String>) -> Self {
+        let counts = vec![ContigCount::default(); names.len()];
+        Self {
+            names,
+            counts,
+            unplaced: ContigCount::default(),
+        }
+    }
+
+    /// Counts a record by the contig it is placed on, as read, before any transform clears it.
+    ///
+    /// # Arguments
+    ///
+    /// * `tid` - The record's reference ID, negative when unplaced
+    /// * `reverse` - Whether the record is reverse strand
+    /// * `transformed` - Whether the plan was applied to the record
+    ///
+    pub fn record(&mut self, tid: i32, reverse: bool, transformed: bool) {
+        let count = match usize::try_from(tid)
+            .ok()
+            .and_then(|tid| self.counts.get_mut(tid))
+        {
+            Some(count) => count,
+            None => &mut self.unplaced,
+        };
+        count.records += 1;
+        count.reverse += u64::from(reverse);
+        count.transformed += u64::from(transformed);
+    }
+
+    /// Adds every count from `other`, which must count the same contigs, to these counts.
+    pub fn merge(&mut self, other: &ContigCounts) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            count.merge(other);
+        }
+        self.unplaced.merge(&other.unplaced);
+    }
+
+    /// Returns the counts of a contig by name, or of unplaced records for `*`.
+    pub fn get(&self, name: &str) -> Option<&ContigCount> {
+        if name == "*" {
+            return Some(&self.unplaced);
+        }
+        self.names
+            .iter()
+            .position(|contig| contig == name)
+            .map(|tid| &self.counts[tid])
+    }
+
+    /// Writes a tab-separated row of counts for every contig, in header order, followed by a
+    /// row for unplaced records, named `*`, if there were any.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - The destination of the table
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if writing fails.
+    ///
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(HEADER)?;
+        let unplaced = (self.unplaced.records > 0).then_some(("*", &self.unplaced));
+        for (name, count) in self
+            .names
+            .iter()
+            .map(String::as_str)
+            .zip(&self.counts)
+            .chain(unplaced)
+        {
+            writeln!(
+                writer,
+                "{name}\t{}\t{}\t{}",
+                count.records, count.reverse, count.transformed
+            )?;
+        }
+        writer.flush()
+    }
+
+    /// Writes the table to a file, per [`ContigCounts::write_to`].
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The table to create
+    ///
+    /// # Returns
+    ///
+    /// Returns an error naming the file if it cannot be written.
+    ///
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        File::create(path)
+            .and_then(|file| self.write_to(BufWriter::new(file)))
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Failed to write contig table {}: {e}", path.display()),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let names = vec!["chr1".to_string(), "chr2".to_string()];
+        let mut counts = ContigCounts::new(names.clone());
+        counts.record(0, true, true);
+        counts.record(0, false, false);
+        counts.record(1, true, false);
+        let mut other = ContigCounts::new(names);
+        other.record(0, true, true);
+        other.record(-1, true, true);
+        counts.merge(&other);
+
+        assert_eq!(
+            counts.get("chr1"),
+            Some(&ContigCount {
+                records: 3,
+                reverse: 2,
+                transformed: 2
+            })
+        );
+        assert_eq!(counts.get("*").unwrap().records, 1);
+        assert_eq!(counts.get("chrM"), None);
+
+        let mut table = Vec::new();
+        counts.write_to(&mut table).unwrap();
+        assert_eq!(
+            String::from_utf8(table).unwrap(),
+            "contig\trecords\treverse\ttransformed\n\
+             chr1\t3\t2\t2\n\
+             chr2\t1\t1\t0\n\
+             *\t1\t1\t1\n"
+        );
+    }
+
+    #[test]
+    fn test_write_without_unplaced() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("contigs.tsv");
+        ContigCounts::new(vec!["chr1".to_string()])
+            .write(&path)
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "contig\trecords\treverse\ttransformed\nchr1\t0\t0\t0\n"
+        );
+        assert!(
+            ContigCounts::new(Vec::new())
+                .write(&tmpdir.path().join("missing").join("contigs.tsv"))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! CRAM outputs written with lossy options rust-htslib writers do not expose, such as lossy read
+//! names and quality binning, to shrink archive-bound outputs in the same pass.
+use rust_htslib::bam::{Read as _, Reader, Record};
+use rust_htslib::htslib;
+use std::ffi::{CString, c_int};
+use std::io::{self, PipeWriter};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+use crate::ops::bin_qualities;
+use crate::quality::QualityBins;
+
+/// An open htslib output file, closed when dropped.
+struct HtsFile(*mut htslib::htsFile);
+
+impl Drop for HtsFile {
+    fn drop(&mut self) {
+        // SAFETY: the file was opened by `hts_open` and is closed only here.
+        unsafe { htslib::hts_close(self.0) };
+    }
+}
+
+/// Converts a path to a C string for htslib.
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)
+}
+
+/// Copies the uncompressed BAM stream read from `source` into a CRAM output, discarding read
+/// names with `lossy_names` and binning qualities with `quality_bins`.
+fn write_cram(
+    source: &Path,
+    output: Option<&Path>,
+    reference: Option<&Path>,
+    threads: usize,
+    lossy_names: bool,
+    quality_bins: Option<&QualityBins>,
+) -> io::Result<()> {
+    let mut input = Reader::from_path(source).map_err(io::Error::other)?;
+    let path = c_path(output.unwrap_or(Path::new("-")))?;
+    // SAFETY: both arguments are valid NUL-terminated strings.
+    let file = HtsFile(unsafe { htslib::hts_open(path.as_ptr(), c"wc".as_ptr()) });
+    if file.0.is_null() {
+        return Err(io::Error::other(format!(
+            "Could not open CRAM output {path:?}"
+        )));
+    }
+    if let Some(reference) = reference {
+        let reference = c_path(reference)?;
+        // SAFETY: the file is open and the path is a valid NUL-terminated string.
+        if unsafe { htslib::hts_set_fai_filename(file.0, reference.as_ptr()) } < 0 {
+            return Err(io::Error::other("Could not set the CRAM reference"));
+        }
+    }
+    if lossy_names {
+        // SAFETY: the file is open and CRAM_OPT_LOSSY_NAMES takes an int.
+        let status = unsafe {
+            htslib::hts_set_opt(
+                file.0,
+                htslib::hts_fmt_option_CRAM_OPT_LOSSY_NAMES,
+                1 as c_int,
+            )
+        };
+        if status < 0 {
+            return Err(io::Error::other("Could not enable lossy CRAM read names"));
+        }
+    }
+    if threads > 1 {
+        // SAFETY: the file is open.
+        unsafe { htslib::hts_set_threads(file.0, (threads - 1) as c_int) };
+    }
+
+    let header = input.header().inner_ptr();
+    // SAFETY: the file is open and the header is owned by the reader, which outlives it.
+    if unsafe { htslib::sam_hdr_write(file.0, header) } < 0 {
+        return Err(io::Error::other("Could not write the CRAM header"));
+    }
+    let mut record = Record::new();
+    while let Some(result) = input.read(&mut record) {
+        result.map_err(io::Error::other)?;
+        if let Some(bins) = quality_bins {
+            bin_qualities(&mut record, bins);
+        }
+        // SAFETY: the file is open and the header and record are valid for the call.
+        if unsafe { htslib::sam_write1(file.0, header, record.inner()) } < 0 {
+            return Err(io::Error::other("Could not write a CRAM record"));
+        }
+    }
+    Ok(())
+}
+
+/// A CRAM output fed through a pipe and written on a separate thread with lossy options that
+/// rust-htslib writers do not expose.
+///
+/// Open the writer on [`CramPipe::path`] as uncompressed BAM, close it, then call
+/// [`CramPipe::finish`] to wait for the output to be complete.
+#[derive(Debug)]
+pub struct CramPipe {
+    path: PathBuf,
+    writer: PipeWriter,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl CramPipe {
+    /// Creates the pipe feeding a CRAM output.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - The output file, or None for stdout
+    /// * `reference` - The reference FASTA records are compressed against, if any
+    /// * `threads` - Total threads; all but one compress the output
+    /// * `lossy_names` - Discard read names, which readers regenerate, except where needed to
+    ///   pair mates
+    /// * `quality_bins` - Bin base qualities before they are written
+    ///
+    /// # Returns
+    ///
+    /// Returns the pipe, or an error if it cannot be created.
+    ///
+    pub fn create(
+        output: Option<&Path>,
+        reference: Option<&Path>,
+        threads: usize,
+        lossy_names: bool,
+        quality_bins: Option<QualityBins>,
+    ) -> io::Result<Self> {
+        let (reader, writer) = io::pipe()?;
+        let path = PathBuf::from(format!("/dev/fd/{}", writer.as_raw_fd()));
+        let output = output.map(Path::to_path_buf);
+        let reference = reference.map(Path::to_path_buf);
+        let thread = thread::spawn(move || {
+            let source = PathBuf::from(format!("/dev/fd/{}", reader.as_raw_fd()));
+            write_cram(
+                &source,
+                output.as_deref(),
+                reference.as_deref(),
+                threads,
+                lossy_names,
+                quality_bins.as_ref(),
+            )
+        });
+        Ok(Self {
+            path,
+            writer,
+            thread,
+        })
+    }
+
+    /// Returns the path to open the writer on, a `/dev/fd` path for the write end of the pipe.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Closes the pipe and waits for the output to be written; every writer opened on
+    /// [`CramPipe::path`] must be closed first.
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the output could not be written.
+    ///
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.writer);
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("The CRAM writer thread panicked")))
+    }
+}
@@ -0,0 +1,150 @@
+//! Rendering of errors for the terminal: colored when stderr is a terminal, with callouts
+//! underlining the part of an argument at fault. Warnings are logged, and colored, by the
+//! logger.
+use std::env;
+use std::io::{self, IsTerminal};
+use std::ops::Range;
+
+use crate::condition::ConditionError;
+use crate::plan::ParseError;
+
+/// The ANSI escape sequence ending any styling.
+const RESET: &str = "\x1b[0m";
+
+/// How a piece of diagnostic text is styled on a color terminal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    /// Bold red, for the label of an error.
+    Error,
+    /// Bold cyan, for the markers underlining a callout.
+    Marker,
+}
+
+impl Style {
+    /// Returns the ANSI escape sequence starting the style.
+    fn code(&self) -> &'static str {
+        match self {
+            Style::Error => "\x1b[1;31m",
+            Style::Marker => "\x1b[1;36m",
+        }
+    }
+}
+
+/// Returns true if diagnostics on stderr should be colored: stderr is a terminal and the
+/// `NO_COLOR` environment variable is unset or empty.
+pub fn use_color() -> bool {
+    io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+/// Returns `text` in `style` when `color` is set, else unchanged.
+pub fn paint(text: &str, style: Style, color: bool) -> String {
+    if color {
+        format!("{}{text}{RESET}", style.code())
+    } else {
+        text.to_string()
+    }
+}
+
+/// A piece of user-supplied text, such as a tag specification or condition, with the part at
+/// fault marked for underlining beneath an error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Callout {
+    /// The text as supplied.
+    pub source: String,
+    /// The byte range of the part at fault; empty ranges mark where something is missing.
+    pub span: Range<usize>,
+}
+
+impl Callout {
+    /// Marks the single character at `column`, counted in characters from 1, as a
+    /// [`ConditionError`] reports it; columns past the end mark the end.
+    pub fn at_column(source: &str, column: usize) -> Self {
+        let start = source
+            .char_indices()
+            .nth(column.saturating_sub(1))
+            .map_or(source.len(), |(offset, _)| offset);
+        let end = source[start..]
+            .chars()
+            .next()
+            .map_or(start, |c| start + c.len_utf8());
+        Self {
+            source: source.to_string(),
+            span: start..end,
+        }
+    }
+
+    /// Marks the part of a condition at which it failed to parse.
+    pub fn for_condition(source: &str, error: &ConditionError) -> Self {
+        Self::at_column(source, error.column)
+    }
+
+    /// Renders the source indented beneath an error, with the part at fault underlined by
+    /// carets, or a single caret where something is missing.
+    ///
+    /// # Arguments
+    ///
+    /// * `color` - Whether to color the carets
+    ///
+    /// # Returns
+    ///
+    /// Returns the two lines of the callout, without a trailing newline.
+    ///
+    pub fn render(&self, color: bool) -> String {
+        let start = self.span.start.min(self.source.len());
+        let end = self.span.end.clamp(start, self.source.len());
+        let indent = self.source[..start].chars().count();
+        let width = self.source[start..end].chars().count().max(1);
+        format!(
+            "    {}\n    {}{}",
+            self.source,
+            " ".repeat(indent),
+            paint(&"^".repeat(width), Style::Marker, color)
+        )
+    }
+}
+
+impl From<&ParseError> for Callout {
+    fn from(error: &ParseError) -> Self {
+        Self {
+            source: error.token.clone(),
+            span: error.span(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::parse_tag_spec;
+
+    #[test]
+    fn test_paint() {
+        assert_eq!(paint("Error", Style::Error, false), "Error");
+        assert_eq!(paint("Error", Style::Error, true), "\x1b[1;31mError\x1b[0m");
+    }
+
+    #[test]
+    fn test_render_parse_error() {
+        let error = parse_tag_spec("QT:rve").unwrap_err();
+        assert_eq!(
+            Callout::from(&error).render(false),
+            "    QT:rve\n       ^^^"
+        );
+        let error = parse_tag_spec("QT").unwrap_err();
+        assert_eq!(Callout::from(&error).render(false), "    QT\n      ^");
+        assert_eq!(
+            Callout::from(&parse_tag_spec("QT:rve").unwrap_err()).render(true),
+            "    QT:rve\n       \x1b[1;36m^^^\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_at_column() {
+        assert_eq!(Callout::at_column("mapq ~ 20", 6).span, 5..6);
+        assert_eq!(Callout::at_column("mapq >=", 8).span, 7..7);
+        assert_eq!(
+            Callout::at_column("mapq ~ 20", 6).render(false),
+            "    mapq ~ 20\n         ^"
+        );
+    }
+}
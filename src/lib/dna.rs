@@ -0,0 +1,166 @@
+//! DNA alphabet helpers shared by every backend.
+//!
+//! The complement table matches the IUPAC-aware table in `bio::alphabets::dna`, preserving case
+//! and leaving unrecognized bytes unchanged. SOLiD colorspace reads are handled here too, as
+//! their colors encode transitions between bases.
+
+/// Lookup table mapping every byte to its DNA complement.
+const COMPLEMENT: [u8; 256] = {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = i as u8;
+        i += 1;
+    }
+    let from = b"AGCTYRWSKMDVHBN";
+    let into = b"TCGARYWSMKHBDVN";
+    let mut j = 0;
+    while j < from.len() {
+        table[from[j] as usize] = into[j];
+        table[from[j] as usize + 32] = into[j] + 32;
+        j += 1;
+    }
+    table
+};
+
+/// Returns the complement of a single IUPAC nucleotide, preserving case.
+pub fn complement(base: u8) -> u8 {
+    COMPLEMENT[base as usize]
+}
+
+/// Returns the reverse complement of a DNA sequence.
+pub fn revcomp(seq: &[u8]) -> Vec<u8> {
+    seq.iter().rev().map(|&base| complement(base)).collect()
+}
+
+/// Reverse complements a DNA sequence in place, complementing and reversing in one pass.
+pub fn revcomp_in_place(seq: &mut [u8]) {
+    let half = seq.len() / 2;
+    let (left, rest) = seq.split_at_mut(half);
+    let (middle, right) = rest.split_at_mut(rest.len() - half);
+    for (a, b) in left.iter_mut().zip(right.iter_mut().rev()) {
+        (*a, *b) = (complement(*b), complement(*a));
+    }
+    for base in middle {
+        *base = complement(*base);
+    }
+}
+
+/// Returns the 2-bit code of an unambiguous base as used by SOLiD colorspace: A, C, G, T as 0
+/// to 3, so a color is the exclusive or of the codes of the bases on either side of it.
+fn colorspace_code(base: u8) -> Option<u8> {
+    match base.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    }
+}
+
+/// Reverses a SOLiD colorspace read, such as the `CS` tag, in place.
+///
+/// A colorspace read is a primer base followed by colors `0` to `3`, each encoding the
+/// transition from one base to the next. Complementing both bases leaves a transition's color
+/// unchanged, so the colors of the reverse complement strand are the colors in reverse order.
+/// The primer base becomes the complement of the last base, decoded from the primer base and
+/// every color, so the reversed read decodes to the reverse complement of the original, primer
+/// base included, and reversing twice restores the original. When a color is not called, such as
+/// `.`, the last base is unknown and the primer base becomes `N`.
+///
+/// # Arguments
+///
+/// * `read` - The colorspace read, primer base first
+///
+/// # Returns
+///
+/// Returns false, leaving the read unchanged, if it does not begin with an A, C, G, or T primer
+/// base.
+///
+pub fn reverse_colorspace(read: &mut [u8]) -> bool {
+    let Some((primer, colors)) = read.split_first_mut() else {
+        return false;
+    };
+    let Some(mut code) = colorspace_code(*primer) else {
+        return false;
+    };
+    let mut called = true;
+    for &color in colors.iter() {
+        match color {
+            b'0'..=b'3' => code ^= color - b'0',
+            _ => called = false,
+        }
+    }
+    *primer = if called {
+        complement(b"ACGT"[code as usize])
+    } else {
+        b'N'
+    };
+    colors.reverse();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complement_preserves_case() {
+        assert_eq!(complement(b'A'), b'T');
+        assert_eq!(complement(b'c'), b'g');
+        assert_eq!(complement(b'N'), b'N');
+        assert_eq!(complement(b'R'), b'Y');
+        assert_eq!(complement(b'k'), b'm');
+    }
+
+    #[test]
+    fn test_complement_leaves_unknown_bytes() {
+        assert_eq!(complement(b'-'), b'-');
+        assert_eq!(complement(b'.'), b'.');
+    }
+
+    #[test]
+    fn test_revcomp() {
+        assert_eq!(revcomp(b"AtCg"), b"cGaT".to_vec());
+        assert_eq!(revcomp(b""), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_reverse_colorspace() {
+        // T3021 decodes to T, A, A, G, T; its reverse complement is A, C, T, T, A.
+        let mut read = b"T3021".to_vec();
+        assert!(reverse_colorspace(&mut read));
+        assert_eq!(read, b"A1203");
+        assert!(reverse_colorspace(&mut read));
+        assert_eq!(read, b"T3021");
+
+        let mut uncalled = b"G1.3".to_vec();
+        assert!(reverse_colorspace(&mut uncalled));
+        assert_eq!(uncalled, b"N3.1");
+
+        let mut primer_only = b"T".to_vec();
+        assert!(reverse_colorspace(&mut primer_only));
+        assert_eq!(primer_only, b"A");
+
+        for invalid in [&b""[..], b"0123", b"N0123"] {
+            let mut read = invalid.to_vec();
+            assert!(!reverse_colorspace(&mut read));
+            assert_eq!(read, invalid);
+        }
+    }
+
+    #[test]
+    fn test_revcomp_in_place() {
+        let mut seq = b"AAtCgN".to_vec();
+        revcomp_in_place(&mut seq);
+        assert_eq!(seq, b"NcGaTT");
+
+        let mut odd = b"ACG".to_vec();
+        revcomp_in_place(&mut odd);
+        assert_eq!(odd, b"CGT");
+
+        let mut empty: Vec<u8> = vec![];
+        revcomp_in_place(&mut empty);
+        assert!(empty.is_empty());
+    }
+}
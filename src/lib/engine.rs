@@ -0,0 +1,265 @@
+//! A reusable transform built once from a plan and options, for library users transforming
+//! records concurrently from their own threads.
+use rust_htslib::bam::Record;
+use std::error;
+
+use crate::aux::{self, Unsupported};
+use crate::ops::aux_data;
+use crate::options::Options;
+use crate::plan::{Plan, Tag};
+use crate::run::transform_record;
+use crate::sanitize::Repairs;
+
+/// A set of SAM tags, one bit for each of the 65,536 possible two-byte names, so membership is
+/// a single lookup however many tags are planned.
+#[derive(Clone, Debug)]
+struct TagSet(Box<[u64]>);
+
+impl TagSet {
+    /// Builds the set from tag names.
+    fn new<'a>(tags: impl IntoIterator<Item = &'a Tag>) -> Self {
+        let mut bits = vec![0u64; 1 << 10].into_boxed_slice();
+        for tag in tags {
+            let index = usize::from(u16::from_be_bytes(*tag));
+            bits[index / 64] |= 1 << (index % 64);
+        }
+        Self(bits)
+    }
+
+    /// Returns true if the set holds a tag.
+    fn contains(&self, tag: &Tag) -> bool {
+        let index = usize::from(u16::from_be_bytes(*tag));
+        self.0[index / 64] & (1 << (index % 64)) != 0
+    }
+}
+
+/// A plan and options compiled once into a transform that can be shared across threads, e.g.
+/// behind an `Arc` or a scoped borrow, to transform records concurrently.
+///
+/// Each record is transformed as [`crate::run`] would: the plan applies to reverse strand
+/// records passing the filters of the options, under the plan's policies, and records dropped
+/// per [`Options::drops`] are left untouched. The planned tags are compiled into a lookup table
+/// up front so that records carrying none of them are passed over with a single scan of their
+/// auxiliary data, whenever the options do nothing to such records.
+///
+/// Options naming inputs and outputs, such as `tee` or `audit_tsv`, have no effect.
+#[derive(Clone, Debug)]
+pub struct RevtagEngine {
+    plan: Plan,
+    options: Options,
+    planned: TagSet,
+    skips_unplanned: bool,
+}
+
+impl RevtagEngine {
+    /// Builds an engine applying a plan with the default options, as [`crate::transform_iter`]
+    /// does.
+    ///
+    /// # Arguments
+    ///
+    /// * `plan` - The tag specifications to apply to reverse strand records
+    ///
+    /// # Returns
+    ///
+    /// Returns the engine, ready to be shared across threads.
+    ///
+    pub fn new(plan: Plan) -> Self {
+        Self::with_options(plan, Options::default())
+    }
+
+    /// Builds an engine applying a plan with the given options, such as `strand_tag`,
+    /// `min_mapq`, or `strict`.
+    ///
+    /// # Arguments
+    ///
+    /// * `plan` - The tag specifications to apply to reverse strand records
+    /// * `options` - Options selecting and checking records
+    ///
+    /// # Returns
+    ///
+    /// Returns the engine, ready to be shared across threads.
+    ///
+    pub fn with_options(plan: Plan, options: Options) -> Self {
+        let planned = TagSet::new(plan.specs().iter().map(|spec| &spec.tag));
+        let skips_unplanned = !plan.is_empty()
+            && !plan.has_strand_sources()
+            && plan.pairs().is_empty()
+            && options.required_tags.is_empty()
+            && !options.strict
+            && !options.sanitize
+            && !options.restore_orientation
+            && !options.revert_to_ubam
+            && options.strand_annotation.is_none()
+            && options.qname_umi.is_none();
+        let options = Options {
+            audit_tsv: None,
+            ..options
+        };
+        Self {
+            plan,
+            options,
+            planned,
+            skips_unplanned,
+        }
+    }
+
+    /// Returns the plan the engine applies.
+    pub fn plan(&self) -> &Plan {
+        &self.plan
+    }
+
+    /// Returns the options the engine selects and checks records by.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Returns true if a record carries any planned tag.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The BAM record to inspect
+    ///
+    /// # Returns
+    ///
+    /// Returns whether any planned tag is present, or an error if the auxiliary data is
+    /// malformed.
+    ///
+    pub fn has_planned_tags(&self, record: &Record) -> Result<bool, Box<dyn error::Error>> {
+        aux::any_tag(aux_data(record), |tag| self.planned.contains(tag))
+    }
+
+    /// Checks the tags of a record and applies the plan if the record is selected.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The BAM record to mutate
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the record was transformed, or an error if the record fails its checks or
+    /// its auxiliary data is malformed.
+    ///
+    pub fn transform(&self, record: &mut Record) -> Result<bool, Box<dyn error::Error>> {
+        self.transform_tracked(record, &mut Unsupported::default(), &mut Repairs::default())
+    }
+
+    /// Transforms a record as [`RevtagEngine::transform`] does, counting planned tags left
+    /// untouched because of their type and any repairs made with `sanitize`. Each thread keeps
+    /// its own counts, to be merged once its records are exhausted.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The BAM record to mutate
+    /// * `unsupported` - Counts of planned tags left untouched, updated in place
+    /// * `repairs` - Counts of repairs to malformed auxiliary data, updated in place
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the record was transformed, or an error if the record fails its checks or
+    /// its auxiliary data is malformed.
+    ///
+    pub fn transform_tracked(
+        &self,
+        record: &mut Record,
+        unsupported: &mut Unsupported,
+        repairs: &mut Repairs,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        if self.skips_unplanned && !self.has_planned_tags(record)? {
+            return Ok(false);
+        }
+        let transformed =
+            transform_record(record, &self.plan, &self.options, unsupported, repairs)?;
+        Ok(transformed.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::Aux;
+    use std::thread;
+
+    /// Builds a record carrying a `BC` barcode, on the reverse strand if `reverse` is set.
+    fn record(barcode: &str, reverse: bool) -> Record {
+        let mut record = Record::new();
+        record.set_qname(b"read");
+        if reverse {
+            record.set_reverse();
+        }
+        record.push_aux(b"BC", Aux::String(barcode)).unwrap();
+        record
+    }
+
+    #[test]
+    fn test_engine_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<RevtagEngine>();
+    }
+
+    #[test]
+    fn test_tag_set() {
+        let set = TagSet::new(&[*b"BC", *b"QT"]);
+        assert!(set.contains(b"BC"));
+        assert!(set.contains(b"QT"));
+        assert!(!set.contains(b"CB"));
+        assert!(!set.contains(b"XX"));
+    }
+
+    #[test]
+    fn test_transform() {
+        let plan = Plan::new(&[], &["BC".to_string()]).unwrap();
+        let engine = RevtagEngine::new(plan);
+
+        let mut reverse = record("AACG", true);
+        assert!(engine.transform(&mut reverse).unwrap());
+        assert_eq!(reverse.aux(b"BC").unwrap(), Aux::String("CGTT"));
+
+        let mut forward = record("AACG", false);
+        assert!(!engine.transform(&mut forward).unwrap());
+        assert_eq!(forward.aux(b"BC").unwrap(), Aux::String("AACG"));
+
+        let mut unplanned = Record::new();
+        unplanned.set_reverse();
+        assert!(!engine.has_planned_tags(&unplanned).unwrap());
+        assert!(!engine.transform(&mut unplanned).unwrap());
+    }
+
+    #[test]
+    fn test_transform_with_options() {
+        let plan = Plan::new(&[], &["XX".to_string()]).unwrap();
+        let options = Options {
+            strict: true,
+            ..Options::default()
+        };
+        let engine = RevtagEngine::with_options(plan, options);
+        assert!(engine.transform(&mut record("AACG", true)).is_err());
+        assert!(engine.transform(&mut record("AACG", false)).is_ok());
+    }
+
+    #[test]
+    fn test_transform_across_threads() {
+        let plan = Plan::new(&[], &["BC".to_string()]).unwrap();
+        let engine = RevtagEngine::new(plan);
+        let barcodes: Vec<String> = thread::scope(|scope| {
+            let workers: Vec<_> = ["AACG", "ACGT", "GGGA"]
+                .into_iter()
+                .map(|barcode| {
+                    let engine = &engine;
+                    scope.spawn(move || {
+                        let mut record = record(barcode, true);
+                        engine.transform(&mut record).unwrap();
+                        match record.aux(b"BC").unwrap() {
+                            Aux::String(value) => value.to_string(),
+                            other => panic!("unexpected BC value {other:?}"),
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect()
+        });
+        assert_eq!(barcodes, ["CGTT", "ACGT", "TCCC"]);
+    }
+}
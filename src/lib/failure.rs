@@ -0,0 +1,166 @@
+//! Structured reports of failed runs, as written by `--error-json`.
+//!
+//! Workflow engines such as Cromwell and Nextflow can attach a failure reason to a task, but
+//! only by scraping stderr unless the tool says what went wrong in a structured form. A
+//! [`Failure`] holds the category of an error, its message, the record it concerns when known,
+//! and the exit status, and is written as a small JSON object.
+use std::error;
+use std::io;
+
+use crate::aux::{MissingTagError, PairLengthError, UnexpectedValueError, UnpairedTagError};
+use crate::config::Json;
+use crate::plan::Tag;
+
+/// What kind of error ended a run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    /// A tag name, specification, condition, or other argument was invalid.
+    Usage,
+    /// A record failed a check, such as a missing required tag or an unpaired companion tag.
+    Record,
+    /// Reading or writing a file or stream failed.
+    Io,
+    /// Any other error.
+    Failure,
+}
+
+impl Category {
+    /// Returns the name of the category as written to the report.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Usage => "usage",
+            Category::Record => "record",
+            Category::Io => "io",
+            Category::Failure => "failure",
+        }
+    }
+}
+
+/// The record a failure concerns.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FailedRecord {
+    /// The name of the record.
+    pub qname: String,
+    /// The SAM tag the failure concerns.
+    pub tag: Tag,
+}
+
+/// A structured report of the error that ended a run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Failure {
+    /// What kind of error ended the run.
+    pub category: Category,
+    /// The error message and its causes, as printed on stderr.
+    pub message: String,
+    /// The record the error concerns, if known.
+    pub record: Option<FailedRecord>,
+    /// The exit status of the run.
+    pub exit_code: i32,
+}
+
+impl Failure {
+    /// Builds a report of an error, recognizing the errors of record checks and I/O.
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - The error that ended the run
+    /// * `message` - The message printed for the error, including any context
+    /// * `exit_code` - The exit status of the run
+    ///
+    /// # Returns
+    ///
+    /// Returns the report.
+    ///
+    pub fn from_error(
+        error: &(dyn error::Error + 'static),
+        message: String,
+        exit_code: i32,
+    ) -> Self {
+        let record = failed_record(error);
+        let category = if record.is_some() {
+            Category::Record
+        } else if error.downcast_ref::<io::Error>().is_some() {
+            Category::Io
+        } else {
+            Category::Failure
+        };
+        Self {
+            category,
+            message,
+            record,
+            exit_code,
+        }
+    }
+
+    /// Returns the report as a JSON object, followed by a newline.
+    pub fn to_json(&self) -> String {
+        let record = Json::optional(self.record.as_ref(), |record| {
+            Json::Object(vec![
+                ("qname", Json::String(record.qname.clone())),
+                ("tag", Json::tag(&record.tag)),
+            ])
+        });
+        let report = Json::Object(vec![
+            ("category", Json::String(self.category.name().to_string())),
+            ("message", Json::String(self.message.clone())),
+            ("record", record),
+            ("exit_code", Json::Number(self.exit_code.max(0) as u64)),
+        ]);
+        let mut out = String::new();
+        report.write(&mut out, 0);
+        out.push('\n');
+        out
+    }
+}
+
+/// Returns the record an error from a record check concerns, or None for other errors.
+fn failed_record(error: &(dyn error::Error + 'static)) -> Option<FailedRecord> {
+    let (qname, tag) = if let Some(e) = error.downcast_ref::<MissingTagError>() {
+        (&e.qname, e.tag)
+    } else if let Some(e) = error.downcast_ref::<UnpairedTagError>() {
+        (&e.qname, e.tag)
+    } else if let Some(e) = error.downcast_ref::<PairLengthError>() {
+        (&e.qname, e.pair.sequence)
+    } else if let Some(e) = error.downcast_ref::<UnexpectedValueError>() {
+        (&e.qname, e.tag)
+    } else {
+        return None;
+    };
+    Some(FailedRecord {
+        qname: qname.clone(),
+        tag,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_from_record_error() {
+        let error = MissingTagError {
+            qname: "read1".to_string(),
+            tag: *b"QT",
+        };
+        let failure = Failure::from_error(&error, error.to_string(), 1);
+        assert_eq!(failure.category, Category::Record);
+        assert_eq!(
+            failure.to_json(),
+            "{\n  \"category\": \"record\",\n  \"message\": \"Record read1 is missing required \
+             tag QT\",\n  \"record\": {\n    \"qname\": \"read1\",\n    \"tag\": \"QT\"\n  },\n  \
+             \"exit_code\": 1\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_failure_categories() {
+        let error = io::Error::new(io::ErrorKind::NotFound, "missing");
+        let failure = Failure::from_error(&error, error.to_string(), 1);
+        assert_eq!((failure.category, failure.record), (Category::Io, None));
+
+        let error: Box<dyn error::Error> = "Batch size must be at least 1".into();
+        let failure = Failure::from_error(error.as_ref(), error.to_string(), 1);
+        assert_eq!(failure.category, Category::Failure);
+        assert!(failure.to_json().contains("\"record\": null,"));
+    }
+}
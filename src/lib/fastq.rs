@@ -0,0 +1,734 @@
+//! Reading, transforming, and writing FASTQ whose header comments carry SAM tags.
+//!
+//! `samtools fastq -T` appends the requested tags to each header line as tab-separated
+//! `TAG:TYPE:VALUE` fields. FASTQ records carry no flags, so the strand of each record is read
+//! from a tag holding `+` or `-`, as named by `options.strand_tag`.
+use log::*;
+use proglog::ProgLogBuilder;
+use std::error;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::aux::{self, MissingTagError, NonAsciiError};
+use crate::checksum::{Md5Writer, write_sidecar};
+use crate::dna;
+use crate::fsync::sync_outputs;
+use crate::options::Options;
+use crate::plan::{DuplicateTags, Encoding, NonSequence, Operation, Plan, Tag};
+
+/// A FASTQ record with the SAM tags of its header comment kept as separate fields.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FastqRecord {
+    /// The read name, without the leading `@`.
+    pub name: String,
+    /// The tab-separated fields following the name, usually `TAG:TYPE:VALUE` SAM tags.
+    pub fields: Vec<String>,
+    /// The bases.
+    pub sequence: String,
+    /// The base qualities.
+    pub quality: String,
+}
+
+/// Returns true if a path names a FASTQ file by its extension, `.fastq` or `.fq`.
+pub fn is_fastq_path(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("fastq" | "fq")
+    )
+}
+
+/// Reads a line into `line` without its line ending, returning false at end of input.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<bool> {
+    line.clear();
+    if reader.read_line(line)? == 0 {
+        return Ok(false);
+    }
+    let len = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(len);
+    Ok(true)
+}
+
+/// Reads the next FASTQ record into `record`.
+///
+/// # Arguments
+///
+/// * `reader` - The FASTQ input
+/// * `record` - The record to overwrite
+///
+/// # Returns
+///
+/// Returns false at end of input, or an error if the record is truncated or malformed.
+///
+pub fn read_record<R: BufRead>(
+    reader: &mut R,
+    record: &mut FastqRecord,
+) -> Result<bool, Box<dyn error::Error>> {
+    let mut line = String::new();
+    if !read_line(reader, &mut line)? {
+        return Ok(false);
+    }
+    let Some(header) = line.strip_prefix('@') else {
+        return Err(format!("FASTQ header does not start with '@': {line}").into());
+    };
+    let mut fields = header.split('\t');
+    record.name = fields.next().unwrap_or_default().to_string();
+    record.fields = fields.map(String::from).collect();
+
+    let mut separator = String::new();
+    let complete = read_line(reader, &mut record.sequence)?
+        && read_line(reader, &mut separator)?
+        && read_line(reader, &mut record.quality)?;
+    if !complete {
+        return Err(format!("Truncated FASTQ record {}", record.name).into());
+    }
+    if !separator.starts_with('+') {
+        return Err(format!("FASTQ record {} has no '+' separator line", record.name).into());
+    }
+    Ok(true)
+}
+
+/// Writes a FASTQ record, joining its header fields with tabs.
+pub fn write_record<W: Write>(writer: &mut W, record: &FastqRecord) -> io::Result<()> {
+    write!(writer, "@{}", record.name)?;
+    for field in &record.fields {
+        write!(writer, "\t{field}")?;
+    }
+    writeln!(writer, "\n{}\n+\n{}", record.sequence, record.quality)
+}
+
+/// Returns the tag of a `TAG:TYPE:VALUE` field, or None if the field is not a SAM tag.
+fn tag_of(field: &str) -> Option<Tag> {
+    match field.as_bytes() {
+        [a, b, b':', _, b':', ..] => Some([*a, *b]),
+        _ => None,
+    }
+}
+
+/// Returns true if a strand tag among the fields holds `-`, as an `A` character or a `Z` string.
+pub fn minus_strand(fields: &[String], tag: &Tag) -> bool {
+    fields
+        .iter()
+        .any(|field| tag_of(field) == Some(*tag) && matches!(&field[3..], "A:-" | "Z:-"))
+}
+
+/// Returns the first of `tags` absent from the fields.
+pub fn missing_tag<'a, I>(fields: &[String], tags: I) -> Option<Tag>
+where
+    I: IntoIterator<Item = &'a Tag>,
+{
+    tags.into_iter()
+        .find(|tag| !fields.iter().any(|field| tag_of(field) == Some(**tag)))
+        .copied()
+}
+
+/// Returns the number of elements of a tag's value among the fields: characters of `Z` and `H`
+/// strings, elements of `B` arrays, and one for scalars, or None if the tag is absent.
+pub fn value_length(fields: &[String], tag: &Tag) -> Option<usize> {
+    let field = fields.iter().find(|field| tag_of(field) == Some(*tag))?;
+    Some(match field.as_bytes()[3] {
+        b'Z' | b'H' => field.len() - 5,
+        b'B' => field[5..].split(',').count() - 1,
+        _ => 1,
+    })
+}
+
+/// Returns the bytes of the `Z` or `H` string in a `TAG:TYPE:VALUE` field for `tag`, or None
+/// if no field holds the tag. Values of other types are returned empty, so they never match a
+/// validated value.
+pub fn string_value(fields: &[String], tag: &Tag) -> Option<Vec<u8>> {
+    let field = fields.iter().find(|field| tag_of(field) == Some(*tag))?;
+    Some(match field.as_bytes()[3] {
+        b'Z' | b'H' => field.as_bytes()[5..].to_vec(),
+        _ => Vec::new(),
+    })
+}
+
+/// Reverses or reverse complements the elements of a `B` array value such as `C,1,2,3`,
+/// returning None if the element type cannot be reverse complemented.
+///
+/// Elements are copied straight into the result from the end of the value, so long arrays such
+/// as per-base kinetics are not split into a string per element.
+fn transform_array(
+    value: &str,
+    operation: Operation,
+) -> Result<Option<String>, Box<dyn error::Error>> {
+    let (subtype, elements) = value.split_once(',').unwrap_or((value, ""));
+    let supported = match operation {
+        Operation::Reverse => true,
+        Operation::ReverseComplement | Operation::Complement => subtype == "C",
+        Operation::ReverseColorspace | Operation::Rename(_) | Operation::Convert(_) => false,
+    };
+    if !supported {
+        return Ok(None);
+    }
+    let mut transformed = String::with_capacity(value.len());
+    transformed.push_str(subtype);
+    if elements.is_empty() {
+        return Ok(Some(transformed));
+    }
+    let elements: Box<dyn Iterator<Item = &str>> = match operation {
+        Operation::Complement => Box::new(elements.split(',')),
+        _ => Box::new(elements.rsplit(',')),
+    };
+    for element in elements {
+        transformed.push(',');
+        if matches!(
+            operation,
+            Operation::ReverseComplement | Operation::Complement
+        ) {
+            let base = dna::complement(element.parse::<u8>()?);
+            write!(transformed, "{base}")?;
+        } else {
+            transformed.push_str(element);
+        }
+    }
+    Ok(Some(transformed))
+}
+
+/// Converts a `TAG:Z:VALUE` or `TAG:B:C,...` field to `encoding`, returning None for other
+/// types.
+fn convert_field(
+    field: &str,
+    tag: Tag,
+    encoding: Encoding,
+) -> Result<Option<String>, Box<dyn error::Error>> {
+    let (prefix, value) = field.split_at(5);
+    let name = &field[..2];
+    match (prefix.as_bytes()[3], encoding) {
+        (b'Z', Encoding::String) => Ok(Some(field.to_string())),
+        (b'Z', Encoding::ByteArray) => {
+            let mut converted = format!("{name}:B:C");
+            for byte in value.bytes() {
+                write!(converted, ",{byte}")?;
+            }
+            Ok(Some(converted))
+        }
+        (b'B', _) if value.split(',').next() != Some("C") => Ok(None),
+        (b'B', Encoding::ByteArray) => Ok(Some(field.to_string())),
+        (b'B', Encoding::String) => {
+            let bytes = value
+                .split(',')
+                .skip(1)
+                .map(str::parse::<u8>)
+                .collect::<Result<Vec<_>, _>>()?;
+            aux::check_printable(&tag, &bytes)?;
+            Ok(Some(format!("{name}:Z:{}", String::from_utf8(bytes)?)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Applies an operation to a `TAG:TYPE:VALUE` field, returning None if its type is unsupported.
+///
+/// Values to reverse complement that are not sequences are handled as directed by `policy`.
+fn transform_field(
+    field: &str,
+    tag: Tag,
+    operation: Operation,
+    policy: NonSequence,
+) -> Result<Option<String>, Box<dyn error::Error>> {
+    if let Operation::Rename(new) = operation {
+        return Ok(Some(format!(
+            "{}{}",
+            String::from_utf8_lossy(&new),
+            &field[2..]
+        )));
+    }
+    if let Operation::Convert(encoding) = operation {
+        return convert_field(field, tag, encoding);
+    }
+    let (prefix, value) = field.split_at(5);
+    let transformed = match (prefix.as_bytes()[3], operation) {
+        (b'Z', Operation::Reverse) => Some(value.chars().rev().collect()),
+        (b'Z', Operation::ReverseComplement | Operation::Complement) => {
+            if let Some(position) = value.bytes().position(|b| !b.is_ascii()) {
+                return Err(Box::new(NonAsciiError {
+                    tag,
+                    position,
+                    byte: value.as_bytes()[position],
+                }));
+            }
+            let bases = match operation {
+                Operation::Complement => value.bytes().map(dna::complement).collect(),
+                _ => dna::revcomp(value.as_bytes()),
+            };
+            Some(String::from_utf8(bases)?)
+        }
+        (b'Z', Operation::ReverseColorspace) => {
+            let mut read = value.as_bytes().to_vec();
+            if dna::reverse_colorspace(&mut read) {
+                Some(String::from_utf8(read)?)
+            } else {
+                None
+            }
+        }
+        (b'B', _) => transform_array(value, operation)?,
+        _ => None,
+    };
+
+    match (transformed, operation, policy) {
+        (Some(value), _, _) => Ok(Some(format!("{prefix}{value}"))),
+        (None, Operation::ReverseComplement, NonSequence::Error) => {
+            let value_type = match prefix.as_bytes()[3] {
+                b'B' => format!("B:{}", value.split(',').next().unwrap_or_default()),
+                value_type => (value_type as char).to_string(),
+            };
+            Err(format!(
+                "Tag {} holds a value of type {value_type} that is not a sequence and cannot be \
+                 reverse complemented",
+                String::from_utf8_lossy(&tag)
+            )
+            .into())
+        }
+        (None, Operation::ReverseComplement, NonSequence::ReverseOnly) => {
+            transform_field(field, tag, Operation::Reverse, policy)
+        }
+        (None, _, _) => Ok(None),
+    }
+}
+
+/// Applies every tag specification in a plan to the SAM tag fields of a FASTQ header.
+///
+/// The record strand is not consulted; callers decide which records the plan applies to.
+///
+/// # Arguments
+///
+/// * `fields` - The header fields following the read name
+/// * `plan` - The tag specifications to apply
+///
+/// # Returns
+///
+/// Returns the number of fields transformed, or an error if a value cannot be transformed.
+///
+pub fn apply(fields: &mut [String], plan: &Plan) -> Result<usize, Box<dyn error::Error>> {
+    let mut transformed = 0;
+    for field in fields.iter_mut() {
+        let Some(tag) = tag_of(field) else {
+            continue;
+        };
+        for spec in plan.specs().iter().filter(|spec| spec.tag == tag) {
+            if let Some(value) = transform_field(field, tag, spec.operation, plan.nonsequence())? {
+                *field = value;
+                transformed += 1;
+            }
+        }
+    }
+    Ok(transformed)
+}
+
+/// Runs the tool `revtag` on FASTQ input whose header comments carry SAM tags.
+///
+/// Records whose `options.strand_tag` holds `-` have the plan applied to their tags; sequences
+/// and qualities are written unchanged. `options.strict` and `options.required_tags` are checked
+/// as for alignment input.
+///
+/// # Arguments
+///
+/// * `input` - The input FASTQ file path, or None for stdin
+/// * `output` - The output FASTQ file path, or None for stdout
+/// * `plan` - The tag specifications to apply to reverse strand records
+/// * `options` - Options controlling how records are transformed
+///
+/// # Returns
+///
+/// Returns the result of the execution with an integer exit code for success (0).
+///
+pub fn run(
+    input: Option<&Path>,
+    output: Option<&Path>,
+    plan: &Plan,
+    options: &Options,
+) -> Result<i32, Box<dyn error::Error>> {
+    if options.strand_from_mi {
+        return Err("Duplex strands from MI tags are not supported for FASTQ input".into());
+    }
+    let Some(strand_tag) = options.strand_tag else {
+        return Err("FASTQ records carry no flags, so FASTQ input requires a strand tag".into());
+    };
+    if options.restore_orientation {
+        return Err("Restoring the original orientation is not supported for FASTQ input".into());
+    }
+    if options.revert_to_ubam {
+        return Err("FASTQ input is already unaligned".into());
+    }
+    if options.strand_annotation.is_some() {
+        return Err("Strand annotations are not supported for FASTQ input".into());
+    }
+    if options.qname_umi.is_some() {
+        return Err("UMIs in read names are not supported for FASTQ input".into());
+    }
+    if options.preserve_header {
+        return Err("FASTQ input has no header to preserve".into());
+    }
+    if options.cram_lossy_names || options.cram_quality_bins.is_some() {
+        return Err("CRAM output options are not supported for FASTQ input".into());
+    }
+    if !options.plugins.is_empty() || options.script.is_some() {
+        return Err("WASM plugins and scripts are not supported for FASTQ input".into());
+    }
+    if options.barcode_fastq.is_some() {
+        return Err("A barcode FASTQ is not supported for FASTQ input".into());
+    }
+    if options.audit_tsv.is_some() {
+        return Err("An audit table is not supported for FASTQ input".into());
+    }
+    if options.contig_tsv.is_some() {
+        return Err(
+            "A per-contig summary is not supported for FASTQ input, whose records are unplaced"
+                .into(),
+        );
+    }
+    if options.sanitize {
+        return Err("Sanitizing aux tags is not supported for FASTQ input".into());
+    }
+    if options.profile {
+        return Err("Profiling stages is not supported for FASTQ input".into());
+    }
+    if !options.tee.is_empty() {
+        return Err("Multiple outputs are not supported for FASTQ input".into());
+    }
+    if options.condition.is_some()
+        || !options.tag_filters.is_empty()
+        || options.min_mapq.is_some()
+        || options.read.is_some()
+        || options.qcfail.is_some()
+    {
+        return Err(
+            "Filtering records by condition, tag value, mapping quality, read of pair, or QC-fail \
+             flag is not supported for FASTQ input, whose records carry no flags or alignment fields"
+                .into(),
+        );
+    }
+    if options.progress_fd.is_some() {
+        return Err("Progress events are not supported for FASTQ input".into());
+    }
+    if options.preflight.is_some() {
+        return Err("A preflight scan is not supported for FASTQ input".into());
+    }
+    if options.expected_sort_order.is_some() {
+        return Err("FASTQ input has no sort order to validate".into());
+    }
+    if !options.pair_fix.is_empty() {
+        return Err("Propagating tags between mates is not supported for FASTQ input".into());
+    }
+    if options.whitelist.is_some() {
+        return Err("Matching barcodes to a whitelist is not supported for FASTQ input".into());
+    }
+    if options.max_errors > 0 {
+        return Err("Tolerating record errors is not supported for FASTQ input".into());
+    }
+    if options.retries > 0 {
+        return Err("Retrying remote inputs is not supported for FASTQ input".into());
+    }
+    if options.auto_orient.is_some() {
+        return Err("Detecting tag orientations is not supported for FASTQ input".into());
+    }
+    if plan.has_strand_sources() {
+        return Err("Tags with their own strand source are not supported for FASTQ input".into());
+    }
+    if plan.has_matrices() {
+        return Err("Matrix tags are not supported for FASTQ input".into());
+    }
+    if plan.duplicate_tags() != DuplicateTags::First {
+        return Err("Transforming repeated tags is not supported for FASTQ input".into());
+    }
+
+    let mut reader: Box<dyn BufRead> = match input {
+        None => {
+            info!("Input: stdin");
+            Box::new(BufReader::new(io::stdin().lock()))
+        }
+        Some(path) => {
+            info!("Input: {path:?}");
+            Box::new(BufReader::new(File::open(path)?))
+        }
+    };
+    match output {
+        None => {
+            info!("Output: stdout");
+            let mut writer = BufWriter::new(io::stdout().lock());
+            transform_records(&mut reader, &mut writer, plan, options, &strand_tag)?;
+        }
+        Some(path) if options.write_md5 => {
+            info!("Output: {path:?}");
+            let mut writer = Md5Writer::new(BufWriter::new(File::create(path)?));
+            transform_records(&mut reader, &mut writer, plan, options, &strand_tag)?;
+            let (_, digest) = writer.finish();
+            write_sidecar(path, &digest)?;
+        }
+        Some(path) => {
+            info!("Output: {path:?}");
+            let mut writer = BufWriter::new(File::create(path)?);
+            transform_records(&mut reader, &mut writer, plan, options, &strand_tag)?;
+        }
+    }
+    sync_outputs(output, options)?;
+    Ok(0)
+}
+
+/// Transforms every FASTQ record of a reader, writing each to `writer`, which is flushed once
+/// the input is exhausted.
+fn transform_records<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    plan: &Plan,
+    options: &Options,
+    strand_tag: &Tag,
+) -> Result<(), Box<dyn error::Error>> {
+    let progress = ProgLogBuilder::new()
+        .name("main")
+        .verb("Processed")
+        .noun("FASTQ records")
+        .unit(100_000)
+        .build();
+
+    let mut record = FastqRecord::default();
+    while read_record(reader, &mut record)? {
+        let reverse = minus_strand(&record.fields, strand_tag);
+        let mut missing = missing_tag(&record.fields, &options.required_tags);
+        if missing.is_none() && options.strict && reverse {
+            missing = missing_tag(&record.fields, plan.specs().iter().map(|spec| &spec.tag));
+        }
+        if let Some(tag) = missing {
+            return Err(Box::new(MissingTagError {
+                qname: record.name.clone(),
+                tag,
+            }));
+        }
+        if reverse {
+            aux::check_pairs(
+                plan,
+                || record.name.clone(),
+                |tag| Ok(value_length(&record.fields, tag)),
+            )?;
+            aux::check_values(
+                plan,
+                || record.name.clone(),
+                |tag| Ok(string_value(&record.fields, tag)),
+            )?;
+            apply(&mut record.fields, plan)?;
+        }
+        write_record(writer, &record)?;
+        progress.record();
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const FASTQ: &str = "@minus\tXS:A:-\tBC:Z:GATT\tQT:Z:ABCD\tXB:B:C,65,67\n\
+ACGT\n\
++\n\
+IIII\n\
+@plus\tXS:A:+\tBC:Z:GATT\n\
+ACGT\n\
++\n\
+IIII\n";
+
+    fn fields(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn test_read_write_round_trip() {
+        let mut reader = Cursor::new(FASTQ);
+        let mut record = FastqRecord::default();
+        let mut output = Vec::new();
+        while read_record(&mut reader, &mut record).unwrap() {
+            write_record(&mut output, &record).unwrap();
+        }
+        assert_eq!(String::from_utf8(output).unwrap(), FASTQ);
+    }
+
+    #[test]
+    fn test_read_record_errors() {
+        let mut record = FastqRecord::default();
+        assert!(read_record(&mut Cursor::new("read\nACGT\n+\nIIII\n"), &mut record).is_err());
+        assert!(read_record(&mut Cursor::new("@read\nACGT\n"), &mut record).is_err());
+        assert!(read_record(&mut Cursor::new("@read\nACGT\nIIII\nIIII\n"), &mut record).is_err());
+        assert!(!read_record(&mut Cursor::new(""), &mut record).unwrap());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut tags = fields(&[
+            "XS:A:-",
+            "BC:Z:GATT",
+            "QT:Z:ABCD",
+            "XB:B:C,65,67",
+            "XQ:B:s,1,2",
+        ]);
+        let plan = Plan::new(
+            &["QT".to_string(), "XQ".to_string()],
+            &["BC".to_string(), "XB".to_string()],
+        )
+        .unwrap();
+        assert_eq!(apply(&mut tags, &plan).unwrap(), 4);
+        assert_eq!(
+            tags,
+            fields(&[
+                "XS:A:-",
+                "BC:Z:AATC",
+                "QT:Z:DCBA",
+                "XB:B:C,71,84",
+                "XQ:B:s,2,1"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_apply_nonsequence_policy() {
+        let plan = Plan::new(&[], &["XQ".to_string()]).unwrap();
+        let mut tags = fields(&["XQ:B:s,1,2"]);
+        assert_eq!(apply(&mut tags, &plan).unwrap(), 0);
+
+        let reverse_only = plan.clone().with_nonsequence(NonSequence::ReverseOnly);
+        assert_eq!(apply(&mut tags, &reverse_only).unwrap(), 1);
+        assert_eq!(tags, fields(&["XQ:B:s,2,1"]));
+
+        let error = plan.with_nonsequence(NonSequence::Error);
+        let err = apply(&mut tags, &error).unwrap_err();
+        assert!(err.to_string().contains("Tag XQ holds a value of type B:s"));
+    }
+
+    #[test]
+    fn test_transform_array() {
+        let reverse = |value| transform_array(value, Operation::Reverse).unwrap();
+        let revcomp = |value| transform_array(value, Operation::ReverseComplement).unwrap();
+        assert_eq!(reverse("s,1,-2,3"), Some("s,3,-2,1".to_string()));
+        assert_eq!(reverse("f,1.5"), Some("f,1.5".to_string()));
+        assert_eq!(reverse("C"), Some("C".to_string()));
+        assert_eq!(revcomp("C,65,67"), Some("C,71,84".to_string()));
+        assert_eq!(revcomp("s,1,2"), None);
+        assert!(transform_array("C,65,x", Operation::ReverseComplement).is_err());
+
+        let complement = |value| transform_array(value, Operation::Complement).unwrap();
+        assert_eq!(complement("C,65,67"), Some("C,84,71".to_string()));
+        assert_eq!(
+            transform_array("C,65", Operation::Rename(*b"BC")).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_apply_chain() {
+        let plan = Plan::parse(&[], &[], &["XB:comp,rev,rename=BC".to_string()]).unwrap();
+        let mut tags = fields(&["XB:Z:AACG", "NM:i:3"]);
+        assert_eq!(apply(&mut tags, &plan).unwrap(), 3);
+        assert_eq!(tags, fields(&["BC:Z:CGTT", "NM:i:3"]));
+    }
+
+    #[test]
+    fn test_apply_conversion() {
+        let plan = Plan::parse(&[], &[], &["XB:revcomp,as=B:C".to_string()]).unwrap();
+        let mut tags = fields(&["XB:Z:AACG", "NM:i:3"]);
+        assert_eq!(apply(&mut tags, &plan).unwrap(), 2);
+        assert_eq!(tags, fields(&["XB:B:C,67,71,84,84", "NM:i:3"]));
+
+        let plan = Plan::parse(&[], &[], &["XB:as=Z".to_string()]).unwrap();
+        assert_eq!(apply(&mut tags, &plan).unwrap(), 1);
+        assert_eq!(tags, fields(&["XB:Z:CGTT", "NM:i:3"]));
+
+        let mut tags = fields(&["XB:B:C,65,10"]);
+        assert!(apply(&mut tags, &plan).is_err());
+        let mut tags = fields(&["XB:B:s,65"]);
+        assert_eq!(apply(&mut tags, &plan).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_minus_strand_and_missing_tag() {
+        let tags = fields(&["XS:A:-", "BC:Z:GATT"]);
+        assert!(minus_strand(&tags, b"XS"));
+        assert!(!minus_strand(&fields(&["XS:A:+"]), b"XS"));
+        assert!(!minus_strand(&tags, b"TS"));
+        assert_eq!(missing_tag(&tags, [b"BC"]), None);
+        assert_eq!(missing_tag(&tags, [b"BC", b"QT"]), Some(*b"QT"));
+    }
+
+    #[test]
+    fn test_value_length() {
+        let tags = fields(&["BC:Z:GATT", "QT:B:C,1,2,3", "NM:i:0", "EM:Z:"]);
+        assert_eq!(value_length(&tags, b"BC"), Some(4));
+        assert_eq!(value_length(&tags, b"QT"), Some(3));
+        assert_eq!(value_length(&tags, b"NM"), Some(1));
+        assert_eq!(value_length(&tags, b"EM"), Some(0));
+        assert_eq!(value_length(&tags, b"XX"), None);
+    }
+
+    #[test]
+    fn test_run() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let input = tmpdir.path().join("in.fq");
+        let output = tmpdir.path().join("out.fq");
+        std::fs::write(&input, FASTQ).unwrap();
+        let plan = Plan::new(&["QT".to_string()], &["BC".to_string()]).unwrap();
+
+        assert!(
+            run(
+                Some(input.as_path()),
+                Some(output.as_path()),
+                &plan,
+                &Options::default()
+            )
+            .is_err()
+        );
+
+        let options = Options {
+            strand_tag: Some(*b"XS"),
+            ..Options::default()
+        };
+        run(
+            Some(input.as_path()),
+            Some(output.as_path()),
+            &plan,
+            &options,
+        )
+        .unwrap();
+        let written = std::fs::read_to_string(&output).unwrap();
+        assert!(written.starts_with("@minus\tXS:A:-\tBC:Z:AATC\tQT:Z:DCBA\t"));
+        assert!(written.contains("@plus\tXS:A:+\tBC:Z:GATT\n"));
+    }
+
+    #[test]
+    fn test_run_write_md5() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let input = tmpdir.path().join("in.fq");
+        let output = tmpdir.path().join("out.fq");
+        std::fs::write(&input, FASTQ).unwrap();
+        let plan = Plan::new(&["QT".to_string()], &[]).unwrap();
+        let options = Options {
+            strand_tag: Some(*b"XS"),
+            write_md5: true,
+            ..Options::default()
+        };
+
+        run(
+            Some(input.as_path()),
+            Some(output.as_path()),
+            &plan,
+            &options,
+        )
+        .unwrap();
+        let mut md5 = Md5Writer::new(Vec::new());
+        md5.write_all(&std::fs::read(&output).unwrap()).unwrap();
+        let (_, digest) = md5.finish();
+        assert_eq!(
+            std::fs::read_to_string(crate::checksum::sidecar_path(&output)).unwrap(),
+            format!("{digest}  out.fq\n")
+        );
+    }
+
+    #[test]
+    fn test_is_fastq_path() {
+        assert!(is_fastq_path(Path::new("reads.fq")));
+        assert!(is_fastq_path(Path::new("reads.fastq")));
+        assert!(!is_fastq_path(Path::new("reads.bam")));
+    }
+}
@@ -0,0 +1,311 @@
+//! A C interface for linking revtag into htslib-based C/C++ tools.
+//!
+//! Functions return `0` on success and `-1` on failure, in which case a message describing the
+//! failure is available from [`revtag_last_error`]. The matching C header is `include/revtag.h`,
+//! generated with `cbindgen --config cbindgen.toml --output include/revtag.h`.
+use rust_htslib::bam::Record;
+use rust_htslib::htslib;
+use std::cell::RefCell;
+use std::error;
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::path::PathBuf;
+use std::ptr;
+
+use crate::ops::apply;
+use crate::plan::Plan;
+use crate::run::revtag;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// An opaque operation plan handle owned by C callers.
+pub struct RevtagPlan(Plan);
+
+/// Records an error message for retrieval with [`revtag_last_error`].
+fn set_last_error(err: Box<dyn error::Error>) {
+    let message = CString::new(err.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Converts a status result into the C convention of `0` for success and `-1` for failure.
+fn status(result: Result<(), Box<dyn error::Error>>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// Reads a UTF-8 string from a NUL-terminated C string.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a valid NUL-terminated string.
+unsafe fn string(ptr: *const c_char) -> Result<String, Box<dyn error::Error>> {
+    if ptr.is_null() {
+        return Err("Unexpected null string".into());
+    }
+    Ok(unsafe { CStr::from_ptr(ptr) }.to_str()?.to_string())
+}
+
+/// Reads an array of UTF-8 strings from `len` NUL-terminated C strings.
+///
+/// # Safety
+///
+/// `ptr` must be null with `len` zero, or point to `len` valid NUL-terminated strings.
+unsafe fn strings(
+    ptr: *const *const c_char,
+    len: usize,
+) -> Result<Vec<String>, Box<dyn error::Error>> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    if ptr.is_null() {
+        return Err("Unexpected null string array".into());
+    }
+    unsafe { std::slice::from_raw_parts(ptr, len) }
+        .iter()
+        .map(|&s| unsafe { string(s) })
+        .collect()
+}
+
+/// Returns the message for the most recent error on this thread, or null if there is none.
+///
+/// The returned string is owned by revtag and is valid until the next call into revtag on this
+/// thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn revtag_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Builds a plan from arrays of tag names to reverse and to reverse complement.
+///
+/// Returns null on failure. A non-null plan must be released with [`revtag_plan_free`].
+///
+/// # Safety
+///
+/// Each array must be null with a zero length, or point to that many valid NUL-terminated
+/// strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn revtag_plan_new(
+    rev: *const *const c_char,
+    n_rev: usize,
+    revcomp: *const *const c_char,
+    n_revcomp: usize,
+) -> *mut RevtagPlan {
+    let plan = unsafe { strings(rev, n_rev) }.and_then(|rev| {
+        let revcomp = unsafe { strings(revcomp, n_revcomp) }?;
+        Plan::new(&rev, &revcomp)
+    });
+    match plan {
+        Ok(plan) => Box::into_raw(Box::new(RevtagPlan(plan))),
+        Err(err) => {
+            set_last_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Releases a plan built by [`revtag_plan_new`]. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `plan` must be null or a pointer returned by [`revtag_plan_new`] that has not been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn revtag_plan_free(plan: *mut RevtagPlan) {
+    if !plan.is_null() {
+        drop(unsafe { Box::from_raw(plan) });
+    }
+}
+
+/// Applies a plan to an htslib record in place if it is a reverse strand alignment.
+///
+/// # Safety
+///
+/// `plan` must be null or a live plan and `b` must be null or point to a valid `bam1_t`.
+unsafe fn transform_bam1(
+    plan: *const RevtagPlan,
+    b: *mut htslib::bam1_t,
+) -> Result<(), Box<dyn error::Error>> {
+    if plan.is_null() || b.is_null() {
+        return Err("Unexpected null plan or record".into());
+    }
+    let plan = unsafe { &(*plan).0 };
+
+    let mut record = Record::new();
+    if unsafe { htslib::bam_copy1(record.inner_mut(), b) }.is_null() {
+        return Err("Failed to copy input record".into());
+    }
+
+    if record.is_reverse() {
+        apply(&mut record, plan)?;
+        if unsafe { htslib::bam_copy1(b, record.inner()) }.is_null() {
+            return Err("Failed to copy transformed record".into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies a plan to an htslib record in place if it is a reverse strand alignment.
+///
+/// The record's variable-length data may be reallocated, exactly as with htslib's own aux
+/// editing functions.
+///
+/// # Safety
+///
+/// `plan` must be a live plan from [`revtag_plan_new`] and `b` must point to a valid `bam1_t`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn revtag_transform_bam1(
+    plan: *const RevtagPlan,
+    b: *mut htslib::bam1_t,
+) -> c_int {
+    status(unsafe { transform_bam1(plan, b) })
+}
+
+/// Reads an optional path from a C string, treating null as absent.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a valid NUL-terminated string.
+unsafe fn path(ptr: *const c_char) -> Result<Option<PathBuf>, Box<dyn error::Error>> {
+    if ptr.is_null() {
+        Ok(None)
+    } else {
+        Ok(Some(PathBuf::from(unsafe { string(ptr) }?)))
+    }
+}
+
+/// Runs `revtag` from one SAM/BAM/CRAM file to another.
+///
+/// # Safety
+///
+/// See [`revtag_run`].
+unsafe fn run(
+    input: *const c_char,
+    output: *const c_char,
+    rev: *const *const c_char,
+    n_rev: usize,
+    revcomp: *const *const c_char,
+    n_revcomp: usize,
+    threads: c_int,
+) -> Result<(), Box<dyn error::Error>> {
+    let threads = usize::try_from(threads).map_err(|_| "Threads must not be negative")?;
+    revtag(
+        unsafe { path(input) }?,
+        unsafe { path(output) }?,
+        unsafe { strings(rev, n_rev) }?,
+        unsafe { strings(revcomp, n_revcomp) }?,
+        threads,
+    )?;
+    Ok(())
+}
+
+/// Runs `revtag` from one SAM/BAM/CRAM file to another.
+///
+/// A null `input` or `output` reads from stdin or writes to stdout, respectively.
+///
+/// # Safety
+///
+/// `input` and `output` must be null or valid NUL-terminated strings, and each tag array must be
+/// null with a zero length, or point to that many valid NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn revtag_run(
+    input: *const c_char,
+    output: *const c_char,
+    rev: *const *const c_char,
+    n_rev: usize,
+    revcomp: *const *const c_char,
+    n_revcomp: usize,
+    threads: c_int,
+) -> c_int {
+    status(unsafe { run(input, output, rev, n_rev, revcomp, n_revcomp, threads) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::Aux;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn c_strings(values: &[&str]) -> Vec<CString> {
+        values.iter().map(|v| CString::new(*v).unwrap()).collect()
+    }
+
+    fn pointers(values: &[CString]) -> Vec<*const c_char> {
+        values.iter().map(|v| v.as_ptr()).collect()
+    }
+
+    #[test]
+    fn test_transform_bam1() {
+        let rev = c_strings(&["MN"]);
+        let revcomp = c_strings(&["BC"]);
+        let (rev, revcomp) = (pointers(&rev), pointers(&revcomp));
+        let plan = unsafe { revtag_plan_new(rev.as_ptr(), 1, revcomp.as_ptr(), 1) };
+        assert!(!plan.is_null());
+
+        let mut record = Record::new();
+        record.set_qname(b"test_read");
+        record.set_reverse();
+        record.push_aux(b"MN", Aux::String("HELLO")).unwrap();
+        record.push_aux(b"BC", Aux::String("ATCG")).unwrap();
+
+        let status = unsafe { revtag_transform_bam1(plan, record.inner_mut()) };
+        unsafe { revtag_plan_free(plan) };
+
+        assert_eq!(status, 0);
+        assert!(matches!(record.aux(b"MN"), Ok(Aux::String("OLLEH"))));
+        assert!(matches!(record.aux(b"BC"), Ok(Aux::String("CGAT"))));
+    }
+
+    #[test]
+    fn test_plan_new_reports_invalid_tag() {
+        let rev = c_strings(&["QTX"]);
+        let rev = pointers(&rev);
+        let plan = unsafe { revtag_plan_new(rev.as_ptr(), 1, ptr::null(), 0) };
+        assert!(plan.is_null());
+
+        let message = unsafe { CStr::from_ptr(revtag_last_error()) };
+        assert!(message.to_str().unwrap().contains("exactly 2 characters"));
+    }
+
+    #[test]
+    fn test_run_file_to_file() {
+        let mut infile = NamedTempFile::new().unwrap();
+        write!(
+            infile,
+            "@SQ\tSN:chr1\tLN:1000\nrev\t16\tchr1\t2\t60\t4M\t*\t0\t0\tACGT\tFFFF\tMN:Z:WORLD\n"
+        )
+        .unwrap();
+        let outfile = NamedTempFile::new().unwrap();
+
+        let input = CString::new(infile.path().to_str().unwrap()).unwrap();
+        let output = CString::new(outfile.path().to_str().unwrap()).unwrap();
+        let rev = c_strings(&["MN"]);
+        let rev = pointers(&rev);
+
+        let status = unsafe {
+            revtag_run(
+                input.as_ptr(),
+                output.as_ptr(),
+                rev.as_ptr(),
+                1,
+                ptr::null(),
+                0,
+                1,
+            )
+        };
+
+        assert_eq!(status, 0);
+        let contents = std::fs::read_to_string(outfile.path()).unwrap();
+        assert!(contents.contains("MN:Z:DLROW"));
+    }
+}
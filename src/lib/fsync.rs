@@ -0,0 +1,146 @@
+//! Flushing finished outputs, and the directory entries naming them, to storage.
+use log::*;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::checksum::sidecar_path;
+use crate::options::Options;
+
+/// Returns the files a run writes: the output and `options.tee` outputs other than stdout, their
+/// checksum sidecars with `options.write_md5`, `options.barcode_fastq`, and the
+/// `options.audit_tsv` and `options.contig_tsv` tables.
+///
+/// # Arguments
+///
+/// * `output` - The output file path, or None for stdout
+/// * `options` - The options naming any further outputs
+///
+/// # Returns
+///
+/// Returns the paths of every file written.
+///
+pub fn output_files(output: Option<&Path>, options: &Options) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = output
+        .into_iter()
+        .map(Path::to_path_buf)
+        .chain(
+            options
+                .tee
+                .iter()
+                .filter(|path| path.as_os_str() != "-")
+                .cloned(),
+        )
+        .collect();
+    if options.write_md5 {
+        let sidecars: Vec<PathBuf> = paths.iter().map(|path| sidecar_path(path)).collect();
+        paths.extend(sidecars);
+    }
+    paths.extend(
+        [
+            &options.barcode_fastq,
+            &options.audit_tsv,
+            &options.contig_tsv,
+        ]
+        .into_iter()
+        .flatten()
+        .cloned(),
+    );
+    paths
+}
+
+/// Flushes each regular file among `paths` to storage, then the directories holding them, so a
+/// crash after a run reports success cannot leave a truncated or missing output behind.
+///
+/// FIFOs, devices, and `/dev/fd` descriptors hold nothing to flush and are skipped.
+///
+/// # Arguments
+///
+/// * `paths` - The files to flush, already closed by their writers
+///
+/// # Returns
+///
+/// Returns an error if a file or directory cannot be flushed.
+///
+pub fn sync_files(paths: &[PathBuf]) -> io::Result<()> {
+    let mut directories: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        if !fs::metadata(path).is_ok_and(|metadata| metadata.is_file()) {
+            continue;
+        }
+        debug!("Syncing {path:?}");
+        File::open(path)?.sync_all()?;
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        if !directories.contains(&directory) {
+            directories.push(directory);
+        }
+    }
+    for directory in directories {
+        debug!("Syncing directory {directory:?}");
+        File::open(&directory)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Flushes the files a run wrote to storage when `options.fsync` is set; see [`output_files`].
+///
+/// # Arguments
+///
+/// * `output` - The output file path, or None for stdout
+/// * `options` - The options naming any further outputs
+///
+/// # Returns
+///
+/// Returns an error if an output cannot be flushed.
+///
+pub fn sync_outputs(output: Option<&Path>, options: &Options) -> io::Result<()> {
+    if !options.fsync {
+        return Ok(());
+    }
+    sync_files(&output_files(output, options))
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to flush outputs to storage: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_files() {
+        let options = Options {
+            tee: vec![PathBuf::from("-"), PathBuf::from("qc.sam")],
+            write_md5: true,
+            barcode_fastq: Some(PathBuf::from("bc.fq")),
+            audit_tsv: Some(PathBuf::from("audit.tsv")),
+            contig_tsv: Some(PathBuf::from("contigs.tsv")),
+            ..Options::default()
+        };
+        assert_eq!(
+            output_files(Some(Path::new("out.bam")), &options),
+            [
+                "out.bam",
+                "qc.sam",
+                "out.bam.md5",
+                "qc.sam.md5",
+                "bc.fq",
+                "audit.tsv",
+                "contigs.tsv"
+            ]
+            .map(PathBuf::from)
+            .to_vec()
+        );
+        assert!(output_files(None, &Options::default()).is_empty());
+    }
+
+    #[test]
+    fn test_sync_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("out.sam");
+        fs::write(&path, "@HD\tVN:1.6\n").unwrap();
+        sync_files(&[path, PathBuf::from("/dev/null")]).unwrap();
+        assert!(sync_files(&[tmpdir.path().join("missing.sam")]).is_ok());
+    }
+}
@@ -0,0 +1,553 @@
+//! Reader, writer, and header handling for SAM/BAM/CRAM streams.
+use log::*;
+use rust_htslib::bam::header::HeaderRecord;
+use rust_htslib::bam::{Format, Header, HeaderView, Read as BamRead, Reader, Writer};
+use std::error;
+use std::path::{Path, PathBuf};
+use std::slice;
+use url::Url;
+
+use crate::options::{Options, OutputFormat, program_args, program_command_line};
+use crate::sort::{reoriented_header, unaligned_header};
+
+const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Environment variables htslib reads S3 credentials from, in the order they are reported.
+const S3_CREDENTIALS: [&str; 3] = [
+    "AWS_ACCESS_KEY_ID",
+    "AWS_PROFILE",
+    "AWS_SHARED_CREDENTIALS_FILE",
+];
+
+/// Environment variables htslib reads Google Cloud Storage credentials from.
+const GCS_CREDENTIALS: [&str; 1] = ["GCS_OAUTH_TOKEN"];
+
+/// Returns the scheme of a cloud storage URL read through htslib, `s3` or `gs`.
+pub fn cloud_scheme(path: &Path) -> Option<&'static str> {
+    let path = path.to_str()?;
+    if path.starts_with("s3://")
+        || path.starts_with("s3+http://")
+        || path.starts_with("s3+https://")
+    {
+        Some("s3")
+    } else if path.starts_with("gs://") || path.starts_with("gs+http://") {
+        Some("gs")
+    } else {
+        None
+    }
+}
+
+/// Returns whether a path is a URL htslib reads over the network, such as `s3://`, `gs://`,
+/// `https://`, or `htsget://`, rather than a local file.
+pub fn is_remote(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| {
+        path.split_once("://")
+            .is_some_and(|(scheme, _)| scheme != "file" && !scheme.contains('/'))
+    })
+}
+
+/// Returns the first environment variable set that supplies credentials for a cloud scheme.
+///
+/// # Arguments
+///
+/// * `scheme` - The cloud scheme, `s3` or `gs`
+/// * `var` - Looks up an environment variable
+///
+/// # Returns
+///
+/// Returns the name of the variable, or None if no credentials are configured in the
+/// environment.
+///
+pub fn credential_source<F>(scheme: &str, var: F) -> Option<&'static str>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let names: &[&'static str] = match scheme {
+        "s3" => &S3_CREDENTIALS,
+        "gs" => &GCS_CREDENTIALS,
+        _ => &[],
+    };
+    names
+        .iter()
+        .copied()
+        .find(|name| var(name).is_some_and(|value| !value.is_empty()))
+}
+
+/// Returns advice on supplying credentials for a cloud scheme, for failed opens.
+fn credential_advice(scheme: &str) -> &'static str {
+    match scheme {
+        "s3" => {
+            "Set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY (with AWS_SESSION_TOKEN for \
+             temporary credentials), or set AWS_PROFILE to a profile in ~/.aws/credentials"
+        }
+        _ => "Set GCS_OAUTH_TOKEN to an access token, e.g. from `gcloud auth print-access-token`",
+    }
+}
+
+/// Opens a reader on a cloud storage URL, logging where credentials come from and explaining
+/// failures, which htslib reports without naming the cause.
+fn open_cloud_reader(path: &Path, scheme: &str) -> Result<Reader, Box<dyn error::Error>> {
+    if !cfg!(feature = "remote") {
+        return Err(format!(
+            "Reading {scheme}:// URLs requires revtag built with the `remote` feature"
+        )
+        .into());
+    }
+    match credential_source(scheme, |name| std::env::var(name).ok()) {
+        Some(name) => info!("Using {scheme}:// credentials from {name}"),
+        None => warn!(
+            "No {scheme}:// credentials found in the environment; only public objects can be read"
+        ),
+    }
+    open_url_reader(path).map_err(|e| {
+        format!(
+            "Could not open {}: {e}. If the object exists, the credentials may be missing, \
+             expired, or lack read access. {}",
+            path.display(),
+            credential_advice(scheme)
+        )
+        .into()
+    })
+}
+
+/// Opens a reader on a URL htslib reads over the network, per [`is_remote`].
+///
+/// `Reader::from_path` requires a local file to exist, so URLs are handed to htslib as URLs.
+fn open_url_reader(path: &Path) -> Result<Reader, Box<dyn error::Error>> {
+    let url = path
+        .to_str()
+        .ok_or_else(|| format!("URL is not valid UTF-8: {}", path.display()))?;
+    let url = Url::parse(url).map_err(|e| format!("Invalid URL {url}: {e}"))?;
+    Ok(Reader::from_url(&url)?)
+}
+
+/// The EBI CRAM reference registry, which serves reference sequences by MD5 checksum.
+pub const EBI_REFERENCE_REGISTRY: &str = "https://www.ebi.ac.uk/ena/cram/md5/%s";
+
+/// Returns the `REF_PATH` and `REF_CACHE` settings that let htslib fetch CRAM references by MD5
+/// checksum and cache them locally, for variables not already set.
+///
+/// `REF_PATH` gains the mirror after any local directories already listed, and `REF_CACHE`
+/// defaults to `hts-ref` under `$XDG_CACHE_HOME` or `$HOME/.cache`, htslib's usual location.
+///
+/// # Arguments
+///
+/// * `mirror` - A URL template with `%s` for the MD5, or None for [`EBI_REFERENCE_REGISTRY`]
+/// * `var` - Looks up an environment variable
+///
+/// # Returns
+///
+/// Returns each variable to set with its value, or an error if the mirror lacks `%s` or no
+/// cache directory can be chosen.
+///
+pub fn auto_reference_env<F>(
+    mirror: Option<&str>,
+    var: F,
+) -> Result<Vec<(&'static str, String)>, Box<dyn error::Error>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mirror = mirror.unwrap_or(EBI_REFERENCE_REGISTRY);
+    if !mirror.contains("%s") {
+        return Err(
+            format!("Reference mirror must contain %s for the MD5 checksum: {mirror}").into(),
+        );
+    }
+    let set = |name: &str| var(name).filter(|value| !value.is_empty());
+
+    let mut env = Vec::new();
+    match set("REF_PATH") {
+        Some(path) if path.contains(mirror) => {}
+        Some(path) => env.push(("REF_PATH", format!("{path}:{mirror}"))),
+        None => env.push(("REF_PATH", mirror.to_string())),
+    }
+    if set("REF_CACHE").is_none() {
+        let cache = match (set("XDG_CACHE_HOME"), set("HOME")) {
+            (Some(cache), _) => cache,
+            (None, Some(home)) => format!("{home}/.cache"),
+            (None, None) => {
+                return Err(
+                    "Set REF_CACHE, XDG_CACHE_HOME, or HOME to cache CRAM references".into(),
+                );
+            }
+        };
+        env.push(("REF_CACHE", format!("{cache}/hts-ref/%2s/%2s/%s")));
+    }
+    Ok(env)
+}
+
+/// Returns the reference path to hand htslib for CRAM, naming the FASTA index with htslib's
+/// `##idx##` syntax when it is not the `.fai` beside the FASTA.
+///
+/// # Arguments
+///
+/// * `fasta` - The reference FASTA
+/// * `index` - The FASTA index, or None for the `.fai` beside the FASTA
+///
+/// # Returns
+///
+/// Returns the path, or an error if either path is not valid UTF-8.
+///
+pub fn reference_path(
+    fasta: &Path,
+    index: Option<&Path>,
+) -> Result<PathBuf, Box<dyn error::Error>> {
+    let Some(index) = index else {
+        return Ok(fasta.to_path_buf());
+    };
+    match (fasta.to_str(), index.to_str()) {
+        (Some(fasta), Some(index)) => Ok(PathBuf::from(format!("{fasta}##idx##{index}"))),
+        _ => Err("Reference and reference index paths must be valid UTF-8".into()),
+    }
+}
+
+/// Opens a SAM/BAM/CRAM reader on a file path or on stdin.
+///
+/// URLs such as `https://host/in.bam` are opened by htslib as URLs. Cloud storage URLs such as
+/// `s3://bucket/in.bam` and `gs://bucket/in.bam` are read with credentials from the
+/// environment, as htslib reads them, when built with the `remote` feature.
+///
+/// # Arguments
+///
+/// * `input` - The input SAM/BAM/CRAM file path, or None for stdin
+/// * `threads` - Total threads; all but one are used for decompression
+///
+/// # Returns
+///
+/// Returns the opened reader, or an error if the input cannot be opened.
+///
+pub fn open_reader(input: Option<&Path>, threads: usize) -> Result<Reader, Box<dyn error::Error>> {
+    let mut reader = match input {
+        None => {
+            info!("Input: stdin");
+            Reader::from_stdin()?
+        }
+        Some(path) => {
+            info!("Input: {path:?}");
+            match cloud_scheme(path) {
+                Some(scheme) => open_cloud_reader(path, scheme)?,
+                None if is_remote(path) => open_url_reader(path)?,
+                None => Reader::from_path(path)?,
+            }
+        }
+    };
+
+    if threads > 1 {
+        reader.set_threads(threads - 1)?;
+    }
+
+    Ok(reader)
+}
+
+/// Infers the alignment file format from a path's extension, defaulting to SAM.
+pub fn format_for_path(path: &Path) -> Format {
+    format_for_output(path, Some(OutputFormat::from_path(path)))
+}
+
+/// Returns the format to write a file in: `requested` if given, else the one implied by the path.
+pub fn format_for_output(path: &Path, requested: Option<OutputFormat>) -> Format {
+    match requested {
+        Some(OutputFormat::Sam) => Format::Sam,
+        Some(OutputFormat::Bam) => Format::Bam,
+        Some(OutputFormat::Cram) => Format::Cram,
+        None => format_for_path(path),
+    }
+}
+
+/// Returns the format to write stdout in: `requested` if given, else SAM.
+pub fn format_for_stdout(requested: Option<OutputFormat>) -> Format {
+    match requested {
+        Some(OutputFormat::Bam) => Format::Bam,
+        Some(OutputFormat::Cram) => Format::Cram,
+        Some(OutputFormat::Sam) | None => Format::Sam,
+    }
+}
+
+/// Opens a SAM/BAM/CRAM writer on a file path or on stdout.
+///
+/// Outputs are written in `format` if given, else files in the format implied by their extension
+/// and stdout as SAM. Writers only append, so FIFOs and `/dev/fd` descriptors work as
+/// outputs given an explicit format.
+/// BAM records are serialized by htslib directly from their in-memory `bam1_t` data, which is
+/// already in BAM layout, so BAM to BAM runs never format records as SAM text.
+///
+/// # Arguments
+///
+/// * `output` - The output SAM/BAM/CRAM file path, or None for stdout
+/// * `header` - The header to write
+/// * `threads` - Total threads; all but one are used for compression
+/// * `format` - The format of the output, overriding a file's extension
+///
+/// # Returns
+///
+/// Returns the opened writer, or an error if the output cannot be created.
+///
+pub fn open_writer(
+    output: Option<&Path>,
+    header: &Header,
+    threads: usize,
+    format: Option<OutputFormat>,
+) -> Result<Writer, Box<dyn error::Error>> {
+    let mut writer = match output {
+        None => {
+            info!("Output: stdout");
+            Writer::from_stdout(header, format_for_stdout(format))?
+        }
+        Some(path) => {
+            info!("Output: {path:?}");
+            Writer::from_path(path, header, format_for_output(path, format))?
+        }
+    };
+
+    if threads > 1 {
+        writer.set_threads(threads - 1)?;
+    }
+
+    Ok(writer)
+}
+
+/// Builds an output header from an input header with a `@PG` record for this program appended.
+///
+/// The record is named by `options.pg_id` and `options.pg_program_name` when given. With
+/// `options.reproducible`, its ID is otherwise made unique within the header, as
+/// [`crate::options::program_id`] does, and its command line names the program without its install path.
+///
+/// With `options.restore_orientation`, the `@HD` line no longer claims a coordinate order; see
+/// [`reoriented_header`]. With `options.revert_to_ubam`, the `@SQ` lines are also removed; see
+/// [`unaligned_header`].
+///
+/// # Returns
+///
+/// Returns the new header, or an error if `options.pg_id` is already in the header.
+///
+pub fn header_with_program(
+    template: &HeaderView,
+    options: &Options,
+) -> Result<Header, Box<dyn error::Error>> {
+    let mut header = if options.revert_to_ubam {
+        Header::from_template(&HeaderView::from_bytes(&unaligned_header(
+            template.as_bytes(),
+        )))
+    } else if options.restore_orientation {
+        Header::from_template(&HeaderView::from_bytes(&reoriented_header(
+            template.as_bytes(),
+        )))
+    } else {
+        Header::from_template(template)
+    };
+    header.push_record(&program_record(&header, options)?);
+    Ok(header)
+}
+
+/// Returns the input header text exactly as read, rather than as htslib rebuilds it from its
+/// parsed records, with the `@PG` record of [`header_with_program`] appended.
+///
+/// htslib may replace the text with its rebuild once the header is rendered, so call this before
+/// [`header_with_program`]. With `options.restore_orientation` or `options.revert_to_ubam`, the
+/// header lines are updated as [`header_with_program`] does.
+///
+/// # Arguments
+///
+/// * `template` - The input header
+/// * `options` - The options naming the `@PG` record, as for [`header_with_program`]
+///
+/// # Returns
+///
+/// Returns the header text, ending in a newline, or an error if `options.pg_id` is already in
+/// the header.
+///
+pub fn verbatim_header(
+    template: &HeaderView,
+    options: &Options,
+) -> Result<Vec<u8>, Box<dyn error::Error>> {
+    // SAFETY: the header outlives the borrow, and its text holds `l_text` bytes when non-null.
+    let mut text = unsafe {
+        let inner = &*template.inner_ptr();
+        if inner.text.is_null() {
+            Vec::new()
+        } else {
+            slice::from_raw_parts(inner.text as *const u8, inner.l_text as usize).to_vec()
+        }
+    };
+    while text.last() == Some(&0) {
+        text.pop();
+    }
+    if !text.is_empty() && !text.ends_with(b"\n") {
+        text.push(b'\n');
+    }
+    if options.revert_to_ubam {
+        text = unaligned_header(&text);
+    } else if options.restore_orientation {
+        text = reoriented_header(&text);
+    }
+    let header = Header::from_template(template);
+    text.extend(program_record(&header, options)?.to_bytes());
+    text.push(b'\n');
+    Ok(text)
+}
+
+/// Returns the `@PG` record for this program appended to a header; see [`header_with_program`].
+fn program_record(
+    header: &Header,
+    options: &Options,
+) -> Result<HeaderRecord<'static>, Box<dyn error::Error>> {
+    let programs = header.to_hashmap().remove("PG").unwrap_or_default();
+    let id = options.program_record_id(programs.iter().filter_map(|program| program.get("ID")))?;
+
+    let mut record = HeaderRecord::new(b"PG");
+    record
+        .push_tag(b"ID", &id)
+        .push_tag(b"PN", options.program_name())
+        .push_tag(b"VN", CARGO_PKG_VERSION)
+        .push_tag(
+            b"CL",
+            program_command_line(program_args(), options.reproducible),
+        );
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_format_for_path() {
+        assert!(matches!(
+            format_for_path(&PathBuf::from("out.bam")),
+            Format::Bam
+        ));
+        assert!(matches!(
+            format_for_path(&PathBuf::from("out.cram")),
+            Format::Cram
+        ));
+        assert!(matches!(
+            format_for_path(&PathBuf::from("out.sam")),
+            Format::Sam
+        ));
+        assert!(matches!(
+            format_for_path(&PathBuf::from("out")),
+            Format::Sam
+        ));
+    }
+
+    #[test]
+    fn test_cloud_scheme() {
+        assert_eq!(cloud_scheme(Path::new("s3://bucket/in.bam")), Some("s3"));
+        assert_eq!(cloud_scheme(Path::new("gs://bucket/in.bam")), Some("gs"));
+        assert_eq!(cloud_scheme(Path::new("in.bam")), None);
+        assert_eq!(cloud_scheme(Path::new("https://host/in.bam")), None);
+    }
+
+    #[test]
+    fn test_is_remote() {
+        assert!(is_remote(Path::new("s3://bucket/in.bam")));
+        assert!(is_remote(Path::new("https://host/in.bam")));
+        assert!(is_remote(Path::new("htsget://host/reads/id")));
+        assert!(!is_remote(Path::new("file:///data/in.bam")));
+        assert!(!is_remote(Path::new("/data/in.bam")));
+        assert!(!is_remote(Path::new("runs/a://b/in.bam")));
+    }
+
+    #[test]
+    fn test_credential_source() {
+        let env = |name: &str| match name {
+            "AWS_PROFILE" => Some("lab".to_string()),
+            "GCS_OAUTH_TOKEN" => Some(String::new()),
+            _ => None,
+        };
+        assert_eq!(credential_source("s3", env), Some("AWS_PROFILE"));
+        assert_eq!(credential_source("gs", env), None);
+        assert_eq!(credential_source("s3", |_| None), None);
+    }
+
+    #[cfg(not(feature = "remote"))]
+    #[test]
+    fn test_open_reader_requires_remote_feature() {
+        let error = open_reader(Some(Path::new("s3://bucket/in.bam")), 1).unwrap_err();
+        assert!(error.to_string().contains("`remote` feature"));
+    }
+
+    #[test]
+    fn test_open_reader_hands_urls_to_htslib() {
+        // Nothing listens on the discard port, so htslib fails to open the URL rather than
+        // rust-htslib failing to find a local file of that name.
+        let error = open_reader(Some(Path::new("http://127.0.0.1:9/in.sam")), 1).unwrap_err();
+        let error = error.downcast::<rust_htslib::errors::Error>().unwrap();
+        assert!(!matches!(
+            *error,
+            rust_htslib::errors::Error::FileNotFound { .. }
+        ));
+        assert!(open_reader(Some(Path::new("http://[::1/in.sam")), 1).is_err());
+    }
+
+    #[test]
+    fn test_reference_path() {
+        let fasta = Path::new("/refs/hg38.fa");
+        assert_eq!(reference_path(fasta, None).unwrap(), fasta);
+        assert_eq!(
+            reference_path(fasta, Some(Path::new("/index/hg38.fa.fai"))).unwrap(),
+            PathBuf::from("/refs/hg38.fa##idx##/index/hg38.fa.fai")
+        );
+    }
+
+    #[test]
+    fn test_auto_reference_env() {
+        let env = auto_reference_env(None, |name| match name {
+            "HOME" => Some("/home/me".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(
+            env,
+            vec![
+                ("REF_PATH", EBI_REFERENCE_REGISTRY.to_string()),
+                (
+                    "REF_CACHE",
+                    "/home/me/.cache/hts-ref/%2s/%2s/%s".to_string()
+                ),
+            ]
+        );
+
+        let env = auto_reference_env(Some("https://mirror/md5/%s"), |name| match name {
+            "REF_PATH" => Some("/refs/%s".to_string()),
+            "REF_CACHE" => Some("/cache/%s".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(
+            env,
+            vec![("REF_PATH", "/refs/%s:https://mirror/md5/%s".to_string())]
+        );
+
+        assert!(auto_reference_env(Some("https://mirror/md5"), |_| None).is_err());
+        assert!(auto_reference_env(None, |_| None).is_err());
+    }
+
+    #[test]
+    fn test_format_for_output() {
+        let fd = PathBuf::from("/dev/fd/63");
+        assert!(matches!(format_for_output(&fd, None), Format::Sam));
+        assert!(matches!(
+            format_for_output(&fd, Some(OutputFormat::Bam)),
+            Format::Bam
+        ));
+        assert!(matches!(
+            format_for_output(&PathBuf::from("out.bam"), Some(OutputFormat::Cram)),
+            Format::Cram
+        ));
+    }
+
+    #[test]
+    fn test_format_for_stdout() {
+        assert!(matches!(format_for_stdout(None), Format::Sam));
+        assert!(matches!(
+            format_for_stdout(Some(OutputFormat::Bam)),
+            Format::Bam
+        ));
+        assert!(matches!(
+            format_for_stdout(Some(OutputFormat::Cram)),
+            Format::Cram
+        ));
+    }
+}
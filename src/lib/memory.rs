@@ -0,0 +1,83 @@
+//! Memory limits on buffering and reporting of peak memory use.
+use std::fs;
+
+/// Parses a byte size such as `512M` or `2G`, with optional binary `K`, `M`, `G`, or `T`
+/// suffixes, as accepted by `--max-memory`.
+///
+/// # Arguments
+///
+/// * `size` - The size to parse
+///
+/// # Returns
+///
+/// Returns the size in bytes, or an error message if it is malformed, zero, or too large.
+///
+pub fn parse_size(size: &str) -> Result<usize, String> {
+    let error = || format!("Invalid size `{size}`; expected bytes with an optional K, M, G, or T");
+    let trimmed = size.trim().trim_end_matches(['B', 'b']);
+    let (digits, shift) = match trimmed.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&trimmed[..trimmed.len() - 1], 10),
+        Some('M') => (&trimmed[..trimmed.len() - 1], 20),
+        Some('G') => (&trimmed[..trimmed.len() - 1], 30),
+        Some('T') => (&trimmed[..trimmed.len() - 1], 40),
+        _ => (trimmed, 0),
+    };
+    let value: usize = digits.parse().map_err(|_| error())?;
+    if value == 0 {
+        return Err(error());
+    }
+    1usize
+        .checked_shl(shift)
+        .and_then(|unit| value.checked_mul(unit))
+        .ok_or_else(|| format!("Size `{size}` is too large"))
+}
+
+/// Formats a byte count in MiB for messages.
+pub fn format_mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1u64 << 20) as f64)
+}
+
+/// Returns the peak resident memory of this process in bytes, where the platform reports it.
+///
+/// On Linux this is `VmHWM` from `/proc/self/status`; elsewhere None.
+pub fn peak_rss() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    parse_peak_rss(&status)
+}
+
+/// Parses the `VmHWM` line of a `/proc/<pid>/status` file into bytes.
+fn parse_peak_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kib: u64 = line["VmHWM:".len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_size("2gb"), Ok(2 << 30));
+        assert_eq!(parse_size("64k"), Ok(64 << 10));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("1.5G").is_err());
+    }
+
+    #[test]
+    fn test_parse_peak_rss() {
+        let status = "Name:\trevtag\nVmPeak:\t  20000 kB\nVmHWM:\t    1536 kB\nVmRSS:\t 1024 kB\n";
+        assert_eq!(parse_peak_rss(status), Some(1536 * 1024));
+        assert_eq!(parse_peak_rss("Name:\trevtag\n"), None);
+        assert_eq!(format_mib(1536 * 1024), "1.5 MiB");
+    }
+}
@@ -0,0 +1,257 @@
+//! Merges of the outputs of sharded or region-parallel processing back into one file, so a
+//! split run needs no separate `samtools merge`.
+//!
+//! Shards whose header declares `SO:coordinate` are merged record by record into coordinate
+//! order; shards of any other order except queryname are concatenated in the order given.
+use log::*;
+use rust_htslib::bam::{Header, HeaderView, Read, Reader, Record};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::error;
+use std::path::{Path, PathBuf};
+
+use crate::fsync::sync_outputs;
+use crate::options::Options;
+use crate::run::{finish_writers, open_writers, reference_for};
+use crate::sort::{SortCheck, SortOrder, declared_order};
+
+/// Fails unless a shard's header lists the same reference sequences as the first shard's.
+fn check_references(
+    first: &HeaderView,
+    header: &HeaderView,
+    path: &Path,
+) -> Result<(), Box<dyn error::Error>> {
+    let same = first.target_count() == header.target_count()
+        && (0..first.target_count()).all(|tid| {
+            first.tid2name(tid) == header.tid2name(tid)
+                && first.target_len(tid) == header.target_len(tid)
+        });
+    if same {
+        Ok(())
+    } else {
+        Err(
+            format!("Shard {path:?} has reference sequences that differ from the first shard's")
+                .into(),
+        )
+    }
+}
+
+/// Reads the next record of a shard, checking it is in coordinate order when `check` is given.
+fn read_next(
+    reader: &mut Reader,
+    record: &mut Record,
+    check: Option<&mut SortCheck>,
+    path: &Path,
+) -> Result<bool, Box<dyn error::Error>> {
+    match reader.read(record) {
+        Some(Ok(())) => {}
+        None => return Ok(false),
+        Some(Err(e)) => return Err(format!("Failed to read shard {path:?}: {e}").into()),
+    }
+    if let Some(check) = check {
+        check
+            .check(record.tid(), record.pos(), record.qname())
+            .map_err(|e| format!("Shard {path:?} cannot be merged: {e}"))?;
+    }
+    Ok(true)
+}
+
+/// Returns the coordinate order key of a record, with unplaced records last.
+fn coordinate(record: &Record) -> (u32, i64) {
+    (
+        u32::try_from(record.tid()).unwrap_or(u32::MAX),
+        record.pos(),
+    )
+}
+
+/// Merges shards written with the same header into one output, in the sort order their header
+/// declares.
+///
+/// Coordinate sorted shards are merged so the output is coordinate sorted, with records at the
+/// same position kept in shard order; each shard is checked to be sorted as it is read. Shards
+/// declaring no order, or `unsorted` or `unknown`, are concatenated in the order given.
+///
+/// # Arguments
+///
+/// * `shards` - The shards, such as the outputs of a `--manifest` run over regions of one input
+/// * `output` - The merged output, in the format of its extension or `options.output_format`
+/// * `options` - Options giving the threads, reference for CRAM, and output format, and
+///   whether to write an MD5 sidecar and flush the output to storage, as for any output
+///
+/// # Returns
+///
+/// Returns the number of records written, or an error if there are no shards, a shard cannot be
+/// read, the shards' reference sequences differ, the shards are sorted by queryname, or a
+/// coordinate sorted shard has a record out of order.
+///
+pub fn merge_shards(
+    shards: &[PathBuf],
+    output: &Path,
+    options: &Options,
+) -> Result<u64, Box<dyn error::Error>> {
+    if shards.is_empty() {
+        return Err("There are no shards to merge".into());
+    }
+    let reference = reference_for(options)?;
+    let mut readers = Vec::with_capacity(shards.len());
+    for path in shards {
+        let mut reader =
+            Reader::from_path(path).map_err(|e| format!("Failed to open shard {path:?}: {e}"))?;
+        if let Some(reference) = &reference {
+            reader.set_reference(reference)?;
+        }
+        readers.push(reader);
+    }
+    let template = readers[0].header().clone();
+    for (path, reader) in shards.iter().zip(&readers).skip(1) {
+        check_references(&template, reader.header(), path)?;
+    }
+    let order = declared_order(template.as_bytes());
+    if order == Some(SortOrder::Queryname) {
+        return Err("Merging shards sorted by queryname is not supported".into());
+    }
+
+    let header = Header::from_template(&template);
+    let options = Options {
+        tee: Vec::new(),
+        ..options.clone()
+    };
+    let (mut writers, pipes) = open_writers(Some(output), &header, None, &options)?;
+    let writer = &mut writers[0];
+
+    let mut written = 0;
+    if order == Some(SortOrder::Coordinate) {
+        let mut records = vec![Record::new(); shards.len()];
+        let mut checks = vec![SortCheck::new(SortOrder::Coordinate); shards.len()];
+        let mut heads = BinaryHeap::with_capacity(shards.len());
+        for (shard, reader) in readers.iter_mut().enumerate() {
+            let (record, check) = (&mut records[shard], Some(&mut checks[shard]));
+            if read_next(reader, record, check, &shards[shard])? {
+                heads.push(Reverse((coordinate(record), shard)));
+            }
+        }
+        while let Some(Reverse((_, shard))) = heads.pop() {
+            writer.write(&records[shard])?;
+            written += 1;
+            let (record, check) = (&mut records[shard], Some(&mut checks[shard]));
+            if read_next(&mut readers[shard], record, check, &shards[shard])? {
+                heads.push(Reverse((coordinate(record), shard)));
+            }
+        }
+    } else {
+        let mut record = Record::new();
+        for (path, reader) in shards.iter().zip(&mut readers) {
+            while read_next(reader, &mut record, None, path)? {
+                writer.write(&record)?;
+                written += 1;
+            }
+        }
+    }
+    finish_writers(writers, pipes)?;
+    sync_outputs(Some(output), &options)?;
+    info!(
+        "Merged {written} records from {} shards into {output:?}",
+        shards.len()
+    );
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::{Format, Writer};
+
+    /// Writes a SAM shard with the given header order and a record at each of `positions`,
+    /// as (tid, pos), named by the shard and its index.
+    fn shard(dir: &Path, name: &str, order: &str, positions: &[(i32, i64)]) -> PathBuf {
+        let path = dir.join(format!("{name}.sam"));
+        let text =
+            format!("@HD\tVN:1.6\tSO:{order}\n@SQ\tSN:chr1\tLN:10000\n@SQ\tSN:chr2\tLN:10000\n");
+        let header = Header::from_template(&HeaderView::from_bytes(text.as_bytes()));
+        let mut writer = Writer::from_path(&path, &header, Format::Sam).unwrap();
+        for (i, (tid, pos)) in positions.iter().enumerate() {
+            let mut record = Record::new();
+            let qname = format!("{name}{i}");
+            record.set(qname.as_bytes(), None, b"ACGT", &[30; 4]);
+            record.set_tid(*tid);
+            record.set_pos(*pos);
+            if *tid < 0 {
+                record.set_unmapped();
+            }
+            writer.write(&record).unwrap();
+        }
+        path
+    }
+
+    /// Returns the read names of a SAM file, in order.
+    fn names(path: &Path) -> Vec<String> {
+        let mut reader = Reader::from_path(path).unwrap();
+        reader
+            .records()
+            .map(|record| String::from_utf8(record.unwrap().qname().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_coordinate_shards() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let shards = vec![
+            shard(
+                tmpdir.path(),
+                "a",
+                "coordinate",
+                &[(0, 10), (1, 5), (-1, -1)],
+            ),
+            shard(tmpdir.path(), "b", "coordinate", &[(0, 10), (0, 20)]),
+        ];
+        let output = tmpdir.path().join("merged.sam");
+
+        let written = merge_shards(&shards, &output, &Options::default()).unwrap();
+        assert_eq!(written, 5);
+        assert_eq!(names(&output), ["a0", "b0", "b1", "a1", "a2"]);
+        let header = std::fs::read_to_string(&output).unwrap();
+        assert!(header.starts_with("@HD\tVN:1.6\tSO:coordinate\n"));
+    }
+
+    #[test]
+    fn test_merge_unsorted_shards() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let shards = vec![
+            shard(tmpdir.path(), "a", "unsorted", &[(1, 5), (0, 10)]),
+            shard(tmpdir.path(), "b", "unsorted", &[(0, 1)]),
+        ];
+        let output = tmpdir.path().join("merged.sam");
+
+        assert_eq!(
+            merge_shards(&shards, &output, &Options::default()).unwrap(),
+            3
+        );
+        assert_eq!(names(&output), ["a0", "a1", "b0"]);
+    }
+
+    #[test]
+    fn test_merge_shards_errors() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let output = tmpdir.path().join("merged.sam");
+        let options = Options::default();
+        assert!(merge_shards(&[], &output, &options).is_err());
+
+        let unsorted = shard(tmpdir.path(), "a", "coordinate", &[(1, 5), (0, 10)]);
+        let error = merge_shards(&[unsorted], &output, &options).unwrap_err();
+        assert!(error.to_string().contains("cannot be merged"));
+
+        let named = shard(tmpdir.path(), "b", "queryname", &[(0, 1)]);
+        let error = merge_shards(&[named], &output, &options).unwrap_err();
+        assert!(error.to_string().contains("queryname"));
+
+        let first = shard(tmpdir.path(), "c", "coordinate", &[(0, 1)]);
+        let other = tmpdir.path().join("other.sam");
+        std::fs::write(&other, "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chrX\tLN:5\n").unwrap();
+        let error = merge_shards(&[first, other], &output, &options).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("reference sequences that differ")
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Conversions between revtag types and noodles alignment records.
+//!
+//! These let noodles-based pipelines apply a [`Plan`] to any
+//! [`noodles_sam::alignment::Record`] implementation, such as the lazily-decoded records yielded
+//! by noodles SAM and BAM readers, without round-tripping through htslib structures.
+use noodles_sam::Header;
+use noodles_sam::alignment::record::data::field::Tag as DataTag;
+use noodles_sam::alignment::{Record, RecordBuf};
+use std::error;
+
+use super::ops::apply;
+use crate::plan::{Plan, Tag};
+
+/// Converts a revtag tag into a noodles data field tag.
+pub fn to_data_tag(tag: &Tag) -> DataTag {
+    DataTag::new(tag[0], tag[1])
+}
+
+/// Converts a noodles data field tag into a revtag tag.
+pub fn from_data_tag(tag: &DataTag) -> Tag {
+    let bytes: &[u8; 2] = tag.as_ref();
+    *bytes
+}
+
+/// Converts any noodles alignment record into an owned, mutable record buffer.
+///
+/// # Arguments
+///
+/// * `header` - The header the record was read with
+/// * `record` - The alignment record to convert
+///
+/// # Returns
+///
+/// Returns the record buffer, or an error if the record fields cannot be decoded.
+///
+pub fn to_record_buf<R>(header: &Header, record: &R) -> Result<RecordBuf, Box<dyn error::Error>>
+where
+    R: Record + ?Sized,
+{
+    Ok(RecordBuf::try_from_alignment_record(header, record)?)
+}
+
+/// Converts any noodles alignment record into a record buffer with the plan applied.
+///
+/// The plan is only applied when the record is a reverse strand alignment, matching the
+/// behavior of [`super::run::revtag`].
+///
+/// # Arguments
+///
+/// * `header` - The header the record was read with
+/// * `record` - The alignment record to convert
+/// * `plan` - The tag specifications to apply to reverse strand records
+///
+/// # Returns
+///
+/// Returns the transformed record buffer, or an error if decoding or transformation fails.
+///
+pub fn transform_record<R>(
+    header: &Header,
+    record: &R,
+    plan: &Plan,
+) -> Result<RecordBuf, Box<dyn error::Error>>
+where
+    R: Record + ?Sized,
+{
+    let mut record = to_record_buf(header, record)?;
+    if record.flags().is_reverse_complemented() {
+        apply(&mut record, plan)?;
+    }
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles_sam::alignment::record_buf::data::field::Value;
+
+    const SAM: &[u8] = b"@HD\tVN:1.6\tSO:unknown\n\
+@SQ\tSN:chr1\tLN:1000\n\
+fwd\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:ATCG\n\
+rev\t16\tchr1\t2\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:GATT\n";
+
+    #[test]
+    fn test_tag_round_trip() {
+        let tag: Tag = *b"BC";
+        assert_eq!(from_data_tag(&to_data_tag(&tag)), tag);
+    }
+
+    #[test]
+    fn test_transform_lazy_sam_records() {
+        let mut reader = noodles_sam::io::Reader::new(SAM);
+        let header = reader.read_header().unwrap();
+        let plan = Plan::new(&[], &["BC".to_string()]).unwrap();
+
+        let values: Vec<Value> = reader
+            .records()
+            .map(|result| {
+                let record = result.unwrap();
+                let record = transform_record(&header, &record, &plan).unwrap();
+                record.data().get(&to_data_tag(b"BC")).unwrap().clone()
+            })
+            .collect();
+
+        assert_eq!(
+            values,
+            vec![Value::String("ATCG".into()), Value::String("AATC".into())]
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Reader, writer, and header handling for SAM/BAM streams using noodles.
+use log::*;
+use noodles_sam::Header;
+use noodles_sam::alignment::io::Write as AlignmentWrite;
+use noodles_sam::header::record::value::Map;
+use noodles_sam::header::record::value::map::Program;
+use noodles_sam::header::record::value::map::program::tag as program_tag;
+use noodles_util::alignment::io::writer::Builder;
+use noodles_util::alignment::io::{CompressionMethod, Format as WriterFormat};
+use std::error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::options::{Options, OutputFormat, program_args, program_command_line};
+use crate::sort::reoriented_header;
+
+const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The gzip magic bytes that begin every BGZF-compressed BAM file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The magic bytes that begin every CRAM file.
+const CRAM_MAGIC: &[u8] = b"CRAM";
+
+/// Alignment formats supported by the noodles backend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Plain-text SAM.
+    Sam,
+    /// BGZF-compressed BAM.
+    Bam,
+}
+
+/// Opens a buffered byte stream on a file path or on stdin.
+///
+/// # Arguments
+///
+/// * `input` - The input SAM/BAM file path, or None for stdin
+///
+/// # Returns
+///
+/// Returns the opened stream, or an error if the input cannot be opened.
+///
+pub fn open_input(input: Option<&Path>) -> Result<Box<dyn BufRead>, Box<dyn error::Error>> {
+    match input {
+        None => {
+            info!("Input: stdin");
+            Ok(Box::new(BufReader::new(io::stdin())))
+        }
+        Some(path) => {
+            info!("Input: {path:?}");
+            Ok(Box::new(BufReader::new(File::open(path)?)))
+        }
+    }
+}
+
+/// Detects the alignment format of a stream from its leading magic bytes without consuming them.
+///
+/// # Returns
+///
+/// Returns the detected format, or an error if the stream is CRAM or cannot be read.
+///
+pub fn detect_format(input: &mut dyn BufRead) -> Result<Format, Box<dyn error::Error>> {
+    let buf = input.fill_buf()?;
+    if buf.starts_with(&GZIP_MAGIC) {
+        Ok(Format::Bam)
+    } else if buf.starts_with(CRAM_MAGIC) {
+        Err("CRAM input is not supported by the noodles backend".into())
+    } else {
+        Ok(Format::Sam)
+    }
+}
+
+/// Returns true if a path names a bgzip-compressed file, ending in `.gz` or `.bgz`.
+pub fn is_bgzip_path(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some("gz" | "bgz")
+    )
+}
+
+/// Infers the output format from a path's extension, as [`OutputFormat::from_path`] does,
+/// looking past a `.gz` or `.bgz` suffix, and defaulting to SAM.
+///
+/// # Returns
+///
+/// Returns the inferred format, or an error if the path names a CRAM file.
+///
+pub fn format_for_path(path: &Path) -> Result<Format, Box<dyn error::Error>> {
+    let format = if is_bgzip_path(path) {
+        OutputFormat::from_path(&path.with_extension(""))
+    } else {
+        OutputFormat::from_path(path)
+    };
+    format_for_output(path, Some(format))
+}
+
+/// Returns the format to write a file in: `requested` if given, else the one implied by the path.
+///
+/// # Returns
+///
+/// Returns the format, or an error if it is CRAM.
+///
+pub fn format_for_output(
+    path: &Path,
+    requested: Option<OutputFormat>,
+) -> Result<Format, Box<dyn error::Error>> {
+    match requested {
+        Some(OutputFormat::Sam) => Ok(Format::Sam),
+        Some(OutputFormat::Bam) => Ok(Format::Bam),
+        Some(OutputFormat::Cram) => {
+            Err("CRAM output is not supported by the noodles backend".into())
+        }
+        None => format_for_path(path),
+    }
+}
+
+/// Returns the format to write stdout in: `requested` if given, else SAM.
+///
+/// # Returns
+///
+/// Returns the format, or an error if it is CRAM.
+///
+pub fn format_for_stdout(requested: Option<OutputFormat>) -> Result<Format, Box<dyn error::Error>> {
+    match requested {
+        Some(OutputFormat::Bam) => Ok(Format::Bam),
+        Some(OutputFormat::Cram) => {
+            Err("CRAM output is not supported by the noodles backend".into())
+        }
+        Some(OutputFormat::Sam) | None => Ok(Format::Sam),
+    }
+}
+
+/// Opens a SAM/BAM writer on a file path or on stdout and writes the header.
+///
+/// Outputs are written in `format` if given, else files in the format implied by their extension
+/// and stdout as SAM. SAM files named `.gz` or `.bgz` are bgzip-compressed. Writers are
+/// built by noodles-util and only append, so FIFOs and `/dev/fd` descriptors work as outputs
+/// given an explicit format.
+///
+/// # Arguments
+///
+/// * `output` - The output SAM/BAM file path, or None for stdout
+/// * `header` - The header to write
+/// * `format` - The format of the output, overriding a file's extension
+///
+/// # Returns
+///
+/// Returns the opened writer, or an error if the output cannot be created.
+///
+pub fn open_writer(
+    output: Option<&Path>,
+    header: &Header,
+    format: Option<OutputFormat>,
+) -> Result<Box<dyn AlignmentWrite>, Box<dyn error::Error>> {
+    let (inner, format, bgzip): (Box<dyn Write>, Format, bool) = match output {
+        None => {
+            info!("Output: stdout");
+            (Box::new(io::stdout()), format_for_stdout(format)?, false)
+        }
+        Some(path) => {
+            info!("Output: {path:?}");
+            (
+                Box::new(File::create(path)?),
+                format_for_output(path, format)?,
+                is_bgzip_path(path),
+            )
+        }
+    };
+
+    let (format, compression) = match format {
+        Format::Sam => (WriterFormat::Sam, bgzip.then_some(CompressionMethod::Bgzf)),
+        Format::Bam => (WriterFormat::Bam, Some(CompressionMethod::Bgzf)),
+    };
+    let mut writer: Box<dyn AlignmentWrite> = Box::new(
+        Builder::default()
+            .set_format(format)
+            .set_compression_method(compression)
+            .build_from_writer(BufWriter::new(inner))?,
+    );
+
+    writer.write_alignment_header(header)?;
+    Ok(writer)
+}
+
+/// Builds an output header from an input header with a `@PG` record for this program appended.
+///
+/// The record is named by `options.pg_id` and `options.pg_program_name` when given. With
+/// `options.reproducible`, its ID is otherwise made unique within the header, as
+/// [`crate::options::program_id`] does, and its command line names the program without its
+/// install path.
+///
+/// With `options.restore_orientation`, the `@HD` line no longer claims a coordinate order; see
+/// [`reoriented_header`].
+///
+/// # Returns
+///
+/// Returns the new header, or an error if the `@PG` record cannot be added.
+///
+pub fn header_with_program(
+    template: &Header,
+    options: &Options,
+) -> Result<Header, Box<dyn error::Error>> {
+    let mut header = if options.restore_orientation {
+        let mut writer = noodles_sam::io::Writer::new(Vec::new());
+        writer.write_header(template)?;
+        String::from_utf8(reoriented_header(writer.get_ref()))?.parse()?
+    } else {
+        template.clone()
+    };
+
+    let program = Map::<Program>::builder()
+        .insert(program_tag::NAME, options.program_name())
+        .insert(program_tag::VERSION, CARGO_PKG_VERSION)
+        .insert(
+            program_tag::COMMAND_LINE,
+            program_command_line(program_args(), options.reproducible),
+        )
+        .build()?;
+    let id = options.program_record_id(header.programs().as_ref().keys())?;
+
+    header.programs_mut().add(id, program)?;
+
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_detect_format() {
+        let mut bam: &[u8] = &[0x1f, 0x8b, 0x08, 0x04];
+        let mut sam: &[u8] = b"@HD\tVN:1.6\n";
+        let mut cram: &[u8] = b"CRAM\x03\x00";
+        assert_eq!(detect_format(&mut bam).unwrap(), Format::Bam);
+        assert_eq!(detect_format(&mut sam).unwrap(), Format::Sam);
+        assert!(detect_format(&mut cram).is_err());
+    }
+
+    #[test]
+    fn test_format_for_path() {
+        assert_eq!(
+            format_for_path(&PathBuf::from("out.bam")).unwrap(),
+            Format::Bam
+        );
+        assert_eq!(
+            format_for_path(&PathBuf::from("out.sam")).unwrap(),
+            Format::Sam
+        );
+        assert_eq!(format_for_path(&PathBuf::from("out")).unwrap(), Format::Sam);
+        assert_eq!(
+            format_for_path(&PathBuf::from("out.sam.gz")).unwrap(),
+            Format::Sam
+        );
+        assert_eq!(
+            format_for_path(&PathBuf::from("out.bam.bgz")).unwrap(),
+            Format::Bam
+        );
+        assert!(is_bgzip_path(&PathBuf::from("out.sam.gz")));
+        assert!(!is_bgzip_path(&PathBuf::from("out.sam")));
+        assert!(format_for_path(&PathBuf::from("out.cram")).is_err());
+    }
+
+    #[test]
+    fn test_format_for_output() {
+        let fd = PathBuf::from("/dev/fd/63");
+        assert_eq!(format_for_output(&fd, None).unwrap(), Format::Sam);
+        assert_eq!(
+            format_for_output(&fd, Some(OutputFormat::Bam)).unwrap(),
+            Format::Bam
+        );
+        assert!(format_for_output(&fd, Some(OutputFormat::Cram)).is_err());
+    }
+
+    #[test]
+    fn test_format_for_stdout() {
+        assert_eq!(format_for_stdout(None).unwrap(), Format::Sam);
+        assert_eq!(
+            format_for_stdout(Some(OutputFormat::Bam)).unwrap(),
+            Format::Bam
+        );
+        assert!(format_for_stdout(Some(OutputFormat::Cram)).is_err());
+    }
+}
@@ -0,0 +1,24 @@
+//! A pure-Rust backend built on noodles for reading and writing SAM/BAM.
+//!
+//! This backend mirrors the htslib-based modules and is enabled with the `noodles` feature. It
+//! is useful where linking htslib is painful, such as static, musl, or WASM builds. CRAM is not
+//! supported by this backend.
+//!
+//! The [`convert`], [`ops`], and [`text`] modules perform no file I/O and compile to `wasm32`
+//! targets; [`io`] and [`run`] are only available natively.
+pub mod convert;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod io;
+pub mod ops;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod run;
+pub mod text;
+
+pub use self::convert::transform_record;
+pub use self::ops::{
+    apply, minus_strand, missing_tag, restore_orientation, reverse_complement_tag, reverse_tag,
+    string_value, value_length,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::run::{revtag, run};
+pub use self::text::transform_sam;
@@ -0,0 +1,641 @@
+//! Tag transforms applied to individual noodles alignment records.
+use noodles_sam::alignment::RecordBuf;
+use noodles_sam::alignment::record::Flags;
+use noodles_sam::alignment::record::cigar::Op;
+use noodles_sam::alignment::record_buf::data::field::Value;
+use noodles_sam::alignment::record_buf::data::field::value::Array;
+use std::error;
+use std::mem;
+
+use super::convert::to_data_tag;
+use crate::aux;
+use crate::condition::{RecordFields, TagValue};
+use crate::dna;
+use crate::plan::{Encoding, NonSequence, Operation, Plan, Tag};
+
+/// Reverses the array-like contents of `value` in place, returning false if the type is
+/// unsupported.
+fn reverse_value(value: &mut Value) -> bool {
+    match value {
+        Value::Array(Array::UInt8(values)) => values.reverse(),
+        Value::Array(Array::UInt16(values)) => values.reverse(),
+        Value::Array(Array::UInt32(values)) => values.reverse(),
+        Value::Array(Array::Int8(values)) => values.reverse(),
+        Value::Array(Array::Int16(values)) => values.reverse(),
+        Value::Array(Array::Int32(values)) => values.reverse(),
+        Value::Array(Array::Float(values)) => values.reverse(),
+        Value::String(s) => s.reverse(),
+        _ => return false,
+    }
+    true
+}
+
+/// Reverse complements the DNA sequence in `value` in place, returning false if the type is
+/// unsupported.
+fn reverse_complement_value(value: &mut Value) -> bool {
+    match value {
+        Value::String(s) => dna::revcomp_in_place(s),
+        Value::Array(Array::UInt8(values)) => dna::revcomp_in_place(values),
+        _ => return false,
+    }
+    true
+}
+
+/// Complements the DNA sequence in `value` in place without reversing it, returning false if
+/// the type is unsupported.
+fn complement_value(value: &mut Value) -> bool {
+    let bases: &mut [u8] = match value {
+        Value::String(s) => s.as_mut(),
+        Value::Array(Array::UInt8(values)) => values,
+        _ => return false,
+    };
+    bases
+        .iter_mut()
+        .for_each(|base| *base = dna::complement(*base));
+    true
+}
+
+/// Reverses the colorspace read in `value` in place, returning false if the type is
+/// unsupported or the string is not a colorspace read.
+fn reverse_colorspace_value(value: &mut Value) -> bool {
+    match value {
+        Value::String(s) => dna::reverse_colorspace(s),
+        _ => false,
+    }
+}
+
+/// Re-encodes a `Z` string or `B:C` byte array in `value` as `encoding`, leaving values already
+/// in that encoding and values of other types untouched.
+fn convert_value(
+    value: &mut Value,
+    tag: &Tag,
+    encoding: Encoding,
+) -> Result<(), Box<dyn error::Error>> {
+    let converted = match (&mut *value, encoding) {
+        (Value::String(s), Encoding::ByteArray) => {
+            Value::Array(Array::UInt8(Vec::from(mem::take(s))))
+        }
+        (Value::Array(Array::UInt8(bytes)), Encoding::String) => {
+            aux::check_printable(tag, bytes)?;
+            Value::String(mem::take(bytes).into())
+        }
+        _ => return Ok(()),
+    };
+    *value = converted;
+    Ok(())
+}
+
+/// Calls `f` on the value stored under `tag`, if it exists, to modify it in place.
+///
+/// The value is moved out of the record and back rather than copied, so per-base arrays of long
+/// reads are never duplicated; replacing an existing tag keeps its position among the fields.
+fn update<F>(record: &mut RecordBuf, tag: &Tag, f: F) -> Result<(), Box<dyn error::Error>>
+where
+    F: FnOnce(&mut Value) -> Result<(), Box<dyn error::Error>>,
+{
+    let tag = to_data_tag(tag);
+    let data = record.data_mut();
+    if data.get(&tag).is_none() {
+        return Ok(());
+    }
+    let Some(mut value) = data.insert(tag, Value::Character(b'*')) else {
+        return Ok(());
+    };
+    let result = f(&mut value);
+    data.insert(tag, value);
+    result
+}
+
+/// Reverses the order of an array-like value stored under `tag`.
+///
+/// Numeric `B` arrays of every element type and `Z` strings are supported. Missing tags and
+/// tags of any other type are left untouched.
+///
+/// # Arguments
+///
+/// * `record` - The noodles record to mutate
+/// * `tag` - The SAM tag to reverse
+///
+/// # Returns
+///
+/// Returns Ok(()) on success, or an error if tag manipulation fails.
+///
+pub fn reverse_tag(record: &mut RecordBuf, tag: &Tag) -> Result<(), Box<dyn error::Error>> {
+    update(record, tag, |value| {
+        reverse_value(value);
+        Ok(())
+    })
+}
+
+/// Reverse complements a DNA sequence stored under `tag`.
+///
+/// Both `Z` strings and `B:C` byte arrays are supported. Missing tags and tags of any other
+/// type are left untouched.
+///
+/// # Arguments
+///
+/// * `record` - The noodles record to mutate
+/// * `tag` - The SAM tag to reverse complement
+///
+/// # Returns
+///
+/// Returns Ok(()) on success, or an error if tag manipulation fails.
+///
+pub fn reverse_complement_tag(
+    record: &mut RecordBuf,
+    tag: &Tag,
+) -> Result<(), Box<dyn error::Error>> {
+    reverse_complement_or(record, tag, NonSequence::Skip)
+}
+
+/// Reverse complements the value stored under `tag`, handling values that are not sequences as
+/// directed by `policy`.
+fn reverse_complement_or(
+    record: &mut RecordBuf,
+    tag: &Tag,
+    policy: NonSequence,
+) -> Result<(), Box<dyn error::Error>> {
+    update(record, tag, |value| {
+        if reverse_complement_value(value) {
+            return Ok(());
+        }
+        match policy {
+            NonSequence::Error => Err(format!(
+                "Tag {} holds a value that is not a sequence and cannot be reverse complemented",
+                String::from_utf8_lossy(tag)
+            )
+            .into()),
+            NonSequence::ReverseOnly => {
+                reverse_value(value);
+                Ok(())
+            }
+            NonSequence::Skip => Ok(()),
+        }
+    })
+}
+
+/// Mutates a record by applying every tag specification in a plan, in order.
+///
+/// The record strand is not consulted; callers decide which records the plan applies to. Values
+/// to reverse complement that are not sequences are handled as directed by the plan's
+/// [`NonSequence`] policy.
+///
+/// # Arguments
+///
+/// * `record` - The noodles record to mutate
+/// * `plan` - The tag specifications to apply
+///
+/// # Returns
+///
+/// Returns Ok(()) on success, or an error if tag manipulation fails.
+///
+pub fn apply(record: &mut RecordBuf, plan: &Plan) -> Result<(), Box<dyn error::Error>> {
+    for spec in plan.specs() {
+        match spec.operation {
+            Operation::Reverse => reverse_tag(record, &spec.tag)?,
+            Operation::ReverseComplement => {
+                reverse_complement_or(record, &spec.tag, plan.nonsequence())?
+            }
+            Operation::ReverseColorspace => update(record, &spec.tag, |value| {
+                reverse_colorspace_value(value);
+                Ok(())
+            })?,
+            Operation::Complement => update(record, &spec.tag, |value| {
+                complement_value(value);
+                Ok(())
+            })?,
+            Operation::Rename(tag) => {
+                let data = record.data_mut();
+                if let Some(value) = data.remove(&to_data_tag(&spec.tag)) {
+                    data.insert(to_data_tag(&tag), value);
+                }
+            }
+            Operation::Convert(encoding) => update(record, &spec.tag, |value| {
+                convert_value(value, &spec.tag, encoding)
+            })?,
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if a strand tag marks a record as reverse strand, regardless of its flags.
+///
+/// This recognizes the same values as [`crate::aux::minus_strand`]: `-` as an `A` character or
+/// a `Z` string.
+///
+/// # Arguments
+///
+/// * `record` - The noodles record to inspect
+/// * `tag` - The SAM tag holding the strand
+///
+/// # Returns
+///
+/// Returns whether the record is reverse strand.
+///
+pub fn minus_strand(record: &RecordBuf, tag: &Tag) -> bool {
+    match record.data().get(&to_data_tag(tag)) {
+        Some(Value::Character(c)) => *c == b'-',
+        Some(Value::String(s)) => s.iter().eq(b"-".iter()),
+        _ => false,
+    }
+}
+
+/// Returns true if a record's `MI` tag marks it as the B strand of a duplex molecule.
+///
+/// See [`aux::duplex_b_strand`](crate::aux::duplex_b_strand) for the values recognized.
+///
+/// # Arguments
+///
+/// * `record` - The noodles record to inspect
+///
+/// # Returns
+///
+/// Returns whether the record is from the B strand.
+///
+pub fn duplex_b_strand(record: &RecordBuf) -> bool {
+    match record.data().get(&to_data_tag(b"MI")) {
+        Some(Value::String(s)) => s.ends_with(b"/B"),
+        _ => false,
+    }
+}
+
+/// Returns the first of `tags` that a record does not carry.
+///
+/// # Arguments
+///
+/// * `record` - The noodles record to inspect
+/// * `tags` - The SAM tags that must be present
+///
+/// # Returns
+///
+/// Returns the first missing tag, or None if every tag is present.
+///
+pub fn missing_tag<'a, I>(record: &RecordBuf, tags: I) -> Option<Tag>
+where
+    I: IntoIterator<Item = &'a Tag>,
+{
+    tags.into_iter()
+        .find(|tag| record.data().get(&to_data_tag(tag)).is_none())
+        .copied()
+}
+
+/// Returns the number of elements of a tag's value: characters of strings, elements of arrays,
+/// and one for scalars.
+///
+/// # Arguments
+///
+/// * `record` - The noodles record to inspect
+/// * `tag` - The SAM tag holding the value
+///
+/// # Returns
+///
+/// Returns the length, or None if the record lacks the tag.
+///
+pub fn value_length(record: &RecordBuf, tag: &Tag) -> Option<usize> {
+    Some(match record.data().get(&to_data_tag(tag))? {
+        Value::String(s) | Value::Hex(s) => s.len(),
+        Value::Array(Array::Int8(values)) => values.len(),
+        Value::Array(Array::UInt8(values)) => values.len(),
+        Value::Array(Array::Int16(values)) => values.len(),
+        Value::Array(Array::UInt16(values)) => values.len(),
+        Value::Array(Array::Int32(values)) => values.len(),
+        Value::Array(Array::UInt32(values)) => values.len(),
+        Value::Array(Array::Float(values)) => values.len(),
+        _ => 1,
+    })
+}
+
+/// Returns the bytes of the `Z` or `H` string stored under `tag`, or None if the record lacks
+/// the tag. Values of other types are returned empty, so they never match a validated value.
+pub fn string_value(record: &RecordBuf, tag: &Tag) -> Option<Vec<u8>> {
+    Some(match record.data().get(&to_data_tag(tag))? {
+        Value::String(s) | Value::Hex(s) => s.to_vec(),
+        _ => Vec::new(),
+    })
+}
+
+/// Re-expresses a reverse strand record in its original sequencing orientation.
+///
+/// This has the same behavior as [`crate::ops::restore_orientation`]: SEQ is reverse
+/// complemented, QUAL and the CIGAR are reversed, and the reverse strand flag is cleared.
+///
+/// # Arguments
+///
+/// * `record` - The noodles record to mutate
+///
+/// # Returns
+///
+/// Returns Ok(()) on success.
+///
+pub fn restore_orientation(record: &mut RecordBuf) -> Result<(), Box<dyn error::Error>> {
+    if !record.flags().is_reverse_complemented() {
+        return Ok(());
+    }
+    let sequence: &mut Vec<u8> = record.sequence_mut().as_mut();
+    dna::revcomp_in_place(sequence);
+    let quality_scores: &mut Vec<u8> = record.quality_scores_mut().as_mut();
+    quality_scores.reverse();
+    let ops: &mut Vec<Op> = record.cigar_mut().as_mut();
+    ops.reverse();
+    record.flags_mut().remove(Flags::REVERSE_COMPLEMENTED);
+    Ok(())
+}
+
+impl RecordFields for RecordBuf {
+    fn flag(&self) -> u16 {
+        self.flags().bits()
+    }
+
+    fn mapq(&self) -> u8 {
+        self.mapping_quality().map_or(255, |mapq| mapq.get())
+    }
+
+    fn pos(&self) -> i64 {
+        self.alignment_start()
+            .map_or(0, |position| usize::from(position) as i64)
+    }
+
+    fn tlen(&self) -> i64 {
+        i64::from(self.template_length())
+    }
+
+    fn seq_len(&self) -> usize {
+        self.sequence().len()
+    }
+
+    fn tag(&self, tag: &Tag) -> Result<Option<TagValue>, Box<dyn error::Error>> {
+        Ok(self.data().get(&to_data_tag(tag)).map(|value| match value {
+            Value::Int8(n) => TagValue::Int(i64::from(*n)),
+            Value::UInt8(n) => TagValue::Int(i64::from(*n)),
+            Value::Int16(n) => TagValue::Int(i64::from(*n)),
+            Value::UInt16(n) => TagValue::Int(i64::from(*n)),
+            Value::Int32(n) => TagValue::Int(i64::from(*n)),
+            Value::UInt32(n) => TagValue::Int(i64::from(*n)),
+            Value::Character(c) => TagValue::Str((*c as char).to_string()),
+            Value::String(s) => TagValue::Str(String::from_utf8_lossy(s).into_owned()),
+            _ => TagValue::Other,
+        }))
+    }
+}
+
+/// Reads the fields of a raw BAM record without decoding it, as [`RecordBuf`] does decoded.
+impl RecordFields for noodles_bam::Record {
+    fn flag(&self) -> u16 {
+        self.flags().bits()
+    }
+
+    fn mapq(&self) -> u8 {
+        self.mapping_quality().map_or(255, |mapq| mapq.get())
+    }
+
+    fn pos(&self) -> i64 {
+        match self.alignment_start() {
+            Some(Ok(position)) => usize::from(position) as i64,
+            _ => 0,
+        }
+    }
+
+    fn tlen(&self) -> i64 {
+        i64::from(self.template_length())
+    }
+
+    fn seq_len(&self) -> usize {
+        self.sequence().len()
+    }
+
+    fn tag(&self, tag: &Tag) -> Result<Option<TagValue>, Box<dyn error::Error>> {
+        let data = self.data();
+        let aux = data.as_ref();
+        Ok(aux::find(aux, tag)?.map(|field| TagValue::from_field(aux, &field)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noodles::convert::from_data_tag;
+
+    /// Helper to create a minimal noodles record carrying the given aux fields
+    fn create_test_record(fields: Vec<(&[u8; 2], Value)>) -> RecordBuf {
+        let data = fields
+            .into_iter()
+            .map(|(tag, value)| (to_data_tag(tag), value))
+            .collect();
+        RecordBuf::builder().set_data(data).build()
+    }
+
+    fn get<'a>(record: &'a RecordBuf, tag: &[u8; 2]) -> Option<&'a Value> {
+        record.data().get(&to_data_tag(tag))
+    }
+
+    #[test]
+    fn test_reverse_u8_array() {
+        let mut record =
+            create_test_record(vec![(b"QT", Value::Array(Array::UInt8(vec![1, 2, 3])))]);
+        reverse_tag(&mut record, b"QT").unwrap();
+        assert_eq!(
+            get(&record, b"QT"),
+            Some(&Value::Array(Array::UInt8(vec![3, 2, 1])))
+        );
+    }
+
+    #[test]
+    fn test_reverse_float_array() {
+        let mut record = create_test_record(vec![(
+            b"KL",
+            Value::Array(Array::Float(vec![1.5, 2.5, 3.5])),
+        )]);
+        reverse_tag(&mut record, b"KL").unwrap();
+        assert_eq!(
+            get(&record, b"KL"),
+            Some(&Value::Array(Array::Float(vec![3.5, 2.5, 1.5])))
+        );
+    }
+
+    #[test]
+    fn test_reverse_long_array_keeps_field_order() {
+        let signal: Vec<u16> = (0..50_000).collect();
+        let mut record = create_test_record(vec![
+            (b"pw", Value::Array(Array::UInt16(signal.clone()))),
+            (b"NM", Value::UInt8(0)),
+        ]);
+        reverse_tag(&mut record, b"pw").unwrap();
+
+        let reversed: Vec<u16> = signal.into_iter().rev().collect();
+        assert_eq!(
+            get(&record, b"pw"),
+            Some(&Value::Array(Array::UInt16(reversed)))
+        );
+        let tags: Vec<Tag> = record
+            .data()
+            .iter()
+            .map(|(tag, _)| from_data_tag(&tag))
+            .collect();
+        assert_eq!(tags, vec![*b"pw", *b"NM"]);
+    }
+
+    #[test]
+    fn test_reverse_string() {
+        let mut record = create_test_record(vec![(b"MN", Value::String("HELLO".into()))]);
+        reverse_tag(&mut record, b"MN").unwrap();
+        assert_eq!(get(&record, b"MN"), Some(&Value::String("OLLEH".into())));
+    }
+
+    #[test]
+    fn test_apply_nonsequence_policy() {
+        let array = || Value::Array(Array::Int16(vec![1, 2]));
+        let plan = Plan::new(&[], &["XS".to_string()]).unwrap();
+
+        let mut record = create_test_record(vec![(b"XS", array())]);
+        apply(&mut record, &plan).unwrap();
+        assert_eq!(get(&record, b"XS"), Some(&array()));
+
+        let reverse_only = plan.clone().with_nonsequence(NonSequence::ReverseOnly);
+        apply(&mut record, &reverse_only).unwrap();
+        assert_eq!(
+            get(&record, b"XS"),
+            Some(&Value::Array(Array::Int16(vec![2, 1])))
+        );
+
+        let error = plan.with_nonsequence(NonSequence::Error);
+        assert!(apply(&mut record, &error).is_err());
+    }
+
+    #[test]
+    fn test_record_fields() {
+        let record = create_test_record(vec![
+            (b"NM", Value::UInt8(2)),
+            (b"XS", Value::Character(b'-')),
+            (b"KL", Value::Array(Array::Float(vec![1.5]))),
+        ]);
+        assert_eq!(record.pos(), 0);
+        assert_eq!(record.mapq(), 255);
+        assert_eq!(record.tag(b"NM").unwrap(), Some(TagValue::Int(2)));
+        assert_eq!(
+            record.tag(b"XS").unwrap(),
+            Some(TagValue::Str("-".to_string()))
+        );
+        assert_eq!(record.tag(b"KL").unwrap(), Some(TagValue::Other));
+        assert_eq!(record.tag(b"QT").unwrap(), None);
+    }
+
+    #[test]
+    fn test_minus_strand() {
+        let minus = create_test_record(vec![(b"XS", Value::Character(b'-'))]);
+        let plus = create_test_record(vec![(b"XS", Value::Character(b'+'))]);
+        let string = create_test_record(vec![(b"XS", Value::String("-".into()))]);
+        assert!(minus_strand(&minus, b"XS"));
+        assert!(!minus_strand(&plus, b"XS"));
+        assert!(minus_strand(&string, b"XS"));
+        assert!(!minus_strand(&minus, b"TS"));
+    }
+
+    #[test]
+    fn test_missing_tag() {
+        let record = create_test_record(vec![(b"MN", Value::String("HELLO".into()))]);
+        assert_eq!(missing_tag(&record, [b"MN"]), None);
+        assert_eq!(missing_tag(&record, [b"MN", b"Mn"]), Some(*b"Mn"));
+    }
+
+    #[test]
+    fn test_value_length() {
+        let record = create_test_record(vec![
+            (b"BC", Value::String("ACGT".into())),
+            (b"QT", Value::Array(Array::Int16(vec![1, 2, 3]))),
+            (b"NM", Value::UInt8(3)),
+        ]);
+        assert_eq!(value_length(&record, b"BC"), Some(4));
+        assert_eq!(value_length(&record, b"QT"), Some(3));
+        assert_eq!(value_length(&record, b"NM"), Some(1));
+        assert_eq!(value_length(&record, b"XX"), None);
+    }
+
+    #[test]
+    fn test_revcomp_string() {
+        let mut record = create_test_record(vec![(b"BC", Value::String("AtCg".into()))]);
+        reverse_complement_tag(&mut record, b"BC").unwrap();
+        assert_eq!(get(&record, b"BC"), Some(&Value::String("cGaT".into())));
+    }
+
+    #[test]
+    fn test_revcomp_array_u8() {
+        let mut record =
+            create_test_record(vec![(b"BC", Value::Array(Array::UInt8(b"ATCG".to_vec())))]);
+        reverse_complement_tag(&mut record, b"BC").unwrap();
+        assert_eq!(
+            get(&record, b"BC"),
+            Some(&Value::Array(Array::UInt8(b"CGAT".to_vec())))
+        );
+    }
+
+    #[test]
+    fn test_revcomp_ignores_numeric_scalar() {
+        let mut record = create_test_record(vec![(b"NM", Value::UInt8(3))]);
+        reverse_complement_tag(&mut record, b"NM").unwrap();
+        assert_eq!(get(&record, b"NM"), Some(&Value::UInt8(3)));
+    }
+
+    #[test]
+    fn test_nonexistent_tag() {
+        let mut record = create_test_record(vec![]);
+        assert!(reverse_tag(&mut record, b"ZZ").is_ok());
+        assert!(get(&record, b"ZZ").is_none());
+    }
+
+    #[test]
+    fn test_restore_orientation() {
+        use noodles_sam::alignment::record::cigar::op::Kind;
+
+        let mut record = RecordBuf::builder()
+            .set_flags(Flags::REVERSE_COMPLEMENTED)
+            .set_cigar(
+                [Op::new(Kind::SoftClip, 1), Op::new(Kind::Match, 3)]
+                    .into_iter()
+                    .collect(),
+            )
+            .set_sequence(b"AACG".to_vec().into())
+            .set_quality_scores(vec![10, 20, 30, 40].into())
+            .build();
+
+        restore_orientation(&mut record).unwrap();
+
+        assert!(!record.flags().is_reverse_complemented());
+        assert_eq!(record.sequence().as_ref(), b"CGTT");
+        assert_eq!(record.quality_scores().as_ref(), &[40, 30, 20, 10]);
+        let ops: &[Op] = record.cigar().as_ref();
+        assert_eq!(ops, &[Op::new(Kind::Match, 3), Op::new(Kind::SoftClip, 1)]);
+    }
+
+    #[test]
+    fn test_apply_plan() {
+        let mut record = create_test_record(vec![
+            (b"QT", Value::Array(Array::UInt8(vec![10, 20, 30]))),
+            (b"BC", Value::String("ATCG".into())),
+        ]);
+        let plan = Plan::new(&["QT".to_string()], &["BC".to_string()]).unwrap();
+        apply(&mut record, &plan).unwrap();
+        assert_eq!(
+            get(&record, b"QT"),
+            Some(&Value::Array(Array::UInt8(vec![30, 20, 10])))
+        );
+        assert_eq!(get(&record, b"BC"), Some(&Value::String("CGAT".into())));
+    }
+
+    #[test]
+    fn test_apply_conversion() {
+        let mut record = create_test_record(vec![(b"BC", Value::String("AACG".into()))]);
+        let plan = Plan::parse(&[], &[], &["BC:revcomp,as=B:C".to_string()]).unwrap();
+        apply(&mut record, &plan).unwrap();
+        assert_eq!(
+            get(&record, b"BC"),
+            Some(&Value::Array(Array::UInt8(b"CGTT".to_vec())))
+        );
+
+        let plan = Plan::parse(&[], &[], &["BC:as=Z".to_string()]).unwrap();
+        apply(&mut record, &plan).unwrap();
+        assert_eq!(get(&record, b"BC"), Some(&Value::String("CGTT".into())));
+
+        let mut record = create_test_record(vec![(b"BC", Value::Array(Array::UInt8(vec![0])))]);
+        assert!(apply(&mut record, &plan).is_err());
+    }
+}
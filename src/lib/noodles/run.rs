@@ -0,0 +1,752 @@
+//! End-to-end execution of `revtag` over an input and output stream using noodles.
+use log::*;
+use noodles_sam::Header;
+use noodles_sam::alignment::RecordBuf;
+use noodles_sam::alignment::io::Write as _;
+use proglog::ProgLogBuilder;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::convert::to_record_buf;
+use super::io::{
+    Format, detect_format, format_for_output, format_for_stdout, header_with_program, open_input,
+    open_writer,
+};
+use super::ops::{
+    apply, duplex_b_strand, minus_strand, missing_tag, restore_orientation, string_value,
+    value_length,
+};
+use crate::aux::{self, MissingTagError};
+use crate::fastq;
+use crate::fsync::sync_outputs;
+use crate::options::Options;
+use crate::plan::{DuplicateTags, Plan};
+
+/// Returns a record name for messages, or `*` for unnamed records.
+fn qname<N: fmt::Display>(name: Option<N>) -> String {
+    name.map(|name| name.to_string())
+        .unwrap_or_else(|| "*".to_string())
+}
+
+/// Returns true if the plan applies to a record: it is reverse strand, as marked by the duplex
+/// strand of its `MI` tag with `options.strand_from_mi`, by `options.strand_tag` if set, and by
+/// its flags otherwise, and passes the filters of [`Options::selects`].
+fn is_selected(record: &RecordBuf, options: &Options) -> Result<bool, Box<dyn error::Error>> {
+    let reverse = match &options.strand_tag {
+        _ if options.strand_from_mi => duplex_b_strand(record),
+        Some(tag) => minus_strand(record, tag),
+        None => record.flags().is_reverse_complemented(),
+    };
+    if !options.is_reverse(record, reverse)? {
+        return Ok(false);
+    }
+    options.selects(record)
+}
+
+/// Returns whether the plan applies to a raw BAM record as [`is_selected`] does, without
+/// decoding it.
+fn is_selected_raw(
+    record: &noodles_bam::Record,
+    options: &Options,
+) -> Result<bool, Box<dyn error::Error>> {
+    let reverse = match &options.strand_tag {
+        _ if options.strand_from_mi => aux::duplex_b_strand(record.data().as_ref())?,
+        Some(tag) => aux::minus_strand(record.data().as_ref(), tag)?,
+        None => record.flags().is_reverse_complemented(),
+    };
+    if !options.is_reverse(record, reverse)? {
+        return Ok(false);
+    }
+    options.selects(record)
+}
+
+/// Fails if a record lacks any of `options.required_tags` or, when the plan applies to it,
+/// carries a companion pair that is incomplete or of mismatched lengths or, with
+/// `options.strict`, lacks any planned tag.
+fn check_tags(
+    record: &RecordBuf,
+    selected: bool,
+    plan: &Plan,
+    options: &Options,
+) -> Result<(), Box<dyn error::Error>> {
+    let mut missing = missing_tag(record, &options.required_tags);
+    if missing.is_none() && options.strict && selected {
+        missing = missing_tag(record, plan.specs().iter().map(|spec| &spec.tag));
+    }
+    if let Some(tag) = missing {
+        return Err(Box::new(MissingTagError {
+            qname: qname(record.name()),
+            tag,
+        }));
+    }
+    if selected {
+        aux::check_pairs(
+            plan,
+            || qname(record.name()),
+            |tag| Ok(value_length(record, tag)),
+        )?;
+        aux::check_values(
+            plan,
+            || qname(record.name()),
+            |tag| Ok(string_value(record, tag)),
+        )?;
+    }
+    Ok(())
+}
+
+/// Checks the tags of a raw BAM record as [`check_tags`] does, without decoding it.
+fn check_tags_raw(
+    record: &noodles_bam::Record,
+    selected: bool,
+    plan: &Plan,
+    options: &Options,
+) -> Result<(), Box<dyn error::Error>> {
+    let data = record.data();
+    let mut missing = aux::missing_tag(data.as_ref(), &options.required_tags)?;
+    if missing.is_none() && options.strict && selected {
+        missing = aux::missing_tag(data.as_ref(), plan.specs().iter().map(|spec| &spec.tag))?;
+    }
+    if let Some(tag) = missing {
+        return Err(Box::new(MissingTagError {
+            qname: qname(record.name()),
+            tag,
+        }));
+    }
+    if selected {
+        aux::check_pairs(
+            plan,
+            || qname(record.name()),
+            |tag| Ok(aux::find(data.as_ref(), tag)?.map(|field| aux::value_length(&field))),
+        )?;
+        aux::check_values(
+            plan,
+            || qname(record.name()),
+            |tag| {
+                Ok(aux::find(data.as_ref(), tag)?.map(|field| data.as_ref()[field.value].to_vec()))
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Streams every record through the plan and into the output.
+///
+/// # Arguments
+///
+/// * `header` - The input header, used to decode records
+/// * `output` - The output SAM/BAM file path, or None for stdout
+/// * `plan` - The tag specifications to apply to reverse strand records
+/// * `options` - Options controlling how records are transformed
+/// * `read` - Reads the next record into a buffer, returning 0 at end of input
+///
+fn transform_all<F>(
+    header: &Header,
+    output: Option<&Path>,
+    plan: &Plan,
+    options: &Options,
+    mut read: F,
+) -> Result<(), Box<dyn error::Error>>
+where
+    F: FnMut(&mut RecordBuf) -> io::Result<usize>,
+{
+    let header = header_with_program(header, options)?;
+    let mut writer = open_writer(output, &header, options.output_format)?;
+
+    let progress = ProgLogBuilder::new()
+        .name("main")
+        .verb("Processed")
+        .noun("alignment records")
+        .unit(100_000)
+        .build();
+
+    let mut record = RecordBuf::default();
+
+    while read(&mut record)? != 0 {
+        if options.drops(&record) {
+            progress.record();
+            continue;
+        }
+        let selected = is_selected(&record, options)?;
+        check_tags(&record, selected, plan, options)?;
+        if selected {
+            apply(&mut record, plan)?;
+            if options.restore_orientation {
+                restore_orientation(&mut record)?;
+            }
+        }
+
+        writer.write_alignment_record(&header, &record)?;
+        progress.record();
+    }
+
+    writer.finish(&header)?;
+    Ok(())
+}
+
+/// Writes a raw BAM record, prefixed by its block size, without re-encoding it.
+fn write_raw<W: Write>(writer: &mut W, record: &[u8]) -> io::Result<()> {
+    let block_size =
+        u32::try_from(record.len()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    writer.write_all(&block_size.to_le_bytes())?;
+    writer.write_all(record)
+}
+
+/// Streams BAM records into a BAM output, copying records the plan leaves unchanged as raw bytes.
+///
+/// Only reverse strand records carrying a planned tag, or every reverse strand record when
+/// restoring orientation, are decoded, transformed, and re-encoded.
+/// The output header only gains a `@PG` record, so raw records stay valid against it.
+/// The output is flushed after the BGZF EOF marker is written, so a failure to write the end of
+/// a stream such as stdout is reported rather than lost when the writer is dropped.
+///
+/// # Arguments
+///
+/// * `reader` - The BAM reader, positioned after the header
+/// * `header` - The input header
+/// * `output` - The BAM output, such as a file or stdout
+/// * `plan` - The tag specifications to apply to reverse strand records
+/// * `options` - Options controlling how records are transformed
+///
+fn passthrough_bam<R: Read, W: Write>(
+    reader: &mut noodles_bam::io::Reader<R>,
+    header: &Header,
+    output: W,
+    plan: &Plan,
+    options: &Options,
+) -> Result<(), Box<dyn error::Error>> {
+    let header = header_with_program(header, options)?;
+    let mut writer = noodles_bam::io::Writer::new(output);
+    writer.write_alignment_header(&header)?;
+
+    let progress = ProgLogBuilder::new()
+        .name("main")
+        .verb("Processed")
+        .noun("alignment records")
+        .unit(100_000)
+        .build();
+
+    let mut record = noodles_bam::Record::default();
+    let mut copied: u64 = 0;
+
+    while reader.read_record(&mut record)? != 0 {
+        if options.drops(&record) {
+            progress.record();
+            continue;
+        }
+        let selected = is_selected_raw(&record, options)?;
+        check_tags_raw(&record, selected, plan, options)?;
+        let planned = selected
+            && (options.restore_orientation
+                || aux::any_tag(record.data().as_ref(), |tag| plan.contains(tag))?);
+
+        if planned {
+            let mut transformed = to_record_buf(&header, &record)?;
+            apply(&mut transformed, plan)?;
+            if options.restore_orientation {
+                restore_orientation(&mut transformed)?;
+            }
+            writer.write_alignment_record(&header, &transformed)?;
+        } else {
+            write_raw(writer.get_mut(), record.as_ref())?;
+            copied += 1;
+        }
+        progress.record();
+    }
+
+    writer.finish(&header)?;
+    writer.get_mut().get_mut().flush()?;
+    debug!("Copied {copied} unmodified records without re-encoding");
+    Ok(())
+}
+
+/// Runs the tool `revtag` with the noodles backend on an input SAM/BAM file.
+///
+/// Inputs named `.fastq` or `.fq` are read and written as FASTQ; see [`fastq::run`].
+///
+/// When both input and output are BAM, records that are forward strand or carry none of the
+/// planned tags are copied as raw bytes rather than decoded and re-encoded.
+///
+/// This has the same behavior as [`crate::run::run`] except that CRAM is not supported,
+/// `options.threads` and `options.batch_size` are ignored, neither WASM plugins, scripts, a barcode
+/// FASTQ, nor multiple outputs are supported, `options.reference` is unused, and planned tags of
+/// unsupported types are left unchanged without a warning.
+///
+/// # Arguments
+///
+/// * `input` - The input SAM/BAM file path, or None for stdin
+/// * `output` - The output SAM/BAM file path, or None for stdout
+/// * `plan` - The tag specifications to apply to reverse strand records
+/// * `options` - Options controlling how records are read, transformed, and written
+///
+/// # Returns
+///
+/// Returns the result of the execution with an integer exit code for success (0).
+///
+pub fn run(
+    input: Option<&Path>,
+    output: Option<&Path>,
+    plan: &Plan,
+    options: &Options,
+) -> Result<i32, Box<dyn error::Error>> {
+    if options.threads > 1 {
+        warn!(
+            "The noodles backend is single-threaded; ignoring --threads {}",
+            options.threads
+        );
+    }
+    if !options.plugins.is_empty() {
+        return Err("WASM plugins are not supported by the noodles backend".into());
+    }
+    if options.script.is_some() {
+        return Err("Scripts are not supported by the noodles backend".into());
+    }
+    if options.barcode_fastq.is_some() {
+        return Err("A barcode FASTQ is not supported by the noodles backend".into());
+    }
+    if !options.tee.is_empty() {
+        return Err("Multiple outputs are not supported by the noodles backend".into());
+    }
+    if options.write_md5 {
+        return Err("Checksum sidecars are not supported by the noodles backend".into());
+    }
+    if options.strand_annotation.is_some() {
+        return Err("Strand annotations are not supported by the noodles backend".into());
+    }
+    if options.qname_umi.is_some() {
+        return Err("UMIs in read names are not supported by the noodles backend".into());
+    }
+    if options.preserve_header {
+        return Err("Preserving the input header is not supported by the noodles backend".into());
+    }
+    if options.audit_tsv.is_some() {
+        return Err("An audit table is not supported by the noodles backend".into());
+    }
+    if options.contig_tsv.is_some() {
+        return Err("A per-contig summary is not supported by the noodles backend".into());
+    }
+    if options.sanitize {
+        return Err("Sanitizing aux tags is not supported by the noodles backend".into());
+    }
+    if options.cram_lossy_names || options.cram_quality_bins.is_some() {
+        return Err("CRAM outputs are not supported by the noodles backend".into());
+    }
+    if options.progress_fd.is_some() {
+        return Err("Progress events are not supported by the noodles backend".into());
+    }
+    if options.profile {
+        return Err("Profiling stages is not supported by the noodles backend".into());
+    }
+    if options.preflight.is_some() {
+        return Err("A preflight scan is not supported by the noodles backend".into());
+    }
+    if options.expected_sort_order.is_some() {
+        return Err("Validating the sort order is not supported by the noodles backend".into());
+    }
+    if options.whitelist.is_some() {
+        return Err(
+            "Matching barcodes to a whitelist is not supported by the noodles backend".into(),
+        );
+    }
+    if options.max_errors > 0 {
+        return Err("Tolerating record errors is not supported by the noodles backend".into());
+    }
+    if options.retries > 0 {
+        return Err("Retrying remote inputs is not supported by the noodles backend".into());
+    }
+    if options.auto_orient.is_some() {
+        return Err("Detecting tag orientations is not supported by the noodles backend".into());
+    }
+    if options.revert_to_ubam {
+        return Err(
+            "Reverting to unaligned records is not supported by the noodles backend".into(),
+        );
+    }
+    if !options.pair_fix.is_empty() {
+        return Err(
+            "Propagating tags between mates is not supported by the noodles backend".into(),
+        );
+    }
+    if plan.has_strand_sources() {
+        return Err(
+            "Tags with their own strand source are not supported by the noodles backend".into(),
+        );
+    }
+    if plan.has_matrices() {
+        return Err("Matrix tags are not supported by the noodles backend".into());
+    }
+    if plan.duplicate_tags() != DuplicateTags::First {
+        return Err("Transforming repeated tags is not supported by the noodles backend".into());
+    }
+    if input.is_some_and(fastq::is_fastq_path) {
+        return fastq::run(input, output, plan, options);
+    }
+
+    let mut input = open_input(input)?;
+
+    match detect_format(&mut input)? {
+        Format::Sam => {
+            let mut reader = noodles_sam::io::Reader::new(input);
+            let header = reader.read_header()?;
+            transform_all(&header, output, plan, options, |record| {
+                reader.read_record_buf(&header, record)
+            })?;
+        }
+        Format::Bam => {
+            let mut reader = noodles_bam::io::Reader::new(input);
+            let header = reader.read_header()?;
+            match output {
+                Some(path) if format_for_output(path, options.output_format)? == Format::Bam => {
+                    info!("Output: {path:?}");
+                    passthrough_bam(&mut reader, &header, File::create(path)?, plan, options)?;
+                }
+                None if format_for_stdout(options.output_format)? == Format::Bam => {
+                    info!("Output: stdout");
+                    let stdout = BufWriter::new(io::stdout());
+                    passthrough_bam(&mut reader, &header, stdout, plan, options)?;
+                }
+                _ => transform_all(&header, output, plan, options, |record| {
+                    reader.read_record_buf(&header, record)
+                })?,
+            }
+        }
+    }
+
+    sync_outputs(output, options)?;
+    Ok(0)
+}
+
+/// Runs the tool `revtag` with the noodles backend on an input SAM/BAM file.
+///
+/// This has the same behavior as [`crate::run::revtag`] except that CRAM is not supported and
+/// `threads` is ignored.
+///
+/// # Arguments
+///
+/// * `input` - The input SAM/BAM file path, or None for stdin
+/// * `output` - The output SAM/BAM file path, or None for stdout
+/// * `rev` - SAM tags to reverse (e.g., base qualities)
+/// * `revcomp` - SAM tags to reverse complement (e.g., sequences)
+/// * `threads` - Extra threads for compression/decompression, unused by this backend
+///
+/// # Returns
+///
+/// Returns the result of the execution with an integer exit code for success (0).
+///
+pub fn revtag(
+    input: Option<PathBuf>,
+    output: Option<PathBuf>,
+    rev: Vec<String>,
+    revcomp: Vec<String>,
+    threads: usize,
+) -> Result<i32, Box<dyn error::Error>> {
+    let plan = Plan::new(&rev, &revcomp)?;
+    let options = Options {
+        threads,
+        ..Options::default()
+    };
+    run(input.as_deref(), output.as_deref(), &plan, &options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn sam_input() -> &'static str {
+        concat!(
+            "@HD\tVN:1.6\tSO:unknown\n",
+            "@SQ\tSN:chr1\tLN:1000\n",
+            "fwd\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:B:C,10,20,30\tMN:Z:HELLO\tBC:Z:ATCG\n",
+            "rev\t16\tchr1\t2\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:B:C,1,2,3\tMN:Z:WORLD\tBC:Z:GATT\n",
+        )
+    }
+
+    #[test]
+    fn test_revtag_sam_to_sam() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_input()).unwrap();
+        let outfile = NamedTempFile::new().expect("temp sam output");
+
+        let exit = revtag(
+            Some(infile.path().to_path_buf()),
+            Some(outfile.path().to_path_buf()),
+            vec!["QT".into(), "MN".into()],
+            vec!["BC".into()],
+            1,
+        )
+        .expect("revtag should succeed");
+        assert_eq!(exit, 0);
+
+        let output = std::fs::read_to_string(outfile.path()).unwrap();
+        let fwd = output.lines().find(|l| l.starts_with("fwd\t")).unwrap();
+        let rev = output.lines().find(|l| l.starts_with("rev\t")).unwrap();
+        assert!(fwd.contains("MN:Z:HELLO") && fwd.contains("BC:Z:ATCG"));
+        assert!(rev.contains("QT:B:C,3,2,1"));
+        assert!(rev.contains("MN:Z:DLROW"));
+        assert!(rev.contains("BC:Z:AATC"));
+        assert!(output.contains("@PG\tID:revtag"));
+    }
+
+    #[test]
+    fn test_revtag_sam_to_bam_round_trip() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_input()).unwrap();
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let bam_out = tmpdir.path().join("out.bam");
+        let sam_out = tmpdir.path().join("out.sam");
+
+        revtag(
+            Some(infile.path().to_path_buf()),
+            Some(bam_out.clone()),
+            vec!["MN".into()],
+            vec![],
+            1,
+        )
+        .expect("revtag should succeed producing BAM");
+
+        // Reading the BAM back with an empty plan should leave the reversed tag as written.
+        revtag(Some(bam_out), Some(sam_out.clone()), vec![], vec![], 1)
+            .expect("revtag should read BAM input");
+
+        let output = std::fs::read_to_string(sam_out).unwrap();
+        let rev = output.lines().find(|l| l.starts_with("rev\t")).unwrap();
+        assert!(rev.contains("MN:Z:DLROW"));
+    }
+
+    #[test]
+    fn test_revtag_sam_to_bgzip_sam() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_input()).unwrap();
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let sam_gz = tmpdir.path().join("out.sam.gz");
+        revtag(
+            Some(infile.path().to_path_buf()),
+            Some(sam_gz.clone()),
+            vec!["MN".into()],
+            vec![],
+            1,
+        )
+        .expect("revtag should succeed producing bgzip SAM");
+
+        // A BGZF block is a gzip member whose extra field is the `BC` subfield.
+        let bytes = std::fs::read(&sam_gz).unwrap();
+        assert_eq!(bytes[..4], [0x1f, 0x8b, 0x08, 0x04]);
+        assert_eq!(&bytes[12..14], b"BC");
+    }
+
+    #[test]
+    fn test_revtag_bam_to_bam_passthrough() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_input()).unwrap();
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let bam_in = tmpdir.path().join("in.bam");
+        let bam_out = tmpdir.path().join("out.bam");
+        let sam_out = tmpdir.path().join("out.sam");
+
+        revtag(
+            Some(infile.path().to_path_buf()),
+            Some(bam_in.clone()),
+            vec![],
+            vec![],
+            1,
+        )
+        .expect("revtag should produce BAM");
+        revtag(
+            Some(bam_in),
+            Some(bam_out.clone()),
+            vec!["QT".into(), "MN".into()],
+            vec!["BC".into()],
+            1,
+        )
+        .expect("revtag should transform BAM to BAM");
+        revtag(Some(bam_out), Some(sam_out.clone()), vec![], vec![], 1)
+            .expect("revtag should read the transformed BAM");
+
+        let output = std::fs::read_to_string(sam_out).unwrap();
+        let fwd = output.lines().find(|l| l.starts_with("fwd\t")).unwrap();
+        let rev = output.lines().find(|l| l.starts_with("rev\t")).unwrap();
+        assert!(fwd.contains("QT:B:C,10,20,30") && fwd.contains("MN:Z:HELLO"));
+        assert!(fwd.contains("BC:Z:ATCG"));
+        assert!(rev.contains("QT:B:C,3,2,1") && rev.contains("MN:Z:DLROW"));
+        assert!(rev.contains("BC:Z:AATC"));
+    }
+
+    #[test]
+    fn test_passthrough_bam_ends_with_eof_marker() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_input()).unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let bam_in = tmpdir.path().join("in.bam");
+        revtag(
+            Some(infile.path().to_path_buf()),
+            Some(bam_in.clone()),
+            vec![],
+            vec![],
+            1,
+        )
+        .expect("revtag should produce BAM");
+
+        let mut reader = noodles_bam::io::Reader::new(File::open(&bam_in).unwrap());
+        let header = reader.read_header().unwrap();
+        let plan = Plan::new(&["MN".to_string()], &[]).unwrap();
+        let mut output = Vec::new();
+        passthrough_bam(
+            &mut reader,
+            &header,
+            &mut output,
+            &plan,
+            &Options::default(),
+        )
+        .unwrap();
+
+        const BGZF_EOF: [u8; 28] = [
+            0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+            0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(output[..2], [0x1f, 0x8b]);
+        assert!(output.ends_with(&BGZF_EOF));
+    }
+
+    #[test]
+    fn test_run_strict_fails_on_missing_tag() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_input()).unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let bam_in = tmpdir.path().join("in.bam");
+        revtag(
+            Some(infile.path().to_path_buf()),
+            Some(bam_in.clone()),
+            vec![],
+            vec![],
+            1,
+        )
+        .expect("revtag should produce BAM");
+
+        let options = Options {
+            strict: true,
+            ..Options::default()
+        };
+        let typo = Plan::new(&["Qt".to_string()], &[]).unwrap();
+        for (input, output) in [
+            (infile.path(), tmpdir.path().join("out.sam")),
+            (bam_in.as_path(), tmpdir.path().join("out.bam")),
+        ] {
+            let error = run(Some(input), Some(output.as_path()), &typo, &options)
+                .expect_err("Qt is missing from the reverse record");
+            assert_eq!(error.to_string(), "Record rev is missing required tag Qt");
+        }
+    }
+
+    #[test]
+    fn test_run_required_tags_checks_every_record() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_input()).unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let bam_in = tmpdir.path().join("in.bam");
+        revtag(
+            Some(infile.path().to_path_buf()),
+            Some(bam_in.clone()),
+            vec![],
+            vec![],
+            1,
+        )
+        .expect("revtag should produce BAM");
+
+        let options = Options {
+            required_tags: vec![*b"MN", *b"XX"],
+            ..Options::default()
+        };
+        for (input, output) in [
+            (infile.path(), tmpdir.path().join("out.sam")),
+            (bam_in.as_path(), tmpdir.path().join("out.bam")),
+        ] {
+            let error = run(
+                Some(input),
+                Some(output.as_path()),
+                &Plan::default(),
+                &options,
+            )
+            .expect_err("no record carries XX");
+            assert_eq!(error.to_string(), "Record fwd is missing required tag XX");
+        }
+    }
+
+    #[test]
+    fn test_run_strand_from_tag() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(
+            infile,
+            "{}{}{}",
+            "@HD\tVN:1.6\tSO:unknown\n",
+            "minus\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF\tXS:A:-\tMN:Z:AB\n",
+            "plus\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF\tXS:A:+\tMN:Z:AB\n",
+        )
+        .unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let bam_in = tmpdir.path().join("in.bam");
+        revtag(
+            Some(infile.path().to_path_buf()),
+            Some(bam_in.clone()),
+            vec![],
+            vec![],
+            1,
+        )
+        .expect("revtag should produce BAM");
+
+        let plan = Plan::new(&["MN".to_string()], &[]).unwrap();
+        let options = Options {
+            strand_tag: Some(*b"XS"),
+            ..Options::default()
+        };
+        let sam_out = tmpdir.path().join("out.sam");
+        run(
+            Some(infile.path()),
+            Some(sam_out.as_path()),
+            &plan,
+            &options,
+        )
+        .expect("run should transform SAM");
+        let bam_out = tmpdir.path().join("out.bam");
+        let bam_sam_out = tmpdir.path().join("out.bam.sam");
+        run(
+            Some(bam_in.as_path()),
+            Some(bam_out.as_path()),
+            &plan,
+            &options,
+        )
+        .expect("run should transform BAM to BAM");
+        revtag(Some(bam_out), Some(bam_sam_out.clone()), vec![], vec![], 1)
+            .expect("revtag should read the transformed BAM");
+
+        for path in [sam_out, bam_sam_out] {
+            let output = std::fs::read_to_string(path).unwrap();
+            let minus = output.lines().find(|l| l.starts_with("minus\t")).unwrap();
+            let plus = output.lines().find(|l| l.starts_with("plus\t")).unwrap();
+            assert!(minus.contains("MN:Z:BA"));
+            assert!(plus.contains("MN:Z:AB"));
+        }
+    }
+
+    #[test]
+    fn test_revtag_rejects_cram_output() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_input()).unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+
+        let result = revtag(
+            Some(infile.path().to_path_buf()),
+            Some(tmpdir.path().join("out.cram")),
+            vec![],
+            vec![],
+            1,
+        );
+        assert!(result.is_err());
+    }
+}
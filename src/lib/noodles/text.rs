@@ -0,0 +1,65 @@
+//! In-memory transforms of SAM text.
+//!
+//! These need no file or stdin access, so they are usable from `wasm32` targets such as a
+//! browser-based QC tool previewing how tags on an uploaded snippet would be re-oriented.
+use noodles_sam::alignment::io::Write as AlignmentWrite;
+use std::error;
+
+use super::convert::transform_record;
+use crate::plan::Plan;
+
+/// Applies a plan to every reverse strand record in a SAM snippet.
+///
+/// The snippet must begin with a header declaring every reference sequence its records use. The
+/// header is written back unchanged, without a `@PG` record.
+///
+/// # Arguments
+///
+/// * `input` - SAM text including its header
+/// * `plan` - The tag specifications to apply to reverse strand records
+///
+/// # Returns
+///
+/// Returns the transformed SAM text, or an error if the snippet cannot be parsed.
+///
+pub fn transform_sam(input: &[u8], plan: &Plan) -> Result<Vec<u8>, Box<dyn error::Error>> {
+    let mut reader = noodles_sam::io::Reader::new(input);
+    let header = reader.read_header()?;
+
+    let mut writer = noodles_sam::io::Writer::new(Vec::new());
+    writer.write_header(&header)?;
+
+    for result in reader.records() {
+        let record = transform_record(&header, &result?, plan)?;
+        writer.write_alignment_record(&header, &record)?;
+    }
+
+    Ok(writer.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transform_sam() {
+        let input = b"@SQ\tSN:chr1\tLN:1000\n\
+fwd\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tMN:Z:HELLO\n\
+rev\t16\tchr1\t2\t60\t4M\t*\t0\t0\tACGT\tFFFF\tMN:Z:WORLD\n";
+        let plan = Plan::new(&["MN".to_string()], &[]).unwrap();
+
+        let output = String::from_utf8(transform_sam(input, &plan).unwrap()).unwrap();
+
+        assert!(output.starts_with("@SQ\tSN:chr1\tLN:1000\n"));
+        assert!(output.contains("fwd\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tMN:Z:HELLO\n"));
+        assert!(output.contains("rev\t16\tchr1\t2\t60\t4M\t*\t0\t0\tACGT\tFFFF\tMN:Z:DLROW\n"));
+    }
+
+    #[test]
+    fn test_transform_sam_rejects_unknown_reference() {
+        let input = b"@SQ\tSN:chr1\tLN:1000\n\
+rev\t16\tchr2\t2\t60\t4M\t*\t0\t0\tACGT\tFFFF\tMN:Z:WORLD\n";
+        let plan = Plan::new(&["MN".to_string()], &[]).unwrap();
+        assert!(transform_sam(input, &plan).is_err());
+    }
+}
@@ -0,0 +1,1086 @@
+//! Tag transforms applied to individual alignment records.
+use rust_htslib::bam::Record;
+use rust_htslib::bam::record::{Aux, CigarString};
+use rust_htslib::htslib::{self, bam1_t};
+use std::error;
+use std::ptr;
+use std::slice;
+
+use crate::aux::{self, Unsupported};
+use crate::condition::{RecordFields, TagValue};
+use crate::dna;
+use crate::plan::{Plan, Tag};
+use crate::quality::QualityBins;
+use crate::sanitize::{self, Repairs};
+use crate::umi::QnameUmi;
+
+/// Returns the offset of the auxiliary data within a record's data, which follows the name,
+/// CIGAR, sequence, and qualities.
+fn aux_offset(inner: &bam1_t) -> usize {
+    let core = &inner.core;
+    let l_qseq = core.l_qseq.max(0) as usize;
+    core.l_qname as usize + core.n_cigar as usize * 4 + l_qseq.div_ceil(2) + l_qseq
+}
+
+/// Returns the raw auxiliary data of a record.
+pub(crate) fn aux_data(record: &Record) -> &[u8] {
+    let inner = record.inner();
+    if inner.data.is_null() {
+        return &[];
+    }
+    // SAFETY: htslib keeps `l_data` initialized bytes at `data`, and the borrow of the record
+    // prevents mutation for the lifetime of the slice.
+    let data = unsafe { slice::from_raw_parts(inner.data, inner.l_data.max(0) as usize) };
+    &data[aux_offset(inner).min(data.len())..]
+}
+
+/// Returns the raw auxiliary data of a record for in-place edits.
+fn aux_data_mut(record: &mut Record) -> &mut [u8] {
+    let inner = record.inner_mut();
+    if inner.data.is_null() {
+        return &mut [];
+    }
+    let offset = aux_offset(inner);
+    // SAFETY: htslib keeps `l_data` initialized bytes at `data`, and the mutable borrow of the
+    // record prevents any other access for the lifetime of the slice.
+    let data = unsafe { slice::from_raw_parts_mut(inner.data, inner.l_data.max(0) as usize) };
+    &mut data[offset.min(data.len())..]
+}
+
+/// Re-encodes the tags a plan converts between `Z` strings and `B:C` byte arrays, per
+/// [`aux::convert`], replacing the record's auxiliary data with the rebuilt block.
+fn convert_aux(record: &mut Record, plan: &Plan) -> Result<(), Box<dyn error::Error>> {
+    if !plan.has_conversions() {
+        return Ok(());
+    }
+    let Some(converted) = aux::convert(aux_data(record), plan)? else {
+        return Ok(());
+    };
+    let inner = record.inner_mut();
+    let offset = aux_offset(inner).min(inner.l_data.max(0) as usize);
+    let length = offset + converted.len();
+    // SAFETY: htslib grows the record's data, keeping its first `l_data` bytes.
+    if length > inner.m_data as usize && unsafe { htslib::sam_realloc_bam_data(inner, length) } < 0
+    {
+        return Err("Failed to grow a record for its converted tags".into());
+    }
+    // SAFETY: the data now holds at least `length` bytes, and `converted` is a separate buffer.
+    unsafe {
+        ptr::copy_nonoverlapping(converted.as_ptr(), inner.data.add(offset), converted.len())
+    };
+    inner.l_data = i32::try_from(length)?;
+    Ok(())
+}
+
+/// Bins the base qualities of a record in place, leaving missing qualities unchanged.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to mutate
+/// * `bins` - The binning scheme to apply
+///
+pub fn bin_qualities(record: &mut Record, bins: &QualityBins) {
+    let inner = record.inner_mut();
+    if inner.data.is_null() {
+        return;
+    }
+    let core = &inner.core;
+    let l_qseq = core.l_qseq.max(0) as usize;
+    let offset = core.l_qname as usize + core.n_cigar as usize * 4 + l_qseq.div_ceil(2);
+    // SAFETY: htslib keeps `l_data` initialized bytes at `data`, and the mutable borrow of the
+    // record prevents any other access for the lifetime of the slice.
+    let data = unsafe { slice::from_raw_parts_mut(inner.data, inner.l_data.max(0) as usize) };
+    if let Some(qualities) = data.get_mut(offset..offset + l_qseq) {
+        bins.apply(qualities);
+    }
+}
+
+/// Repairs malformed auxiliary data of a record in place, per [`sanitize::sanitize`], shrinking
+/// the record by any bytes the repairs removed.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to mutate
+/// * `plan` - The plan whose companion pairs name further quality tags
+/// * `repairs` - Counts of each repair, updated in place
+///
+/// # Returns
+///
+/// Returns an error if the auxiliary data is malformed in a way that cannot be repaired.
+///
+pub fn sanitize_aux(
+    record: &mut Record,
+    plan: &Plan,
+    repairs: &mut Repairs,
+) -> Result<(), Box<dyn error::Error>> {
+    let offset = aux_offset(record.inner());
+    let length = sanitize::sanitize(
+        aux_data_mut(record),
+        |tag| sanitize::is_quality_tag(plan, tag),
+        repairs,
+    )?;
+    let inner = record.inner_mut();
+    if !inner.data.is_null() && offset + length < inner.l_data.max(0) as usize {
+        inner.l_data = (offset + length) as i32;
+    }
+    Ok(())
+}
+
+/// Returns true if a record carries any tag named in the plan.
+///
+/// This scans the record's raw auxiliary data once, which is cheaper than looking up each
+/// planned tag when most records carry none of them.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to inspect
+/// * `plan` - The tag specifications to look for
+///
+/// # Returns
+///
+/// Returns whether any planned tag is present, or an error if the auxiliary data is malformed.
+///
+pub fn has_planned_tags(record: &Record, plan: &Plan) -> Result<bool, Box<dyn error::Error>> {
+    if plan.is_empty() {
+        return Ok(false);
+    }
+    aux::any_tag(aux_data(record), |tag| plan.contains(tag))
+}
+
+/// Returns true if a strand tag marks a record as reverse strand, regardless of its flag.
+///
+/// See [`aux::minus_strand`] for the values recognized.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to inspect
+/// * `tag` - The SAM tag holding the strand
+///
+/// # Returns
+///
+/// Returns whether the record is reverse strand, or an error if the auxiliary data is malformed.
+///
+pub fn minus_strand(record: &Record, tag: &Tag) -> Result<bool, Box<dyn error::Error>> {
+    aux::minus_strand(aux_data(record), tag)
+}
+
+/// Returns true if a record's `MI` tag marks it as the B strand of a duplex molecule.
+///
+/// See [`aux::duplex_b_strand`] for the values recognized.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to inspect
+///
+/// # Returns
+///
+/// Returns whether the record is from the B strand, or an error if the auxiliary data is
+/// malformed.
+///
+pub fn duplex_b_strand(record: &Record) -> Result<bool, Box<dyn error::Error>> {
+    aux::duplex_b_strand(aux_data(record))
+}
+
+/// Returns the first of `tags` that a record does not carry.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to inspect
+/// * `tags` - The SAM tags that must be present
+///
+/// # Returns
+///
+/// Returns the first missing tag, None if every tag is present, or an error if the auxiliary
+/// data is malformed.
+///
+pub fn missing_tag<'a, I>(record: &Record, tags: I) -> Result<Option<Tag>, Box<dyn error::Error>>
+where
+    I: IntoIterator<Item = &'a Tag>,
+{
+    aux::missing_tag(aux_data(record), tags)
+}
+
+/// Reverses the order of an array-like value stored under `tag`.
+///
+/// Numeric `B` arrays of every element type and `Z` strings are supported. Values are reversed
+/// in place within the record's auxiliary data, and strings byte-wise since SAM restricts them
+/// to ASCII. Missing tags and tags of any other type are left untouched.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to mutate
+/// * `tag` - The SAM tag to reverse
+///
+/// # Returns
+///
+/// Returns Ok(()) on success, or an error if tag manipulation fails or the auxiliary data is
+/// malformed.
+///
+pub fn reverse_tag(record: &mut Record, tag: &Tag) -> Result<(), Box<dyn error::Error>> {
+    aux::reverse(aux_data_mut(record), tag)?;
+    Ok(())
+}
+
+/// Reverse complements a DNA sequence stored under `tag`.
+///
+/// Both `Z` strings and `B:C` byte arrays are supported. Values are reverse complemented in
+/// place within the record's auxiliary data, without allocating. Missing tags and tags of any
+/// other type are left untouched.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to mutate
+/// * `tag` - The SAM tag to reverse complement
+///
+/// # Returns
+///
+/// Returns Ok(()) on success, or an error if tag manipulation fails or a string holds a
+/// non-ASCII byte.
+///
+pub fn reverse_complement_tag(record: &mut Record, tag: &Tag) -> Result<(), Box<dyn error::Error>> {
+    aux::reverse_complement(aux_data_mut(record), tag)?;
+    Ok(())
+}
+
+/// Mutates a record by reversing and/or reverse complementing specified tags.
+///
+/// This function modifies the record in-place by:
+/// - Reversing the order of array-like values in specified `rev` tags
+/// - Reverse complementing array-like string values in specified `revcomp` tags
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to mutate
+/// * `rev` - SAM tags to reverse (e.g., base qualities) as 2-byte arrays
+/// * `revcomp` - SAM tags to reverse complement (e.g., sequences) as 2-byte arrays
+///
+/// # Returns
+///
+/// Returns Ok(()) on success, or an error if tag manipulation fails.
+///
+pub fn reverse_tags_for(
+    record: &mut Record,
+    rev: &[Tag],
+    revcomp: &[Tag],
+) -> Result<(), Box<dyn error::Error>> {
+    for tag in rev {
+        reverse_tag(record, tag)?;
+    }
+    for tag in revcomp {
+        reverse_complement_tag(record, tag)?;
+    }
+    Ok(())
+}
+
+/// Mutates a record by applying every tag specification in a plan.
+///
+/// The record's auxiliary data is walked once, transforming each planned tag in place as it is
+/// reached, so the cost does not grow with the number of planned tags. The record strand is not
+/// consulted; callers decide which records the plan applies to.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to mutate
+/// * `plan` - The tag specifications to apply
+///
+/// # Returns
+///
+/// Returns Ok(()) on success, or an error if tag manipulation fails.
+///
+pub fn apply(record: &mut Record, plan: &Plan) -> Result<(), Box<dyn error::Error>> {
+    if !plan.is_empty() {
+        convert_aux(record, plan)?;
+        aux::apply(aux_data_mut(record), plan)?;
+    }
+    Ok(())
+}
+
+/// Mutates a record by applying a plan as [`apply`] does, counting planned tags whose type does
+/// not support their operation.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to mutate
+/// * `plan` - The tag specifications to apply
+/// * `unsupported` - Counts of planned tags left untouched, updated in place
+///
+/// # Returns
+///
+/// Returns Ok(()) on success, or an error if tag manipulation fails.
+///
+pub fn apply_tracked(
+    record: &mut Record,
+    plan: &Plan,
+    unsupported: &mut Unsupported,
+) -> Result<(), Box<dyn error::Error>> {
+    if !plan.is_empty() {
+        convert_aux(record, plan)?;
+        aux::apply_tracked(aux_data_mut(record), plan, unsupported)?;
+    }
+    Ok(())
+}
+
+/// Re-expresses a reverse strand record in its original sequencing orientation.
+///
+/// SEQ is reverse complemented, QUAL and the CIGAR are reversed, and the reverse strand flag
+/// (0x10) is cleared. Auxiliary data, including tags such as MD that describe the alignment,
+/// are left as they are. Records not on the reverse strand are left untouched.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to mutate
+///
+/// # Returns
+///
+/// Returns Ok(()) on success, or an error if the record cannot be rewritten.
+///
+pub fn restore_orientation(record: &mut Record) -> Result<(), Box<dyn error::Error>> {
+    if !record.is_reverse() {
+        return Ok(());
+    }
+    let qname = record.qname().to_vec();
+    let cigar = CigarString(record.cigar().iter().rev().cloned().collect());
+    let mut seq = record.seq().as_bytes();
+    dna::revcomp_in_place(&mut seq);
+    let mut qual = record.qual().to_vec();
+    qual.reverse();
+
+    record.set(&qname, Some(&cigar), &seq, &qual);
+    record.unset_reverse();
+    Ok(())
+}
+
+/// Tags describing a record's alignment, removed when it is reverted to an unaligned record, as
+/// Picard's `RevertSam` does by default.
+pub const ALIGNMENT_TAGS: [Tag; 8] = [
+    *b"NM", *b"UQ", *b"PG", *b"MD", *b"MQ", *b"SA", *b"MC", *b"AS",
+];
+
+/// The BAM bin of a record without a position.
+const UNPLACED_BIN: u16 = 4680;
+
+/// Reverts a record to an unaligned record suitable for re-alignment, as Picard's `RevertSam`
+/// does.
+///
+/// A reverse strand record is first re-expressed in its sequencing orientation, per
+/// [`restore_orientation`]. The reference, position, mapping quality, CIGAR, mate reference,
+/// mate position, and template length are then cleared, [`ALIGNMENT_TAGS`] are removed, and
+/// the flags keep only the paired (0x1), first (0x40), second (0x80), and QC-fail (0x200) bits,
+/// marking the record, and its mate if paired, unmapped. Other tags are left as they are.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to mutate
+///
+/// # Returns
+///
+/// Returns Ok(()) on success, or an error if the record cannot be rewritten.
+///
+pub fn revert_alignment(record: &mut Record) -> Result<(), Box<dyn error::Error>> {
+    restore_orientation(record)?;
+    let qname = record.qname().to_vec();
+    let seq = record.seq().as_bytes();
+    let qual = record.qual().to_vec();
+    record.set(&qname, None, &seq, &qual);
+    record.set_tid(-1);
+    record.set_pos(-1);
+    record.set_bin(UNPLACED_BIN);
+    record.set_mapq(0);
+    record.set_mtid(-1);
+    record.set_mpos(-1);
+    record.set_insert_size(0);
+
+    let mut flags = record.flags() & (0x1 | 0x40 | 0x80 | 0x200);
+    flags |= 0x4;
+    if flags & 0x1 != 0 {
+        flags |= 0x8;
+    }
+    record.set_flags(flags);
+
+    for tag in &ALIGNMENT_TAGS {
+        if record.aux(tag).is_ok() {
+            record.remove_aux(tag)?;
+        }
+    }
+    Ok(())
+}
+
+/// Stamps a record with the orientation it was transformed as, replacing any earlier value of
+/// the tag, so downstream tools can tell which strand rule applied without re-deriving it.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to mutate
+/// * `tag` - The tag to set, e.g. `os`
+/// * `reverse` - Whether the record was treated as reverse strand, stored as `-`, else `+`
+///
+/// # Returns
+///
+/// Returns Ok(()) on success, or an error if the tag cannot be written.
+///
+pub fn annotate_strand(
+    record: &mut Record,
+    tag: &Tag,
+    reverse: bool,
+) -> Result<(), Box<dyn error::Error>> {
+    if record.aux(tag).is_ok() {
+        record.remove_aux(tag)?;
+    }
+    record.push_aux(tag, Aux::Char(if reverse { b'-' } else { b'+' }))?;
+    Ok(())
+}
+
+/// Reverse complements the UMI carried in a record's name, leaving names without one as they
+/// are.
+///
+/// # Arguments
+///
+/// * `record` - The BAM record to mutate
+/// * `umi` - The pattern locating the UMI in the name
+///
+pub fn reverse_complement_qname_umi(record: &mut Record, umi: &QnameUmi) {
+    if let Some(qname) = umi.reverse_complement(record.qname()) {
+        record.set_qname(&qname);
+    }
+}
+
+impl RecordFields for Record {
+    fn flag(&self) -> u16 {
+        self.flags()
+    }
+
+    fn mapq(&self) -> u8 {
+        Record::mapq(self)
+    }
+
+    fn pos(&self) -> i64 {
+        Record::pos(self).max(-1) + 1
+    }
+
+    fn tlen(&self) -> i64 {
+        self.insert_size()
+    }
+
+    fn seq_len(&self) -> usize {
+        Record::seq_len(self)
+    }
+
+    fn tag(&self, tag: &Tag) -> Result<Option<TagValue>, Box<dyn error::Error>> {
+        let data = aux_data(self);
+        Ok(aux::find(data, tag)?.map(|field| TagValue::from_field(data, &field)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Aux, Cigar};
+
+    /// Helper to create a minimal BAM record for testing
+    fn create_test_record() -> Record {
+        let mut record = Record::new();
+        record.set_qname(b"test_read");
+        record.set_pos(100);
+        record.set_mapq(60);
+        record.set_tid(0);
+        record
+    }
+
+    /// Helper to convert tag strings to byte arrays for testing
+    fn tags_to_bytes(tags: &[&str]) -> Vec<[u8; 2]> {
+        tags.iter()
+            .filter_map(|s| {
+                if s.len() >= 2 {
+                    let b = s.as_bytes();
+                    Some([b[0], b[1]])
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reverse_u8_array() {
+        let mut record = create_test_record();
+        let values = vec![10u8, 20, 30, 40, 50];
+        record
+            .push_aux(b"QT", Aux::ArrayU8((&values[..]).into()))
+            .unwrap();
+
+        reverse_tags_for(&mut record, &tags_to_bytes(&["QT"]), &[]).unwrap();
+
+        if let Ok(Aux::ArrayU8(arr)) = record.aux(b"QT") {
+            let result: Vec<u8> = arr.iter().collect();
+            assert_eq!(result, vec![50, 40, 30, 20, 10]);
+        } else {
+            panic!("Expected ArrayU8");
+        }
+    }
+
+    #[test]
+    fn test_reverse_u16_array() {
+        let mut record = create_test_record();
+        let values = vec![100u16, 200, 300];
+        record
+            .push_aux(b"AB", Aux::ArrayU16((&values[..]).into()))
+            .unwrap();
+
+        reverse_tags_for(&mut record, &tags_to_bytes(&["AB"]), &[]).unwrap();
+
+        if let Ok(Aux::ArrayU16(arr)) = record.aux(b"AB") {
+            let result: Vec<u16> = arr.iter().collect();
+            assert_eq!(result, vec![300, 200, 100]);
+        } else {
+            panic!("Expected ArrayU16");
+        }
+    }
+
+    #[test]
+    fn test_reverse_u32_array() {
+        let mut record = create_test_record();
+        let values = vec![1000u32, 2000, 3000];
+        record
+            .push_aux(b"CD", Aux::ArrayU32((&values[..]).into()))
+            .unwrap();
+
+        reverse_tags_for(&mut record, &tags_to_bytes(&["CD"]), &[]).unwrap();
+
+        if let Ok(Aux::ArrayU32(arr)) = record.aux(b"CD") {
+            let result: Vec<u32> = arr.iter().collect();
+            assert_eq!(result, vec![3000, 2000, 1000]);
+        } else {
+            panic!("Expected ArrayU32");
+        }
+    }
+
+    #[test]
+    fn test_reverse_i8_array() {
+        let mut record = create_test_record();
+        let values = vec![-10i8, -5, 0, 5, 10];
+        record
+            .push_aux(b"EF", Aux::ArrayI8((&values[..]).into()))
+            .unwrap();
+
+        reverse_tags_for(&mut record, &tags_to_bytes(&["EF"]), &[]).unwrap();
+
+        if let Ok(Aux::ArrayI8(arr)) = record.aux(b"EF") {
+            let result: Vec<i8> = arr.iter().collect();
+            assert_eq!(result, vec![10, 5, 0, -5, -10]);
+        } else {
+            panic!("Expected ArrayI8");
+        }
+    }
+
+    #[test]
+    fn test_reverse_i16_array() {
+        let mut record = create_test_record();
+        let values = vec![-100i16, 0, 100];
+        record
+            .push_aux(b"GH", Aux::ArrayI16((&values[..]).into()))
+            .unwrap();
+
+        reverse_tags_for(&mut record, &tags_to_bytes(&["GH"]), &[]).unwrap();
+
+        if let Ok(Aux::ArrayI16(arr)) = record.aux(b"GH") {
+            let result: Vec<i16> = arr.iter().collect();
+            assert_eq!(result, vec![100, 0, -100]);
+        } else {
+            panic!("Expected ArrayI16");
+        }
+    }
+
+    #[test]
+    fn test_reverse_i32_array() {
+        let mut record = create_test_record();
+        let values = vec![-1000i32, 0, 1000];
+        record
+            .push_aux(b"IJ", Aux::ArrayI32((&values[..]).into()))
+            .unwrap();
+
+        reverse_tags_for(&mut record, &tags_to_bytes(&["IJ"]), &[]).unwrap();
+
+        if let Ok(Aux::ArrayI32(arr)) = record.aux(b"IJ") {
+            let result: Vec<i32> = arr.iter().collect();
+            assert_eq!(result, vec![1000, 0, -1000]);
+        } else {
+            panic!("Expected ArrayI32");
+        }
+    }
+
+    #[test]
+    fn test_reverse_float_array() {
+        let mut record = create_test_record();
+        let values = vec![1.5f32, 2.5, 3.5];
+        record
+            .push_aux(b"KL", Aux::ArrayFloat((&values[..]).into()))
+            .unwrap();
+
+        reverse_tags_for(&mut record, &tags_to_bytes(&["KL"]), &[]).unwrap();
+
+        if let Ok(Aux::ArrayFloat(arr)) = record.aux(b"KL") {
+            let result: Vec<f32> = arr.iter().collect();
+            assert_eq!(result, vec![3.5, 2.5, 1.5]);
+        } else {
+            panic!("Expected ArrayFloat");
+        }
+    }
+
+    #[test]
+    fn test_reverse_string() {
+        let mut record = create_test_record();
+        record.push_aux(b"MN", Aux::String("HELLO")).unwrap();
+
+        reverse_tags_for(&mut record, &tags_to_bytes(&["MN"]), &[]).unwrap();
+
+        if let Ok(Aux::String(s)) = record.aux(b"MN") {
+            assert_eq!(s, "OLLEH");
+        } else {
+            panic!("Expected String");
+        }
+    }
+
+    #[test]
+    fn test_revcomp_string_uppercase() {
+        let mut record = create_test_record();
+        record.push_aux(b"BC", Aux::String("ATCG")).unwrap();
+
+        reverse_tags_for(&mut record, &[], &tags_to_bytes(&["BC"])).unwrap();
+
+        if let Ok(Aux::String(s)) = record.aux(b"BC") {
+            assert_eq!(s, "CGAT");
+        } else {
+            panic!("Expected String");
+        }
+    }
+
+    #[test]
+    fn test_revcomp_string_lowercase() {
+        let mut record = create_test_record();
+        record.push_aux(b"BC", Aux::String("atcg")).unwrap();
+
+        reverse_tags_for(&mut record, &[], &tags_to_bytes(&["BC"])).unwrap();
+
+        if let Ok(Aux::String(s)) = record.aux(b"BC") {
+            assert_eq!(s, "cgat");
+        } else {
+            panic!("Expected String");
+        }
+    }
+
+    #[test]
+    fn test_revcomp_string_mixed_case() {
+        let mut record = create_test_record();
+        record.push_aux(b"BC", Aux::String("AtCg")).unwrap();
+
+        reverse_tags_for(&mut record, &[], &tags_to_bytes(&["BC"])).unwrap();
+
+        if let Ok(Aux::String(s)) = record.aux(b"BC") {
+            assert_eq!(s, "cGaT");
+        } else {
+            panic!("Expected String");
+        }
+    }
+
+    #[test]
+    fn test_revcomp_string_palindrome() {
+        let mut record = create_test_record();
+        record.push_aux(b"BC", Aux::String("TCGA")).unwrap();
+
+        reverse_tags_for(&mut record, &[], &tags_to_bytes(&["BC"])).unwrap();
+
+        if let Ok(Aux::String(s)) = record.aux(b"BC") {
+            assert_eq!(s, "TCGA");
+        } else {
+            panic!("Expected String");
+        }
+    }
+
+    #[test]
+    fn test_revcomp_array_u8() {
+        let mut record = create_test_record();
+        let seq = b"ATCG";
+        let values: Vec<u8> = seq.to_vec();
+        record
+            .push_aux(b"BC", Aux::ArrayU8((&values[..]).into()))
+            .unwrap();
+
+        reverse_tags_for(&mut record, &[], &tags_to_bytes(&["BC"])).unwrap();
+
+        if let Ok(Aux::ArrayU8(arr)) = record.aux(b"BC") {
+            let result: Vec<u8> = arr.iter().collect();
+            assert_eq!(result, b"CGAT".to_vec());
+        } else {
+            panic!("Expected ArrayU8");
+        }
+    }
+
+    #[test]
+    fn test_revcomp_longer_sequence() {
+        let mut record = create_test_record();
+        record.push_aux(b"BC", Aux::String("ATCGATCGATCG")).unwrap();
+
+        reverse_tags_for(&mut record, &[], &tags_to_bytes(&["BC"])).unwrap();
+
+        if let Ok(Aux::String(s)) = record.aux(b"BC") {
+            assert_eq!(s, "CGATCGATCGAT");
+        } else {
+            panic!("Expected String");
+        }
+    }
+
+    #[test]
+    fn test_multiple_rev_tags() {
+        let mut record = create_test_record();
+        record
+            .push_aux(b"QT", Aux::ArrayU8((&[10u8, 20, 30][..]).into()))
+            .unwrap();
+        record
+            .push_aux(b"AB", Aux::ArrayU8((&[1u8, 2, 3][..]).into()))
+            .unwrap();
+
+        reverse_tags_for(&mut record, &tags_to_bytes(&["QT", "AB"]), &[]).unwrap();
+
+        if let Ok(Aux::ArrayU8(arr)) = record.aux(b"QT") {
+            let result: Vec<u8> = arr.iter().collect();
+            assert_eq!(result, vec![30, 20, 10]);
+        } else {
+            panic!("Expected ArrayU8 for QT");
+        }
+
+        if let Ok(Aux::ArrayU8(arr)) = record.aux(b"AB") {
+            let result: Vec<u8> = arr.iter().collect();
+            assert_eq!(result, vec![3, 2, 1]);
+        } else {
+            panic!("Expected ArrayU8 for AB");
+        }
+    }
+
+    #[test]
+    fn test_multiple_revcomp_tags() {
+        let mut record = create_test_record();
+        record.push_aux(b"BC", Aux::String("ATCG")).unwrap();
+        record.push_aux(b"XY", Aux::String("GGCC")).unwrap();
+
+        reverse_tags_for(&mut record, &[], &tags_to_bytes(&["BC", "XY"])).unwrap();
+
+        if let Ok(Aux::String(s)) = record.aux(b"BC") {
+            assert_eq!(s, "CGAT");
+        } else {
+            panic!("Expected String for BC");
+        }
+
+        if let Ok(Aux::String(s)) = record.aux(b"XY") {
+            assert_eq!(s, "GGCC");
+        } else {
+            panic!("Expected String for XY");
+        }
+    }
+
+    #[test]
+    fn test_both_rev_and_revcomp() {
+        let mut record = create_test_record();
+        record
+            .push_aux(b"QT", Aux::ArrayU8((&[10u8, 20, 30][..]).into()))
+            .unwrap();
+        record.push_aux(b"BC", Aux::String("ATCG")).unwrap();
+
+        reverse_tags_for(
+            &mut record,
+            &tags_to_bytes(&["QT"]),
+            &tags_to_bytes(&["BC"]),
+        )
+        .unwrap();
+
+        if let Ok(Aux::ArrayU8(arr)) = record.aux(b"QT") {
+            let result: Vec<u8> = arr.iter().collect();
+            assert_eq!(result, vec![30, 20, 10]);
+        } else {
+            panic!("Expected ArrayU8 for QT");
+        }
+
+        if let Ok(Aux::String(s)) = record.aux(b"BC") {
+            assert_eq!(s, "CGAT");
+        } else {
+            panic!("Expected String for BC");
+        }
+    }
+
+    #[test]
+    fn test_nonexistent_tag() {
+        let mut record = create_test_record();
+        let result = reverse_tags_for(&mut record, &tags_to_bytes(&["ZZ"]), &[]);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_empty_arrays() {
+        let mut record = create_test_record();
+        record
+            .push_aux(b"QT", Aux::ArrayU8((&[][..]).into()))
+            .unwrap();
+
+        reverse_tags_for(&mut record, &tags_to_bytes(&["QT"]), &[]).unwrap();
+
+        if let Ok(Aux::ArrayU8(arr)) = record.aux(b"QT") {
+            let result: Vec<u8> = arr.iter().collect();
+            assert_eq!(result, Vec::<u8>::new());
+        } else {
+            panic!("Expected ArrayU8");
+        }
+    }
+
+    #[test]
+    fn test_single_element_array() {
+        let mut record = create_test_record();
+        record
+            .push_aux(b"QT", Aux::ArrayU8((&[42u8][..]).into()))
+            .unwrap();
+
+        reverse_tags_for(&mut record, &tags_to_bytes(&["QT"]), &[]).unwrap();
+
+        if let Ok(Aux::ArrayU8(arr)) = record.aux(b"QT") {
+            let result: Vec<u8> = arr.iter().collect();
+            assert_eq!(result, vec![42]);
+        } else {
+            panic!("Expected ArrayU8");
+        }
+    }
+
+    #[test]
+    fn test_empty_string() {
+        let mut record = create_test_record();
+        record.push_aux(b"BC", Aux::String("")).unwrap();
+
+        reverse_tags_for(&mut record, &[], &tags_to_bytes(&["BC"])).unwrap();
+
+        if let Ok(Aux::String(s)) = record.aux(b"BC") {
+            assert_eq!(s, "");
+        } else {
+            panic!("Expected String");
+        }
+    }
+
+    #[test]
+    fn test_single_nucleotide() {
+        let mut record = create_test_record();
+        record.push_aux(b"BC", Aux::String("A")).unwrap();
+
+        reverse_tags_for(&mut record, &[], &tags_to_bytes(&["BC"])).unwrap();
+
+        if let Ok(Aux::String(s)) = record.aux(b"BC") {
+            assert_eq!(s, "T");
+        } else {
+            panic!("Expected String");
+        }
+    }
+
+    #[test]
+    fn test_reverse_array_keeps_neighbouring_tags() {
+        let mut record = create_test_record();
+        record.set(b"read", None, b"ACGT", &[30, 31, 32, 33]);
+        record.push_aux(b"NM", Aux::U8(3)).unwrap();
+        record
+            .push_aux(b"QT", Aux::ArrayI16((&[-1i16, 2, -300][..]).into()))
+            .unwrap();
+        record.push_aux(b"MN", Aux::String("HELLO")).unwrap();
+
+        reverse_tags_for(&mut record, &tags_to_bytes(&["QT"]), &[]).unwrap();
+
+        if let Ok(Aux::ArrayI16(arr)) = record.aux(b"QT") {
+            let result: Vec<i16> = arr.iter().collect();
+            assert_eq!(result, vec![-300, 2, -1]);
+        } else {
+            panic!("Expected ArrayI16");
+        }
+        assert!(matches!(record.aux(b"NM"), Ok(Aux::U8(3))));
+        assert!(matches!(record.aux(b"MN"), Ok(Aux::String("HELLO"))));
+        assert_eq!(record.seq().as_bytes(), b"ACGT");
+        assert_eq!(record.qual(), &[30, 31, 32, 33]);
+    }
+
+    #[test]
+    fn test_has_planned_tags() {
+        let plan = Plan::new(&["QT".to_string()], &["BC".to_string()]).unwrap();
+        let mut record = create_test_record();
+        record.push_aux(b"XY", Aux::String("QTBC")).unwrap();
+        assert!(!has_planned_tags(&record, &plan).unwrap());
+
+        record.push_aux(b"BC", Aux::String("ACGT")).unwrap();
+        assert!(has_planned_tags(&record, &plan).unwrap());
+        assert!(!has_planned_tags(&record, &Plan::default()).unwrap());
+    }
+
+    #[test]
+    fn test_revcomp_rejects_non_ascii_string() {
+        let mut record = create_test_record();
+        record.push_aux(b"BC", Aux::String("ACé")).unwrap();
+
+        let err = reverse_complement_tag(&mut record, b"BC").unwrap_err();
+        assert!(
+            err.to_string().contains("non-ASCII"),
+            "unexpected error: {err}"
+        );
+        assert!(matches!(record.aux(b"BC"), Ok(Aux::String("ACé"))));
+    }
+
+    #[test]
+    fn test_record_fields() {
+        let mut record = create_test_record();
+        record.set_pos(99);
+        record.set_mapq(30);
+        record.push_aux(b"NM", Aux::U8(2)).unwrap();
+        let fields: &dyn RecordFields = &record;
+        assert_eq!(fields.pos(), 100);
+        assert_eq!(fields.mapq(), 30);
+        assert_eq!(fields.tag(b"NM").unwrap(), Some(TagValue::Int(2)));
+        assert_eq!(fields.tag(b"XX").unwrap(), None);
+    }
+
+    #[test]
+    fn test_restore_orientation() {
+        let mut record = create_test_record();
+        let cigar = CigarString(vec![Cigar::SoftClip(1), Cigar::Match(3), Cigar::Ins(1)]);
+        record.set(b"read", Some(&cigar), b"AACGT", &[10, 20, 30, 40, 50]);
+        record.set_reverse();
+        record.push_aux(b"BC", Aux::String("ACGG")).unwrap();
+
+        restore_orientation(&mut record).unwrap();
+
+        assert!(!record.is_reverse());
+        assert_eq!(record.qname(), b"read");
+        assert_eq!(record.seq().as_bytes(), b"ACGTT");
+        assert_eq!(record.qual(), &[50, 40, 30, 20, 10]);
+        assert_eq!(record.cigar().to_string(), "1I3M1S");
+        assert!(matches!(record.aux(b"BC"), Ok(Aux::String("ACGG"))));
+    }
+
+    #[test]
+    fn test_restore_orientation_ignores_forward_records() {
+        let mut record = create_test_record();
+        record.set(b"read", None, b"AACG", &[10, 20, 30, 40]);
+
+        restore_orientation(&mut record).unwrap();
+
+        assert_eq!(record.seq().as_bytes(), b"AACG");
+        assert_eq!(record.qual(), &[10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn test_revert_alignment() {
+        let mut record = create_test_record();
+        let cigar = CigarString(vec![Cigar::SoftClip(1), Cigar::Match(4)]);
+        record.set(b"read", Some(&cigar), b"AACGT", &[10, 20, 30, 40, 50]);
+        record.set_tid(0);
+        record.set_pos(100);
+        record.set_mapq(60);
+        record.set_mtid(0);
+        record.set_mpos(300);
+        record.set_insert_size(-204);
+        record.set_flags(0x1 | 0x2 | 0x10 | 0x80 | 0x400);
+        record.push_aux(b"NM", Aux::U8(1)).unwrap();
+        record.push_aux(b"BC", Aux::String("ACGG")).unwrap();
+        record.push_aux(b"MC", Aux::String("5M")).unwrap();
+
+        revert_alignment(&mut record).unwrap();
+
+        assert_eq!(record.flags(), 0x1 | 0x4 | 0x8 | 0x80);
+        assert_eq!((record.tid(), record.pos(), record.mapq()), (-1, -1, 0));
+        assert_eq!(
+            (record.mtid(), record.mpos(), record.insert_size()),
+            (-1, -1, 0)
+        );
+        assert_eq!(record.cigar().len(), 0);
+        assert_eq!(record.seq().as_bytes(), b"ACGTT");
+        assert_eq!(record.qual(), &[50, 40, 30, 20, 10]);
+        assert!(record.aux(b"NM").is_err());
+        assert!(record.aux(b"MC").is_err());
+        assert!(matches!(record.aux(b"BC"), Ok(Aux::String("ACGG"))));
+    }
+
+    #[test]
+    fn test_bin_qualities() {
+        let mut record = create_test_record();
+        let cigar = CigarString(vec![Cigar::Match(5)]);
+        record.set(b"read", Some(&cigar), b"AACGT", &[5, 12, 21, 33, 41]);
+        record.push_aux(b"BC", Aux::String("ACGG")).unwrap();
+
+        bin_qualities(&mut record, &QualityBins::parse("illumina").unwrap());
+
+        assert_eq!(record.qual(), &[6, 15, 22, 33, 40]);
+        assert_eq!(record.seq().as_bytes(), b"AACGT");
+        assert!(matches!(record.aux(b"BC"), Ok(Aux::String("ACGG"))));
+    }
+
+    #[test]
+    fn test_annotate_strand() {
+        let mut record = create_test_record();
+        annotate_strand(&mut record, b"os", true).unwrap();
+        assert!(matches!(record.aux(b"os"), Ok(Aux::Char(b'-'))));
+
+        annotate_strand(&mut record, b"os", false).unwrap();
+        assert!(matches!(record.aux(b"os"), Ok(Aux::Char(b'+'))));
+        assert_eq!(aux_data(&record), b"osA+");
+    }
+
+    #[test]
+    fn test_reverse_complement_qname_umi() {
+        let mut record = create_test_record();
+        record.set_qname(b"read1_AAACCG");
+        record.push_aux(b"RX", Aux::String("AAACCG")).unwrap();
+        reverse_complement_qname_umi(&mut record, &QnameUmi::default());
+        assert_eq!(record.qname(), b"read1_CGGTTT");
+        assert!(matches!(record.aux(b"RX"), Ok(Aux::String("AAACCG"))));
+
+        record.set_qname(b"read1");
+        reverse_complement_qname_umi(&mut record, &QnameUmi::default());
+        assert_eq!(record.qname(), b"read1");
+    }
+
+    #[test]
+    fn test_apply_plan() {
+        let mut record = create_test_record();
+        record
+            .push_aux(b"QT", Aux::ArrayU8((&[10u8, 20, 30][..]).into()))
+            .unwrap();
+        record.push_aux(b"BC", Aux::String("ATCG")).unwrap();
+
+        let plan = Plan::new(&["QT".to_string()], &["BC".to_string()]).unwrap();
+        apply(&mut record, &plan).unwrap();
+
+        if let Ok(Aux::ArrayU8(arr)) = record.aux(b"QT") {
+            let result: Vec<u8> = arr.iter().collect();
+            assert_eq!(result, vec![30, 20, 10]);
+        } else {
+            panic!("Expected ArrayU8 for QT");
+        }
+
+        if let Ok(Aux::String(s)) = record.aux(b"BC") {
+            assert_eq!(s, "CGAT");
+        } else {
+            panic!("Expected String for BC");
+        }
+    }
+
+    #[test]
+    fn test_apply_conversion() {
+        let mut record = create_test_record();
+        record.push_aux(b"BC", Aux::String("AACG")).unwrap();
+        record.push_aux(b"NM", Aux::U8(3)).unwrap();
+
+        let plan = Plan::parse(&[], &[], &["BC:revcomp,as=B:C".to_string()]).unwrap();
+        apply(&mut record, &plan).unwrap();
+        if let Ok(Aux::ArrayU8(arr)) = record.aux(b"BC") {
+            let result: Vec<u8> = arr.iter().collect();
+            assert_eq!(result, b"CGTT".to_vec());
+        } else {
+            panic!("Expected ArrayU8 for BC");
+        }
+        assert_eq!(record.aux(b"NM").unwrap(), Aux::U8(3));
+
+        let plan = Plan::parse(&[], &[], &["BC:as=Z".to_string()]).unwrap();
+        apply(&mut record, &plan).unwrap();
+        assert_eq!(record.aux(b"BC").unwrap(), Aux::String("CGTT"));
+        assert_eq!(record.aux(b"NM").unwrap(), Aux::U8(3));
+    }
+}
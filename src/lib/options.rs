@@ -0,0 +1,1279 @@
+//! Options controlling a run beyond its input, output, and plan.
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use std::error;
+
+use crate::condition::{Condition, RecordFields, TagValue};
+use crate::plan::{Operation, ParseError, ParseErrorKind, Tag, parse_tag};
+use crate::quality::QualityBins;
+use crate::sort::SortOrder;
+use crate::tagfilter::{TagFilter, passes_all};
+use crate::umi::QnameUmi;
+
+/// An alignment output format, chosen explicitly rather than inferred from the output's name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Plain-text SAM.
+    Sam,
+    /// BGZF-compressed BAM.
+    Bam,
+    /// CRAM.
+    Cram,
+}
+
+impl OutputFormat {
+    /// Parses a format name as accepted by `--output-format`: `sam`, `bam`, or `cram`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sam" => Some(OutputFormat::Sam),
+            "bam" => Some(OutputFormat::Bam),
+            "cram" => Some(OutputFormat::Cram),
+            _ => None,
+        }
+    }
+
+    /// Returns the format implied by a path's extension: BAM for `.bam`, CRAM for `.cram`, and
+    /// SAM otherwise.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("bam") => OutputFormat::Bam,
+            Some("cram") => OutputFormat::Cram,
+            _ => OutputFormat::Sam,
+        }
+    }
+
+    /// Returns the name of the format as accepted by `--output-format`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Sam => "sam",
+            OutputFormat::Bam => "bam",
+            OutputFormat::Cram => "cram",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s)
+            .ok_or_else(|| format!("Unknown format `{s}`; expected one of sam, bam, or cram"))
+    }
+}
+
+/// Which read of a pair the plan applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadOfPair {
+    /// The first read of a pair, flagged 0x40.
+    First,
+    /// The second read of a pair, flagged 0x80.
+    Second,
+}
+
+impl ReadOfPair {
+    /// Parses a read number as accepted by `--read`: `1` or `2`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "1" => Some(ReadOfPair::First),
+            "2" => Some(ReadOfPair::Second),
+            _ => None,
+        }
+    }
+
+    /// Returns the read number as accepted by `--read`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReadOfPair::First => "1",
+            ReadOfPair::Second => "2",
+        }
+    }
+
+    /// Returns the flag bit marking this read of a pair.
+    pub fn flag(self) -> u16 {
+        match self {
+            ReadOfPair::First => 0x40,
+            ReadOfPair::Second => 0x80,
+        }
+    }
+}
+
+impl FromStr for ReadOfPair {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s).ok_or_else(|| format!("Unknown read `{s}`; expected 1 or 2"))
+    }
+}
+
+/// What to do with records flagged both unmapped (0x4) and reverse strand (0x10), whose strand
+/// is meaningless but which some tools, e.g. quirks of `samtools fixmate`, produce.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnmappedPolicy {
+    /// Transform them like any other reverse strand record.
+    #[default]
+    Transform,
+    /// Pass them through untouched.
+    Skip,
+    /// Fail with an error.
+    Error,
+}
+
+impl UnmappedPolicy {
+    /// Parses a policy name as accepted by `--unmapped-policy`: `transform`, `skip`, or `error`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "transform" => Some(UnmappedPolicy::Transform),
+            "skip" => Some(UnmappedPolicy::Skip),
+            "error" => Some(UnmappedPolicy::Error),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the policy as accepted by `--unmapped-policy`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            UnmappedPolicy::Transform => "transform",
+            UnmappedPolicy::Skip => "skip",
+            UnmappedPolicy::Error => "error",
+        }
+    }
+}
+
+impl FromStr for UnmappedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s).ok_or_else(|| {
+            format!("Unknown policy `{s}`; expected one of transform, skip, or error")
+        })
+    }
+}
+
+/// What to do with records whose SEQ is `*`, such as secondary alignments, which often carry
+/// per-base tags copied from their primary alignment in its orientation rather than theirs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingSeqPolicy {
+    /// Transform them by their own strand, like any other record.
+    #[default]
+    Transform,
+    /// Pass them through untouched.
+    Skip,
+}
+
+impl MissingSeqPolicy {
+    /// Parses a policy name as accepted by `--on-missing-seq`: `transform` or `skip`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "transform" => Some(MissingSeqPolicy::Transform),
+            "skip" => Some(MissingSeqPolicy::Skip),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the policy as accepted by `--on-missing-seq`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MissingSeqPolicy::Transform => "transform",
+            MissingSeqPolicy::Skip => "skip",
+        }
+    }
+}
+
+impl FromStr for MissingSeqPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s)
+            .ok_or_else(|| format!("Unknown policy `{s}`; expected transform or skip"))
+    }
+}
+
+/// Which records the plan applies to by their QC-fail flag (0x200), for re-processing
+/// vendor-failed reads with different tag handling than passing reads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QcFailFilter {
+    /// Apply the plan only to records not flagged QC-fail, as given by `--exclude-qcfail`.
+    Exclude,
+    /// Apply the plan only to records flagged QC-fail, as given by `--only-qcfail`.
+    Only,
+}
+
+impl QcFailFilter {
+    /// Returns the name of the filter, `exclude` or `only`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            QcFailFilter::Exclude => "exclude",
+            QcFailFilter::Only => "only",
+        }
+    }
+
+    /// Returns true if a record with the given SAM flag passes the filter.
+    pub fn passes(self, flag: u16) -> bool {
+        let qcfail = flag & 0x200 != 0;
+        match self {
+            QcFailFilter::Exclude => !qcfail,
+            QcFailFilter::Only => qcfail,
+        }
+    }
+}
+
+/// What to do with records rejected by a [`QcFailFilter`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QcFailAction {
+    /// Pass them through untouched.
+    #[default]
+    Skip,
+    /// Leave them out of the output.
+    Drop,
+}
+
+impl QcFailAction {
+    /// Parses an action name as accepted by `--qcfail-action`: `skip` or `drop`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "skip" => Some(QcFailAction::Skip),
+            "drop" => Some(QcFailAction::Drop),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the action as accepted by `--qcfail-action`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            QcFailAction::Skip => "skip",
+            QcFailAction::Drop => "drop",
+        }
+    }
+}
+
+impl FromStr for QcFailAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s)
+            .ok_or_else(|| format!("Unknown action `{s}`; expected one of skip or drop"))
+    }
+}
+
+/// An error for a record flagged both unmapped and reverse strand under
+/// [`UnmappedPolicy::Error`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnmappedReverseError {
+    /// The SAM flag of the record.
+    pub flag: u16,
+}
+
+impl std::fmt::Display for UnmappedReverseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Found a record flagged both unmapped and reverse strand (flag {}); choose how to \
+             handle these with --unmapped-policy",
+            self.flag
+        )
+    }
+}
+
+impl error::Error for UnmappedReverseError {}
+
+/// The most threads `--threads auto` uses, since BGZF compression gains little beyond this.
+pub const MAX_AUTO_THREADS: usize = 16;
+
+/// Parses a thread count as accepted by `--threads`, where `0` or `auto` means the available
+/// parallelism, which honors CPU affinity and container CPU quotas, capped at
+/// [`MAX_AUTO_THREADS`].
+///
+/// # Arguments
+///
+/// * `threads` - The thread count, `0`, or `auto`
+///
+/// # Returns
+///
+/// Returns the number of threads to use, or an error message if the count is malformed.
+///
+pub fn parse_threads(threads: &str) -> Result<usize, String> {
+    match threads {
+        "auto" | "0" => Ok(auto_threads(
+            std::thread::available_parallelism().ok().map(usize::from),
+        )),
+        _ => threads
+            .parse()
+            .map_err(|_| format!("Invalid thread count `{threads}`; expected a number or auto")),
+    }
+}
+
+/// Returns the threads to use given the available parallelism, if it could be determined.
+fn auto_threads(available: Option<usize>) -> usize {
+    available.unwrap_or(1).clamp(1, MAX_AUTO_THREADS)
+}
+
+/// A tag propagated from the source mate to the other records of its name by `--pair-fix`: as
+/// given by `TAG:OP`, its value is re-oriented by the operation where the records lie on
+/// opposite strands, and as a bare `TAG` it is copied unchanged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MateTag {
+    /// The SAM tag to propagate.
+    pub tag: Tag,
+    /// The operation re-orienting the value between opposite strands, or None to copy it.
+    pub operation: Option<Operation>,
+}
+
+impl MateTag {
+    /// Parses a tag to propagate, as `TAG` or `TAG:OP` with an operation accepted by
+    /// [`Operation::from_name`].
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The tag to parse, e.g. `RX` or `BC:revcomp`
+    ///
+    /// # Returns
+    ///
+    /// Returns the tag, or a [`ParseError`] if the tag or operation is invalid.
+    ///
+    pub fn parse(token: &str) -> Result<Self, ParseError> {
+        let error = |kind| ParseError {
+            token: token.to_string(),
+            kind,
+        };
+        let (tag, operation) = match token.split_once(':') {
+            Some((tag, name)) => {
+                let operation = Operation::from_name(name)
+                    .ok_or_else(|| error(ParseErrorKind::UnknownOperation(name.to_string())))?;
+                (tag, Some(operation))
+            }
+            None => (token, None),
+        };
+        let tag = parse_tag(tag).map_err(|e| error(e.kind))?;
+        Ok(Self { tag, operation })
+    }
+}
+
+impl fmt::Display for MateTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.tag))?;
+        match self.operation {
+            Some(operation) => write!(f, ":{operation}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Options controlling how a run reads, transforms, and writes records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// Total threads; all but one are used for BAM/CRAM compression/decompression. With more
+    /// than one thread, records are also transformed on a separate thread.
+    pub threads: usize,
+    /// Records per batch handed between the read, transform, and write stages.
+    pub batch_size: usize,
+    /// An upper bound in bytes on the records buffered between stages, which shrinks batches of
+    /// long reads below `batch_size`. Buffers inside htslib are not counted.
+    pub max_memory: Option<usize>,
+    /// A tag holding `+` or `-` that decides the strand of each record in place of the reverse
+    /// flag, e.g. for unmapped BAMs where the flag is meaningless.
+    pub strand_tag: Option<Tag>,
+    /// Decides the strand of each record from the duplex strand suffix of its `MI` tag in place
+    /// of the reverse flag, treating `/B` records as reverse strand, since the consensus tags of
+    /// duplex BAMs follow the molecule's strand rather than the alignment's.
+    pub strand_from_mi: bool,
+    /// A condition reverse strand records must also meet for the plan to apply to them; other
+    /// records pass through untouched.
+    pub condition: Option<Condition>,
+    /// Filters on tag values reverse strand records must all pass for the plan to apply to
+    /// them, e.g. a read group pattern picking out one lane; other records pass through
+    /// untouched.
+    pub tag_filters: Vec<TagFilter>,
+    /// The minimum mapping quality of records the plan applies to, so low-quality multi-mappers,
+    /// whose strand is unreliable, pass through untouched. A mapping quality of 255, meaning
+    /// unavailable, is not filtered.
+    pub min_mapq: Option<u8>,
+    /// Restricts the plan to the first or second read of pairs, for library preps that only
+    /// mis-orient the tags of one mate. Unpaired reads are left untouched.
+    pub read: Option<ReadOfPair>,
+    /// Tags propagated, after the plan, from the source mate to the other records of its name,
+    /// re-oriented where their strands differ, so both mates carry the same corrected values.
+    /// Records of a name must be adjacent, as in queryname-sorted or grouped input.
+    pub pair_fix: Vec<MateTag>,
+    /// The mate whose primary record `pair_fix` propagates tags from.
+    pub pair_fix_source: ReadOfPair,
+    /// How records flagged both unmapped and reverse strand are handled.
+    pub unmapped: UnmappedPolicy,
+    /// How records whose SEQ is `*` are handled.
+    pub missing_seq: MissingSeqPolicy,
+    /// Restricts the plan to records not flagged QC-fail (0x200), or only to those flagged.
+    pub qcfail: Option<QcFailFilter>,
+    /// What happens to records rejected by `qcfail`: they pass through untouched, or are left
+    /// out of the output.
+    pub qcfail_action: QcFailAction,
+    /// Re-express reverse strand records in their original sequencing orientation after the
+    /// plan is applied.
+    pub restore_orientation: bool,
+    /// Revert every record to an unaligned record after the plan is applied, for re-alignment:
+    /// alignment fields and tags are stripped, reverse strand records are restored to their
+    /// sequencing orientation, and secondary and supplementary records are dropped.
+    pub revert_to_ubam: bool,
+    /// A tag stamped on each transformed record with the orientation it was treated as, `-` for
+    /// reverse strand records and `+` for records transformed only by a per-tag strand source,
+    /// e.g. `os:A:-`.
+    pub strand_annotation: Option<Tag>,
+    /// A pattern locating a UMI in the read name, which is reverse complemented on reverse
+    /// strand records for pipelines carrying the UMI only in the name.
+    pub qname_umi: Option<QnameUmi>,
+    /// Write the input header text byte for byte, with only the `@PG` record appended, instead
+    /// of htslib's rebuild of it, for downstream checks that diff headers. SAM and BAM only.
+    pub preserve_header: bool,
+    /// Fail on the first reverse strand record lacking any planned tag, which catches
+    /// misspelled tags that would otherwise silently transform nothing.
+    pub strict: bool,
+    /// The number of records that may fail to transform, e.g. under `strict`, before the run
+    /// fails; each is logged with its read name and position and left out of the output. Zero
+    /// fails on the first.
+    pub max_errors: usize,
+    /// The number of times to reopen a remote input, such as an `s3://` or `https://` URL, after
+    /// it fails to open or read, resuming after the last record read. Zero fails on the first
+    /// failure.
+    pub retries: u32,
+    /// The wait before the first retry of a remote input; each later wait doubles, up to five
+    /// minutes.
+    pub retry_delay: Duration,
+    /// Repair common malformations of auxiliary data before the plan is applied: trailing NULs
+    /// in strings, arrays declaring a count without elements, and quality arrays of a wider
+    /// element type than `C`. The count of each repair is logged at the end of the run.
+    pub sanitize: bool,
+    /// Write byte-identical output across reruns with the same inputs and thread count, so
+    /// outputs can be pinned by checksum: the `@PG` record gets an ID unique within the input
+    /// header and a command line naming the program without its install path.
+    pub reproducible: bool,
+    /// The ID of the `@PG` record, for pipelines with strict naming conventions, e.g.
+    /// `revtag-step3`; must not already be in the input header. Defaults to the program name.
+    pub pg_id: Option<String>,
+    /// The program name (`PN`) of the `@PG` record, defaulting to `revtag`.
+    pub pg_program_name: Option<String>,
+    /// Before the run, scan this many records from the start of the input and fail if none
+    /// carries any planned tag, which almost always means a misspelled tag. The input is read
+    /// twice, so stdin is first copied to a scratch directory under `tmpdir`.
+    pub preflight: Option<usize>,
+    /// The sort order the input must have, such as queryname for modes handling mates
+    /// together. A header declaring another order fails the run before any record is read,
+    /// and the first record out of order fails it as the input streams.
+    pub expected_sort_order: Option<SortOrder>,
+    /// An inherited file descriptor, such as 3, receiving newline-delimited JSON progress events
+    /// for programs wrapping revtag; see [`crate::progress::ProgressEvents`]. Unix only.
+    pub progress_fd: Option<i32>,
+    /// Log the wall time spent reading, transforming, and writing records at the end of the
+    /// run, to tell whether more threads or a faster transform would help.
+    pub profile: bool,
+    /// Tags every record must carry; the run fails on the first record lacking one.
+    pub required_tags: Vec<Tag>,
+    /// The reference FASTA for CRAM inputs and outputs.
+    pub reference: Option<PathBuf>,
+    /// The FASTA index of `reference`, when it is not the `.fai` beside the FASTA, e.g. in a
+    /// separate read-only location.
+    pub reference_index: Option<PathBuf>,
+    /// The format of the output file, overriding the one implied by its extension. Needed for
+    /// outputs whose names carry no extension, such as FIFOs and `/dev/fd` descriptors from
+    /// process substitution.
+    pub output_format: Option<OutputFormat>,
+    /// Discard read names in CRAM outputs, which readers regenerate, except where needed to
+    /// pair mates, to shrink archive-bound outputs.
+    pub cram_lossy_names: bool,
+    /// Bin base qualities in CRAM outputs, to shrink archive-bound outputs.
+    pub cram_quality_bins: Option<QualityBins>,
+    /// Additional outputs receiving every record in the same pass, each in the format implied by
+    /// its extension; `-` writes SAM to stdout.
+    pub tee: Vec<PathBuf>,
+    /// Write an MD5 checksum of each output file, computed as it is written, to a `.md5` sidecar
+    /// beside it.
+    pub write_md5: bool,
+    /// Flush every output file, and the directory entries naming them, to storage before the
+    /// run reports success.
+    pub fsync: bool,
+    /// A FASTQ file to receive each record's barcode and qualities, after transformation, for
+    /// demultiplexers that only read FASTQ. BGZF compressed when named `.gz`.
+    pub barcode_fastq: Option<PathBuf>,
+    /// The `Z` tag holding each record's barcode, written to `barcode_fastq`.
+    pub barcode_tag: Tag,
+    /// The `Z` tag holding each barcode's qualities, written to `barcode_fastq`.
+    pub barcode_quality_tag: Tag,
+    /// A file of known barcodes, one per line, against which the barcodes of reverse strand
+    /// records are matched before and after the transform, with both match rates logged at the
+    /// end of the run.
+    pub whitelist: Option<PathBuf>,
+    /// The `Z` tag holding each record's barcode, matched against `whitelist`.
+    pub whitelist_tag: Tag,
+    /// Before the run, sample this many records from the start of the input and add an
+    /// operation to the plan for each tag whose orientation the sample shows to need fixing,
+    /// logging every decision with its evidence. The input is read twice, so stdin is first
+    /// copied to a scratch directory under `tmpdir`.
+    pub auto_orient: Option<usize>,
+    /// A tab-separated table listing every transformed record's name, contig, position, flag,
+    /// and the tags modified, for auditors to spot-check a sample against the source data.
+    /// BGZF compressed when named `.gz`.
+    pub audit_tsv: Option<PathBuf>,
+    /// A tab-separated table of the records, reverse strand records, and transformed records
+    /// placed on each contig, written at the end of the run to spot region-specific anomalies.
+    pub contig_tsv: Option<PathBuf>,
+    /// The directory for scratch files of modes that buffer records beyond memory, such as the
+    /// copy of stdin read twice by `preflight` and `auto_orient`, or None for `TMPDIR`. Scratch
+    /// files are removed when the run ends.
+    pub tmpdir: Option<PathBuf>,
+    /// WASM plugin modules applied to reverse strand records after the plan. Requires the
+    /// `plugins` feature.
+    pub plugins: Vec<PathBuf>,
+    /// A Rhai script deciding how each reverse strand record is transformed. Requires the
+    /// `scripting` feature.
+    pub script: Option<PathBuf>,
+}
+
+/// Replaces every `@FILE` argument with the arguments listed in `FILE`, one per line.
+///
+/// Plans with dozens of `--tag` entries can exceed the command-line length some schedulers
+/// allow, so they can be kept in a file instead. Lines are taken whole, so arguments may hold
+/// spaces without quoting; blank lines and lines starting with `#` are skipped. Files are not
+/// expanded recursively, and the program name, the first argument, is never expanded.
+///
+/// # Arguments
+///
+/// * `args` - The program arguments, including the program name
+///
+/// # Returns
+///
+/// Returns the expanded arguments, or an error naming the first file that cannot be read.
+///
+pub fn expand_argsfiles<I>(args: I) -> io::Result<Vec<OsString>>
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    let mut expanded = Vec::new();
+    for (i, arg) in args.into_iter().enumerate() {
+        let arg = arg.into();
+        let path = arg
+            .to_str()
+            .and_then(|arg| arg.strip_prefix('@'))
+            .filter(|path| i > 0 && !path.is_empty());
+        let Some(path) = path else {
+            expanded.push(arg);
+            continue;
+        };
+        let text = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read arguments file {path}: {e}"),
+            )
+        })?;
+        expanded.extend(
+            text.lines()
+                .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                .map(OsString::from),
+        );
+    }
+    Ok(expanded)
+}
+
+/// Reads tag specifications from a file with a `TAG<TAB>OPERATION` line for each tag, as
+/// exported from a spreadsheet, e.g. `QT\trev`.
+///
+/// Each line becomes a `TAG:OPERATION` specification as accepted by `--tag`, so the operation
+/// may be a chain or name a strand source, e.g. `MB\trevcomp:strand=mate`. Blank lines and lines
+/// starting with `#` are skipped, and trailing whitespace, such as a carriage return, is ignored.
+///
+/// # Arguments
+///
+/// * `path` - The file listing tags and their operations
+///
+/// # Returns
+///
+/// Returns the tag specifications in file order, or an error naming the file and line if it
+/// cannot be read or a line does not hold exactly a tag and an operation.
+///
+pub fn read_tag_file(path: &Path) -> io::Result<Vec<String>> {
+    let text = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to read tag file {}: {e}", path.display()),
+        )
+    })?;
+    let mut specs = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split('\t').collect::<Vec<_>>()[..] {
+            [tag, operation] if !tag.is_empty() && !operation.is_empty() => {
+                specs.push(format!("{tag}:{operation}"));
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Line {} of tag file {} is not TAG<TAB>OPERATION: {line:?}",
+                        i + 1,
+                        path.display()
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(specs)
+}
+
+/// Returns the arguments of this process with argument files expanded, as the `@PG` command
+/// line records them; see [`expand_argsfiles`].
+pub fn program_args() -> Vec<String> {
+    expand_argsfiles(std::env::args_os())
+        .unwrap_or_else(|_| std::env::args_os().collect())
+        .into_iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Returns a command line for the `@PG` `CL` field that omits options not affecting the output.
+///
+/// Thread counts only change how fast a run completes, so `-t`/`--threads` are dropped to keep
+/// the output bit-identical across reruns with different thread counts.
+///
+/// # Arguments
+///
+/// * `args` - The program arguments, including the program name
+///
+/// # Returns
+///
+/// Returns the arguments joined by spaces, without any thread options.
+///
+pub fn reproducible_command_line<I>(args: I) -> String
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut kept = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-t" || arg == "--threads" {
+            args.next();
+        } else if !(arg.starts_with("--threads=")
+            || arg
+                .strip_prefix("-t")
+                .is_some_and(|n| parse_threads(n).is_ok()))
+        {
+            kept.push(arg);
+        }
+    }
+    kept.join(" ")
+}
+
+/// Returns the command line for the `@PG` `CL` field, as [`reproducible_command_line`] does.
+///
+/// With `reproducible`, the program path is also reduced to its file name, since where the
+/// program is installed differs between machines.
+///
+/// # Arguments
+///
+/// * `args` - The program arguments, including the program name
+/// * `reproducible` - Whether to drop the directories of the program path
+///
+/// # Returns
+///
+/// Returns the arguments joined by spaces, without any thread options.
+///
+pub fn program_command_line<I>(args: I, reproducible: bool) -> String
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter();
+    let program = args
+        .next()
+        .map(|program| match Path::new(&program).file_name() {
+            Some(name) if reproducible => name.to_string_lossy().into_owned(),
+            _ => program,
+        });
+    reproducible_command_line(program.into_iter().chain(args))
+}
+
+/// Returns the `@PG` ID for a program: `name` itself, or the first of `name.1`, `name.2`, and so
+/// on not already among the `existing` IDs, so reruns on the same input get the same ID.
+///
+/// # Arguments
+///
+/// * `name` - The program name
+/// * `existing` - The IDs of the `@PG` records already in the header
+///
+/// # Returns
+///
+/// Returns an ID unique within the header.
+///
+pub fn program_id<I, S>(name: &str, existing: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<[u8]>,
+{
+    let existing: Vec<S> = existing.into_iter().collect();
+    let taken = |id: &str| existing.iter().any(|other| other.as_ref() == id.as_bytes());
+    let mut id = name.to_string();
+    let mut suffix = 0;
+    while taken(&id) {
+        suffix += 1;
+        id = format!("{name}.{suffix}");
+    }
+    id
+}
+
+impl Options {
+    /// Returns the program name of the `@PG` record: `pg_program_name`, else the crate name.
+    pub fn program_name(&self) -> &str {
+        self.pg_program_name
+            .as_deref()
+            .unwrap_or(env!("CARGO_PKG_NAME"))
+    }
+
+    /// Returns the ID of the `@PG` record: `pg_id` if given, else the program name, made unique
+    /// within the header as [`program_id`] does when `reproducible`.
+    ///
+    /// # Arguments
+    ///
+    /// * `existing` - The IDs of the `@PG` records already in the header
+    ///
+    /// # Returns
+    ///
+    /// Returns the ID, or an error if `pg_id` is already in the header.
+    ///
+    pub fn program_record_id<I, S>(&self, existing: I) -> Result<String, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        match &self.pg_id {
+            Some(id) if program_id(id, existing) != *id => {
+                Err(format!("The @PG ID {id} is already in the input header"))
+            }
+            Some(id) => Ok(id.clone()),
+            None if self.reproducible => Ok(program_id(self.program_name(), existing)),
+            None => Ok(self.program_name().to_string()),
+        }
+    }
+
+    /// Returns true if a record is treated as reverse strand, given whether its flags, or the
+    /// strand tag, mark it reverse strand.
+    ///
+    /// Records whose SEQ is `*` are treated as directed by `missing_seq`; all others keep their
+    /// own strand.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The record to test
+    /// * `reverse` - Whether the record's own strand is reverse
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the record is treated as reverse strand, or an error if its tags cannot
+    /// be read.
+    ///
+    pub fn is_reverse<R: RecordFields + ?Sized>(
+        &self,
+        record: &R,
+        reverse: bool,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        if record.seq_len() > 0 {
+            return Ok(reverse);
+        }
+        match self.missing_seq {
+            MissingSeqPolicy::Transform => Ok(reverse),
+            MissingSeqPolicy::Skip => Ok(false),
+        }
+    }
+
+    /// Returns true if a reverse strand record passes the filters deciding whether the plan
+    /// applies to it: `unmapped`, `qcfail`, `read`, `min_mapq`, `condition`, and `tag_filters`.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The reverse strand record to test
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the record passes, or an error if its tags cannot be read or it is
+    /// flagged both unmapped and reverse strand under [`UnmappedPolicy::Error`].
+    ///
+    pub fn selects<R: RecordFields + ?Sized>(
+        &self,
+        record: &R,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        let flag = record.flag();
+        if flag & 0x14 == 0x14 {
+            match self.unmapped {
+                UnmappedPolicy::Transform => {}
+                UnmappedPolicy::Skip => return Ok(false),
+                UnmappedPolicy::Error => return Err(Box::new(UnmappedReverseError { flag })),
+            }
+        }
+        if self.qcfail.is_some_and(|filter| !filter.passes(flag)) {
+            return Ok(false);
+        }
+        if self
+            .read
+            .is_some_and(|read| record.flag() & read.flag() == 0)
+        {
+            return Ok(false);
+        }
+        let mapq = record.mapq();
+        if self
+            .min_mapq
+            .is_some_and(|min_mapq| mapq != 255 && mapq < min_mapq)
+        {
+            return Ok(false);
+        }
+        let meets = match &self.condition {
+            Some(condition) => condition.matches(record)?,
+            None => true,
+        };
+        Ok(meets && passes_all(&self.tag_filters, record)?)
+    }
+
+    /// Returns true if a record is left out of the output: it is rejected by `qcfail` under
+    /// [`QcFailAction::Drop`], or it is secondary (0x100) or supplementary (0x800) and
+    /// `revert_to_ubam` is set, since an unaligned BAM holds each read once.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The record to test
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the record is dropped.
+    ///
+    pub fn drops<R: RecordFields + ?Sized>(&self, record: &R) -> bool {
+        if self.revert_to_ubam && record.flag() & (0x100 | 0x800) != 0 {
+            return true;
+        }
+        self.qcfail_action == QcFailAction::Drop
+            && self
+                .qcfail
+                .is_some_and(|filter| !filter.passes(record.flag()))
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            threads: 1,
+            batch_size: 1024,
+            max_memory: None,
+            strand_tag: None,
+            strand_from_mi: false,
+            condition: None,
+            tag_filters: Vec::new(),
+            min_mapq: None,
+            read: None,
+            pair_fix: Vec::new(),
+            pair_fix_source: ReadOfPair::First,
+            unmapped: UnmappedPolicy::default(),
+            missing_seq: MissingSeqPolicy::default(),
+            qcfail: None,
+            qcfail_action: QcFailAction::default(),
+            restore_orientation: false,
+            revert_to_ubam: false,
+            strand_annotation: None,
+            qname_umi: None,
+            preserve_header: false,
+            strict: false,
+            sanitize: false,
+            reproducible: false,
+            pg_id: None,
+            pg_program_name: None,
+            preflight: None,
+            expected_sort_order: None,
+            progress_fd: None,
+            profile: false,
+            required_tags: Vec::new(),
+            max_errors: 0,
+            retries: 0,
+            retry_delay: Duration::from_secs(1),
+            reference: None,
+            reference_index: None,
+            output_format: None,
+            cram_lossy_names: false,
+            cram_quality_bins: None,
+            tee: Vec::new(),
+            write_md5: false,
+            fsync: false,
+            barcode_fastq: None,
+            barcode_tag: *b"BC",
+            barcode_quality_tag: *b"QT",
+            whitelist: None,
+            whitelist_tag: *b"BC",
+            auto_orient: None,
+            audit_tsv: None,
+            contig_tsv: None,
+            tmpdir: None,
+            plugins: Vec::new(),
+            script: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_reproducible_command_line_drops_threads() {
+        let expected = "revtag -i in.bam -o out.bam --rev QT";
+        for line in [
+            "revtag -i in.bam -t 4 -o out.bam --rev QT",
+            "revtag -i in.bam --threads 8 -o out.bam --rev QT",
+            "revtag -i in.bam --threads=8 -o out.bam --rev QT",
+            "revtag -t4 -i in.bam -o out.bam --rev QT",
+            "revtag -tauto -i in.bam -o out.bam --rev QT",
+            "revtag -i in.bam -o out.bam --rev QT",
+        ] {
+            assert_eq!(reproducible_command_line(args(line)), expected);
+        }
+    }
+
+    struct Mapped {
+        mapq: u8,
+    }
+
+    impl RecordFields for Mapped {
+        fn flag(&self) -> u16 {
+            0x1 | 0x10 | 0x80
+        }
+
+        fn mapq(&self) -> u8 {
+            self.mapq
+        }
+
+        fn pos(&self) -> i64 {
+            1
+        }
+
+        fn tlen(&self) -> i64 {
+            0
+        }
+
+        fn seq_len(&self) -> usize {
+            4
+        }
+
+        fn tag(&self, _: &Tag) -> Result<Option<TagValue>, Box<dyn error::Error>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_selects() {
+        let options = Options {
+            min_mapq: Some(20),
+            ..Options::default()
+        };
+        assert!(options.selects(&Mapped { mapq: 20 }).unwrap());
+        assert!(!options.selects(&Mapped { mapq: 3 }).unwrap());
+        assert!(options.selects(&Mapped { mapq: 255 }).unwrap());
+
+        let options = Options {
+            condition: Some(Condition::parse("mapq < 40").unwrap()),
+            ..options
+        };
+        assert!(options.selects(&Mapped { mapq: 30 }).unwrap());
+        assert!(!options.selects(&Mapped { mapq: 50 }).unwrap());
+        assert!(!options.selects(&Mapped { mapq: 10 }).unwrap());
+        assert!(Options::default().selects(&Mapped { mapq: 0 }).unwrap());
+
+        let options = Options {
+            tag_filters: vec![TagFilter::parse("RG=~lane1").unwrap()],
+            ..Options::default()
+        };
+        assert!(!options.selects(&Mapped { mapq: 60 }).unwrap());
+    }
+
+    #[test]
+    fn test_selects_read_of_pair() {
+        let mapped = Mapped { mapq: 60 };
+        let read = |read| Options {
+            read: Some(read),
+            ..Options::default()
+        };
+        assert!(read(ReadOfPair::Second).selects(&mapped).unwrap());
+        assert!(!read(ReadOfPair::First).selects(&mapped).unwrap());
+        assert_eq!("1".parse::<ReadOfPair>(), Ok(ReadOfPair::First));
+        assert!("R1".parse::<ReadOfPair>().is_err());
+    }
+
+    #[test]
+    fn test_selects_qcfail() {
+        assert!(QcFailFilter::Exclude.passes(0x10));
+        assert!(!QcFailFilter::Exclude.passes(0x210));
+        assert!(QcFailFilter::Only.passes(0x210));
+        assert!(!QcFailFilter::Only.passes(0x10));
+
+        let mapped = Mapped { mapq: 60 };
+        let qcfail = |filter, action| Options {
+            qcfail: Some(filter),
+            qcfail_action: action,
+            ..Options::default()
+        };
+        assert!(
+            qcfail(QcFailFilter::Exclude, QcFailAction::Skip)
+                .selects(&mapped)
+                .unwrap()
+        );
+        assert!(
+            !qcfail(QcFailFilter::Only, QcFailAction::Skip)
+                .selects(&mapped)
+                .unwrap()
+        );
+        assert!(!qcfail(QcFailFilter::Only, QcFailAction::Skip).drops(&mapped));
+        assert!(qcfail(QcFailFilter::Only, QcFailAction::Drop).drops(&mapped));
+        assert!(!qcfail(QcFailFilter::Exclude, QcFailAction::Drop).drops(&mapped));
+        let revert = Options {
+            revert_to_ubam: true,
+            ..Options::default()
+        };
+        assert!(!revert.drops(&mapped));
+        assert_eq!("drop".parse::<QcFailAction>(), Ok(QcFailAction::Drop));
+    }
+
+    /// A mapped record, flagged as [`Mapped`] is, with `0x4` set as well.
+    struct Unmapped;
+
+    impl RecordFields for Unmapped {
+        fn flag(&self) -> u16 {
+            Mapped { mapq: 0 }.flag() | 0x4
+        }
+
+        fn mapq(&self) -> u8 {
+            0
+        }
+
+        fn pos(&self) -> i64 {
+            0
+        }
+
+        fn tlen(&self) -> i64 {
+            0
+        }
+
+        fn seq_len(&self) -> usize {
+            0
+        }
+
+        fn tag(&self, _: &Tag) -> Result<Option<TagValue>, Box<dyn error::Error>> {
+            Ok(None)
+        }
+    }
+
+    /// A secondary alignment with SEQ `*`.
+    struct Secondary;
+
+    impl RecordFields for Secondary {
+        fn flag(&self) -> u16 {
+            0x100
+        }
+
+        fn mapq(&self) -> u8 {
+            0
+        }
+
+        fn pos(&self) -> i64 {
+            1
+        }
+
+        fn tlen(&self) -> i64 {
+            0
+        }
+
+        fn seq_len(&self) -> usize {
+            0
+        }
+
+        fn tag(&self, _: &Tag) -> Result<Option<TagValue>, Box<dyn error::Error>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_is_reverse_without_seq() {
+        let policy = |missing_seq| Options {
+            missing_seq,
+            ..Options::default()
+        };
+        let transform = policy(MissingSeqPolicy::Transform);
+        assert!(transform.is_reverse(&Secondary, true).unwrap());
+        assert!(!transform.is_reverse(&Secondary, false).unwrap());
+        assert!(transform.is_reverse(&Mapped { mapq: 60 }, true).unwrap());
+
+        let skip = policy(MissingSeqPolicy::Skip);
+        assert!(!skip.is_reverse(&Secondary, true).unwrap());
+        assert!(skip.is_reverse(&Mapped { mapq: 60 }, true).unwrap());
+        assert!("primary".parse::<MissingSeqPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_selects_unmapped_reverse() {
+        let unmapped = |unmapped| Options {
+            unmapped,
+            ..Options::default()
+        };
+        assert!(Options::default().selects(&Unmapped).unwrap());
+        assert!(!unmapped(UnmappedPolicy::Skip).selects(&Unmapped).unwrap());
+        assert!(
+            unmapped(UnmappedPolicy::Skip)
+                .selects(&Mapped { mapq: 60 })
+                .unwrap()
+        );
+        let err = unmapped(UnmappedPolicy::Error)
+            .selects(&Unmapped)
+            .unwrap_err();
+        assert!(err.to_string().contains("flag 149"), "{err}");
+        assert_eq!("skip".parse::<UnmappedPolicy>(), Ok(UnmappedPolicy::Skip));
+        assert!("drop".parse::<UnmappedPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_parse_threads() {
+        assert_eq!(parse_threads("4"), Ok(4));
+        assert!(parse_threads("auto").unwrap() >= 1);
+        assert_eq!(parse_threads("0"), parse_threads("auto"));
+        assert!(parse_threads("-1").is_err());
+        assert!(parse_threads("many").is_err());
+        assert_eq!(auto_threads(Some(4)), 4);
+        assert_eq!(auto_threads(Some(96)), MAX_AUTO_THREADS);
+        assert_eq!(auto_threads(None), 1);
+    }
+
+    #[test]
+    fn test_output_format_names() {
+        assert_eq!("bam".parse::<OutputFormat>(), Ok(OutputFormat::Bam));
+        assert_eq!(OutputFormat::from_name("cram"), Some(OutputFormat::Cram));
+        assert!("BAM".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_mate_tag() {
+        let tag = MateTag::parse("BC:revcomp").unwrap();
+        assert_eq!(tag.tag, *b"BC");
+        assert_eq!(tag.operation, Some(Operation::ReverseComplement));
+        assert_eq!(tag.to_string(), "BC:revcomp");
+        assert_eq!(MateTag::parse("RX").unwrap().operation, None);
+        assert_eq!(
+            MateTag::parse("RX:rename=BC").unwrap_err().kind,
+            ParseErrorKind::UnknownOperation("rename=BC".to_string())
+        );
+        assert!(MateTag::parse("R:rev").is_err());
+    }
+
+    #[test]
+    fn test_read_tag_file() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("tags.txt");
+        fs::write(
+            &path,
+            "# from the tag sheet\nQT\trev\r\n\nMB\trevcomp:strand=mate\n",
+        )
+        .unwrap();
+        assert_eq!(
+            read_tag_file(&path).unwrap(),
+            ["QT:rev", "MB:revcomp:strand=mate"]
+        );
+
+        fs::write(&path, "QT\trev\nBC revcomp\n").unwrap();
+        let err = read_tag_file(&path).unwrap_err();
+        assert!(err.to_string().starts_with("Line 2 of tag file"));
+        assert!(read_tag_file(&tmpdir.path().join("missing.txt")).is_err());
+    }
+
+    #[test]
+    fn test_expand_argsfiles() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("args.txt");
+        fs::write(&path, "--tag\r\nQT:rev\n\n# barcodes\n--tag\nBC:revcomp\n").unwrap();
+        let argsfile = format!("@{}", path.display());
+
+        let expanded = expand_argsfiles(["revtag", "-i", argsfile.as_str(), "@", "-o"]).unwrap();
+        assert_eq!(
+            expanded,
+            [
+                "revtag",
+                "-i",
+                "--tag",
+                "QT:rev",
+                "--tag",
+                "BC:revcomp",
+                "@",
+                "-o"
+            ]
+            .map(OsString::from)
+        );
+        assert_eq!(
+            expand_argsfiles([argsfile.as_str()]).unwrap(),
+            [OsString::from(&argsfile)]
+        );
+
+        let missing = format!("@{}", tmpdir.path().join("missing.txt").display());
+        let err = expand_argsfiles(["revtag", missing.as_str()]).unwrap_err();
+        assert!(err.to_string().starts_with("Failed to read arguments file"));
+    }
+
+    #[test]
+    fn test_program_command_line() {
+        let line = "/opt/bin/revtag -t 4 -i in.bam";
+        assert_eq!(
+            program_command_line(args(line), false),
+            "/opt/bin/revtag -i in.bam"
+        );
+        assert_eq!(program_command_line(args(line), true), "revtag -i in.bam");
+        assert_eq!(program_command_line(Vec::new(), true), "");
+    }
+
+    #[test]
+    fn test_program_id() {
+        assert_eq!(program_id("revtag", ["bwa"]), "revtag");
+        assert_eq!(program_id("revtag", ["bwa", "revtag"]), "revtag.1");
+        assert_eq!(
+            program_id("revtag", ["revtag.1", "revtag", "revtag.2"]),
+            "revtag.3"
+        );
+        assert_eq!(program_id("revtag", Vec::<String>::new()), "revtag");
+    }
+
+    #[test]
+    fn test_program_record_id() {
+        let options = Options::default();
+        assert_eq!(options.program_name(), "revtag");
+        assert_eq!(options.program_record_id(["revtag"]).unwrap(), "revtag");
+
+        let options = Options {
+            pg_program_name: Some("tag-fixer".to_string()),
+            reproducible: true,
+            ..Options::default()
+        };
+        assert_eq!(options.program_name(), "tag-fixer");
+        assert_eq!(
+            options.program_record_id(["tag-fixer"]).unwrap(),
+            "tag-fixer.1"
+        );
+
+        let options = Options {
+            pg_id: Some("step3.v2".to_string()),
+            ..Options::default()
+        };
+        assert_eq!(options.program_record_id(["bwa"]).unwrap(), "step3.v2");
+        assert!(options.program_record_id(["step3.v2"]).is_err());
+    }
+
+    #[test]
+    fn test_reproducible_command_line_keeps_other_args() {
+        let line = "revtag --tag QT:rev -i t.bam";
+        assert_eq!(reproducible_command_line(args(line)), line);
+    }
+}
@@ -0,0 +1,573 @@
+//! Automatic detection of how each tag of an input of unknown provenance should be oriented on
+//! reverse strand records, from a sample of its primary records.
+//!
+//! Every `Z` string holding DNA or base qualities and every integer `B` array on the sample is a
+//! candidate, apart from tags ignored by the caller, such as those already planned, and
+//! [`ALIGNMENT_TAGS`]. Each is decided by the strongest evidence available for it:
+//! - Whitelist matches: for the whitelist tag, the fraction of reverse strand barcodes on the
+//!   whitelist as stored, reversed, and reverse complemented, as `--whitelist` reports.
+//! - Agreement with SEQ or QUAL: for tags as long as the read on reverse strand records, the
+//!   fraction of bases differing from SEQ, or the mean difference from QUAL, as stored and
+//!   transformed.
+//! - Trend along the read: for tags as long as the read, the mean of the first half of their
+//!   values less that of the second, or of their GC content for DNA. Forward strand records hold
+//!   their values in sequencing order, so a tag whose trend runs the same way on reverse strand
+//!   records was left in sequencing order and needs transforming, while one running the other
+//!   way already follows SEQ.
+//!
+//! The operation is `revcomp` for DNA and `rev` for anything else, except that the whitelist may
+//! choose either. Evidence only counts when the better score beats the other by a tenth, so a
+//! tag whose evidence is missing or inconclusive is left as stored.
+use rust_htslib::bam::Record;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+
+use crate::aux;
+use crate::dna;
+use crate::ops::{ALIGNMENT_TAGS, aux_data};
+use crate::plan::{Operation, Tag, TagSpec};
+use crate::whitelist::Whitelist;
+
+/// The fraction by which the better score of a heuristic must beat the other to count.
+const MARGIN: f64 = 0.1;
+
+/// Returns true if a value holds only bases and `N`.
+fn is_dna(value: &[u8]) -> bool {
+    !value.is_empty()
+        && value
+            .iter()
+            .all(|b| matches!(b.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T' | b'N'))
+}
+
+/// Returns true if a value holds only Phred+33 quality characters.
+fn is_quality(value: &[u8]) -> bool {
+    !value.is_empty() && value.iter().all(|b| (b'!'..=b'~').contains(b))
+}
+
+/// Returns the elements of an integer `B` array, or None for float arrays.
+fn integers(elements: &[u8], subtype: u8) -> Option<Vec<f64>> {
+    let width = aux::width(subtype)?;
+    elements
+        .chunks_exact(width)
+        .map(|bytes| match subtype {
+            b'c' => Some(f64::from(bytes[0] as i8)),
+            b'C' => Some(f64::from(bytes[0])),
+            b's' => Some(f64::from(i16::from_le_bytes([bytes[0], bytes[1]]))),
+            b'S' => Some(f64::from(u16::from_le_bytes([bytes[0], bytes[1]]))),
+            b'i' => Some(f64::from(i32::from_le_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3],
+            ]))),
+            b'I' => Some(f64::from(u32::from_le_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3],
+            ]))),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns the mean of the first half of values less the mean of the second, or None for fewer
+/// than two values.
+fn trend(values: &[f64]) -> Option<f64> {
+    let half = values.len() / 2;
+    if half == 0 {
+        return None;
+    }
+    let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+    Some(mean(&values[..half]) - mean(&values[values.len() - half..]))
+}
+
+/// Returns the fraction of positions at which two sequences differ, ignoring `N`.
+fn mismatches(a: &[u8], b: &[u8]) -> Option<f64> {
+    let (mut compared, mut differing) = (0u64, 0u64);
+    for (x, y) in a.iter().zip(b) {
+        let (x, y) = (x.to_ascii_uppercase(), y.to_ascii_uppercase());
+        if x != b'N' && y != b'N' {
+            compared += 1;
+            differing += u64::from(x != y);
+        }
+    }
+    (compared > 0).then(|| differing as f64 / compared as f64)
+}
+
+/// Returns the mean absolute difference between two equally long series of values.
+fn distance<'a>(a: impl Iterator<Item = &'a f64>, b: &[f64]) -> f64 {
+    a.zip(b).map(|(x, y)| (x - y).abs()).sum::<f64>() / b.len() as f64
+}
+
+/// Returns true if `better` beats `worse` by the margin, where lower scores are better.
+fn beats(better: f64, worse: f64) -> bool {
+    better < worse * (1.0 - MARGIN)
+}
+
+/// Sums of a score over reverse strand records, as stored and transformed.
+#[derive(Clone, Copy, Debug, Default)]
+struct Scores {
+    sums: [f64; 2],
+    records: u64,
+}
+
+impl Scores {
+    fn add(&mut self, stored: f64, transformed: f64) {
+        self.sums[0] += stored;
+        self.sums[1] += transformed;
+        self.records += 1;
+    }
+
+    fn means(&self) -> Option<(f64, f64)> {
+        (self.records > 0).then(|| {
+            let n = self.records as f64;
+            (self.sums[0] / n, self.sums[1] / n)
+        })
+    }
+}
+
+/// Sums of trends along the read on forward and reverse strand records.
+#[derive(Clone, Copy, Debug, Default)]
+struct Trends {
+    sums: [f64; 2],
+    records: [u64; 2],
+}
+
+impl Trends {
+    fn add(&mut self, reverse: bool, values: &[f64]) {
+        if let Some(trend) = trend(values) {
+            self.sums[usize::from(reverse)] += trend;
+            self.records[usize::from(reverse)] += 1;
+        }
+    }
+
+    /// Returns the mean trends on forward and reverse strand records.
+    fn means(&self) -> Option<(f64, f64)> {
+        (self.records[0] > 0 && self.records[1] > 0).then(|| {
+            (
+                self.sums[0] / self.records[0] as f64,
+                self.sums[1] / self.records[1] as f64,
+            )
+        })
+    }
+}
+
+/// Evidence gathered for one tag across the sample.
+#[derive(Clone, Debug, Default)]
+struct TagStats {
+    /// `Z` values holding DNA.
+    dna: u64,
+    /// `Z` values of quality characters, or integer `B` arrays, DNA included.
+    numeric: u64,
+    /// Values of any other kind.
+    other: u64,
+    /// Reverse strand barcodes on the whitelist as stored, reversed, and reverse complemented.
+    whitelisted: [u64; 3],
+    /// Reverse strand barcodes matched against the whitelist.
+    barcodes: u64,
+    /// Fractions of bases differing from SEQ.
+    seq: Scores,
+    /// Mean differences from QUAL.
+    qual: Scores,
+    /// Trends of GC content along the read.
+    gc: Trends,
+    /// Trends of values along the read.
+    values: Trends,
+}
+
+impl TagStats {
+    /// Gathers evidence from a `Z` string.
+    fn observe_string(&mut self, value: &[u8], record: &Observed, whitelist: Option<&Whitelist>) {
+        let dna = is_dna(value);
+        let quality = is_quality(value);
+        self.dna += u64::from(dna);
+        if !quality {
+            self.other += 1;
+            return;
+        }
+        if dna {
+            if let Some(whitelist) = whitelist.filter(|_| record.reverse) {
+                let reversed: Vec<u8> = value.iter().rev().copied().collect();
+                let candidates = [value.to_vec(), reversed, dna::revcomp(value)];
+                for (i, candidate) in candidates.iter().enumerate() {
+                    self.whitelisted[i] += u64::from(whitelist.contains(candidate));
+                }
+                self.barcodes += 1;
+            }
+            if value.len() == record.seq.len() {
+                if record.reverse {
+                    let revcomp = dna::revcomp(value);
+                    if let (Some(stored), Some(transformed)) = (
+                        mismatches(value, &record.seq),
+                        mismatches(&revcomp, &record.seq),
+                    ) {
+                        self.seq.add(stored, transformed);
+                    }
+                }
+                let gc: Vec<f64> = value
+                    .iter()
+                    .map(|b| f64::from(u8::from(matches!(b.to_ascii_uppercase(), b'C' | b'G'))))
+                    .collect();
+                self.gc.add(record.reverse, &gc);
+            }
+        }
+        let values: Vec<f64> = value.iter().map(|b| f64::from(b - b'!')).collect();
+        self.observe_values(&values, record);
+    }
+
+    /// Gathers evidence from numeric values: qualities or the elements of an integer array.
+    fn observe_values(&mut self, values: &[f64], record: &Observed) {
+        self.numeric += 1;
+        if values.len() != record.seq.len() {
+            return;
+        }
+        if record.reverse && record.qual.len() == values.len() {
+            self.qual.add(
+                distance(values.iter(), &record.qual),
+                distance(values.iter().rev(), &record.qual),
+            );
+        }
+        self.values.add(record.reverse, values);
+    }
+
+    /// Decides the operation for the tag from the strongest conclusive evidence.
+    fn decide(&self, tag: Tag) -> Option<Decision> {
+        let dna = self.dna > 0 && self.dna == self.numeric && self.other == 0;
+        if self.other > 0 || (self.dna == 0 && self.numeric == 0) {
+            return None;
+        }
+        let operation = if dna {
+            Operation::ReverseComplement
+        } else {
+            Operation::Reverse
+        };
+        let decision = |operation: Option<Operation>, evidence: String| Decision {
+            tag,
+            operation,
+            evidence,
+        };
+
+        if dna && self.barcodes > 0 {
+            let n = self.barcodes as f64;
+            let rates = self.whitelisted.map(|count| count as f64 / n);
+            let best = (0..3)
+                .max_by(|&a, &b| rates[a].total_cmp(&rates[b]))
+                .unwrap_or(0);
+            let conclusive = (0..3)
+                .filter(|&i| i != best)
+                .all(|i| beats(1.0 - rates[best], 1.0 - rates[i]));
+            if conclusive {
+                let operations = [
+                    None,
+                    Some(Operation::Reverse),
+                    Some(Operation::ReverseComplement),
+                ];
+                return Some(decision(
+                    operations[best],
+                    format!(
+                        "whitelist matches {:.1}% as stored, {:.1}% reversed, and {:.1}% reverse \
+                         complemented",
+                        rates[0] * 100.0,
+                        rates[1] * 100.0,
+                        rates[2] * 100.0
+                    ),
+                ));
+            }
+        }
+
+        let (agreement, what, unit) = if dna {
+            (self.seq.means(), "SEQ", "of bases differing")
+        } else {
+            (self.qual.means(), "QUAL", "mean difference")
+        };
+        if let Some((stored, transformed)) = agreement {
+            let chosen = if beats(transformed, stored) {
+                Some(Some(operation))
+            } else if beats(stored, transformed) {
+                Some(None)
+            } else {
+                None
+            };
+            if let Some(chosen) = chosen {
+                let (stored, transformed) = if dna {
+                    (
+                        format!("{:.1}%", stored * 100.0),
+                        format!("{:.1}%", transformed * 100.0),
+                    )
+                } else {
+                    (format!("{stored:.2}"), format!("{transformed:.2}"))
+                };
+                return Some(decision(
+                    chosen,
+                    format!(
+                        "agreement with {what}: {stored} {unit} as stored, {transformed} after \
+                         {operation}"
+                    ),
+                ));
+            }
+        }
+
+        let trends = if dna { self.gc } else { self.values };
+        if let Some((forward, reverse)) = trends.means() {
+            // A reverse strand trend matching the forward one runs in sequencing order.
+            let same = (reverse - forward).abs();
+            let opposite = (reverse + forward).abs();
+            let chosen = if beats(same, opposite) {
+                Some(Some(operation))
+            } else if beats(opposite, same) {
+                Some(None)
+            } else {
+                None
+            };
+            if let Some(chosen) = chosen {
+                return Some(decision(
+                    chosen,
+                    format!(
+                        "trend along the read of {forward:.3} on forward strand records and \
+                         {reverse:.3} on reverse strand records"
+                    ),
+                ));
+            }
+        }
+
+        Some(decision(None, "no conclusive evidence".to_string()))
+    }
+}
+
+/// The parts of a sampled record the heuristics compare tags against.
+struct Observed {
+    reverse: bool,
+    seq: Vec<u8>,
+    qual: Vec<f64>,
+}
+
+/// The operation chosen for a tag and the evidence it was chosen by.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decision {
+    /// The tag decided.
+    pub tag: Tag,
+    /// The operation to apply on reverse strand records, or None to leave the tag as stored.
+    pub operation: Option<Operation>,
+    /// A description of the evidence, for reporting.
+    pub evidence: String,
+}
+
+impl Decision {
+    /// Returns the tag specification applying the decision, or None if the tag is left as
+    /// stored.
+    pub fn spec(&self) -> Option<TagSpec> {
+        self.operation.map(|operation| TagSpec {
+            tag: self.tag,
+            operation,
+        })
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tag = String::from_utf8_lossy(&self.tag);
+        match self.operation {
+            Some(operation) => write!(f, "{tag}:{operation} from {}", self.evidence),
+            None => write!(f, "{tag} left as stored from {}", self.evidence),
+        }
+    }
+}
+
+/// Gathers evidence from sampled records and decides how to orient each candidate tag.
+#[derive(Debug)]
+pub struct Sampler<'a> {
+    whitelist: Option<&'a Whitelist>,
+    ignored: Vec<Tag>,
+    tags: BTreeMap<Tag, TagStats>,
+    records: u64,
+}
+
+impl<'a> Sampler<'a> {
+    /// Creates a sampler.
+    ///
+    /// # Arguments
+    ///
+    /// * `whitelist` - An optional whitelist to match barcodes in its tag against
+    /// * `ignored` - Tags not to decide, e.g. those the plan already transforms, in addition to
+    ///   [`ALIGNMENT_TAGS`]
+    ///
+    /// # Returns
+    ///
+    /// Returns the sampler, without any records observed.
+    ///
+    pub fn new(whitelist: Option<&'a Whitelist>, ignored: impl IntoIterator<Item = Tag>) -> Self {
+        let ignored = ALIGNMENT_TAGS.into_iter().chain(ignored).collect();
+        Self {
+            whitelist,
+            ignored,
+            tags: BTreeMap::new(),
+            records: 0,
+        }
+    }
+
+    /// Gathers evidence from a record. Secondary and supplementary records, which repeat their
+    /// primary record's tags, are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The BAM record to observe
+    /// * `reverse` - Whether the record is reverse strand
+    ///
+    /// # Returns
+    ///
+    /// Returns Ok(()) on success, or an error if the auxiliary data is malformed.
+    ///
+    pub fn observe(&mut self, record: &Record, reverse: bool) -> Result<(), Box<dyn error::Error>> {
+        if record.is_secondary() || record.is_supplementary() {
+            return Ok(());
+        }
+        self.records += 1;
+        let qual = record.qual();
+        let observed = Observed {
+            reverse,
+            seq: record.seq().as_bytes(),
+            qual: if qual.first() == Some(&0xff) {
+                Vec::new()
+            } else {
+                qual.iter().map(|&q| f64::from(q)).collect()
+            },
+        };
+        let aux = aux_data(record);
+        for field in aux::fields(aux) {
+            let field = field?;
+            if self.ignored.contains(&field.tag) {
+                continue;
+            }
+            let whitelist = self.whitelist.filter(|w| w.tag() == field.tag);
+            let stats = self.tags.entry(field.tag).or_default();
+            let value = &aux[field.value.clone()];
+            match (
+                field.value_type,
+                field.subtype.and_then(|s| integers(value, s)),
+            ) {
+                (b'Z', _) => stats.observe_string(value, &observed, whitelist),
+                (b'B', Some(values)) => stats.observe_values(&values, &observed),
+                _ => stats.other += 1,
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of records observed.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Decides how to orient each candidate tag seen, in tag order.
+    pub fn decide(&self) -> Vec<Decision> {
+        self.tags
+            .iter()
+            .filter_map(|(tag, stats)| stats.decide(*tag))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::Aux;
+
+    /// Builds a primary record with a sequence and qualities, on the reverse strand if
+    /// `reverse` is set.
+    fn record(seq: &[u8], qual: &[u8], reverse: bool) -> Record {
+        let mut record = Record::new();
+        record.set(b"read", None, seq, qual);
+        if reverse {
+            record.set_reverse();
+        }
+        record
+    }
+
+    fn decision(sampler: &Sampler, tag: &Tag) -> Decision {
+        sampler
+            .decide()
+            .into_iter()
+            .find(|decision| decision.tag == *tag)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_agreement_with_seq_and_qual() {
+        let mut sampler = Sampler::new(None, []);
+        for _ in 0..10 {
+            // Original bases left in sequencing order, and qualities already following QUAL.
+            let mut reverse = record(b"AAACCG", &[10, 20, 30, 30, 20, 5], true);
+            reverse.push_aux(b"OS", Aux::String("CGGTTT")).unwrap();
+            reverse.push_aux(b"OQ", Aux::String("+5??5&")).unwrap();
+            sampler.observe(&reverse, true).unwrap();
+        }
+        assert_eq!(sampler.records(), 10);
+        assert_eq!(
+            decision(&sampler, b"OS").operation,
+            Some(Operation::ReverseComplement)
+        );
+        let oq = decision(&sampler, b"OQ");
+        assert_eq!(oq.operation, None);
+        assert!(oq.evidence.starts_with("agreement with QUAL"));
+    }
+
+    #[test]
+    fn test_trend_along_the_read() {
+        let mut sampler = Sampler::new(None, []);
+        let depths = [9u8, 8, 7, 2, 1, 0];
+        for reverse in [false, true] {
+            let mut record = record(b"ACGTAC", &[30; 6], reverse);
+            record
+                .push_aux(b"cd", Aux::ArrayU8((&depths[..]).into()))
+                .unwrap();
+            sampler.observe(&record, reverse).unwrap();
+        }
+        let cd = decision(&sampler, b"cd");
+        assert_eq!(cd.operation, Some(Operation::Reverse));
+        assert_eq!(cd.spec().unwrap().tag, *b"cd");
+        assert!(
+            cd.to_string()
+                .starts_with("cd:rev from trend along the read")
+        );
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let whitelist = Whitelist::new(["AACG"], *b"BC");
+        let mut sampler = Sampler::new(Some(&whitelist), [*b"QT"]);
+        for _ in 0..4 {
+            let mut reverse = record(b"ACGT", &[30; 4], true);
+            reverse.push_aux(b"BC", Aux::String("CGTT")).unwrap();
+            reverse.push_aux(b"QT", Aux::String("????")).unwrap();
+            sampler.observe(&reverse, true).unwrap();
+        }
+        let bc = decision(&sampler, b"BC");
+        assert_eq!(bc.operation, Some(Operation::ReverseComplement));
+        assert!(bc.evidence.contains("100.0% reverse complemented"));
+        assert!(
+            sampler
+                .decide()
+                .iter()
+                .all(|decision| decision.tag != *b"QT")
+        );
+    }
+
+    #[test]
+    fn test_inconclusive_and_ignored_tags() {
+        let mut sampler = Sampler::new(None, []);
+        let mut reverse = record(b"ACGT", &[30; 4], true);
+        reverse.push_aux(b"XB", Aux::String("GGCC")).unwrap();
+        reverse.push_aux(b"NM", Aux::U8(0)).unwrap();
+        reverse.push_aux(b"XF", Aux::Float(1.5)).unwrap();
+        sampler.observe(&reverse, true).unwrap();
+        let mut secondary = record(b"ACGT", &[30; 4], true);
+        secondary.set_secondary();
+        secondary.push_aux(b"YY", Aux::String("ACGT")).unwrap();
+        sampler.observe(&secondary, true).unwrap();
+
+        let decisions = sampler.decide();
+        assert_eq!(decisions.len(), 1);
+        assert_eq!(decisions[0].tag, *b"XB");
+        assert_eq!(decisions[0].operation, None);
+        assert_eq!(
+            decisions[0].to_string(),
+            "XB left as stored from no conclusive evidence"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Propagation of corrected tags between mates, so that after a barcode or UMI is fixed on one
+//! read of a pair, the other read carries the same value, re-oriented for its strand.
+//!
+//! Records sharing a read name must be adjacent, as in queryname-sorted or grouped input; they
+//! are buffered as a group by [`MateGroups`] and fixed together by [`fix_mates`].
+use rust_htslib::bam::Record;
+use std::error;
+use std::mem;
+
+use crate::ops::apply;
+use crate::options::{MateTag, ReadOfPair};
+use crate::plan::{Plan, TagSpec};
+
+/// Buffers the records of one read name at a time, as they arrive from grouped input.
+#[derive(Debug, Default)]
+pub struct MateGroups {
+    group: Vec<Record>,
+}
+
+impl MateGroups {
+    /// Creates an empty buffer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the next record, returning the group before it once a record of another name
+    /// arrives.
+    pub fn push(&mut self, record: Record) -> Option<Vec<Record>> {
+        let done = self
+            .group
+            .first()
+            .is_some_and(|first| first.qname() != record.qname());
+        let previous = done.then(|| mem::take(&mut self.group));
+        self.group.push(record);
+        previous
+    }
+
+    /// Returns the last group, which may be empty.
+    pub fn finish(self) -> Vec<Record> {
+        self.group
+    }
+}
+
+/// Copies tags from the primary record of the source mate to every other record of its name.
+///
+/// A tag given with an operation is re-oriented by it on records whose strand differs from the
+/// source's, and copied unchanged on the others; a tag without one is always copied unchanged.
+/// Tags the source lacks are left as they are, as are groups without a primary source record.
+///
+/// # Arguments
+///
+/// * `group` - The records of one read name
+/// * `tags` - The tags to propagate
+/// * `source` - The mate whose primary record the tags are taken from
+/// * `is_reverse` - Whether a record is reverse strand
+///
+/// # Returns
+///
+/// Returns the number of tag values propagated, or an error if a record cannot be edited.
+///
+pub fn fix_mates<F>(
+    group: &mut [Record],
+    tags: &[MateTag],
+    source: ReadOfPair,
+    mut is_reverse: F,
+) -> Result<usize, Box<dyn error::Error>>
+where
+    F: FnMut(&Record) -> Result<bool, Box<dyn error::Error>>,
+{
+    let Some(index) = group.iter().position(|record| {
+        record.flags() & source.flag() != 0 && !record.is_secondary() && !record.is_supplementary()
+    }) else {
+        return Ok(0);
+    };
+    let mate = group[index].clone();
+    let mate_reverse = is_reverse(&mate)?;
+    let plans: Vec<Option<Plan>> = tags
+        .iter()
+        .map(|tag| {
+            tag.operation.map(|operation| {
+                Plan::from_specs(vec![TagSpec {
+                    tag: tag.tag,
+                    operation,
+                }])
+            })
+        })
+        .collect();
+
+    let mut propagated = 0;
+    for (i, record) in group.iter_mut().enumerate() {
+        if i == index {
+            continue;
+        }
+        let flipped = is_reverse(record)? != mate_reverse;
+        for (tag, plan) in tags.iter().zip(&plans) {
+            let Ok(value) = mate.aux(&tag.tag) else {
+                continue;
+            };
+            if record.aux(&tag.tag).is_ok() {
+                record.remove_aux(&tag.tag)?;
+            }
+            record.push_aux(&tag.tag, value)?;
+            if let Some(plan) = plan.as_ref().filter(|_| flipped) {
+                apply(record, plan)?;
+            }
+            propagated += 1;
+        }
+    }
+    Ok(propagated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::Aux;
+
+    /// Builds a record of a read name with a flag, carrying a `BC` barcode if given.
+    fn record(qname: &[u8], flags: u16, barcode: Option<&str>) -> Record {
+        let mut record = Record::new();
+        record.set_qname(qname);
+        record.set_flags(flags);
+        if let Some(barcode) = barcode {
+            record.push_aux(b"BC", Aux::String(barcode)).unwrap();
+        }
+        record
+    }
+
+    fn barcode(record: &Record) -> Option<String> {
+        match record.aux(b"BC") {
+            Ok(Aux::String(value)) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_mate_groups() {
+        let mut groups = MateGroups::new();
+        assert!(groups.push(record(b"a", 0x41, None)).is_none());
+        assert!(groups.push(record(b"a", 0x81, None)).is_none());
+        let group = groups.push(record(b"b", 0x41, None)).unwrap();
+        assert_eq!(group.len(), 2);
+        let last = groups.finish();
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].qname(), b"b");
+    }
+
+    #[test]
+    fn test_fix_mates() {
+        let tags = [MateTag::parse("BC:revcomp").unwrap()];
+        let reverse = |record: &Record| Ok(record.is_reverse());
+
+        // The second read, on the opposite strand, gets the reverse complement.
+        let mut group = vec![
+            record(b"a", 0x41 | 0x20, Some("AACG")),
+            record(b"a", 0x81 | 0x10, Some("NNNN")),
+        ];
+        assert_eq!(
+            fix_mates(&mut group, &tags, ReadOfPair::First, reverse).unwrap(),
+            1
+        );
+        assert_eq!(barcode(&group[0]).as_deref(), Some("AACG"));
+        assert_eq!(barcode(&group[1]).as_deref(), Some("CGTT"));
+
+        // A supplementary record of the first read on its strand gets the value unchanged.
+        let mut group = vec![
+            record(b"a", 0x81, None),
+            record(b"a", 0x41, Some("AACG")),
+            record(b"a", 0x41 | 0x800, None),
+        ];
+        assert_eq!(
+            fix_mates(&mut group, &tags, ReadOfPair::First, reverse).unwrap(),
+            2
+        );
+        assert_eq!(barcode(&group[0]).as_deref(), Some("AACG"));
+        assert_eq!(barcode(&group[2]).as_deref(), Some("AACG"));
+
+        // Copies ignore strands, and groups without the source are left alone.
+        let copy = [MateTag::parse("BC").unwrap()];
+        let mut group = vec![
+            record(b"a", 0x41, None),
+            record(b"a", 0x81 | 0x10, Some("AACG")),
+        ];
+        assert_eq!(
+            fix_mates(&mut group, &copy, ReadOfPair::Second, reverse).unwrap(),
+            1
+        );
+        assert_eq!(barcode(&group[0]).as_deref(), Some("AACG"));
+        let mut orphan = vec![record(b"a", 0x81, Some("AACG"))];
+        assert_eq!(
+            fix_mates(&mut orphan, &copy, ReadOfPair::First, reverse).unwrap(),
+            0
+        );
+    }
+}
@@ -0,0 +1,1626 @@
+//! Operation plans describing which SAM tags to transform and how.
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::preset::Preset;
+
+/// A two-byte SAM tag name.
+pub type Tag = [u8; 2];
+
+/// The transformation applied to a tag value on reverse strand alignments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Reverse the order of array-like values.
+    Reverse,
+    /// Reverse complement array-like string values.
+    ReverseComplement,
+    /// Reverse a SOLiD colorspace read, a primer base followed by colors, re-deriving the primer
+    /// base; see [`crate::dna::reverse_colorspace`].
+    ReverseColorspace,
+    /// Complement array-like string values without reversing them.
+    Complement,
+    /// Rename the tag, keeping its value; only valid as the last operation of a chain.
+    Rename(Tag),
+    /// Re-encode a sequence stored as a `Z` string or `B:C` byte array in the other
+    /// representation, keeping its bytes, for downstream tools that insist on one of them.
+    Convert(Encoding),
+}
+
+impl Operation {
+    /// Parses an operation name as accepted by `--tag TAG:OP`.
+    ///
+    /// `rev` and `reverse` map to [`Operation::Reverse`]; `revcomp` and `reverse-complement`
+    /// map to [`Operation::ReverseComplement`]; `revcs` and `reverse-colorspace` map to
+    /// [`Operation::ReverseColorspace`]; `comp` and `complement` map to
+    /// [`Operation::Complement`]. Renames and conversions take an argument and are parsed by
+    /// [`parse_tag_chain`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rev" | "reverse" => Some(Operation::Reverse),
+            "revcomp" | "reverse-complement" => Some(Operation::ReverseComplement),
+            "revcs" | "reverse-colorspace" => Some(Operation::ReverseColorspace),
+            "comp" | "complement" => Some(Operation::Complement),
+            _ => None,
+        }
+    }
+
+    /// Returns the short name of the operation as accepted by `--tag TAG:OP`, without the new
+    /// tag of a rename; see the [`fmt::Display`] implementation for the full form.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Reverse => "rev",
+            Operation::ReverseComplement => "revcomp",
+            Operation::ReverseColorspace => "revcs",
+            Operation::Complement => "comp",
+            Operation::Rename(_) => "rename",
+            Operation::Convert(_) => "as",
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Rename(tag) => write!(f, "rename={}", String::from_utf8_lossy(tag)),
+            Operation::Convert(encoding) => write!(f, "as={}", encoding.name()),
+            operation => f.write_str(operation.name()),
+        }
+    }
+}
+
+/// A representation of a sequence in a SAM tag, chosen by an `as=` conversion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// A `Z` string of printable characters.
+    String,
+    /// A `B:C` array of unsigned bytes.
+    ByteArray,
+}
+
+impl Encoding {
+    /// Parses an encoding as written after `as=`: `Z` or `B:C`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Z" => Some(Encoding::String),
+            "B:C" => Some(Encoding::ByteArray),
+            _ => None,
+        }
+    }
+
+    /// Returns the encoding as written after `as=`, which is its SAM type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::String => "Z",
+            Encoding::ByteArray => "B:C",
+        }
+    }
+}
+
+/// What to do when a tag to reverse complement holds a value that is not a sequence, such as a
+/// 16-bit or floating point `B` array.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonSequence {
+    /// Fail with an error naming the tag.
+    Error,
+    /// Reverse the value without complementing it.
+    ReverseOnly,
+    /// Leave the value unchanged.
+    #[default]
+    Skip,
+}
+
+impl NonSequence {
+    /// Parses a policy name as accepted by `--on-revcomp-nonsequence`: `error`, `reverse-only`,
+    /// or `skip`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(NonSequence::Error),
+            "reverse-only" => Some(NonSequence::ReverseOnly),
+            "skip" => Some(NonSequence::Skip),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the policy as accepted by `--on-revcomp-nonsequence`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            NonSequence::Error => "error",
+            NonSequence::ReverseOnly => "reverse-only",
+            NonSequence::Skip => "skip",
+        }
+    }
+}
+
+impl FromStr for NonSequence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s).ok_or_else(|| {
+            format!("Unknown policy `{s}`; expected one of error, reverse-only, or skip")
+        })
+    }
+}
+
+/// What to do when the tags of a companion pair hold values of different lengths, which
+/// suggests upstream corruption.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PairMismatch {
+    /// Fail with an error naming the record and both lengths.
+    #[default]
+    Error,
+    /// Log a warning naming the record and transform the pair anyway.
+    Warn,
+}
+
+impl PairMismatch {
+    /// Parses a policy name as accepted by `--on-pair-length-mismatch`: `error` or `warn`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(PairMismatch::Error),
+            "warn" => Some(PairMismatch::Warn),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the policy as accepted by `--on-pair-length-mismatch`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PairMismatch::Error => "error",
+            PairMismatch::Warn => "warn",
+        }
+    }
+}
+
+impl FromStr for PairMismatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s)
+            .ok_or_else(|| format!("Unknown policy `{s}`; expected one of error or warn"))
+    }
+}
+
+/// What to do when a record carries a planned tag more than once, which the SAM specification
+/// forbids but some malformed files do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicateTags {
+    /// Transform the first occurrence and leave the others as they are, as tag lookups see
+    /// only the first.
+    #[default]
+    First,
+    /// Transform every occurrence.
+    All,
+    /// Fail with an error naming the tag.
+    Error,
+}
+
+impl DuplicateTags {
+    /// Parses a policy name as accepted by `--on-duplicate-tag`: `first`, `all`, or `error`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "first" => Some(DuplicateTags::First),
+            "all" => Some(DuplicateTags::All),
+            "error" => Some(DuplicateTags::Error),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the policy as accepted by `--on-duplicate-tag`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DuplicateTags::First => "first",
+            DuplicateTags::All => "all",
+            DuplicateTags::Error => "error",
+        }
+    }
+}
+
+impl FromStr for DuplicateTags {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s)
+            .ok_or_else(|| format!("Unknown policy `{s}`; expected one of first, all, or error"))
+    }
+}
+
+/// What decides whether a tag is transformed on a record: its own strand, its mate's, a tag, or
+/// nothing at all.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StrandSource {
+    /// The record's own strand, from its flag or the run's strand tag.
+    #[default]
+    Read,
+    /// The mate's strand, from the mate reverse flag (0x20), for tags describing the mate.
+    Mate,
+    /// A tag holding `+` or `-`.
+    Tag(Tag),
+    /// Every record is transformed, whatever its strand.
+    Always,
+}
+
+impl StrandSource {
+    /// Parses a strand source as accepted by `--tag TAG:OP:strand=SOURCE`: `read`, `mate`,
+    /// `always`, or the name of a tag holding `+` or `-`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(StrandSource::Read),
+            "mate" => Some(StrandSource::Mate),
+            "always" => Some(StrandSource::Always),
+            _ => parse_tag(name).ok().map(StrandSource::Tag),
+        }
+    }
+}
+
+/// The number of columns of a matrix packed into a `B` array.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Columns {
+    /// A fixed number of columns, the same on every record.
+    Fixed(usize),
+    /// The number of columns held by an integer tag of each record.
+    Tag(Tag),
+}
+
+/// The shape of a matrix packed row-major into a `B` array, such as per-base signal with one
+/// column per channel, whose rows are reversed while each row keeps its internal order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MatrixShape {
+    /// The number of rows, or None if any number of rows is allowed.
+    pub rows: Option<usize>,
+    /// The number of columns.
+    pub columns: Columns,
+}
+
+impl MatrixShape {
+    /// Parses a shape as accepted by `--matrix TAG:SHAPE`: `ROWSxCOLS`, where `ROWS` is a
+    /// number or `*` for any number of rows, and `COLS` is a number or the name of an integer
+    /// tag holding the number of columns, e.g. `100x4`, `*x4`, or `*xNC`. Dimensions must be
+    /// positive.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let (rows, columns) = name.split_once('x')?;
+        let rows = match rows {
+            "*" => None,
+            rows => Some(rows.parse().ok().filter(|&rows| rows > 0)?),
+        };
+        let columns = match columns.parse::<usize>() {
+            Ok(0) => return None,
+            Ok(columns) => Columns::Fixed(columns),
+            Err(_) => Columns::Tag(parse_tag(columns).ok()?),
+        };
+        Some(Self { rows, columns })
+    }
+
+    /// Returns the shape as accepted by `--matrix TAG:SHAPE`.
+    pub fn name(&self) -> String {
+        let rows = self.rows.map_or("*".to_string(), |rows| rows.to_string());
+        match self.columns {
+            Columns::Fixed(columns) => format!("{rows}x{columns}"),
+            Columns::Tag(tag) => format!("{rows}x{}", String::from_utf8_lossy(&tag)),
+        }
+    }
+}
+
+/// Why a tag name or tag specification failed to parse.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The tag name was not exactly 2 characters; holds the number of characters found.
+    InvalidLength(usize),
+    /// The tag name did not match `[A-Za-z][A-Za-z0-9]` as required by the SAM specification.
+    InvalidCharacter(char),
+    /// A `TAG:OP` specification had no `:OP` part.
+    MissingOperation,
+    /// A `TAG:OP` specification named an unknown operation.
+    UnknownOperation(String),
+    /// The same tag was given more than one operation.
+    DuplicateTag,
+    /// A `SEQ:QUAL` tag pair had no `:QUAL` part.
+    MissingCompanion,
+    /// A `TAG:OP:strand=SOURCE` specification named an unknown strand source, or had a third
+    /// part other than `strand=SOURCE`; holds that part.
+    UnknownStrandSource(String),
+    /// The tags of a `SEQ:QUAL` pair were given different strand sources.
+    MismatchedStrandSources,
+    /// A `TAG:ROWSxCOLS` matrix specification had a malformed shape; holds the shape.
+    InvalidMatrixShape(String),
+    /// A chain of operations such as `XB:comp,rev,rename=BC` was not coherent; holds the
+    /// operation at fault and why.
+    IncoherentChain {
+        /// The operation at fault, as given.
+        operation: String,
+        /// Why the operation cannot be chained there.
+        reason: &'static str,
+    },
+}
+
+/// A detailed error from parsing user-supplied tag names or tag specifications.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// The token that failed to parse, exactly as supplied.
+    pub token: String,
+    /// The reason the token was rejected.
+    pub kind: ParseErrorKind,
+}
+
+impl ParseError {
+    /// Returns the byte range of the part of the token at fault, such as the misspelled
+    /// operation of `QT:rve`, for pointing at it beneath the error. Parts that are missing
+    /// altogether are located at the end of the token, where they belong.
+    pub fn span(&self) -> Range<usize> {
+        let token = &self.token;
+        let whole = 0..token.len();
+        let end = token.len()..token.len();
+        let found = |part: &str| {
+            token
+                .rfind(part)
+                .filter(|_| !part.is_empty())
+                .map_or(whole.clone(), |start| start..start + part.len())
+        };
+        let mut parts = token.split(':').scan(0, |start, part| {
+            let range = *start..*start + part.len();
+            *start = range.end + 1;
+            Some(range)
+        });
+        match &self.kind {
+            ParseErrorKind::InvalidLength(_) => parts
+                .find(|range| parse_tag(&token[range.clone()]).is_err())
+                .unwrap_or(whole),
+            ParseErrorKind::DuplicateTag => parts.next().unwrap_or(whole),
+            ParseErrorKind::InvalidCharacter(c) => parts
+                .find(|range| parse_tag(&token[range.clone()]).is_err())
+                .and_then(|range| {
+                    let offset = token[range.clone()].find(*c)?;
+                    Some(range.start + offset..range.start + offset + c.len_utf8())
+                })
+                .unwrap_or(whole),
+            ParseErrorKind::UnknownOperation(op) if op.is_empty() => parts.nth(1).unwrap_or(end),
+            ParseErrorKind::UnknownOperation(op) => found(op),
+            ParseErrorKind::IncoherentChain { operation, .. } => found(operation),
+            ParseErrorKind::UnknownStrandSource(part)
+            | ParseErrorKind::InvalidMatrixShape(part) => found(part),
+            ParseErrorKind::MissingOperation | ParseErrorKind::MissingCompanion => end,
+            ParseErrorKind::MismatchedStrandSources => whole,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let token = &self.token;
+        match &self.kind {
+            ParseErrorKind::InvalidLength(n) => write!(
+                f,
+                "Tag name must be exactly 2 characters: {token} (found {n})"
+            ),
+            ParseErrorKind::InvalidCharacter(c) => write!(
+                f,
+                "Tag name must match [A-Za-z][A-Za-z0-9]: {token} (invalid character {c:?})"
+            ),
+            ParseErrorKind::MissingOperation => write!(
+                f,
+                "Tag specification must be TAG:OP, e.g. QT:rev or BC:revcomp: {token}"
+            ),
+            ParseErrorKind::UnknownOperation(op) => write!(
+                f,
+                "Unknown operation {op:?} in tag specification {token}; expected rev, revcomp, \
+                 revcs, comp, rename=TAG, as=Z, or as=B:C"
+            ),
+            ParseErrorKind::DuplicateTag => {
+                write!(f, "Tag given more than one operation: {token}")
+            }
+            ParseErrorKind::MissingCompanion => {
+                write!(f, "Tag pair must be SEQ:QUAL, e.g. BC:QT: {token}")
+            }
+            ParseErrorKind::UnknownStrandSource(source) => write!(
+                f,
+                "Unknown strand source {source:?} in tag specification {token}; expected \
+                 strand=read, strand=mate, strand=always, or strand=TAG"
+            ),
+            ParseErrorKind::MismatchedStrandSources => {
+                write!(f, "Tags of a pair must share a strand source: {token}")
+            }
+            ParseErrorKind::InvalidMatrixShape(shape) => write!(
+                f,
+                "Invalid matrix shape {shape:?} in {token}; expected ROWSxCOLS, e.g. 100x4, *x4, \
+                 or *xNC"
+            ),
+            ParseErrorKind::IncoherentChain { operation, reason } => {
+                write!(f, "Cannot chain {operation:?} in {token}: {reason}")
+            }
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// A single SAM tag paired with the operation to apply to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TagSpec {
+    /// The SAM tag to transform.
+    pub tag: Tag,
+    /// The operation to apply to the tag value.
+    pub operation: Operation,
+}
+
+/// A sequence tag and the tag holding its qualities, which are always transformed together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TagPair {
+    /// The tag holding a sequence, which is reverse complemented.
+    pub sequence: Tag,
+    /// The tag holding the sequence's qualities, which is reversed.
+    pub quality: Tag,
+}
+
+/// An ordered collection of tag specifications applied to every reverse strand alignment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    specs: Vec<TagSpec>,
+    strands: Vec<(Tag, StrandSource)>,
+    pairs: Vec<TagPair>,
+    matrices: Vec<(Tag, MatrixShape)>,
+    expected: Vec<(Tag, &'static [&'static str])>,
+    pair_mismatch: PairMismatch,
+    nonsequence: NonSequence,
+    duplicate_tags: DuplicateTags,
+}
+
+impl Plan {
+    /// Builds a plan from lists of tag names to reverse and to reverse complement.
+    ///
+    /// Tags to reverse are applied before tags to reverse complement.
+    ///
+    /// # Arguments
+    ///
+    /// * `rev` - SAM tags to reverse (e.g., base qualities)
+    /// * `revcomp` - SAM tags to reverse complement (e.g., sequences)
+    ///
+    /// # Returns
+    ///
+    /// Returns the plan on success, or an error if any tag name is not exactly 2 characters.
+    ///
+    pub fn new(rev: &[String], revcomp: &[String]) -> Result<Self, Box<dyn error::Error>> {
+        Ok(Self::parse(rev, revcomp, &[])?)
+    }
+
+    /// Parses a plan from plain tag lists and `TAG:OP` specifications.
+    ///
+    /// Tags to reverse are applied first, then tags to reverse complement, then the
+    /// specifications in the order given. A specification may chain operations applied in
+    /// order, such as `XB:comp,rev,rename=BC`, and may end with `:strand=SOURCE` to choose what
+    /// decides whether its tag is transformed; see [`parse_tag_spec_with_strand`].
+    /// This performs no I/O, so wrappers can use it to validate user input before launching a
+    /// run.
+    ///
+    /// # Arguments
+    ///
+    /// * `rev` - SAM tags to reverse, as given to `--rev`
+    /// * `revcomp` - SAM tags to reverse complement, as given to `--revcomp`
+    /// * `specs` - Tag specifications such as `QT:rev` or `MB:revcomp:strand=mate`, as given to
+    ///   `--tag`
+    ///
+    /// # Returns
+    ///
+    /// Returns the plan on success, or a [`ParseError`] naming the first offending token.
+    ///
+    pub fn parse(rev: &[String], revcomp: &[String], specs: &[String]) -> Result<Self, ParseError> {
+        let mut parsed = Vec::with_capacity(rev.len() + revcomp.len() + specs.len());
+        for (tokens, operation) in [
+            (rev, Operation::Reverse),
+            (revcomp, Operation::ReverseComplement),
+        ] {
+            for token in tokens {
+                let tag = parse_tag(token)?;
+                parsed.push(TagSpec { tag, operation });
+                check_unique(&parsed, token)?;
+            }
+        }
+        let mut strands = Vec::new();
+        for token in specs {
+            let (chain, strand) = parse_tag_spec_with_strand(token)?;
+            parsed.push(chain[0]);
+            check_unique(&parsed, token)?;
+            parsed.extend_from_slice(&chain[1..]);
+            if strand != StrandSource::Read {
+                strands.push((chain[0].tag, strand));
+            }
+        }
+        Ok(Self {
+            strands,
+            ..Self::from_specs(parsed)
+        })
+    }
+
+    /// Builds a plan from an ordered list of tag specifications.
+    pub fn from_specs(specs: Vec<TagSpec>) -> Self {
+        Self {
+            specs,
+            strands: Vec::new(),
+            pairs: Vec::new(),
+            matrices: Vec::new(),
+            expected: Vec::new(),
+            pair_mismatch: PairMismatch::default(),
+            nonsequence: NonSequence::default(),
+            duplicate_tags: DuplicateTags::default(),
+        }
+    }
+
+    /// Returns the plan with companion tag pairs such as `BC:QT` added.
+    ///
+    /// The sequence tag of each pair is reverse complemented and its quality tag reversed. Tags
+    /// already in the plan with the same operation are not added twice.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - Tag pairs such as `BC:QT`, as given to `--pair`
+    ///
+    /// # Returns
+    ///
+    /// Returns the plan on success, or a [`ParseError`] naming the first pair that is malformed
+    /// or gives a tag a different operation than the plan already does.
+    ///
+    pub fn with_pairs(mut self, tokens: &[String]) -> Result<Self, ParseError> {
+        for token in tokens {
+            let pair = parse_tag_pair(token)?;
+            let duplicate = || ParseError {
+                token: token.to_string(),
+                kind: ParseErrorKind::DuplicateTag,
+            };
+            if pair.sequence == pair.quality {
+                return Err(duplicate());
+            }
+            if self.strand_source(&pair.sequence) != self.strand_source(&pair.quality) {
+                return Err(ParseError {
+                    token: token.to_string(),
+                    kind: ParseErrorKind::MismatchedStrandSources,
+                });
+            }
+            for (tag, operation) in [
+                (pair.sequence, Operation::ReverseComplement),
+                (pair.quality, Operation::Reverse),
+            ] {
+                match self.specs.iter().find(|spec| spec.tag == tag) {
+                    Some(spec) if spec.operation != operation => return Err(duplicate()),
+                    Some(_) => {}
+                    None => self.specs.push(TagSpec { tag, operation }),
+                }
+            }
+            self.pairs.push(pair);
+        }
+        Ok(self)
+    }
+
+    /// Returns the plan with matrix tags such as `SG:*x4` added, whose `B` arrays are reversed
+    /// row by row.
+    ///
+    /// Each row keeps its internal order, so per-base values with one column per channel stay
+    /// matched to their channels. Tags already in the plan to reverse gain the shape.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - Matrix specifications such as `SG:100x4` or `SG:*xNC`, as given to
+    ///   `--matrix`
+    ///
+    /// # Returns
+    ///
+    /// Returns the plan on success, or a [`ParseError`] naming the first specification that is
+    /// malformed, repeats a matrix tag, or gives a tag a different operation than the plan
+    /// already does.
+    ///
+    pub fn with_matrices(mut self, tokens: &[String]) -> Result<Self, ParseError> {
+        for token in tokens {
+            let (tag, shape) = parse_matrix(token)?;
+            let duplicate = || ParseError {
+                token: token.to_string(),
+                kind: ParseErrorKind::DuplicateTag,
+            };
+            if self.matrix_shape(&tag).is_some() {
+                return Err(duplicate());
+            }
+            match self.specs.iter().find(|spec| spec.tag == tag) {
+                Some(spec) if spec.operation != Operation::Reverse => return Err(duplicate()),
+                Some(_) => {}
+                None => self.specs.push(TagSpec {
+                    tag,
+                    operation: Operation::Reverse,
+                }),
+            }
+            self.matrices.push((tag, shape));
+        }
+        Ok(self)
+    }
+
+    /// Returns the matrix shape of `tag`, or None if its values are reversed element by
+    /// element.
+    pub fn matrix_shape(&self, tag: &Tag) -> Option<MatrixShape> {
+        self.matrices
+            .iter()
+            .find(|(matrix_tag, _)| matrix_tag == tag)
+            .map(|(_, shape)| *shape)
+    }
+
+    /// Returns the matrix tags of the plan with their shapes.
+    pub fn matrices(&self) -> &[(Tag, MatrixShape)] {
+        &self.matrices
+    }
+
+    /// Returns true if any tag is reversed as a matrix.
+    pub fn has_matrices(&self) -> bool {
+        !self.matrices.is_empty()
+    }
+
+    /// Returns true if any tag is converted between a `Z` string and a `B:C` byte array.
+    pub fn has_conversions(&self) -> bool {
+        self.specs
+            .iter()
+            .any(|spec| matches!(spec.operation, Operation::Convert(_)))
+    }
+
+    /// Returns the plan with the tags, companion pairs, and validated tags of a preset added.
+    ///
+    /// Tags already in the plan with the same operation, and pairs and validated tags already in
+    /// the plan, are not added twice.
+    ///
+    /// # Arguments
+    ///
+    /// * `preset` - The preset, as given to `--preset`
+    ///
+    /// # Returns
+    ///
+    /// Returns the plan on success, or a [`ParseError`] naming the first specification or pair
+    /// of the preset that gives a tag a different operation than the plan already does.
+    ///
+    pub fn with_preset(mut self, preset: Preset) -> Result<Self, ParseError> {
+        for token in preset.specs() {
+            let spec = parse_tag_spec(token)?;
+            match self.specs.iter().find(|existing| existing.tag == spec.tag) {
+                Some(existing) if existing.operation != spec.operation => {
+                    return Err(ParseError {
+                        token: token.to_string(),
+                        kind: ParseErrorKind::DuplicateTag,
+                    });
+                }
+                Some(_) => {}
+                None => self.specs.push(spec),
+            }
+        }
+        for (token, values) in preset.expected_values() {
+            let tag = parse_tag(token)?;
+            if !self.expected.iter().any(|(expected, _)| *expected == tag) {
+                self.expected.push((tag, values));
+            }
+        }
+        let mut pairs = Vec::new();
+        for token in preset.pairs() {
+            if !self.pairs.contains(&parse_tag_pair(token)?) {
+                pairs.push(token.to_string());
+            }
+        }
+        self.with_pairs(&pairs)
+    }
+
+    /// Returns the plan with specifications added for tags it does not already transform, e.g.
+    /// those chosen by `--auto-orient`; the plan's own specification of a tag wins.
+    pub fn with_specs(mut self, specs: impl IntoIterator<Item = TagSpec>) -> Self {
+        for spec in specs {
+            if !self.specs.iter().any(|existing| existing.tag == spec.tag) {
+                self.specs.push(spec);
+            }
+        }
+        self
+    }
+
+    /// Returns what decides whether `tag` is transformed, [`StrandSource::Read`] unless its
+    /// specification chose another source.
+    pub fn strand_source(&self, tag: &Tag) -> StrandSource {
+        self.strands
+            .iter()
+            .find(|(strand_tag, _)| strand_tag == tag)
+            .map_or(StrandSource::Read, |(_, source)| *source)
+    }
+
+    /// Returns true if any tag is transformed by a strand source other than the record's own.
+    pub fn has_strand_sources(&self) -> bool {
+        !self.strands.is_empty()
+    }
+
+    /// Returns the plan restricted to the tags whose strand source marks a record as reverse
+    /// strand, keeping companion pairs only when both their tags are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `is_reverse` - Decides whether a strand source marks the record reverse strand
+    ///
+    /// # Returns
+    ///
+    /// Returns the plan itself when every tag is kept, the restricted plan otherwise, or the
+    /// first error from `is_reverse`.
+    ///
+    pub fn restrict<F, E>(&self, mut is_reverse: F) -> Result<Cow<'_, Plan>, E>
+    where
+        F: FnMut(StrandSource) -> Result<bool, E>,
+    {
+        let mut kept = Vec::with_capacity(self.specs.len());
+        for spec in &self.specs {
+            if is_reverse(self.strand_source(&spec.tag))? {
+                kept.push(*spec);
+            }
+        }
+        if kept.len() == self.specs.len() {
+            return Ok(Cow::Borrowed(self));
+        }
+        let is_kept = |tag: &Tag| kept.iter().any(|spec| spec.tag == *tag);
+        let pairs = self
+            .pairs
+            .iter()
+            .filter(|pair| is_kept(&pair.sequence) && is_kept(&pair.quality))
+            .copied()
+            .collect();
+        let strands = self
+            .strands
+            .iter()
+            .filter(|(tag, _)| is_kept(tag))
+            .copied()
+            .collect();
+        let matrices = self
+            .matrices
+            .iter()
+            .filter(|(tag, _)| is_kept(tag))
+            .copied()
+            .collect();
+        Ok(Cow::Owned(Plan {
+            specs: kept,
+            strands,
+            pairs,
+            matrices,
+            expected: self.expected.clone(),
+            pair_mismatch: self.pair_mismatch,
+            nonsequence: self.nonsequence,
+            duplicate_tags: self.duplicate_tags,
+        }))
+    }
+
+    /// Returns the tags validated on reverse strand records without being transformed, each
+    /// with the `Z` values it may hold.
+    pub fn expected_values(&self) -> &[(Tag, &'static [&'static str])] {
+        &self.expected
+    }
+
+    /// Returns the companion tag pairs of the plan.
+    pub fn pairs(&self) -> &[TagPair] {
+        &self.pairs
+    }
+
+    /// Returns the plan with `policy` deciding how companion pairs of different lengths are
+    /// handled.
+    pub fn with_pair_mismatch(mut self, policy: PairMismatch) -> Self {
+        self.pair_mismatch = policy;
+        self
+    }
+
+    /// Returns how companion pairs of different lengths are handled.
+    pub fn pair_mismatch(&self) -> PairMismatch {
+        self.pair_mismatch
+    }
+
+    /// Returns the plan with `policy` deciding how tags to reverse complement that do not hold
+    /// a sequence are handled.
+    pub fn with_nonsequence(mut self, policy: NonSequence) -> Self {
+        self.nonsequence = policy;
+        self
+    }
+
+    /// Returns how tags to reverse complement that do not hold a sequence are handled.
+    pub fn nonsequence(&self) -> NonSequence {
+        self.nonsequence
+    }
+
+    /// Returns the plan with `policy` deciding how planned tags a record carries more than once
+    /// are handled.
+    pub fn with_duplicate_tags(mut self, policy: DuplicateTags) -> Self {
+        self.duplicate_tags = policy;
+        self
+    }
+
+    /// Returns how planned tags a record carries more than once are handled.
+    pub fn duplicate_tags(&self) -> DuplicateTags {
+        self.duplicate_tags
+    }
+
+    /// Returns the tag specifications in the order they are applied.
+    pub fn specs(&self) -> &[TagSpec] {
+        &self.specs
+    }
+
+    /// Returns true if the plan has a specification for `tag`.
+    pub fn contains(&self, tag: &Tag) -> bool {
+        self.specs.iter().any(|spec| spec.tag == *tag)
+    }
+
+    /// Returns true if the plan contains no tag specifications.
+    pub fn is_empty(&self) -> bool {
+        self.specs.is_empty()
+    }
+}
+
+/// Returns an error naming `token` if the last spec in `specs` repeats an earlier tag.
+fn check_unique(specs: &[TagSpec], token: &str) -> Result<(), ParseError> {
+    match specs.split_last() {
+        Some((last, rest)) if rest.iter().any(|spec| spec.tag == last.tag) => Err(ParseError {
+            token: token.to_string(),
+            kind: ParseErrorKind::DuplicateTag,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Parses a single SAM tag name.
+///
+/// # Arguments
+///
+/// * `token` - The tag name, e.g. `QT`
+///
+/// # Returns
+///
+/// Returns the 2-byte tag on success, or a [`ParseError`] if the name is not a valid SAM tag.
+///
+pub fn parse_tag(token: &str) -> Result<Tag, ParseError> {
+    let error = |kind| ParseError {
+        token: token.to_string(),
+        kind,
+    };
+    let chars: Vec<char> = token.chars().collect();
+    let [first, second] = chars[..] else {
+        return Err(error(ParseErrorKind::InvalidLength(chars.len())));
+    };
+    if !first.is_ascii_alphabetic() {
+        return Err(error(ParseErrorKind::InvalidCharacter(first)));
+    }
+    if !second.is_ascii_alphanumeric() {
+        return Err(error(ParseErrorKind::InvalidCharacter(second)));
+    }
+    Ok([first as u8, second as u8])
+}
+
+/// Parses a `TAG:OP` specification such as `QT:rev` or `BC:revcomp`.
+///
+/// # Arguments
+///
+/// * `token` - The specification to parse
+///
+/// # Returns
+///
+/// Returns the tag specification on success, or a [`ParseError`] describing why it is invalid.
+///
+pub fn parse_tag_spec(token: &str) -> Result<TagSpec, ParseError> {
+    let error = |kind| ParseError {
+        token: token.to_string(),
+        kind,
+    };
+    let Some((tag, operation)) = token.split_once(':') else {
+        return Err(error(ParseErrorKind::MissingOperation));
+    };
+    let tag = parse_tag(tag).map_err(|e| error(e.kind))?;
+    let operation = parse_operation(operation).map_err(error)?;
+    Ok(TagSpec { tag, operation })
+}
+
+/// Parses a single operation: a name accepted by [`Operation::from_name`], `rename=TAG`, or
+/// `as=ENCODING` with an encoding accepted by [`Encoding::from_name`].
+fn parse_operation(name: &str) -> Result<Operation, ParseErrorKind> {
+    if let Some(tag) = name.strip_prefix("rename=") {
+        return parse_tag(tag).map(Operation::Rename).map_err(|e| e.kind);
+    }
+    name.strip_prefix("as=")
+        .map_or_else(
+            || Operation::from_name(name),
+            |encoding| Encoding::from_name(encoding).map(Operation::Convert),
+        )
+        .ok_or_else(|| ParseErrorKind::UnknownOperation(name.to_string()))
+}
+
+/// Parses a `TAG:OP,OP,...` specification chaining operations applied in order, such as
+/// `XB:comp,rev,rename=BC`, so complex per-tag fixes take a single pass over a file.
+///
+/// A chain may also convert the tag between a `Z` string and a `B:C` byte array with `as=Z` or
+/// `as=B:C`, such as `XB:revcomp,as=Z`; the conversion keeps the value's bytes, so where it
+/// falls in the chain does not change the result.
+///
+/// A chain must be coherent: no operation may appear twice, a rename must come last and give
+/// the tag a new name, a tag may be converted only once, and colorspace reversal cannot be
+/// combined with complementing or with conversion to a byte array.
+///
+/// # Arguments
+///
+/// * `token` - The specification to parse
+///
+/// # Returns
+///
+/// Returns one tag specification for each operation, in order, or a [`ParseError`] naming the
+/// operation at fault.
+///
+pub fn parse_tag_chain(token: &str) -> Result<Vec<TagSpec>, ParseError> {
+    let error = |kind| ParseError {
+        token: token.to_string(),
+        kind,
+    };
+    let Some((tag, operations)) = token.split_once(':') else {
+        return Err(error(ParseErrorKind::MissingOperation));
+    };
+    let tag = parse_tag(tag).map_err(|e| error(e.kind))?;
+    let complements = |operation: Operation| {
+        matches!(
+            operation,
+            Operation::ReverseComplement | Operation::Complement
+        )
+    };
+    let mut chain: Vec<TagSpec> = Vec::new();
+    for name in operations.split(',') {
+        let operation = parse_operation(name).map_err(error)?;
+        let incoherent = |reason| {
+            Err(error(ParseErrorKind::IncoherentChain {
+                operation: name.to_string(),
+                reason,
+            }))
+        };
+        if chain
+            .iter()
+            .any(|spec| matches!(spec.operation, Operation::Rename(_)))
+        {
+            return incoherent("a rename must end the chain");
+        }
+        if chain.iter().any(|spec| spec.operation == operation) {
+            return incoherent("the operation is already in the chain");
+        }
+        let converts = |op: Operation| matches!(op, Operation::Convert(_));
+        if converts(operation) && chain.iter().any(|spec| converts(spec.operation)) {
+            return incoherent("the tag is already converted");
+        }
+        let colorspace = chain.iter().map(|spec| spec.operation).chain([operation]);
+        if colorspace
+            .clone()
+            .any(|op| op == Operation::ReverseColorspace)
+            && colorspace.clone().any(complements)
+        {
+            return incoherent("colorspace reads hold colors, which cannot be complemented");
+        }
+        if colorspace
+            .clone()
+            .any(|op| op == Operation::ReverseColorspace)
+            && colorspace
+                .clone()
+                .any(|op| op == Operation::Convert(Encoding::ByteArray))
+        {
+            return incoherent("colorspace reads are reversed only as Z strings");
+        }
+        if operation == Operation::Rename(tag) {
+            return incoherent("the tag is renamed to itself");
+        }
+        chain.push(TagSpec { tag, operation });
+    }
+    Ok(chain)
+}
+
+/// Parses a `TAG:OP` specification or chain, as read by [`parse_tag_chain`], optionally followed
+/// by `:strand=SOURCE`, such as `MB:revcomp:strand=mate`.
+///
+/// The source decides whether the tag is transformed on a record, as read by
+/// [`StrandSource::from_name`]; without one, the record's own strand does.
+///
+/// # Arguments
+///
+/// * `token` - The specification to parse
+///
+/// # Returns
+///
+/// Returns the tag specifications of the chain and its strand source on success, or a
+/// [`ParseError`] describing why it is invalid.
+///
+pub fn parse_tag_spec_with_strand(token: &str) -> Result<(Vec<TagSpec>, StrandSource), ParseError> {
+    let error = |kind| ParseError {
+        token: token.to_string(),
+        kind,
+    };
+    // The colon of an `as=B:C` conversion is part of the chain, not the start of a modifier.
+    let (spec, modifier) = match token
+        .match_indices(':')
+        .skip(1)
+        .find(|(offset, _)| !token[..*offset].ends_with("as=B"))
+    {
+        Some((offset, _)) => (&token[..offset], Some(&token[offset + 1..])),
+        None => (token, None),
+    };
+    let chain = parse_tag_chain(spec).map_err(|e| error(e.kind))?;
+    let strand = match modifier {
+        None => StrandSource::Read,
+        Some(modifier) => modifier
+            .strip_prefix("strand=")
+            .and_then(StrandSource::from_name)
+            .ok_or_else(|| error(ParseErrorKind::UnknownStrandSource(modifier.to_string())))?,
+    };
+    Ok((chain, strand))
+}
+
+/// Parses a `SEQ:QUAL` companion tag pair such as `BC:QT`.
+///
+/// # Arguments
+///
+/// * `token` - The tag pair to parse
+///
+/// # Returns
+///
+/// Returns the tag pair on success, or a [`ParseError`] describing why it is invalid.
+///
+pub fn parse_tag_pair(token: &str) -> Result<TagPair, ParseError> {
+    let error = |kind| ParseError {
+        token: token.to_string(),
+        kind,
+    };
+    let Some((sequence, quality)) = token.split_once(':') else {
+        return Err(error(ParseErrorKind::MissingCompanion));
+    };
+    Ok(TagPair {
+        sequence: parse_tag(sequence).map_err(|e| error(e.kind))?,
+        quality: parse_tag(quality).map_err(|e| error(e.kind))?,
+    })
+}
+
+/// Parses a `TAG:ROWSxCOLS` matrix specification such as `SG:100x4` or `SG:*xNC`; see
+/// [`MatrixShape::from_name`].
+///
+/// # Arguments
+///
+/// * `token` - The specification to parse
+///
+/// # Returns
+///
+/// Returns the tag and its matrix shape on success, or a [`ParseError`] describing why the
+/// specification is invalid.
+///
+pub fn parse_matrix(token: &str) -> Result<(Tag, MatrixShape), ParseError> {
+    let error = |kind| ParseError {
+        token: token.to_string(),
+        kind,
+    };
+    let Some((tag, shape)) = token.split_once(':') else {
+        return Err(error(ParseErrorKind::InvalidMatrixShape(String::new())));
+    };
+    let tag = parse_tag(tag).map_err(|e| error(e.kind))?;
+    let shape = MatrixShape::from_name(shape)
+        .ok_or_else(|| error(ParseErrorKind::InvalidMatrixShape(shape.to_string())))?;
+    Ok((tag, shape))
+}
+
+/// Validates and converts tag names to byte arrays.
+///
+/// # Arguments
+///
+/// * `tags` - Vector of tag name strings to validate and convert
+///
+/// # Returns
+///
+/// Returns a vector of 2-byte arrays on success, or an error if any tag name is not exactly 2 characters.
+///
+pub fn validate_tags(tags: &[String]) -> Result<Vec<Tag>, Box<dyn error::Error>> {
+    Ok(tags
+        .iter()
+        .map(|tag| parse_tag(tag))
+        .collect::<Result<_, _>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_tags_happy_path() {
+        let tags = vec!["QT".to_string(), "BC".to_string()];
+        let validated = validate_tags(&tags).expect("expected Ok for valid tags");
+        assert_eq!(validated, vec!([b'Q', b'T'], [b'B', b'C']));
+    }
+
+    #[test]
+    fn test_validate_tags_unhappy_path_invalid_length() {
+        let tags = vec!["Q".to_string(), "ABC".to_string(), "BC".to_string()];
+        let err = validate_tags(&tags).expect_err("expected Err for invalid tag lengths");
+        let msg = err.to_string();
+        assert!(
+            msg.contains("exactly 2 characters"),
+            "unexpected error: {}",
+            msg
+        );
+    }
+
+    #[test]
+    fn test_plan_orders_rev_before_revcomp() {
+        let plan = Plan::new(&["QT".to_string()], &["BC".to_string()]).unwrap();
+        assert_eq!(
+            plan.specs(),
+            &[
+                TagSpec {
+                    tag: *b"QT",
+                    operation: Operation::Reverse
+                },
+                TagSpec {
+                    tag: *b"BC",
+                    operation: Operation::ReverseComplement
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_tag_rejects_invalid_characters() {
+        let err = parse_tag("1A").unwrap_err();
+        assert_eq!(err.token, "1A");
+        assert_eq!(err.kind, ParseErrorKind::InvalidCharacter('1'));
+        let err = parse_tag("Q-").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::InvalidCharacter('-'));
+        let err = parse_tag("é").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::InvalidLength(1));
+    }
+
+    #[test]
+    fn test_parse_tag_spec() {
+        assert_eq!(
+            parse_tag_spec("QT:rev").unwrap(),
+            TagSpec {
+                tag: *b"QT",
+                operation: Operation::Reverse
+            }
+        );
+        assert_eq!(
+            parse_tag_spec("BC:reverse-complement").unwrap().operation,
+            Operation::ReverseComplement
+        );
+    }
+
+    #[test]
+    fn test_parse_error_span() {
+        let span = |token: &str| {
+            let error = parse_tag_spec(token).unwrap_err();
+            token[error.span()].to_string()
+        };
+        assert_eq!(span("QT:rve"), "rve");
+        assert_eq!(span("QTX:rev"), "QTX");
+        assert_eq!(span("Q_:rev"), "_");
+        assert_eq!(parse_tag_spec("QT").unwrap_err().span(), 2..2);
+        let error = parse_tag_pair("BC:QTX").unwrap_err();
+        assert_eq!(error.span(), 3..6);
+        let error = parse_tag_spec_with_strand("MB:revcomp:strand=moon").unwrap_err();
+        assert_eq!(error.span(), 11..22);
+    }
+
+    #[test]
+    fn test_operation_name_round_trips() {
+        for operation in [Operation::Reverse, Operation::ReverseComplement] {
+            assert_eq!(Operation::from_name(operation.name()), Some(operation));
+        }
+    }
+
+    #[test]
+    fn test_nonsequence_policy() {
+        assert_eq!(Plan::default().nonsequence(), NonSequence::Skip);
+        let plan = Plan::default().with_nonsequence(NonSequence::Error);
+        assert_eq!(plan.nonsequence(), NonSequence::Error);
+        assert_eq!(
+            "reverse-only".parse::<NonSequence>(),
+            Ok(NonSequence::ReverseOnly)
+        );
+        assert!("reverse".parse::<NonSequence>().is_err());
+    }
+
+    #[test]
+    fn test_parse_tag_spec_errors() {
+        assert_eq!(
+            parse_tag_spec("QT").unwrap_err().kind,
+            ParseErrorKind::MissingOperation
+        );
+        assert_eq!(
+            parse_tag_spec("QT:flip").unwrap_err().kind,
+            ParseErrorKind::UnknownOperation("flip".to_string())
+        );
+        let err = parse_tag_spec("QTX:rev").unwrap_err();
+        assert_eq!(err.token, "QTX:rev");
+        assert_eq!(err.kind, ParseErrorKind::InvalidLength(3));
+    }
+
+    #[test]
+    fn test_plan_parse_orders_lists_before_specs() {
+        let plan = Plan::parse(
+            &["QT".to_string()],
+            &["BC".to_string()],
+            &["OQ:rev".to_string()],
+        )
+        .unwrap();
+        let tags: Vec<Tag> = plan.specs().iter().map(|spec| spec.tag).collect();
+        assert_eq!(tags, vec![*b"QT", *b"BC", *b"OQ"]);
+    }
+
+    #[test]
+    fn test_plan_parse_rejects_duplicate_tags() {
+        let err = Plan::parse(&["QT".to_string()], &[], &["QT:revcomp".to_string()]).unwrap_err();
+        assert_eq!(err.token, "QT:revcomp");
+        assert_eq!(err.kind, ParseErrorKind::DuplicateTag);
+    }
+
+    #[test]
+    fn test_plan_with_pairs() {
+        let plan = Plan::parse(&["QT".to_string()], &[], &[])
+            .unwrap()
+            .with_pairs(&["BC:QT".to_string()])
+            .unwrap();
+        assert_eq!(
+            plan.specs(),
+            &[
+                TagSpec {
+                    tag: *b"QT",
+                    operation: Operation::Reverse
+                },
+                TagSpec {
+                    tag: *b"BC",
+                    operation: Operation::ReverseComplement
+                },
+            ]
+        );
+        assert_eq!(
+            plan.pairs(),
+            &[TagPair {
+                sequence: *b"BC",
+                quality: *b"QT"
+            }]
+        );
+    }
+
+    #[test]
+    fn test_pair_mismatch_policy() {
+        assert_eq!(Plan::default().pair_mismatch(), PairMismatch::Error);
+        let plan = Plan::default().with_pair_mismatch(PairMismatch::Warn);
+        assert_eq!(plan.pair_mismatch(), PairMismatch::Warn);
+        assert_eq!("warn".parse::<PairMismatch>(), Ok(PairMismatch::Warn));
+        assert!("skip".parse::<PairMismatch>().is_err());
+    }
+
+    #[test]
+    fn test_duplicate_tags_policy() {
+        assert_eq!(Plan::default().duplicate_tags(), DuplicateTags::First);
+        let plan = Plan::default().with_duplicate_tags(DuplicateTags::All);
+        assert_eq!(plan.duplicate_tags(), DuplicateTags::All);
+        assert_eq!("error".parse::<DuplicateTags>(), Ok(DuplicateTags::Error));
+        assert_eq!(DuplicateTags::Error.name(), "error");
+        assert!("last".parse::<DuplicateTags>().is_err());
+    }
+
+    #[test]
+    fn test_plan_with_pairs_errors() {
+        let err = Plan::default().with_pairs(&["BC".to_string()]).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::MissingCompanion);
+        let err = Plan::default()
+            .with_pairs(&["BC:BC".to_string()])
+            .unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::DuplicateTag);
+        let err = Plan::new(&["BC".to_string()], &[])
+            .unwrap()
+            .with_pairs(&["BC:QT".to_string()])
+            .unwrap_err();
+        assert_eq!(err.token, "BC:QT");
+        assert_eq!(err.kind, ParseErrorKind::DuplicateTag);
+    }
+
+    #[test]
+    fn test_parse_tag_chain() {
+        let operations = |token: &str| -> Vec<Operation> {
+            parse_tag_chain(token)
+                .unwrap()
+                .iter()
+                .map(|spec| spec.operation)
+                .collect()
+        };
+        assert_eq!(
+            operations("XB:comp,rev,rename=BC"),
+            vec![
+                Operation::Complement,
+                Operation::Reverse,
+                Operation::Rename(*b"BC")
+            ]
+        );
+        assert_eq!(operations("QT:rev"), vec![Operation::Reverse]);
+        assert_eq!(
+            operations("XB:revcomp,as=B:C,rename=BC"),
+            vec![
+                Operation::ReverseComplement,
+                Operation::Convert(Encoding::ByteArray),
+                Operation::Rename(*b"BC")
+            ]
+        );
+        assert_eq!(
+            operations("XC:revcs,as=Z"),
+            vec![
+                Operation::ReverseColorspace,
+                Operation::Convert(Encoding::String)
+            ]
+        );
+
+        for (token, operation) in [
+            ("XB:rename=BC,rev", "rev"),
+            ("XB:rev,comp,rev", "rev"),
+            ("XB:revcs,comp", "comp"),
+            ("XB:revcomp,revcs", "revcs"),
+            ("XB:rev,rename=XB", "rename=XB"),
+            ("XB:as=Z,rev,as=B:C", "as=B:C"),
+            ("XB:revcs,as=B:C", "as=B:C"),
+        ] {
+            let error = parse_tag_chain(token).unwrap_err();
+            assert!(
+                matches!(&error.kind, ParseErrorKind::IncoherentChain { operation: op, .. } if op == operation),
+                "{token}: {error}"
+            );
+            assert_eq!(&token[error.span()], operation);
+        }
+        let error = parse_tag_chain("XB:comp,rvv").unwrap_err();
+        assert_eq!(
+            error.kind,
+            ParseErrorKind::UnknownOperation("rvv".to_string())
+        );
+        assert_eq!(error.span(), 8..11);
+
+        let plan = Plan::parse(&[], &[], &["XB:comp,rev,rename=BC".to_string()]).unwrap();
+        assert_eq!(plan.specs().len(), 3);
+        assert!(Plan::parse(&["XB".to_string()], &[], &["XB:comp,rev".to_string()]).is_err());
+        assert_eq!(Operation::Rename(*b"BC").to_string(), "rename=BC");
+        assert_eq!(
+            Operation::Convert(Encoding::ByteArray).to_string(),
+            "as=B:C"
+        );
+        assert!(parse_tag_chain("XB:as=H").is_err());
+        assert!(!plan.has_conversions());
+        let plan = Plan::parse(&[], &[], &["XB:revcomp,as=Z".to_string()]).unwrap();
+        assert!(plan.has_conversions());
+    }
+
+    #[test]
+    fn test_parse_tag_spec_with_strand() {
+        let (chain, strand) = parse_tag_spec_with_strand("MB:revcomp:strand=mate").unwrap();
+        assert_eq!(chain[0].tag, *b"MB");
+        assert_eq!(strand, StrandSource::Mate);
+        assert_eq!(
+            parse_tag_spec_with_strand("QT:rev").unwrap().1,
+            StrandSource::Read
+        );
+        assert_eq!(
+            parse_tag_spec_with_strand("QT:rev:strand=XS").unwrap().1,
+            StrandSource::Tag(*b"XS")
+        );
+        assert_eq!(
+            parse_tag_spec_with_strand("QT:rev:strand=always")
+                .unwrap()
+                .1,
+            StrandSource::Always
+        );
+        let err = parse_tag_spec_with_strand("QT:rev:strand=both").unwrap_err();
+        assert_eq!(err.token, "QT:rev:strand=both");
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::UnknownStrandSource("strand=both".to_string())
+        );
+        assert!(parse_tag_spec("QT:rev:strand=mate").is_err());
+
+        let (chain, strand) = parse_tag_spec_with_strand("XB:revcomp,as=B:C").unwrap();
+        assert_eq!(chain[1].operation, Operation::Convert(Encoding::ByteArray));
+        assert_eq!(strand, StrandSource::Read);
+        let (chain, strand) = parse_tag_spec_with_strand("XB:as=B:C:strand=mate").unwrap();
+        assert_eq!(chain.len(), 1);
+        assert_eq!(strand, StrandSource::Mate);
+    }
+
+    #[test]
+    fn test_plan_strand_sources() {
+        let plan = Plan::parse(
+            &["QT".to_string()],
+            &[],
+            &[
+                "MB:revcomp:strand=mate".to_string(),
+                "XX:rev:strand=read".to_string(),
+            ],
+        )
+        .unwrap();
+        assert!(plan.has_strand_sources());
+        assert_eq!(plan.strand_source(b"MB"), StrandSource::Mate);
+        assert_eq!(plan.strand_source(b"QT"), StrandSource::Read);
+        assert_eq!(plan.strand_source(b"XX"), StrandSource::Read);
+
+        let all = plan.restrict(|_| Ok::<_, ()>(true)).unwrap();
+        assert!(matches!(all, Cow::Borrowed(_)));
+        let mate = plan
+            .restrict(|source| Ok::<_, ()>(source == StrandSource::Mate))
+            .unwrap();
+        let tags: Vec<Tag> = mate.specs().iter().map(|spec| spec.tag).collect();
+        assert_eq!(tags, vec![*b"MB"]);
+        assert_eq!(mate.strand_source(b"MB"), StrandSource::Mate);
+        assert!(!Plan::default().has_strand_sources());
+    }
+
+    #[test]
+    fn test_operation_names() {
+        for operation in [
+            Operation::Reverse,
+            Operation::ReverseComplement,
+            Operation::ReverseColorspace,
+            Operation::Complement,
+        ] {
+            assert_eq!(Operation::from_name(operation.name()), Some(operation));
+        }
+        assert_eq!(
+            Operation::from_name("reverse-colorspace"),
+            Some(Operation::ReverseColorspace)
+        );
+    }
+
+    #[test]
+    fn test_plan_with_preset() {
+        let plan = Plan::parse(&["QT".to_string()], &[], &[])
+            .unwrap()
+            .with_preset(Preset::OriginalQualities)
+            .unwrap();
+        let specs: Vec<(Tag, Operation)> = plan
+            .specs()
+            .iter()
+            .map(|spec| (spec.tag, spec.operation))
+            .collect();
+        assert_eq!(
+            specs,
+            vec![(*b"QT", Operation::Reverse), (*b"OQ", Operation::Reverse)]
+        );
+
+        let plan = Plan::parse(&["OQ".to_string()], &[], &[]).unwrap();
+        let same = plan.clone().with_preset(Preset::OriginalQualities).unwrap();
+        assert_eq!(same, plan);
+
+        let err = Plan::parse(&[], &["OQ".to_string()], &[])
+            .unwrap()
+            .with_preset(Preset::OriginalQualities)
+            .unwrap_err();
+        assert_eq!(err.token, "OQ:rev");
+        assert_eq!(err.kind, ParseErrorKind::DuplicateTag);
+
+        let plan = Plan::parse(&["OQ".to_string()], &[], &[])
+            .unwrap()
+            .with_specs([
+                TagSpec {
+                    tag: *b"OQ",
+                    operation: Operation::ReverseComplement,
+                },
+                TagSpec {
+                    tag: *b"OS",
+                    operation: Operation::ReverseComplement,
+                },
+            ]);
+        let specs: Vec<(Tag, Operation)> = plan
+            .specs()
+            .iter()
+            .map(|spec| (spec.tag, spec.operation))
+            .collect();
+        assert_eq!(
+            specs,
+            vec![
+                (*b"OQ", Operation::Reverse),
+                (*b"OS", Operation::ReverseComplement)
+            ]
+        );
+
+        let plan = Plan::default()
+            .with_pairs(&["E2:U2".to_string()])
+            .unwrap()
+            .with_preset(Preset::SecondaryBasecalls)
+            .unwrap();
+        assert_eq!(plan.specs().len(), 2);
+        assert_eq!(
+            plan.pairs(),
+            &[TagPair {
+                sequence: *b"E2",
+                quality: *b"U2"
+            }]
+        );
+
+        let plan = Plan::default()
+            .with_preset(Preset::Bismark)
+            .unwrap()
+            .with_preset(Preset::Bismark)
+            .unwrap();
+        assert_eq!(plan.specs().len(), 1);
+        let tags: Vec<Tag> = plan.expected_values().iter().map(|(tag, _)| *tag).collect();
+        assert_eq!(tags, vec![*b"XR", *b"XG"]);
+    }
+
+    #[test]
+    fn test_plan_pairs_share_strand_sources() {
+        let plan = Plan::parse(&[], &[], &["BC:revcomp:strand=mate".to_string()]).unwrap();
+        let err = plan.clone().with_pairs(&["BC:QT".to_string()]).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::MismatchedStrandSources);
+
+        let plan = Plan::parse(
+            &[],
+            &[],
+            &[
+                "BC:revcomp:strand=mate".to_string(),
+                "QT:rev:strand=mate".to_string(),
+            ],
+        )
+        .unwrap()
+        .with_pairs(&["BC:QT".to_string()])
+        .unwrap();
+        let read = plan
+            .restrict(|source| Ok::<_, ()>(source == StrandSource::Read))
+            .unwrap();
+        assert!(read.is_empty());
+        assert!(read.pairs().is_empty());
+    }
+
+    #[test]
+    fn test_parse_matrix() {
+        assert_eq!(
+            parse_matrix("SG:100x4").unwrap(),
+            (
+                *b"SG",
+                MatrixShape {
+                    rows: Some(100),
+                    columns: Columns::Fixed(4)
+                }
+            )
+        );
+        assert_eq!(
+            parse_matrix("SG:*xNC").unwrap().1,
+            MatrixShape {
+                rows: None,
+                columns: Columns::Tag(*b"NC")
+            }
+        );
+        for shape in ["100x4", "*xNC"] {
+            assert_eq!(MatrixShape::from_name(shape).unwrap().name(), shape);
+        }
+        for token in ["SG", "SG:4", "SG:0x4", "SG:*x0", "SG:*x4C", "SG:x4"] {
+            let err = parse_matrix(token).unwrap_err();
+            assert!(
+                matches!(err.kind, ParseErrorKind::InvalidMatrixShape(_)),
+                "{token}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_plan_with_matrices() {
+        let plan = Plan::parse(&["SG".to_string()], &[], &[])
+            .unwrap()
+            .with_matrices(&["SG:*x4".to_string(), "KN:2xNC".to_string()])
+            .unwrap();
+        assert_eq!(plan.specs().len(), 2);
+        assert!(plan.has_matrices());
+        assert_eq!(
+            plan.matrix_shape(b"KN").unwrap().columns,
+            Columns::Tag(*b"NC")
+        );
+        assert_eq!(plan.matrix_shape(b"QT"), None);
+
+        let err = plan.with_matrices(&["SG:*x2".to_string()]).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::DuplicateTag);
+        let err = Plan::parse(&[], &["SG".to_string()], &[])
+            .unwrap()
+            .with_matrices(&["SG:*x4".to_string()])
+            .unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::DuplicateTag);
+    }
+
+    #[test]
+    fn test_plan_contains() {
+        let plan = Plan::parse(&[], &[], &["BC:revcomp".to_string()]).unwrap();
+        assert!(plan.contains(b"BC"));
+        assert!(!plan.contains(b"QT"));
+    }
+
+    #[test]
+    fn test_plan_empty() {
+        let plan = Plan::new(&[], &[]).unwrap();
+        assert!(plan.is_empty());
+    }
+}
@@ -0,0 +1,266 @@
+//! User-supplied WASM modules registered as custom tag operations.
+//!
+//! A plugin is a WASM module exporting:
+//!
+//! - `memory`: the module's linear memory
+//! - `tag() -> i32`: the SAM tag it transforms, packed big-endian (e.g., `0x4D4E` for `MN`)
+//! - `alloc(len: i32) -> i32`: a pointer to `len` writable bytes for the input value
+//! - `transform(type: i32, ptr: i32, len: i32) -> i64`: transforms the value bytes at `ptr` and
+//!   returns the output pointer in the high 32 bits and the output length in the low 32 bits
+//!
+//! `type` is the SAM type character of the value: `Z` or `H` for strings, or the element type
+//! of a `B` array (`c`, `C`, `s`, `S`, `i`, `I`, or `f`). String values are passed as their raw
+//! bytes and arrays as their little-endian element bytes. The output is decoded with the same
+//! type, so array outputs must be a whole number of elements. Plugins are free to reuse memory
+//! between calls since revtag copies the output before calling into the module again.
+use rust_htslib::bam::Record;
+use rust_htslib::bam::record::Aux;
+use std::error;
+use std::path::Path;
+use wasmi::{Engine, Linker, Memory, Module, Store, TypedFunc};
+
+use crate::plan::Tag;
+
+/// A loaded WASM plugin that transforms a single SAM tag.
+pub struct Plugin {
+    tag: Tag,
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    transform: TypedFunc<(i32, i32, i32), i64>,
+}
+
+impl Plugin {
+    /// Loads a plugin from a `.wasm` file.
+    ///
+    /// # Returns
+    ///
+    /// Returns the plugin, or an error if the file cannot be read or does not implement the
+    /// plugin interface.
+    ///
+    pub fn load(path: &Path) -> Result<Self, Box<dyn error::Error>> {
+        let wasm = std::fs::read(path)
+            .map_err(|e| format!("Cannot read plugin {}: {e}", path.display()))?;
+        Self::from_bytes(&wasm)
+            .map_err(|e| format!("Invalid plugin {}: {e}", path.display()).into())
+    }
+
+    /// Loads a plugin from the bytes of a compiled WASM module.
+    ///
+    /// # Returns
+    ///
+    /// Returns the plugin, or an error if the module does not implement the plugin interface.
+    ///
+    pub fn from_bytes(wasm: &[u8]) -> Result<Self, Box<dyn error::Error>> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm)?;
+        let mut store = Store::new(&engine, ());
+        let linker = <Linker<()>>::new(&engine);
+        let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or("Plugin does not export `memory`")?;
+        let tag = instance.get_typed_func::<(), i32>(&store, "tag")?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc")?;
+        let transform = instance.get_typed_func::<(i32, i32, i32), i64>(&store, "transform")?;
+
+        let packed = tag.call(&mut store, ())?;
+        let tag = [(packed >> 8) as u8, packed as u8];
+        if !tag.iter().all(u8::is_ascii_alphanumeric) {
+            return Err(format!("Plugin returned an invalid tag: {packed:#06x}").into());
+        }
+
+        Ok(Self {
+            tag,
+            store,
+            memory,
+            alloc,
+            transform,
+        })
+    }
+
+    /// Returns the SAM tag this plugin transforms.
+    pub fn tag(&self) -> Tag {
+        self.tag
+    }
+
+    /// Passes a value's bytes through the plugin and returns the transformed bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `value_type` - The SAM type character of the value
+    /// * `bytes` - The value bytes
+    ///
+    /// # Returns
+    ///
+    /// Returns the transformed bytes, or an error if the plugin traps or returns bad pointers.
+    ///
+    pub fn call(&mut self, value_type: u8, bytes: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let len = i32::try_from(bytes.len())?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, bytes)?;
+
+        let packed = self
+            .transform
+            .call(&mut self.store, (i32::from(value_type), ptr, len))? as u64;
+        let (out_ptr, out_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+
+        let mut output = vec![0u8; out_len];
+        self.memory.read(&self.store, out_ptr, &mut output)?;
+        Ok(output)
+    }
+
+    /// Transforms this plugin's tag on a record in place, if present with a supported type.
+    ///
+    /// # Returns
+    ///
+    /// Returns Ok(()) on success, or an error if the plugin fails or returns a malformed value.
+    ///
+    pub fn apply(&mut self, record: &mut Record) -> Result<(), Box<dyn error::Error>> {
+        let tag = self.tag;
+
+        macro_rules! try_plugin_array {
+            ($variant:ident, $ty:ty, $code:expr) => {
+                if let Ok(Aux::$variant(arr)) = record.aux(&tag) {
+                    let bytes: Vec<u8> = arr.iter().flat_map(<$ty>::to_le_bytes).collect();
+                    let output = self.call($code, &bytes)?;
+                    let width = std::mem::size_of::<$ty>();
+                    if output.len() % width != 0 {
+                        return Err(format!(
+                            "Plugin for {} returned {} bytes, not a whole number of {} elements",
+                            String::from_utf8_lossy(&tag),
+                            output.len(),
+                            char::from($code),
+                        )
+                        .into());
+                    }
+                    let values: Vec<$ty> = output
+                        .chunks_exact(width)
+                        .map(|chunk| <$ty>::from_le_bytes(chunk.try_into().expect("element width")))
+                        .collect();
+                    record.remove_aux(&tag)?;
+                    record.push_aux(&tag, Aux::$variant((&values[..]).into()))?;
+                    return Ok(());
+                }
+            };
+        }
+
+        try_plugin_array!(ArrayU8, u8, b'C');
+        try_plugin_array!(ArrayU16, u16, b'S');
+        try_plugin_array!(ArrayU32, u32, b'I');
+        try_plugin_array!(ArrayI8, i8, b'c');
+        try_plugin_array!(ArrayI16, i16, b's');
+        try_plugin_array!(ArrayI32, i32, b'i');
+        try_plugin_array!(ArrayFloat, f32, b'f');
+
+        if let Ok(Aux::String(s)) = record.aux(&tag) {
+            let output = String::from_utf8(self.call(b'Z', s.as_bytes())?)?;
+            record.remove_aux(&tag)?;
+            record.push_aux(&tag, Aux::String(&output))?;
+        } else if let Ok(Aux::HexByteArray(s)) = record.aux(&tag) {
+            let output = String::from_utf8(self.call(b'H', s.as_bytes())?)?;
+            record.remove_aux(&tag)?;
+            record.push_aux(&tag, Aux::HexByteArray(&output))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A plugin for the `MN` tag that lowercases every byte in place.
+    const LOWERCASE_PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (func (export "tag") (result i32) (i32.const 0x4D4E))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func (export "transform") (param $type i32) (param $ptr i32) (param $len i32) (result i64)
+            (local $i i32)
+            (block $done
+              (loop $next_byte
+                (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                (i32.store8
+                  (i32.add (local.get $ptr) (local.get $i))
+                  (i32.or (i32.load8_u (i32.add (local.get $ptr) (local.get $i))) (i32.const 0x20)))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $next_byte)))
+            (i64.or
+              (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+              (i64.extend_i32_u (local.get $len)))))
+    "#;
+
+    fn plugin() -> Plugin {
+        Plugin::from_bytes(&wat::parse_str(LOWERCASE_PLUGIN).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_plugin_tag() {
+        assert_eq!(plugin().tag(), *b"MN");
+    }
+
+    #[test]
+    fn test_plugin_transforms_string() {
+        let mut record = Record::new();
+        record.push_aux(b"MN", Aux::String("HELLO")).unwrap();
+        plugin().apply(&mut record).unwrap();
+        assert!(matches!(record.aux(b"MN"), Ok(Aux::String("hello"))));
+    }
+
+    #[test]
+    fn test_plugin_transforms_array() {
+        let mut record = Record::new();
+        record
+            .push_aux(b"MN", Aux::ArrayU8((&[0x41u8, 0x42][..]).into()))
+            .unwrap();
+        plugin().apply(&mut record).unwrap();
+        if let Ok(Aux::ArrayU8(arr)) = record.aux(b"MN") {
+            assert_eq!(arr.iter().collect::<Vec<u8>>(), vec![0x61, 0x62]);
+        } else {
+            panic!("Expected ArrayU8");
+        }
+    }
+
+    #[test]
+    fn test_plugin_rejects_partial_array_elements() {
+        // A plugin for the `MN` tag that always returns a single byte.
+        let wasm = wat::parse_str(
+            r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "tag") (result i32) (i32.const 0x4D4E))
+              (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+              (func (export "transform") (param i32 i32 i32) (result i64)
+                (i64.or (i64.shl (i64.extend_i32_u (local.get 1)) (i64.const 32)) (i64.const 1))))
+            "#,
+        )
+        .unwrap();
+        let mut record = Record::new();
+        record
+            .push_aux(b"MN", Aux::ArrayU16((&[1u16, 2][..]).into()))
+            .unwrap();
+        let err = Plugin::from_bytes(&wasm)
+            .unwrap()
+            .apply(&mut record)
+            .expect_err("expected Err for a partial element");
+        assert!(
+            err.to_string().contains("whole number"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn test_plugin_missing_exports() {
+        let wasm = wat::parse_str("(module)").unwrap();
+        assert!(Plugin::from_bytes(&wasm).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! Presets bundling the tag specifications of well-known SAM tags.
+//!
+//! Some tags are defined by the SAM specification or by widely used tools, which also fix how
+//! they must be transformed. A preset names such a set of tags so users need not look up which
+//! operation each one needs; see [`crate::plan::Plan::with_preset`].
+use std::str::FromStr;
+
+/// A named set of tag specifications and companion pairs for well-known tags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// The original base qualities in `OQ`, as carried through realignment and base quality
+    /// recalibration by GATK and Picard. A quality string, so reversed and never complemented.
+    OriginalQualities,
+    /// The secondary basecalls in `E2` and their qualities in `U2`, as defined by the SAM
+    /// specification. A companion pair, so the bases are reverse complemented and the qualities
+    /// reversed together.
+    SecondaryBasecalls,
+    /// The SOLiD colorspace read in `CS` and its qualities in `CQ`, one per color. The read is
+    /// reversed with its primer base re-derived, as [`crate::dna::reverse_colorspace`] does, and
+    /// the qualities reversed.
+    Colorspace,
+    /// The Bismark methylation calls in `XM`, one per base of the read. The calls name the
+    /// methylation context rather than a base, so they are reversed and never complemented. The
+    /// read and genome conversions in `XR` and `XG` do not depend on orientation, so they are
+    /// left as they are but must each be `CT` or `GA`.
+    Bismark,
+}
+
+impl Preset {
+    /// Parses a preset name as accepted by `--preset`: `oq`, `e2u2`, `colorspace`, or
+    /// `bismark`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "oq" => Some(Preset::OriginalQualities),
+            "e2u2" => Some(Preset::SecondaryBasecalls),
+            "colorspace" => Some(Preset::Colorspace),
+            "bismark" => Some(Preset::Bismark),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the preset as accepted by `--preset`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preset::OriginalQualities => "oq",
+            Preset::SecondaryBasecalls => "e2u2",
+            Preset::Colorspace => "colorspace",
+            Preset::Bismark => "bismark",
+        }
+    }
+
+    /// Returns the `TAG:OP` specifications of the preset, in the order they are applied.
+    pub fn specs(&self) -> &'static [&'static str] {
+        match self {
+            Preset::OriginalQualities => &["OQ:rev"],
+            Preset::SecondaryBasecalls => &[],
+            Preset::Colorspace => &["CS:revcs", "CQ:rev"],
+            Preset::Bismark => &["XM:rev"],
+        }
+    }
+
+    /// Returns the `SEQ:QUAL` companion pairs of the preset, as given to `--pair`.
+    pub fn pairs(&self) -> &'static [&'static str] {
+        match self {
+            Preset::OriginalQualities => &[],
+            Preset::SecondaryBasecalls => &["E2:U2"],
+            Preset::Colorspace => &[],
+            Preset::Bismark => &[],
+        }
+    }
+
+    /// Returns the tags the preset leaves untransformed but validates, each with the `Z` values
+    /// it may hold.
+    pub fn expected_values(&self) -> &'static [(&'static str, &'static [&'static str])] {
+        match self {
+            Preset::Bismark => &[("XR", &["CT", "GA"]), ("XG", &["CT", "GA"])],
+            _ => &[],
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s).ok_or_else(|| {
+            format!("Unknown preset `{s}`; expected one of oq, e2u2, colorspace, or bismark")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::{parse_tag, parse_tag_pair, parse_tag_spec};
+
+    #[test]
+    fn test_preset_names() {
+        assert_eq!("oq".parse::<Preset>(), Ok(Preset::OriginalQualities));
+        assert_eq!(Preset::OriginalQualities.name(), "oq");
+        assert_eq!("e2u2".parse::<Preset>(), Ok(Preset::SecondaryBasecalls));
+        assert!("OQ".parse::<Preset>().is_err());
+    }
+
+    #[test]
+    fn test_preset_tokens_parse() {
+        for preset in [
+            Preset::OriginalQualities,
+            Preset::SecondaryBasecalls,
+            Preset::Colorspace,
+            Preset::Bismark,
+        ] {
+            for token in preset.specs() {
+                parse_tag_spec(token).unwrap();
+            }
+            for token in preset.pairs() {
+                parse_tag_pair(token).unwrap();
+            }
+            for (tag, _) in preset.expected_values() {
+                parse_tag(tag).unwrap();
+            }
+        }
+    }
+}
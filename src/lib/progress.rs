@@ -0,0 +1,292 @@
+//! Progress through file inputs by compressed bytes consumed.
+//!
+//! Record counts say little about how much of an input remains when read lengths vary widely,
+//! as they do for long reads. For BGZF-compressed inputs, the reader's virtual offset gives the
+//! compressed bytes consumed, from which a percentage and an estimate of the time remaining
+//! follow.
+//!
+//! Programs wrapping revtag, such as GUIs, can also receive progress as newline-delimited JSON
+//! events on a file descriptor of their choosing, apart from the logs on stderr.
+use log::*;
+use std::error;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::memory::format_mib;
+
+/// Percentage points of the input between progress messages.
+const STEP_PERCENT: u64 = 5;
+
+/// Records read between progress events, matching the progress log.
+const EVENT_RECORDS: u64 = 100_000;
+
+/// The gzip magic bytes that begin every BGZF-compressed file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Returns the size of an input if it is a regular, BGZF-compressed file, such as BAM, whose
+/// virtual offsets measure progress through it.
+///
+/// # Arguments
+///
+/// * `input` - The input file path, or None for stdin
+///
+/// # Returns
+///
+/// Returns the file size in bytes, or None for stdin, other files, and empty files.
+///
+pub fn bgzf_input_size(input: Option<&Path>) -> Option<u64> {
+    let path = input?;
+    let metadata = path.metadata().ok()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return None;
+    }
+    let mut magic = [0u8; 2];
+    File::open(path).ok()?.read_exact(&mut magic).ok()?;
+    (magic == GZIP_MAGIC).then_some(metadata.len())
+}
+
+/// Formats a duration to the second, e.g. `1h 02m 03s` or `45s`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s:02}s"),
+        (h, m, s) => format!("{h}h {m:02}m {s:02}s"),
+    }
+}
+
+/// Logs the percentage of an input consumed, every [`STEP_PERCENT`] percent.
+#[derive(Debug)]
+pub struct ByteProgress {
+    total: u64,
+    started: Instant,
+    next_percent: u64,
+}
+
+impl ByteProgress {
+    /// Creates a progress logger for an input of `total` bytes.
+    pub fn new(total: u64) -> Self {
+        Self {
+            total,
+            started: Instant::now(),
+            next_percent: STEP_PERCENT,
+        }
+    }
+
+    /// Records that `consumed` bytes of the input have been read, logging once another step of
+    /// the input has been passed.
+    pub fn update(&mut self, consumed: u64) {
+        let percent = consumed.min(self.total) * 100 / self.total;
+        if percent < self.next_percent {
+            return;
+        }
+        self.next_percent = (percent / STEP_PERCENT + 1) * STEP_PERCENT;
+        info!("{}", self.message(consumed, self.started.elapsed()));
+    }
+
+    /// Returns the progress message after `consumed` bytes were read in `elapsed` time.
+    fn message(&self, consumed: u64, elapsed: Duration) -> String {
+        let consumed = consumed.min(self.total);
+        let percent = consumed * 100 / self.total;
+        let mut message = format!(
+            "Read {percent}% of input ({} of {})",
+            format_mib(consumed),
+            format_mib(self.total)
+        );
+        if consumed > 0 && consumed < self.total {
+            let remaining = elapsed.mul_f64((self.total - consumed) as f64 / consumed as f64);
+            message.push_str(&format!(", about {} remaining", format_duration(remaining)));
+        }
+        message
+    }
+}
+
+/// Opens an inherited file descriptor, such as 3, to write progress events to.
+///
+/// The descriptors of stdin, stdout, and stderr are refused, as are descriptors that are not
+/// open, so a run never writes events into a file it opens itself.
+///
+/// # Arguments
+///
+/// * `fd` - The file descriptor, opened for writing by the parent process
+///
+/// # Returns
+///
+/// Returns the descriptor as a file, or an error if it cannot receive events.
+///
+pub fn open_fd(fd: i32) -> Result<File, Box<dyn error::Error>> {
+    if fd <= 2 {
+        return Err(format!(
+            "Progress events need a file descriptor other than stdin, stdout, or stderr, not {fd}"
+        )
+        .into());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::fd::FromRawFd;
+        // SAFETY: the parent process handed the descriptor to this process to write events to,
+        // and the check below ensures it is open before anything else could reuse its number.
+        let file = unsafe { File::from_raw_fd(fd) };
+        if let Err(e) = file.metadata() {
+            // The descriptor is not open, so closing it on drop must not be attempted.
+            std::mem::forget(file);
+            return Err(
+                format!("Cannot write progress events to file descriptor {fd}: {e}").into(),
+            );
+        }
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    Err("Progress events on a file descriptor are only supported on Unix".into())
+}
+
+/// Writes newline-delimited JSON progress events: `start` once, `progress` every
+/// [`EVENT_RECORDS`] records read, and `finish` when the run succeeds.
+///
+/// Every event has an `event` name; `progress` and `finish` events also have the `records` read
+/// so far. For BGZF input files, `bytes_total` is the input size and `bytes_read` the compressed
+/// bytes consumed so far; both are null otherwise.
+#[derive(Debug)]
+pub struct ProgressEvents<W: Write> {
+    out: W,
+    total: Option<u64>,
+    records: u64,
+    next_records: u64,
+}
+
+impl<W: Write> ProgressEvents<W> {
+    /// Creates an event stream for an input of `total` bytes, if known, writing the `start`
+    /// event.
+    pub fn new(out: W, total: Option<u64>) -> io::Result<Self> {
+        let mut events = Self {
+            out,
+            total,
+            records: 0,
+            next_records: EVENT_RECORDS,
+        };
+        let line = format!(
+            r#"{{"event":"start","bytes_total":{}}}"#,
+            json_number(events.total)
+        );
+        events.write(&line)?;
+        Ok(events)
+    }
+
+    /// Records that `records` more records were read, having consumed `consumed` bytes of the
+    /// input, writing a `progress` event once another [`EVENT_RECORDS`] have been read.
+    pub fn update(&mut self, records: u64, consumed: u64) -> io::Result<()> {
+        self.records += records;
+        if self.records < self.next_records {
+            return Ok(());
+        }
+        self.next_records = (self.records / EVENT_RECORDS + 1) * EVENT_RECORDS;
+        let line = format!(
+            r#"{{"event":"progress","records":{},"bytes_read":{},"bytes_total":{}}}"#,
+            self.records,
+            json_number(self.total.map(|total| consumed.min(total))),
+            json_number(self.total)
+        );
+        self.write(&line)
+    }
+
+    /// Writes the `finish` event with the total records read.
+    pub fn finish(mut self) -> io::Result<()> {
+        let line = format!(r#"{{"event":"finish","records":{}}}"#, self.records);
+        self.write(&line)
+    }
+
+    /// Writes one event, flushed so readers see it immediately.
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.out, "{line}")?;
+        self.out.flush()
+    }
+}
+
+/// Formats an optional number as JSON.
+fn json_number(value: Option<u64>) -> String {
+    value.map_or("null".to_string(), |value| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message() {
+        let progress = ByteProgress::new(4 << 20);
+        assert_eq!(
+            progress.message(1 << 20, Duration::from_secs(10)),
+            "Read 25% of input (1.0 MiB of 4.0 MiB), about 30s remaining"
+        );
+        assert_eq!(
+            progress.message(4 << 20, Duration::from_secs(40)),
+            "Read 100% of input (4.0 MiB of 4.0 MiB)"
+        );
+    }
+
+    #[test]
+    fn test_update_steps() {
+        let mut progress = ByteProgress::new(1000);
+        progress.update(10);
+        assert_eq!(progress.next_percent, 5);
+        progress.update(120);
+        assert_eq!(progress.next_percent, 15);
+        progress.update(5000);
+        assert_eq!(progress.next_percent, 105);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(200)), "3m 20s");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1h 02m 03s");
+    }
+
+    #[test]
+    fn test_progress_events() {
+        let mut out = Vec::new();
+        let mut events = ProgressEvents::new(&mut out, Some(1000)).unwrap();
+        events.update(60_000, 100).unwrap();
+        events.update(60_000, 5000).unwrap();
+        events.update(10, 5000).unwrap();
+        events.finish().unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"event\":\"start\",\"bytes_total\":1000}\n\
+             {\"event\":\"progress\",\"records\":120000,\"bytes_read\":1000,\"bytes_total\":1000}\n\
+             {\"event\":\"finish\",\"records\":120010}\n"
+        );
+
+        let mut out = Vec::new();
+        let mut events = ProgressEvents::new(&mut out, None).unwrap();
+        events.update(100_000, 100).unwrap();
+        drop(events);
+        assert!(
+            String::from_utf8(out)
+                .unwrap()
+                .ends_with("\"bytes_read\":null,\"bytes_total\":null}\n")
+        );
+    }
+
+    #[test]
+    fn test_open_fd_refuses_standard_streams() {
+        for fd in [0, 1, 2] {
+            assert!(open_fd(fd).is_err());
+        }
+    }
+
+    #[test]
+    fn test_bgzf_input_size() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let bam = tmpdir.path().join("in.bam");
+        let sam = tmpdir.path().join("in.sam");
+        std::fs::write(&bam, [0x1f, 0x8b, 0x08, 0x04]).unwrap();
+        std::fs::write(&sam, "@HD\tVN:1.6\n").unwrap();
+        assert_eq!(bgzf_input_size(Some(&bam)), Some(4));
+        assert_eq!(bgzf_input_size(Some(&sam)), None);
+        assert_eq!(bgzf_input_size(Some(tmpdir.path())), None);
+        assert_eq!(bgzf_input_size(None), None);
+    }
+}
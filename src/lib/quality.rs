@@ -0,0 +1,147 @@
+//! Base quality binning, which maps each quality to a representative of its bin so that
+//! archive-bound CRAM outputs compress further.
+use std::fmt;
+use std::str::FromStr;
+
+/// The bins of the Illumina 8-level scheme, as `LOW:VALUE` pairs.
+const ILLUMINA: &str = "2:6,10:15,20:22,25:27,30:33,35:37,40:40";
+
+/// A quality binning scheme, given as `illumina` or as comma-separated `LOW:VALUE` pairs with
+/// ascending lower bounds, e.g. `10:15,20:22,30:33`.
+///
+/// Each quality at or above a bin's lower bound, and below the next bin's, becomes the bin's
+/// value; qualities below the first bound are left unchanged. The Illumina scheme leaves 0 and
+/// 1 unchanged and bins the rest as `2:6,10:15,20:22,25:27,30:33,35:37,40:40`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QualityBins {
+    source: String,
+    table: Vec<u8>,
+}
+
+impl QualityBins {
+    /// Parses a binning scheme.
+    ///
+    /// # Arguments
+    ///
+    /// * `scheme` - `illumina`, or comma-separated `LOW:VALUE` pairs with ascending lower bounds
+    ///
+    /// # Returns
+    ///
+    /// Returns the scheme, or an error message if a pair is malformed or the bounds do not
+    /// ascend.
+    ///
+    pub fn parse(scheme: &str) -> Result<Self, String> {
+        let pairs = if scheme == "illumina" {
+            ILLUMINA
+        } else {
+            scheme
+        };
+        let mut table: Vec<u8> = (0..=u8::MAX).collect();
+        let mut bins = Vec::new();
+        for pair in pairs.split(',') {
+            let parsed = pair.split_once(':').and_then(|(low, value)| {
+                Some((low.parse::<u8>().ok()?, value.parse::<u8>().ok()?))
+            });
+            let Some((low, value)) = parsed.filter(|(_, value)| *value < u8::MAX) else {
+                return Err(format!(
+                    "Invalid quality bin `{pair}`; expected LOW:VALUE with qualities below 255"
+                ));
+            };
+            if bins.last().is_some_and(|(last, _)| *last >= low) {
+                return Err(format!(
+                    "Quality bins must have ascending lower bounds, but `{pair}` does not"
+                ));
+            }
+            bins.push((low, value));
+        }
+        for (i, (low, value)) in bins.iter().enumerate() {
+            let high = bins.get(i + 1).map_or(u8::MAX, |(next, _)| *next);
+            table[usize::from(*low)..usize::from(high)].fill(*value);
+        }
+        Ok(Self {
+            source: scheme.to_string(),
+            table,
+        })
+    }
+
+    /// Returns the scheme as given.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns the value of the bin holding a quality; 255, meaning missing, is left unchanged.
+    pub fn bin(&self, quality: u8) -> u8 {
+        self.table[usize::from(quality)]
+    }
+
+    /// Bins every quality of a record in place. Qualities marked missing, whose first byte is
+    /// 255, are left unchanged.
+    pub fn apply(&self, qualities: &mut [u8]) {
+        if qualities.first() == Some(&u8::MAX) {
+            return;
+        }
+        for quality in qualities {
+            *quality = self.bin(*quality);
+        }
+    }
+}
+
+impl FromStr for QualityBins {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for QualityBins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_illumina() {
+        let bins = QualityBins::parse("illumina").unwrap();
+        let binned: Vec<u8> = [
+            0, 1, 2, 9, 10, 19, 20, 24, 25, 29, 30, 34, 35, 39, 40, 41, 93,
+        ]
+        .into_iter()
+        .map(|quality| bins.bin(quality))
+        .collect();
+        assert_eq!(
+            binned,
+            vec![
+                0, 1, 6, 6, 15, 15, 22, 22, 27, 27, 33, 33, 37, 37, 40, 40, 40
+            ]
+        );
+        assert_eq!(bins.bin(255), 255);
+        assert_eq!(bins.to_string(), "illumina");
+    }
+
+    #[test]
+    fn test_apply() {
+        let bins = QualityBins::parse("10:15,30:33").unwrap();
+        let mut qualities = vec![5, 10, 29, 30, 41];
+        bins.apply(&mut qualities);
+        assert_eq!(qualities, vec![5, 15, 15, 33, 33]);
+
+        let mut missing = vec![255, 255];
+        bins.apply(&mut missing);
+        assert_eq!(missing, vec![255, 255]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(QualityBins::parse("").is_err());
+        assert!(QualityBins::parse("10").is_err());
+        assert!(QualityBins::parse("10:x").is_err());
+        assert!(QualityBins::parse("10:255").is_err());
+        assert!(QualityBins::parse("20:22,10:15").is_err());
+        assert!(QualityBins::parse("10:15,10:20").is_err());
+    }
+}
@@ -0,0 +1,259 @@
+//! Dry-run reports of what a plan would change, without writing any output.
+use proglog::ProgLogBuilder;
+use rust_htslib::bam::{Read as BamRead, Record};
+use std::error;
+use std::fmt;
+use std::path::Path;
+
+use crate::aux;
+use crate::io::open_reader;
+use crate::ops::aux_data;
+use crate::options::Options;
+use crate::plan::{NonSequence, Operation, Plan, Tag, TagSpec};
+use crate::run::{is_selected, plan_for, reference_for};
+
+/// The number of example read names kept by a report.
+pub const EXAMPLES: usize = 5;
+
+/// A summary of the records and tags a plan would modify.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// The number of records read.
+    pub records: u64,
+    /// The number of reverse strand records the plan applies to, which meet any condition.
+    pub reverse: u64,
+    /// The number of reverse strand records with at least one value the plan would transform.
+    pub modified: u64,
+    /// Each tag specification of the plan, in order, with the number of values it would
+    /// transform.
+    pub tags: Vec<(TagSpec, u64)>,
+    /// The names of the first records that would be modified, at most [`EXAMPLES`].
+    pub examples: Vec<String>,
+    /// How the plan handles values to reverse complement that are not sequences.
+    nonsequence: NonSequence,
+}
+
+impl Report {
+    /// Creates an empty report for a plan.
+    pub fn new(plan: &Plan) -> Self {
+        Self {
+            records: 0,
+            reverse: 0,
+            modified: 0,
+            tags: plan.specs().iter().map(|spec| (*spec, 0)).collect(),
+            examples: Vec::new(),
+            nonsequence: plan.nonsequence(),
+        }
+    }
+
+    /// Counts the values the plan would transform in a record, without modifying it.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The record to inspect
+    /// * `reverse` - Whether the plan applies to the record, as it is reverse strand and meets any
+    ///   condition
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the record's auxiliary data is malformed.
+    ///
+    pub fn observe(&mut self, record: &Record, reverse: bool) -> Result<(), Box<dyn error::Error>> {
+        self.observe_tags(record, reverse, |_| true)
+    }
+
+    /// Counts the values the plan would transform in a record, for only the tags that apply to
+    /// it, as when tags have their own strand source.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The record to inspect
+    /// * `reverse` - Whether any part of the plan applies to the record
+    /// * `applies` - Whether the tag of a specification applies to the record
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the record's auxiliary data is malformed.
+    ///
+    pub fn observe_tags<F>(
+        &mut self,
+        record: &Record,
+        reverse: bool,
+        applies: F,
+    ) -> Result<(), Box<dyn error::Error>>
+    where
+        F: Fn(&Tag) -> bool,
+    {
+        self.records += 1;
+        if !reverse {
+            return Ok(());
+        }
+        self.reverse += 1;
+
+        let mut modified = false;
+        for field in aux::fields(aux_data(record)) {
+            let field = field?;
+            for (spec, count) in self
+                .tags
+                .iter_mut()
+                .filter(|(spec, _)| spec.tag == field.tag && applies(&spec.tag))
+            {
+                let reversible = spec.operation == Operation::ReverseComplement
+                    && self.nonsequence == NonSequence::ReverseOnly
+                    && aux::supports(Operation::Reverse, &field);
+                if aux::supports(spec.operation, &field) || reversible {
+                    *count += 1;
+                    modified = true;
+                }
+            }
+        }
+
+        if modified {
+            self.modified += 1;
+            if self.examples.len() < EXAMPLES {
+                self.examples
+                    .push(String::from_utf8_lossy(record.qname()).into_owned());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Records read: {}", self.records)?;
+        writeln!(f, "Reverse strand records: {}", self.reverse)?;
+        writeln!(f, "Records that would be modified: {}", self.modified)?;
+        for (spec, count) in &self.tags {
+            writeln!(
+                f,
+                "Tag {}:{} would be modified in {} records",
+                String::from_utf8_lossy(&spec.tag),
+                spec.operation,
+                count
+            )?;
+        }
+        if !self.examples.is_empty() {
+            writeln!(f, "Example records: {}", self.examples.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads an input and reports what a plan would change, without writing any output.
+///
+/// # Arguments
+///
+/// * `input` - The input SAM/BAM/CRAM file path, or None for stdin
+/// * `plan` - The tag specifications to evaluate against reverse strand records
+/// * `options` - Options controlling how records are read and which are reverse strand
+///
+/// # Returns
+///
+/// Returns the report, or an error if the input cannot be read or a record is malformed.
+///
+pub fn dry_run(
+    input: Option<&Path>,
+    plan: &Plan,
+    options: &Options,
+) -> Result<Report, Box<dyn error::Error>> {
+    let mut reader = open_reader(input, options.threads)?;
+    if let Some(reference) = reference_for(options)? {
+        reader.set_reference(reference)?;
+    }
+    let mut report = Report::new(plan);
+
+    let progress = ProgLogBuilder::new()
+        .name("main")
+        .verb("Inspected")
+        .noun("alignment records")
+        .unit(100_000)
+        .build();
+
+    let mut record = Record::new();
+    loop {
+        match reader.read(&mut record) {
+            Some(Ok(())) => {}
+            None => break,
+            Some(Err(e)) => return Err(Box::new(e)),
+        }
+        let selected = is_selected(&record, options)?;
+        let applied = plan_for(&record, selected, plan, options)?;
+        report.observe_tags(&record, !applied.is_empty(), |tag| applied.contains(tag))?;
+        progress.record();
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn sam_input(records: &[&str]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().expect("temp sam input");
+        writeln!(file, "@HD\tVN:1.6\tSO:unknown\n@SQ\tSN:chr1\tLN:1000").unwrap();
+        for record in records {
+            writeln!(file, "{record}").unwrap();
+        }
+        file
+    }
+
+    #[test]
+    fn test_dry_run_counts_reverse_records_and_tags() {
+        let input = sam_input(&[
+            "fwd\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\tBC:Z:ACGT",
+            "rev1\t16\tchr1\t2\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\tBC:Z:ACGT",
+            "rev2\t16\tchr1\t3\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\tBC:B:s,1,2",
+            "rev3\t16\tchr1\t4\t60\t4M\t*\t0\t0\tACGT\tFFFF\tNM:i:0",
+        ]);
+        let plan = Plan::parse(&["QT".into()], &["BC".into()], &[]).unwrap();
+
+        let report = dry_run(Some(input.path()), &plan, &Options::default()).unwrap();
+        assert_eq!(report.records, 4);
+        assert_eq!(report.reverse, 3);
+        assert_eq!(report.modified, 2);
+        let counts: Vec<u64> = report.tags.iter().map(|(_, count)| *count).collect();
+        // BC:B:s cannot be reverse complemented, so only rev1 counts for BC.
+        assert_eq!(counts, vec![2, 1]);
+        assert_eq!(report.examples, vec!["rev1", "rev2"]);
+
+        let text = report.to_string();
+        assert!(text.contains("Records that would be modified: 2"));
+        assert!(text.contains("Tag BC:revcomp would be modified in 1 records"));
+    }
+
+    #[test]
+    fn test_dry_run_strand_from_tag() {
+        let input = sam_input(&[
+            "minus\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF\tXS:A:-\tQT:Z:ABCD",
+            "plus\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF\tXS:A:+\tQT:Z:ABCD",
+            "flag\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD",
+        ]);
+        let plan = Plan::parse(&["QT".into()], &[], &[]).unwrap();
+        let options = Options {
+            strand_tag: Some(*b"XS"),
+            ..Options::default()
+        };
+
+        let report = dry_run(Some(input.path()), &plan, &options).unwrap();
+        assert_eq!(report.reverse, 1);
+        assert_eq!(report.examples, vec!["minus"]);
+    }
+
+    #[test]
+    fn test_dry_run_keeps_a_few_examples() {
+        let records: Vec<String> = (0..EXAMPLES + 3)
+            .map(|i| format!("r{i}\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD"))
+            .collect();
+        let records: Vec<&str> = records.iter().map(String::as_str).collect();
+        let input = sam_input(&records);
+        let plan = Plan::parse(&["QT".into()], &[], &[]).unwrap();
+
+        let report = dry_run(Some(input.path()), &plan, &Options::default()).unwrap();
+        assert_eq!(report.modified, (EXAMPLES + 3) as u64);
+        assert_eq!(report.examples.len(), EXAMPLES);
+    }
+}
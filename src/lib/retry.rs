@@ -0,0 +1,158 @@
+//! Retries with exponential backoff for remote inputs, so that a transient network failure such
+//! as a timeout, a server error, or a connection reset does not end a long streaming run.
+//!
+//! htslib reports failures of remote reads without naming their cause, so every failure of a
+//! remote input is retried, up to the configured number of times.
+use log::*;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+/// The longest wait between attempts, however many have failed.
+pub const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// How many times to retry a failed operation, and how long to wait before the first retry;
+/// each later wait doubles, up to [`MAX_DELAY`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The number of retries after the first attempt; zero never retries.
+    pub retries: u32,
+    /// The wait before the first retry.
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the wait before a retry, counting retries from zero.
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.delay
+            .checked_mul(2u32.saturating_pow(retry))
+            .map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY))
+    }
+
+    /// Logs a failed attempt and waits before the given retry.
+    ///
+    /// # Arguments
+    ///
+    /// * `what` - A description of the operation, e.g. `Reading s3://bucket/in.bam`
+    /// * `error` - The failure
+    /// * `retry` - The retry about to be made, counted from zero
+    ///
+    pub fn wait(&self, what: &str, error: &dyn fmt::Display, retry: u32) {
+        let delay = self.delay(retry);
+        warn!(
+            "{what} failed: {error}; retrying in {delay:?} ({} of {})",
+            retry + 1,
+            self.retries
+        );
+        thread::sleep(delay);
+    }
+
+    /// Runs an operation, retrying it after each failure until it succeeds or the retries run
+    /// out.
+    ///
+    /// # Arguments
+    ///
+    /// * `what` - A description of the operation, logged with each failure
+    /// * `operation` - The operation to run
+    ///
+    /// # Returns
+    ///
+    /// Returns the first success, or the last failure once every retry has failed.
+    ///
+    pub fn run<T, E, F>(&self, what: &str, mut operation: F) -> Result<T, E>
+    where
+        E: fmt::Display,
+        F: FnMut() -> Result<T, E>,
+    {
+        let mut retry = 0;
+        loop {
+            match operation() {
+                Ok(value) => return Ok(value),
+                Err(e) if retry < self.retries => {
+                    self.wait(what, &e, retry);
+                    retry += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Parses a delay in seconds, such as `1` or `0.25`, as accepted by `--retry-delay`.
+pub fn parse_delay(delay: &str) -> Result<Duration, String> {
+    delay
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("Invalid delay {delay:?}; expected a number of seconds"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            retries: 40,
+            delay: Duration::from_millis(500),
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(500));
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+        assert_eq!(policy.delay(20), MAX_DELAY);
+        assert_eq!(policy.delay(40), MAX_DELAY);
+    }
+
+    #[test]
+    fn test_run() {
+        let policy = RetryPolicy {
+            retries: 2,
+            delay: Duration::ZERO,
+        };
+        let mut attempts = 0;
+        let result: Result<u32, String> = policy.run("Reading", || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(format!("attempt {attempts}"))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result, Ok(3));
+
+        let mut attempts = 0;
+        let result: Result<(), String> = policy.run("Reading", || {
+            attempts += 1;
+            Err(format!("attempt {attempts}"))
+        });
+        assert_eq!(result, Err("attempt 3".to_string()));
+
+        let mut attempts = 0;
+        let result: Result<(), String> = RetryPolicy::default().run("Reading", || {
+            attempts += 1;
+            Err("failed".to_string())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_parse_delay() {
+        assert_eq!(parse_delay("2"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_delay("0.25"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_delay("0"), Ok(Duration::ZERO));
+        assert!(parse_delay("-1").is_err());
+        assert!(parse_delay("inf").is_err());
+        assert!(parse_delay("1s").is_err());
+    }
+}
@@ -0,0 +1,2419 @@
+//! End-to-end execution of `revtag` over an input and output stream.
+use log::*;
+use proglog::{ProgLog, ProgLogBuilder};
+use rust_htslib::bam::{
+    CompressionLevel, Header, HeaderView, Read as BamRead, Reader, Record, Writer,
+};
+use std::borrow::Cow;
+use std::error;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use crate::audit::{AuditWriter, candidate_tags, modified_tags};
+use crate::aux::{
+    self, MissingTagError, PairLengthError, UnexpectedValueError, UnpairedTagError, Unsupported,
+};
+use crate::barcode::BarcodeWriter;
+use crate::checksum::ChecksumPipe;
+use crate::contigs::ContigCounts;
+use crate::cram::CramPipe;
+use crate::fastq;
+use crate::fsync::sync_outputs;
+use crate::io::{
+    header_with_program, is_remote, open_reader, open_writer, reference_path, verbatim_header,
+};
+use crate::ops::{
+    annotate_strand, apply_tracked, aux_data, duplex_b_strand, minus_strand, missing_tag,
+    restore_orientation, reverse_complement_qname_umi, revert_alignment, sanitize_aux,
+};
+use crate::options::{Options, OutputFormat};
+use crate::orient::{Decision, Sampler};
+use crate::pairfix::{MateGroups, fix_mates};
+use crate::plan::{Plan, StrandSource, Tag};
+#[cfg(feature = "plugins")]
+use crate::plugin::Plugin;
+use crate::progress::{ByteProgress, ProgressEvents, bgzf_input_size, open_fd};
+use crate::retry::RetryPolicy;
+use crate::sanitize::Repairs;
+use crate::scratch::ScratchDir;
+#[cfg(feature = "scripting")]
+use crate::script::Script;
+use crate::sort::{SortCheck, check_header};
+use crate::splice::HeaderSplice;
+use crate::trace::{Stage, StageSpan, StageTimes, timed};
+use crate::whitelist::{MatchRates, Whitelist};
+
+/// Optional extensions applied to reverse strand records alongside the plan.
+struct Extensions {
+    #[cfg(feature = "plugins")]
+    plugins: Vec<Plugin>,
+    #[cfg(feature = "scripting")]
+    script: Option<Script>,
+}
+
+impl Extensions {
+    /// Loads the extensions named in `options`, failing if their feature is not enabled.
+    fn load(options: &Options) -> Result<Self, Box<dyn error::Error>> {
+        #[cfg(not(feature = "plugins"))]
+        if !options.plugins.is_empty() {
+            return Err(
+                "WASM plugins require revtag to be built with the `plugins` feature".into(),
+            );
+        }
+        #[cfg(not(feature = "scripting"))]
+        if options.script.is_some() {
+            return Err("Scripts require revtag to be built with the `scripting` feature".into());
+        }
+
+        Ok(Self {
+            #[cfg(feature = "plugins")]
+            plugins: options
+                .plugins
+                .iter()
+                .map(|path| Plugin::load(path))
+                .collect::<Result<Vec<_>, _>>()?,
+            #[cfg(feature = "scripting")]
+            script: options.script.as_deref().map(Script::load).transpose()?,
+        })
+    }
+
+    /// Returns true if no extensions are loaded, so records need only the plan.
+    fn is_empty(&self) -> bool {
+        #[cfg(feature = "plugins")]
+        if !self.plugins.is_empty() {
+            return false;
+        }
+        #[cfg(feature = "scripting")]
+        if self.script.is_some() {
+            return false;
+        }
+        true
+    }
+
+    /// Transforms a reverse strand record, returning false if a script vetoed the transform.
+    ///
+    /// Planned tags of unsupported types are counted in `unsupported` unless a script decides
+    /// the transform.
+    fn transform(
+        &mut self,
+        record: &mut Record,
+        plan: &Plan,
+        unsupported: &mut Unsupported,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        #[cfg(feature = "scripting")]
+        let transformed = match &self.script {
+            Some(script) => script.apply(record, plan)?,
+            None => {
+                apply_tracked(record, plan, unsupported)?;
+                true
+            }
+        };
+        #[cfg(not(feature = "scripting"))]
+        let transformed = {
+            apply_tracked(record, plan, unsupported)?;
+            true
+        };
+
+        #[cfg(feature = "plugins")]
+        if transformed {
+            for plugin in self.plugins.iter_mut() {
+                plugin.apply(record)?;
+            }
+        }
+
+        Ok(transformed)
+    }
+}
+
+/// Number of batches that may wait between pipeline stages.
+const QUEUED_BATCHES: usize = 2;
+
+/// Upper bound on the batches alive at once: queued on both channels, held by the worker and the
+/// reader, and recycled for reading. `options.max_memory` is divided among them.
+const LIVE_BATCHES: usize = 2 * QUEUED_BATCHES + 4;
+
+/// The records of a batch that were transformed, by index, with the tags modified in each.
+type Audited = Vec<(usize, Vec<Tag>)>;
+
+/// An error sent from the transform stage to the writing thread.
+type BatchError = Box<dyn error::Error + Send + Sync>;
+
+/// A batch of records returned by the transform stage, with the records to list in the audit
+/// table and the first error it encountered.
+type Transformed = (Vec<Record>, Audited, Option<BatchError>);
+
+/// Reads the input of a run, reopening a remote input after a failed open or read, with the
+/// backoff of `options.retries` and `options.retry_delay`, and resuming after the last record
+/// read. Local files and stdin are never retried.
+struct Resume<'a> {
+    input: Option<&'a Path>,
+    options: &'a Options,
+    policy: RetryPolicy,
+    /// Records read so far, skipped when the input is reopened.
+    read: u64,
+}
+
+impl<'a> Resume<'a> {
+    fn new(input: Option<&'a Path>, options: &'a Options) -> Self {
+        let policy = match input {
+            Some(path) if is_remote(path) => RetryPolicy {
+                retries: options.retries,
+                delay: options.retry_delay,
+            },
+            _ => RetryPolicy::default(),
+        };
+        Self {
+            input,
+            options,
+            policy,
+            read: 0,
+        }
+    }
+
+    /// Opens the input with the reference of `options`, retrying a remote input that fails to
+    /// open.
+    fn open(&self) -> Result<Reader, Box<dyn error::Error>> {
+        self.policy
+            .run(&self.describe("Opening"), || self.open_once())
+    }
+
+    fn open_once(&self) -> Result<Reader, Box<dyn error::Error>> {
+        let mut reader = open_reader(self.input, self.options.threads)?;
+        if let Some(reference) = reference_for(self.options)? {
+            reader.set_reference(reference)?;
+        }
+        Ok(reader)
+    }
+
+    /// Reopens the input and skips the records already read.
+    fn reopen(&self) -> Result<Reader, Box<dyn error::Error>> {
+        let mut reader = self.open_once()?;
+        let mut record = Record::new();
+        for _ in 0..self.read {
+            match reader.read(&mut record) {
+                Some(Ok(())) => {}
+                None => return Err("The input ended before the records already read".into()),
+                Some(Err(e)) => return Err(Box::new(e)),
+            }
+        }
+        info!(
+            "Resumed {} after {} records",
+            self.describe("reading"),
+            self.read
+        );
+        Ok(reader)
+    }
+
+    fn describe(&self, verb: &str) -> String {
+        match self.input {
+            Some(path) => format!("{verb} {}", path.display()),
+            None => format!("{verb} stdin"),
+        }
+    }
+
+    /// Reads the next record, replacing `reader` with a reopened one after a failed read until
+    /// a read succeeds or the retries run out.
+    ///
+    /// # Returns
+    ///
+    /// Returns None once the input is exhausted, or the last error once every retry has failed.
+    ///
+    fn read(
+        &mut self,
+        reader: &mut Reader,
+        record: &mut Record,
+    ) -> Option<Result<(), Box<dyn error::Error>>> {
+        let mut retry = 0;
+        loop {
+            let mut error: Box<dyn error::Error> = match reader.read(record)? {
+                Ok(()) => {
+                    self.read += 1;
+                    return Some(Ok(()));
+                }
+                Err(e) => Box::new(e),
+            };
+            loop {
+                if retry >= self.policy.retries {
+                    return Some(Err(error));
+                }
+                self.policy.wait(&self.describe("Reading"), &error, retry);
+                retry += 1;
+                match self.reopen() {
+                    Ok(reopened) => {
+                        *reader = reopened;
+                        break;
+                    }
+                    Err(e) => error = e,
+                }
+            }
+        }
+    }
+}
+
+/// Reads up to `batch_size` records into `batch`, reusing the records it already holds.
+///
+/// Reading also stops once the records hold `max_bytes` of data, so long reads cannot grow a
+/// batch without bound; a batch always holds at least one record.
+///
+/// # Returns
+///
+/// Returns false once the input is exhausted and no records were read, or an error if reading
+/// fails.
+///
+fn read_batch(
+    reader: &mut Reader,
+    resume: &mut Resume,
+    batch: &mut Vec<Record>,
+    batch_size: usize,
+    max_bytes: usize,
+) -> Result<bool, Box<dyn error::Error>> {
+    batch.resize_with(batch_size, Record::new);
+    let mut len = 0;
+    let mut bytes = 0;
+    while len < batch_size && bytes < max_bytes {
+        match resume.read(reader, &mut batch[len]) {
+            Some(Ok(())) => {
+                bytes += record_bytes(&batch[len]);
+                len += 1;
+            }
+            None => break,
+            Some(Err(e)) => return Err(e),
+        }
+    }
+    batch.truncate(len);
+    Ok(len > 0)
+}
+
+/// Progress through the input: logged by compressed bytes for BGZF files and, with
+/// `options.progress_fd`, written as JSON events.
+struct InputProgress {
+    bytes: Option<ByteProgress>,
+    events: Option<ProgressEvents<File>>,
+}
+
+impl InputProgress {
+    /// Opens the progress of an input, writing the `start` event if events were requested.
+    fn new(input: Option<&Path>, options: &Options) -> Result<Self, Box<dyn error::Error>> {
+        let total = bgzf_input_size(input);
+        let events = match options.progress_fd {
+            Some(fd) => Some(ProgressEvents::new(open_fd(fd)?, total)?),
+            None => None,
+        };
+        Ok(Self {
+            bytes: total.map(ByteProgress::new),
+            events,
+        })
+    }
+
+    /// Updates progress after `records` records were read, from the reader's virtual offset,
+    /// whose upper 48 bits are the compressed offset of the block being read.
+    fn update(&mut self, reader: &Reader, records: usize) -> io::Result<()> {
+        let consumed = (reader.tell().max(0) >> 16) as u64;
+        if let Some(bytes) = &mut self.bytes {
+            bytes.update(consumed);
+        }
+        match &mut self.events {
+            Some(events) => events.update(records as u64, consumed),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the `finish` event if events were requested.
+    fn finish(self) -> io::Result<()> {
+        self.events.map_or(Ok(()), ProgressEvents::finish)
+    }
+}
+
+/// Returns the approximate memory held by a record: its fixed fields and variable-length data.
+fn record_bytes(record: &Record) -> usize {
+    std::mem::size_of::<Record>() + record.inner().l_data.max(0) as usize
+}
+
+/// Returns true if a record is reverse strand, as marked by the duplex strand of its `MI` tag
+/// with `options.strand_from_mi`, by `options.strand_tag` if set, and by its flag otherwise.
+pub(crate) fn is_reverse(
+    record: &Record,
+    options: &Options,
+) -> Result<bool, Box<dyn error::Error>> {
+    if options.strand_from_mi {
+        return duplex_b_strand(record);
+    }
+    match &options.strand_tag {
+        Some(tag) => minus_strand(record, tag),
+        None => Ok(record.is_reverse()),
+    }
+}
+
+/// Returns true if the plan applies to a record: it is reverse strand, per [`is_reverse`], and
+/// passes the filters of [`Options::selects`].
+pub(crate) fn is_selected(
+    record: &Record,
+    options: &Options,
+) -> Result<bool, Box<dyn error::Error>> {
+    if !options.is_reverse(record, is_reverse(record, options)?)? {
+        return Ok(false);
+    }
+    options.selects(record)
+}
+
+/// Returns the part of the plan that applies to a record: all of it when the record is
+/// selected, per [`is_selected`], unless tags name their own strand source with
+/// `TAG:OP:strand=SOURCE`, in which case each tag applies when its source marks the record
+/// reverse strand and the record passes the filters of [`Options::selects`].
+pub(crate) fn plan_for<'a>(
+    record: &Record,
+    selected: bool,
+    plan: &'a Plan,
+    options: &Options,
+) -> Result<Cow<'a, Plan>, Box<dyn error::Error>> {
+    if !plan.has_strand_sources() {
+        return Ok(if selected {
+            Cow::Borrowed(plan)
+        } else {
+            Cow::Owned(Plan::default())
+        });
+    }
+    if !selected && !options.selects(record)? {
+        return Ok(Cow::Owned(Plan::default()));
+    }
+    plan.restrict(|source| match source {
+        StrandSource::Read => Ok(selected),
+        StrandSource::Mate => Ok(record.is_mate_reverse()),
+        StrandSource::Tag(tag) => minus_strand(record, &tag),
+        StrandSource::Always => Ok(true),
+    })
+}
+
+/// Copies stdin into a scratch directory under `options.tmpdir` when a mode reads the input
+/// twice, `options.preflight` or `options.auto_orient`, so the copy can be read in its place.
+///
+/// Returns the scratch directory, which removes the copy when dropped at the end of the run,
+/// with the path of the copy, or None for input files and runs reading the input once.
+fn spool_stdin(
+    input: Option<&Path>,
+    options: &Options,
+) -> Result<Option<(ScratchDir, PathBuf)>, Box<dyn error::Error>> {
+    if input.is_some() || (options.preflight.is_none() && options.auto_orient.is_none()) {
+        return Ok(None);
+    }
+    let scratch = ScratchDir::create(options.tmpdir.as_deref())?;
+    let path = scratch.path().join("stdin");
+    let copied = io::copy(&mut io::stdin().lock(), &mut File::create(&path)?)?;
+    info!("Copied {copied} bytes of stdin to {path:?} to read it twice");
+    Ok(Some((scratch, path)))
+}
+
+/// Fails if none of the first `records` records of an input file carries any planned tag, which
+/// almost always means a misspelled tag. Empty inputs and plans pass.
+fn preflight(
+    input: Option<&Path>,
+    plan: &Plan,
+    options: &Options,
+    records: usize,
+) -> Result<(), Box<dyn error::Error>> {
+    let Some(path) = input else {
+        return Err("A preflight scan needs an input file, as stdin cannot be read twice".into());
+    };
+    if plan.is_empty() {
+        return Ok(());
+    }
+    let mut reader = open_reader(Some(path), options.threads)?;
+    if let Some(reference) = reference_for(options)? {
+        reader.set_reference(reference)?;
+    }
+
+    let mut record = Record::new();
+    let mut scanned = 0;
+    while scanned < records {
+        match reader.read(&mut record) {
+            Some(Ok(())) => {}
+            None => break,
+            Some(Err(e)) => return Err(Box::new(e)),
+        }
+        scanned += 1;
+        for spec in plan.specs() {
+            if aux::find(aux_data(&record), &spec.tag)?.is_some() {
+                info!("Preflight found planned tags within the first {scanned} records");
+                return Ok(());
+            }
+        }
+    }
+    if scanned == 0 {
+        return Ok(());
+    }
+    let tags: Vec<_> = plan
+        .specs()
+        .iter()
+        .map(|spec| String::from_utf8_lossy(&spec.tag).into_owned())
+        .collect();
+    Err(format!(
+        "None of the planned tags ({}) appear in the first {scanned} records of {path:?}; check \
+         the tag names for typos",
+        tags.join(", ")
+    )
+    .into())
+}
+
+/// Samples up to `records` records from the start of an input file and returns the plan with
+/// the operations [`Sampler`] chooses for the tags the plan does not already transform, logging
+/// each decision with its evidence. Dropped records are not sampled.
+fn auto_orient(
+    input: Option<&Path>,
+    plan: &Plan,
+    options: &Options,
+    whitelist: Option<&Whitelist>,
+    records: usize,
+) -> Result<Plan, Box<dyn error::Error>> {
+    let Some(path) = input else {
+        return Err(
+            "Detecting tag orientations needs an input file, as stdin cannot be read twice".into(),
+        );
+    };
+    let mut reader = open_reader(Some(path), options.threads)?;
+    if let Some(reference) = reference_for(options)? {
+        reader.set_reference(reference)?;
+    }
+
+    let mut sampler = Sampler::new(whitelist, plan.specs().iter().map(|spec| spec.tag));
+    let mut record = Record::new();
+    let mut scanned = 0;
+    while scanned < records {
+        match reader.read(&mut record) {
+            Some(Ok(())) => {}
+            None => break,
+            Some(Err(e)) => return Err(Box::new(e)),
+        }
+        scanned += 1;
+        if !options.drops(&record) {
+            sampler.observe(&record, is_reverse(&record, options)?)?;
+        }
+    }
+
+    let decisions = sampler.decide();
+    info!(
+        "Detected the orientation of {} tags from {} sampled records",
+        decisions.len(),
+        sampler.records()
+    );
+    for decision in &decisions {
+        info!("{decision}");
+    }
+    Ok(plan
+        .clone()
+        .with_specs(decisions.iter().filter_map(Decision::spec)))
+}
+
+/// Fails if a record lacks any of `options.required_tags` or, when the plan applies to it,
+/// carries a companion pair that is incomplete or of mismatched lengths or, with
+/// `options.strict`, lacks any planned tag.
+fn check_tags(
+    record: &Record,
+    selected: bool,
+    plan: &Plan,
+    options: &Options,
+) -> Result<(), Box<dyn error::Error>> {
+    let qname = || String::from_utf8_lossy(record.qname()).into_owned();
+    let mut missing = missing_tag(record, &options.required_tags)?;
+    if missing.is_none() && options.strict && selected {
+        missing = missing_tag(record, plan.specs().iter().map(|spec| &spec.tag))?;
+    }
+    if let Some(tag) = missing {
+        return Err(Box::new(MissingTagError {
+            qname: qname(),
+            tag,
+        }));
+    }
+    if selected {
+        aux::check_pairs(plan, qname, |tag| {
+            Ok(aux::find(aux_data(record), tag)?.map(|field| aux::value_length(&field)))
+        })?;
+        aux::check_values(plan, qname, |tag| {
+            let aux = aux_data(record);
+            Ok(aux::find(aux, tag)?.map(|field| aux[field.value].to_vec()))
+        })?;
+    }
+    Ok(())
+}
+
+/// Returns the tags a transform modified, given the auxiliary data from before it was applied
+/// with `plan`, for the audit table.
+fn audit_tags(
+    record: &Record,
+    before: &[u8],
+    plan: &Plan,
+    options: &Options,
+) -> Result<Vec<Tag>, Box<dyn error::Error>> {
+    let candidates = candidate_tags(plan, options.strand_annotation.as_ref());
+    modified_tags(before, aux_data(record), &candidates)
+}
+
+/// Checks the tags of a record and transforms it if it is selected, optionally restoring its
+/// original orientation or reverting it to an unaligned record.
+///
+/// Selected records are transformed by `transform`, given the part of the plan that applies,
+/// which returns false to leave the record as it was. Records transformed only by tags with their
+/// own strand source, per [`plan_for`], have those tags applied directly. Records dropped per
+/// [`Options::drops`] are neither checked nor transformed. With `options.sanitize`, malformed
+/// auxiliary data is repaired first and each repair counted in `repairs`.
+///
+/// Both the sequential and batched paths of [`run`] transform records here, so the output is the
+/// same for any number of threads.
+///
+/// Returns whether the record was transformed, and the tags modified when `options.audit_tsv` is
+/// set and a planned tag applied.
+fn transform_with<F>(
+    record: &mut Record,
+    plan: &Plan,
+    options: &Options,
+    unsupported: &mut Unsupported,
+    repairs: &mut Repairs,
+    transform: F,
+) -> Result<(bool, Option<Vec<Tag>>), Box<dyn error::Error>>
+where
+    F: FnOnce(&mut Record, &Plan, &mut Unsupported) -> Result<bool, Box<dyn error::Error>>,
+{
+    if options.drops(record) {
+        return Ok((false, None));
+    }
+    if options.sanitize {
+        sanitize_aux(record, plan, repairs)?;
+    }
+    let selected = is_selected(record, options)?;
+    let applied = plan_for(record, selected, plan, options)?;
+    check_tags(record, !applied.is_empty(), &applied, options)?;
+    let before =
+        (options.audit_tsv.is_some() && !applied.is_empty()).then(|| aux_data(record).to_vec());
+    let transformed = if selected {
+        transform(record, &applied, unsupported)?
+    } else if !applied.is_empty() {
+        apply_tracked(record, &applied, unsupported)?;
+        true
+    } else {
+        false
+    };
+    if selected && transformed {
+        if let Some(umi) = &options.qname_umi {
+            reverse_complement_qname_umi(record, umi);
+        }
+        if options.restore_orientation {
+            restore_orientation(record)?;
+        }
+    }
+    if let Some(tag) = options.strand_annotation.as_ref().filter(|_| transformed) {
+        annotate_strand(record, tag, selected)?;
+    }
+    let audited = match before.filter(|_| transformed) {
+        Some(before) => Some(audit_tags(record, &before, &applied, options)?),
+        None => None,
+    };
+    if options.revert_to_ubam {
+        revert_alignment(record)?;
+    }
+    Ok((transformed, audited))
+}
+
+/// Checks the tags of a record and applies the plan if the record is selected, optionally
+/// restoring its original orientation or reverting it to an unaligned record, per
+/// [`transform_with`].
+///
+/// Returns None if the record was not transformed, and otherwise the tags modified when
+/// `options.audit_tsv` is set, or no tags when it is not.
+pub(crate) fn transform_record(
+    record: &mut Record,
+    plan: &Plan,
+    options: &Options,
+    unsupported: &mut Unsupported,
+    repairs: &mut Repairs,
+) -> Result<Option<Vec<Tag>>, Box<dyn error::Error>> {
+    let (transformed, audited) =
+        transform_with(record, plan, options, unsupported, repairs, apply_all)?;
+    Ok(transformed.then(|| audited.unwrap_or_default()))
+}
+
+/// Applies a plan to a selected record, always transforming it.
+fn apply_all(
+    record: &mut Record,
+    plan: &Plan,
+    unsupported: &mut Unsupported,
+) -> Result<bool, Box<dyn error::Error>> {
+    apply_tracked(record, plan, unsupported)?;
+    Ok(true)
+}
+
+/// Returns the reference ID and strand of a record to count per contig before it is
+/// transformed, or None when not counting or for records left out of the output.
+fn contig_of(
+    record: &Record,
+    counting: bool,
+    options: &Options,
+) -> Result<Option<(i32, bool)>, Box<dyn error::Error>> {
+    if !counting || options.drops(record) {
+        return Ok(None);
+    }
+    Ok(Some((record.tid(), is_reverse(record, options)?)))
+}
+
+/// Returns whether the barcode of a record the plan may apply to matches a whitelist: None
+/// without a whitelist, and for records that are dropped, forward strand, or lack the barcode.
+fn whitelisted(
+    record: &Record,
+    whitelist: Option<&Whitelist>,
+    options: &Options,
+) -> Result<Option<bool>, Box<dyn error::Error>> {
+    let Some(whitelist) = whitelist else {
+        return Ok(None);
+    };
+    if options.drops(record) || !is_reverse(record, options)? {
+        return Ok(None);
+    }
+    whitelist.matches(aux_data(record))
+}
+
+/// Counts a record whose barcode was matched against a whitelist before the transform, per
+/// [`whitelisted`], by whether it still matches after.
+fn count_match(
+    record: &Record,
+    before: Option<bool>,
+    whitelist: Option<&Whitelist>,
+    rates: &mut MatchRates,
+) -> Result<(), Box<dyn error::Error>> {
+    if let (Some(before), Some(whitelist)) = (before, whitelist) {
+        let after = whitelist.matches(aux_data(record))?.unwrap_or(false);
+        rates.record(before, after);
+    }
+    Ok(())
+}
+
+/// Checks the tags of every record in a batch and applies the plan to selected records, per
+/// [`transform_record`], and returns the records to list in the audit table with the first
+/// error. With a whitelist, the match rates of barcodes are counted in `rates`, and with
+/// `contigs`, the records and transforms of each contig. The wall time taken is added to
+/// `times` if given.
+#[allow(clippy::too_many_arguments)]
+fn transform_batch(
+    batch: &mut [Record],
+    plan: &Plan,
+    options: &Options,
+    whitelist: Option<&Whitelist>,
+    unsupported: &mut Unsupported,
+    repairs: &mut Repairs,
+    rates: &mut MatchRates,
+    contigs: &mut Option<ContigCounts>,
+    times: Option<&StageTimes>,
+) -> (Audited, Option<BatchError>) {
+    let span = StageSpan::enter(Stage::Transform, times);
+    span.records(batch.len());
+    let mut transformed = 0;
+    let mut audited = Audited::new();
+    let result = batch
+        .iter_mut()
+        .enumerate()
+        .try_for_each(|(index, record)| {
+            let matched = whitelisted(record, whitelist, options)?;
+            let placed = contig_of(record, contigs.is_some(), options)?;
+            let (applied, tags) =
+                transform_with(record, plan, options, unsupported, repairs, apply_all)?;
+            transformed += usize::from(applied);
+            if let Some(tags) = tags {
+                audited.push((index, tags));
+            }
+            if let (Some(contigs), Some((tid, reverse))) = (contigs.as_mut(), placed) {
+                contigs.record(tid, reverse, applied);
+            }
+            count_match(record, matched, whitelist, rates)?;
+            Ok::<(), Box<dyn error::Error>>(())
+        });
+    span.transformed(transformed);
+    (audited, result.err().map(sendable))
+}
+
+/// Keeps an error of type `T` as an error that can be sent between threads.
+fn keep<T>(error: Box<dyn error::Error>) -> Result<BatchError, Box<dyn error::Error>>
+where
+    T: error::Error + Send + Sync + 'static,
+{
+    error.downcast::<T>().map(|error| error as BatchError)
+}
+
+/// Makes an error of the transform stage sendable to the writing thread, keeping the types of
+/// record check and I/O errors so the record they concern is still reported, as by
+/// [`crate::failure::Failure::from_error`]. Other errors are kept as their message.
+fn sendable(error: Box<dyn error::Error>) -> BatchError {
+    keep::<MissingTagError>(error)
+        .or_else(keep::<UnpairedTagError>)
+        .or_else(keep::<PairLengthError>)
+        .or_else(keep::<UnexpectedValueError>)
+        .or_else(keep::<io::Error>)
+        .or_else(keep::<rust_htslib::errors::Error>)
+        .unwrap_or_else(|error| error.to_string().into())
+}
+
+/// An iterator applying a plan to records from another iterator; see [`transform_iter`].
+#[derive(Debug)]
+pub struct TransformIter<'a, I> {
+    records: I,
+    plan: &'a Plan,
+    options: Options,
+    unsupported: Unsupported,
+    repairs: Repairs,
+}
+
+impl<I> TransformIter<'_, I> {
+    /// Selects and checks records per `options`, such as `strand_tag`, `min_mapq`, or `strict`,
+    /// instead of the defaults, and skips records dropped per [`Options::drops`]. Options naming
+    /// inputs and outputs have no effect.
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Returns the counts of planned tags left untouched so far because their type does not
+    /// support their operation, for callers to report once the records are exhausted.
+    pub fn unsupported(&self) -> &Unsupported {
+        &self.unsupported
+    }
+
+    /// Returns the counts of repairs made so far to malformed auxiliary data, which is only
+    /// repaired when the options set `sanitize`.
+    pub fn repairs(&self) -> &Repairs {
+        &self.repairs
+    }
+}
+
+impl<I, E> Iterator for TransformIter<'_, I>
+where
+    I: Iterator<Item = Result<Record, E>>,
+    E: Into<Box<dyn error::Error>>,
+{
+    type Item = Result<Record, Box<dyn error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let record = match self.records.next()? {
+                Ok(record) if self.options.drops(&record) => continue,
+                record => record,
+            };
+            return Some(record.map_err(Into::into).and_then(|mut record| {
+                transform_record(
+                    &mut record,
+                    self.plan,
+                    &self.options,
+                    &mut self.unsupported,
+                    &mut self.repairs,
+                )?;
+                Ok(record)
+            }));
+        }
+    }
+}
+
+/// Applies a plan to records from any source, such as region fetches, in-memory sets, or other
+/// transforms, yielding them for the caller to write however it likes.
+///
+/// Each record is transformed as [`run`] would with default options, so the plan applies to
+/// reverse strand records; see [`TransformIter::with_options`] to change that. Records are
+/// yielded in order, and errors from the source are passed through, so iteration may continue
+/// past them.
+///
+/// # Arguments
+///
+/// * `records` - The records to transform, e.g. from `Reader::records`
+/// * `plan` - The tag specifications to apply to reverse strand records
+///
+/// # Returns
+///
+/// Returns an iterator over the transformed records, or an error for each record that could
+/// not be read or fails its checks.
+///
+pub fn transform_iter<I, E>(records: I, plan: &Plan) -> TransformIter<'_, I::IntoIter>
+where
+    I: IntoIterator<Item = Result<Record, E>>,
+    E: Into<Box<dyn error::Error>>,
+{
+    TransformIter {
+        records: records.into_iter(),
+        plan,
+        options: Options::default(),
+        unsupported: Unsupported::default(),
+        repairs: Repairs::default(),
+    }
+}
+
+/// Streams records through a transform thread in batches passed over bounded channels.
+///
+/// Reading and writing stay on the calling thread and batches are transformed in order by a
+/// single worker, so the output is identical to a sequential run. Transformed batches are
+/// recycled for reading to avoid reallocating records.
+///
+/// # Arguments
+///
+/// * `reader` - The input reader, positioned after the header
+/// * `resume` - Reopens the input in place of `reader` after a failed read of a remote input
+/// * `writers` - The output writers, with the header written
+/// * `barcodes` - An optional barcode FASTQ receiving each record as it is written
+/// * `audit` - An optional audit table listing each transformed record as it is written
+/// * `plan` - The tag specifications to apply to reverse strand records
+/// * `options` - Options controlling the batch size and transform
+/// * `progress` - Progress logger updated for every record written
+/// * `input_progress` - Progress through the input, updated for every batch read
+/// * `sort_check` - An optional check of the input's sort order, applied to every record read
+/// * `whitelist` - An optional whitelist the barcodes of reverse strand records are matched
+///   against
+/// * `contigs` - Optional counts of records and transforms per contig, updated once the
+///   transform thread finishes
+/// * `times` - Wall time spent in each stage, updated for every batch if given
+///
+/// # Returns
+///
+/// Returns the counts of planned tags left untouched because of their type, of repairs made
+/// to malformed auxiliary data, and of whitelist matches, or the first error.
+///
+#[allow(clippy::too_many_arguments)]
+fn run_batched(
+    reader: &mut Reader,
+    resume: &mut Resume,
+    writers: &mut [Writer],
+    barcodes: &mut Option<BarcodeWriter>,
+    audit: &mut Option<AuditWriter>,
+    plan: &Plan,
+    options: &Options,
+    progress: &ProgLog,
+    input_progress: &mut InputProgress,
+    sort_check: &mut Option<SortCheck>,
+    whitelist: Option<&Whitelist>,
+    contigs: &mut Option<ContigCounts>,
+    times: Option<&StageTimes>,
+) -> Result<(Unsupported, Repairs, MatchRates), Box<dyn error::Error>> {
+    let batch_size = options.batch_size;
+    let max_bytes = options
+        .max_memory
+        .map_or(usize::MAX, |max_memory| (max_memory / LIVE_BATCHES).max(1));
+    let (batches, inbox) = mpsc::sync_channel::<Vec<Record>>(QUEUED_BATCHES);
+    // Room for every batch in flight, so the worker never blocks while the reader waits on it.
+    let (outbox, transformed) = mpsc::sync_channel::<Transformed>(QUEUED_BATCHES + 2);
+
+    let mut counted = contigs.take();
+
+    thread::scope(
+        |scope| -> Result<(Unsupported, Repairs, MatchRates), Box<dyn error::Error>> {
+            let worker = scope.spawn(move || {
+                let mut unsupported = Unsupported::default();
+                let mut repairs = Repairs::default();
+                let mut rates = MatchRates::default();
+                for mut batch in inbox {
+                    let (audited, error) = transform_batch(
+                        &mut batch,
+                        plan,
+                        options,
+                        whitelist,
+                        &mut unsupported,
+                        &mut repairs,
+                        &mut rates,
+                        &mut counted,
+                        times,
+                    );
+                    if outbox.send((batch, audited, error)).is_err() {
+                        break;
+                    }
+                }
+                (unsupported, repairs, rates, counted)
+            });
+
+            let mut write =
+            |(batch, audited, error): Transformed| -> Result<Vec<Record>, Box<dyn error::Error>> {
+                if let Some(error) = error {
+                    let error: Box<dyn error::Error> = error;
+                    return Err(error);
+                }
+                let span = StageSpan::enter(Stage::Write, times);
+                span.records(batch.len());
+                for record in &batch {
+                    progress.record();
+                    if options.drops(record) {
+                        continue;
+                    }
+                    for writer in writers.iter_mut() {
+                        writer.write(record)?;
+                    }
+                    if let Some(barcodes) = barcodes.as_mut() {
+                        barcodes.write(record)?;
+                    }
+                }
+                if let Some(audit) = audit.as_mut() {
+                    for (index, tags) in &audited {
+                        audit.write(&batch[*index], tags)?;
+                    }
+                }
+                Ok(batch)
+            };
+
+            let mut spare = Vec::new();
+            loop {
+                while let Ok(done) = transformed.try_recv() {
+                    spare.push(write(done)?);
+                }
+                let mut batch = spare.pop().unwrap_or_default();
+                let span = StageSpan::enter(Stage::Read, times);
+                if !read_batch(reader, resume, &mut batch, batch_size, max_bytes)? {
+                    break;
+                }
+                span.records(batch.len());
+                drop(span);
+                input_progress.update(reader, batch.len())?;
+                if let Some(check) = sort_check.as_mut() {
+                    for record in &batch {
+                        check.check(record.tid(), record.pos(), record.qname())?;
+                    }
+                }
+                batches
+                    .send(batch)
+                    .map_err(|_| "The transform thread stopped unexpectedly")?;
+            }
+
+            drop(batches);
+            for done in transformed {
+                write(done)?;
+            }
+            let (unsupported, repairs, rates, counted) = worker
+                .join()
+                .map_err(|_| "The transform thread stopped unexpectedly")?;
+            *contigs = counted;
+            Ok((unsupported, repairs, rates))
+        },
+    )
+}
+
+/// Returns the CRAM reference named in `options`, with its index if given.
+pub(crate) fn reference_for(options: &Options) -> Result<Option<PathBuf>, Box<dyn error::Error>> {
+    match &options.reference {
+        Some(fasta) => Ok(Some(reference_path(
+            fasta,
+            options.reference_index.as_deref(),
+        )?)),
+        None if options.reference_index.is_some() => {
+            Err("A reference index requires a reference FASTA".into())
+        }
+        None => Ok(None),
+    }
+}
+
+/// The pipes between writers and their outputs, which complete once the writers are closed.
+#[derive(Debug, Default)]
+pub(crate) struct OutputPipes {
+    splices: Vec<HeaderSplice>,
+    crams: Vec<CramPipe>,
+    checksums: Vec<ChecksumPipe>,
+}
+
+/// A buffered record's audit entry: the tags its transform modified, if it was transformed, and
+/// its auxiliary data before [`fix_mates`] ran on its group.
+type PendingAudit = (Option<Vec<Tag>>, Vec<u8>);
+
+/// Lists the records of a mate group in the audit table once [`fix_mates`] has run on it, with
+/// the tags propagated to each added to those its transform modified.
+///
+/// Records neither transformed nor given a propagated value are left out, as without
+/// `options.pair_fix`.
+fn write_audits(
+    audit: &mut AuditWriter,
+    group: &[Record],
+    pending: &[PendingAudit],
+    options: &Options,
+) -> Result<(), Box<dyn error::Error>> {
+    let mate_tags: Vec<Tag> = options.pair_fix.iter().map(|mate| mate.tag).collect();
+    for (record, (audited, before)) in group.iter().zip(pending) {
+        let propagated = modified_tags(before, aux_data(record), &mate_tags)?;
+        if audited.is_none() && propagated.is_empty() {
+            continue;
+        }
+        let mut tags = audited.clone().unwrap_or_default();
+        for tag in propagated {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        audit.write(record, &tags)?;
+    }
+    Ok(())
+}
+
+/// Writes records to every output and, with `options.barcode_fastq`, their barcodes.
+fn write_records(
+    records: &[Record],
+    writers: &mut [Writer],
+    barcodes: &mut Option<BarcodeWriter>,
+) -> Result<(), Box<dyn error::Error>> {
+    for record in records {
+        for writer in writers.iter_mut() {
+            writer.write(record)?;
+        }
+        if let Some(barcodes) = barcodes.as_mut() {
+            barcodes.write(record)?;
+        }
+    }
+    Ok(())
+}
+
+/// Opens the output, in `options.output_format` if given, and any `options.tee` outputs, failing
+/// if more than one writes to stdout.
+///
+/// With `options.write_md5`, file outputs are written through a [`ChecksumPipe`] each, and with
+/// `verbatim`, every output is written through a [`HeaderSplice`] writing that header text.
+/// With `options.cram_lossy_names` or `options.cram_quality_bins`, CRAM outputs are written
+/// through a [`CramPipe`] each, failing if no output is CRAM. The pipes are returned alongside
+/// the writers for [`finish_writers`].
+pub(crate) fn open_writers(
+    output: Option<&Path>,
+    header: &Header,
+    verbatim: Option<&[u8]>,
+    options: &Options,
+) -> Result<(Vec<Writer>, OutputPipes), Box<dyn error::Error>> {
+    let outputs: Vec<Option<&Path>> = std::iter::once(output)
+        .chain(
+            options
+                .tee
+                .iter()
+                .map(|path| (path.as_os_str() != "-").then_some(path.as_path())),
+        )
+        .collect();
+    if outputs.iter().filter(|output| output.is_none()).count() > 1 {
+        return Err("At most one output may be written to stdout".into());
+    }
+    let reference = reference_for(options)?;
+    let lossy = options.cram_lossy_names || options.cram_quality_bins.is_some();
+    let mut writers = Vec::with_capacity(outputs.len());
+    let mut pipes = OutputPipes::default();
+    for (i, output) in outputs.into_iter().enumerate() {
+        let format = if i == 0 { options.output_format } else { None };
+        let resolved = match output {
+            Some(path) => format.unwrap_or_else(|| OutputFormat::from_path(path)),
+            None => format.unwrap_or(OutputFormat::Sam),
+        };
+        let mut target = output.map(Path::to_path_buf);
+        if let Some(path) = output.filter(|_| options.write_md5) {
+            let pipe = ChecksumPipe::create(path)?;
+            target = Some(pipe.path().to_path_buf());
+            pipes.checksums.push(pipe);
+        }
+        if let Some(text) = verbatim {
+            let splice = HeaderSplice::create(target.as_deref(), resolved, text.to_vec())?;
+            target = Some(splice.path().to_path_buf());
+            pipes.splices.push(splice);
+        }
+        if lossy && resolved == OutputFormat::Cram {
+            let cram = CramPipe::create(
+                target.as_deref(),
+                reference.as_deref(),
+                options.threads,
+                options.cram_lossy_names,
+                options.cram_quality_bins.clone(),
+            )?;
+            let mut writer = open_writer(Some(cram.path()), header, 1, Some(OutputFormat::Bam))?;
+            writer.set_compression_level(CompressionLevel::Uncompressed)?;
+            pipes.crams.push(cram);
+            writers.push(writer);
+            continue;
+        }
+        let mut writer = match target {
+            Some(path) if Some(path.as_path()) != output => {
+                open_writer(Some(&path), header, options.threads, Some(resolved))?
+            }
+            _ => open_writer(output, header, options.threads, format)?,
+        };
+        if let Some(reference) = &reference {
+            writer.set_reference(reference)?;
+        }
+        writers.push(writer);
+    }
+    if lossy && pipes.crams.is_empty() {
+        return Err("Lossy CRAM read names and quality bins need a CRAM output".into());
+    }
+    Ok((writers, pipes))
+}
+
+/// Closes the writers, then waits for the header splices, CRAM outputs, and checksum sidecars
+/// of their outputs, which are only complete once every writer has flushed and closed.
+pub(crate) fn finish_writers(
+    writers: Vec<Writer>,
+    pipes: OutputPipes,
+) -> Result<(), Box<dyn error::Error>> {
+    drop(writers);
+    for splice in pipes.splices {
+        splice.finish()?;
+    }
+    for cram in pipes.crams {
+        cram.finish()?;
+    }
+    for checksum in pipes.checksums {
+        checksum.finish()?;
+    }
+    Ok(())
+}
+
+/// Logs a warning for each planned tag and type left untouched because the type does not
+/// support the planned operation.
+fn warn_unsupported(unsupported: &Unsupported) {
+    for entry in unsupported.types() {
+        warn!("{entry}");
+    }
+}
+
+/// Logs the breakdown of the run's wall time by stage when `options.profile` is set.
+fn report_times(times: Option<&StageTimes>, started: Instant) {
+    if let Some(times) = times {
+        times.report(started.elapsed());
+    }
+}
+
+/// Logs the counts of repairs made to malformed auxiliary data when `options.sanitize` is set.
+fn report_repairs(repairs: &Repairs, options: &Options) {
+    if options.sanitize {
+        info!("{repairs}");
+    }
+}
+
+/// Writes the counts of records and transforms per contig to `options.contig_tsv`, if set.
+fn write_contigs(
+    contigs: Option<&ContigCounts>,
+    options: &Options,
+) -> Result<(), Box<dyn error::Error>> {
+    if let (Some(contigs), Some(path)) = (contigs, &options.contig_tsv) {
+        info!("Per-contig summary: {path:?}");
+        contigs.write(path)?;
+    }
+    Ok(())
+}
+
+/// Tolerates up to `options.max_errors` records failing to transform, each logged with its read
+/// name and position and left out of the output, before the run fails on the next.
+#[derive(Debug)]
+struct ErrorBudget {
+    max: usize,
+    errors: usize,
+}
+
+impl ErrorBudget {
+    fn new(options: &Options) -> Self {
+        Self {
+            max: options.max_errors,
+            errors: 0,
+        }
+    }
+
+    /// Counts an error transforming a record, returning it instead once the budget is spent.
+    fn absorb(
+        &mut self,
+        record: &Record,
+        header: &HeaderView,
+        error: Box<dyn error::Error>,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let contig = match u32::try_from(record.tid()) {
+            Ok(tid) => String::from_utf8_lossy(header.tid2name(tid)).into_owned(),
+            Err(_) => "*".to_string(),
+        };
+        let context = format!(
+            "read {} at {contig}:{}",
+            String::from_utf8_lossy(record.qname()),
+            record.pos() + 1
+        );
+        if self.errors >= self.max {
+            if self.max == 0 {
+                return Err(error);
+            }
+            return Err(format!(
+                "Too many records failed to transform, more than the {} allowed; the last was \
+                 {context}: {error}",
+                self.max
+            )
+            .into());
+        }
+        self.errors += 1;
+        warn!(
+            "Skipping {context} after error {} of at most {}: {error}",
+            self.errors, self.max
+        );
+        Ok(())
+    }
+
+    /// Logs how many records were skipped, if any.
+    fn report(&self) {
+        if self.errors > 0 {
+            warn!(
+                "Skipped {} records that failed to transform, of at most {} allowed",
+                self.errors, self.max
+            );
+        }
+    }
+}
+
+/// Logs the match rates of barcodes against a whitelist, warning if the transform lowered it.
+fn report_matches(whitelist: Option<&Whitelist>, rates: &MatchRates) {
+    let Some(whitelist) = whitelist else {
+        return;
+    };
+    info!("{}", rates.summary(&whitelist.tag()));
+    if rates.worsened() {
+        warn!(
+            "Fewer reverse strand barcodes match the whitelist after the transform than before; \
+             check that the chosen operations suit this data"
+        );
+    }
+}
+
+/// Runs the tool `revtag` on an input SAM/BAM/CRAM file and writes the records to an output file.
+///
+/// For reverse strand alignments (flag 0x10 set, `-` in `options.strand_tag` when given, or an
+/// `MI` tag ending in `/B` with `options.strand_from_mi`), this function applies every tag
+/// specification in `plan` followed by any WASM plugins named in `options`. A script named in
+/// `options` may veto or override the transform of each record.
+///
+/// Planned tags whose type does not support their operation, such as an integer to reverse, are
+/// left unchanged and counted in a warning for each tag and type at the end of the run.
+///
+/// With `options.strict`, the run fails on the first reverse strand record lacking a planned tag,
+/// and on the first record lacking any of `options.required_tags`.
+///
+/// Tags given as `TAG:OP:strand=SOURCE` are instead transformed on records whose source, such
+/// as the mate's strand, marks them reverse strand; plugins and scripts cannot be combined with
+/// them.
+///
+/// With `options.qcfail`, the plan applies only to records passing its filter on the QC-fail
+/// flag, and with [`QcFailAction::Drop`](crate::options::QcFailAction::Drop) the others are
+/// left out of the output.
+///
+/// With `options.restore_orientation`, transformed records are then re-expressed in their
+/// original sequencing orientation.
+///
+/// With `options.revert_to_ubam`, every record is then reverted to an unaligned record, per
+/// [`revert_alignment`], secondary and supplementary records are dropped, and the outputs lose
+/// their `@SQ` lines. Reverse strand records the plan does not select, e.g. under
+/// `options.min_mapq`, are restored to their sequencing orientation without their tags being
+/// transformed. It cannot be combined with `options.audit_tsv`, whose positions it clears.
+///
+/// Every record is also written to each of `options.tee` in the same pass.
+///
+/// With `options.preserve_header`, outputs carry the input header text byte for byte, with only
+/// the `@PG` record appended, instead of htslib's rebuild of it; see [`HeaderSplice`].
+///
+/// With `options.write_md5`, the MD5 checksum of each output file is computed as it is written
+/// and saved beside it as a `.md5` sidecar; see [`ChecksumPipe`]. With `options.fsync`, every
+/// file written is flushed to storage before the run returns.
+///
+/// With `options.preflight`, the run first fails if none of that many records from the start of
+/// the input carries any planned tag.
+///
+/// With `options.barcode_fastq`, the barcode and qualities of every record carrying the barcode
+/// tag are also written, as transformed, to a FASTQ named by read name.
+///
+/// With `options.audit_tsv`, every transformed record is also listed, with its position and the
+/// tags modified, in an audit table; see [`AuditWriter`].
+///
+/// With `options.expected_sort_order`, the run fails before reading any record if the input
+/// header declares another sort order, and at the first record out of order otherwise; see
+/// [`SortCheck`].
+///
+/// With `options.pair_fix`, records are buffered by read name once transformed, and the listed
+/// tags are propagated from the source mate to the other records of its name before they are
+/// written; see [`fix_mates`]. Their audit rows are written with the group, and also list the
+/// tags propagated to each record.
+///
+/// With `options.whitelist`, the barcodes of reverse strand records are matched against it
+/// before and after the transform, and both match rates are logged at the end of the run, with
+/// a warning if the transform lowered it; see [`Whitelist`].
+///
+/// With `options.auto_orient`, that many records from the start of the input are first sampled
+/// to choose an operation, if any, for each tag the plan does not transform, which is then added
+/// to the plan; see [`Sampler`].
+///
+/// With `options.contig_tsv`, the records, reverse strand records, and transformed records
+/// placed on each contig are counted and written there as a table at the end of the run; see
+/// [`ContigCounts`].
+///
+/// With `options.profile`, the wall time spent reading, transforming, and writing records is
+/// logged at the end of the run.
+///
+/// Inputs named `.fastq` or `.fq` are read as FASTQ with SAM tags in their header comments and
+/// written as FASTQ; see [`fastq::run`].
+///
+/// With `options.max_errors`, up to that many records failing to transform, e.g. for lacking a
+/// required tag, are logged with their read name and position and left out of the output before
+/// the run fails on the next.
+///
+/// With more than one thread, no plugins or script, no `options.pair_fix`, and no
+/// `options.max_errors`, records are transformed on a separate thread in batches of
+/// `options.batch_size`; the output is the same either way.
+///
+/// # Arguments
+///
+/// * `input` - The input SAM/BAM/CRAM file path, or None for stdin
+/// * `output` - The output SAM/BAM/CRAM file path, or None for stdout
+/// * `plan` - The tag specifications to apply to reverse strand records
+/// * `options` - Options controlling how records are read, transformed, and written
+///
+/// # Returns
+///
+/// Returns the result of the execution with an integer exit code for success (0).
+///
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip_all, fields(input = ?input, output = ?output))
+)]
+pub fn run(
+    input: Option<&Path>,
+    output: Option<&Path>,
+    plan: &Plan,
+    options: &Options,
+) -> Result<i32, Box<dyn error::Error>> {
+    if options.batch_size == 0 {
+        return Err("Batch size must be at least 1".into());
+    }
+    if input.is_some_and(fastq::is_fastq_path) {
+        return fastq::run(input, output, plan, options);
+    }
+    let started = Instant::now();
+    let times = options.profile.then(StageTimes::default);
+    let mut extensions = Extensions::load(options)?;
+    if plan.has_strand_sources() && !extensions.is_empty() {
+        return Err(
+            "Tags with their own strand source cannot be combined with plugins or scripts".into(),
+        );
+    }
+    if options.revert_to_ubam && options.audit_tsv.is_some() {
+        return Err("An audit table cannot be combined with reverting to unaligned records".into());
+    }
+    let spooled = spool_stdin(input, options)?;
+    let input = spooled
+        .as_ref()
+        .map_or(input, |(_, path)| Some(path.as_path()));
+    if let Some(records) = options.preflight {
+        preflight(input, plan, options, records)?;
+    }
+    let whitelist = options
+        .whitelist
+        .as_deref()
+        .map(|path| Whitelist::read(path, options.whitelist_tag))
+        .transpose()?;
+    let oriented;
+    let plan = match options.auto_orient {
+        Some(records) => {
+            oriented = auto_orient(input, plan, options, whitelist.as_ref(), records)?;
+            &oriented
+        }
+        None => plan,
+    };
+
+    let mut resume = Resume::new(input, options);
+    let mut reader = resume.open()?;
+    if let Some(order) = options.expected_sort_order {
+        check_header(reader.header().as_bytes(), order)?;
+    }
+    let mut sort_check = options.expected_sort_order.map(SortCheck::new);
+    let mut contigs = options.contig_tsv.as_ref().map(|_| {
+        ContigCounts::new(
+            reader
+                .header()
+                .target_names()
+                .into_iter()
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .collect(),
+        )
+    });
+    let verbatim = options
+        .preserve_header
+        .then(|| verbatim_header(reader.header(), options))
+        .transpose()?;
+    let header = header_with_program(reader.header(), options)?;
+    let (mut writers, pipes) = open_writers(output, &header, verbatim.as_deref(), options)?;
+    let mut barcodes = options
+        .barcode_fastq
+        .as_deref()
+        .map(|path| BarcodeWriter::create(path, options.barcode_tag, options.barcode_quality_tag))
+        .transpose()?;
+    let mut audit = options
+        .audit_tsv
+        .as_deref()
+        .map(|path| AuditWriter::create(path, reader.header()))
+        .transpose()?;
+
+    let progress = ProgLogBuilder::new()
+        .name("main")
+        .verb("Processed")
+        .noun("alignment records")
+        .unit(100_000)
+        .build();
+    let mut input_progress = InputProgress::new(input, options)?;
+
+    if options.threads > 1
+        && extensions.is_empty()
+        && options.pair_fix.is_empty()
+        && options.max_errors == 0
+    {
+        let (unsupported, repairs, rates) = run_batched(
+            &mut reader,
+            &mut resume,
+            &mut writers,
+            &mut barcodes,
+            &mut audit,
+            plan,
+            options,
+            &progress,
+            &mut input_progress,
+            &mut sort_check,
+            whitelist.as_ref(),
+            &mut contigs,
+            times.as_ref(),
+        )?;
+        write_contigs(contigs.as_ref(), options)?;
+        warn_unsupported(&unsupported);
+        report_repairs(&repairs, options);
+        report_matches(whitelist.as_ref(), &rates);
+        if let Some(barcodes) = barcodes {
+            barcodes.finish()?;
+        }
+        if let Some(audit) = audit {
+            audit.finish()?;
+        }
+        timed(times.as_ref(), Stage::Write, || {
+            finish_writers(writers, pipes)
+        })?;
+        sync_outputs(output, options)?;
+        input_progress.finish()?;
+        report_times(times.as_ref(), started);
+        return Ok(0);
+    }
+
+    let mut record = Record::new();
+    let mut unsupported = Unsupported::default();
+    let mut repairs = Repairs::default();
+    let mut rates = MatchRates::default();
+    let mut mates = (!options.pair_fix.is_empty()).then(MateGroups::new);
+    let mut pending: Vec<PendingAudit> = Vec::new();
+    let mut propagated = 0;
+    let mut errors = ErrorBudget::new(options);
+
+    loop {
+        let span = StageSpan::enter(Stage::Read, times.as_ref());
+        match resume.read(&mut reader, &mut record) {
+            Some(Ok(())) => {}
+            None => break,
+            Some(Err(e)) => return Err(e),
+        }
+        span.records(1);
+        drop(span);
+        input_progress.update(&reader, 1)?;
+        if let Some(check) = sort_check.as_mut() {
+            check.check(record.tid(), record.pos(), record.qname())?;
+        }
+        if options.drops(&record) {
+            progress.record();
+            continue;
+        }
+
+        let span = StageSpan::enter(Stage::Transform, times.as_ref());
+        span.records(1);
+        let outcome = (|| -> Result<(bool, Option<Vec<Tag>>, _), Box<dyn error::Error>> {
+            let matched = whitelisted(&record, whitelist.as_ref(), options)?;
+            let placed = contig_of(&record, contigs.is_some(), options)?;
+            let (transformed, audited) = transform_with(
+                &mut record,
+                plan,
+                options,
+                &mut unsupported,
+                &mut repairs,
+                |record, applied, unsupported| extensions.transform(record, applied, unsupported),
+            )?;
+            count_match(&record, matched, whitelist.as_ref(), &mut rates)?;
+            Ok((transformed, audited, placed))
+        })();
+        let (transformed, audited, placed) = match outcome {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                errors.absorb(&record, reader.header(), e)?;
+                progress.record();
+                continue;
+            }
+        };
+        if let (Some(contigs), Some((tid, reverse))) = (contigs.as_mut(), placed) {
+            contigs.record(tid, reverse, transformed);
+        }
+        span.transformed(usize::from(transformed));
+        drop(span);
+
+        let span = StageSpan::enter(Stage::Write, times.as_ref());
+        span.records(1);
+        match mates.as_mut() {
+            Some(mates) => {
+                if audit.is_some() {
+                    pending.push((audited, aux_data(&record).to_vec()));
+                }
+                if let Some(mut group) = mates.push(record.clone()) {
+                    propagated += fix_mates(
+                        &mut group,
+                        &options.pair_fix,
+                        options.pair_fix_source,
+                        |record| is_reverse(record, options),
+                    )?;
+                    if let Some(audit) = audit.as_mut() {
+                        let rest = pending.split_off(group.len());
+                        write_audits(audit, &group, &pending, options)?;
+                        pending = rest;
+                    }
+                    write_records(&group, &mut writers, &mut barcodes)?;
+                }
+            }
+            None => {
+                if let (Some(audit), Some(tags)) = (audit.as_mut(), audited) {
+                    audit.write(&record, &tags)?;
+                }
+                write_records(std::slice::from_ref(&record), &mut writers, &mut barcodes)?;
+            }
+        }
+        progress.record();
+    }
+
+    if let Some(mates) = mates {
+        let mut group = mates.finish();
+        timed(
+            times.as_ref(),
+            Stage::Write,
+            || -> Result<(), Box<dyn error::Error>> {
+                propagated += fix_mates(
+                    &mut group,
+                    &options.pair_fix,
+                    options.pair_fix_source,
+                    |record| is_reverse(record, options),
+                )?;
+                if let Some(audit) = audit.as_mut() {
+                    write_audits(audit, &group, &pending, options)?;
+                }
+                write_records(&group, &mut writers, &mut barcodes)
+            },
+        )?;
+        info!("Propagated {propagated} tag values between mates");
+    }
+    errors.report();
+    write_contigs(contigs.as_ref(), options)?;
+    warn_unsupported(&unsupported);
+    report_repairs(&repairs, options);
+    report_matches(whitelist.as_ref(), &rates);
+    if let Some(barcodes) = barcodes {
+        barcodes.finish()?;
+    }
+    if let Some(audit) = audit {
+        audit.finish()?;
+    }
+    timed(times.as_ref(), Stage::Write, || {
+        finish_writers(writers, pipes)
+    })?;
+    sync_outputs(output, options)?;
+    input_progress.finish()?;
+    report_times(times.as_ref(), started);
+    Ok(0)
+}
+
+/// Runs the tool `revtag` on an input SAM/BAM/CRAM file and writes the records to an output file.
+///
+/// For reverse strand alignments (flag 0x10 set), this function will:
+/// - Reverse the order of array-like values in specified tags (--rev)
+/// - Reverse complement array-like string values in specified tags (--revcomp)
+///
+/// # Arguments
+///
+/// * `input` - The input SAM/BAM/CRAM file path, or None/Some("-") for stdin
+/// * `output` - The output SAM/BAM/CRAM file path, or None/Some("-") for stdout
+/// * `rev` - SAM tags to reverse (e.g., base qualities)
+/// * `revcomp` - SAM tags to reverse complement (e.g., sequences)
+/// * `threads` - Extra threads for BAM/CRAM compression/decompression
+///
+/// # Returns
+///
+/// Returns the result of the execution with an integer exit code for success (0).
+///
+pub fn revtag(
+    input: Option<PathBuf>,
+    output: Option<PathBuf>,
+    rev: Vec<String>,
+    revcomp: Vec<String>,
+    threads: usize,
+) -> Result<i32, Box<dyn error::Error>> {
+    let plan = Plan::new(&rev, &revcomp)?;
+    let options = Options {
+        threads,
+        ..Options::default()
+    };
+    run(input.as_deref(), output.as_deref(), &plan, &options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::Reader;
+    use rust_htslib::bam::record::Aux;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    // Helper to build a minimal SAM header
+    fn sam_header() -> &'static str {
+        "@HD\tVN:1.6\tSO:unknown\n@SQ\tSN:chr1\tLN:1000\n"
+    }
+
+    // Helper to create two SAM alignment lines: one forward and one reverse with tags
+    fn sam_body_with_tags() -> String {
+        // Fields: QNAME FLAG RNAME POS MAPQ CIGAR RNEXT PNEXT TLEN SEQ QUAL TAGS...
+        // Reverse read flag: 16 (0x10), Forward read flag: 0
+        // rev tags: QT (ArrayU8), MN (String)
+        // revcomp tags: BC (String), SQ (ArrayU8 simulated sequence)
+        let forward = "fwd\t0\tchr1\t1\t60\t10M\t*\t0\t0\tATCGATCGAA\tFFFFFFFFFF\tQT:B:C,10,20,30\tMN:Z:HELLO\tBC:Z:ATCG\tSQ:B:C,65,84,67,71"; // A T C G
+        let reverse = "rev\t16\tchr1\t2\t60\t10M\t*\t0\t0\tGGCATCGTTA\tFFFFFFFFFF\tQT:B:C,1,2,3\tMN:Z:WORLD\tBC:Z:GATT\tSQ:B:C,71,65,84,84"; // G A T T
+        format!("{forward}\n{reverse}\n")
+    }
+
+    // Parse SAM output produced by revtag, returning vector of (qname, tags_map)
+    fn parse_sam_tags(sam: &str) -> Vec<(String, std::collections::HashMap<String, String>)> {
+        sam.lines()
+            .filter(|l| !l.starts_with('@') && !l.is_empty())
+            .map(|line| {
+                let parts: Vec<&str> = line.split('\t').collect();
+                let qname = parts[0].to_string();
+                let mut tags = std::collections::HashMap::new();
+                for field in parts.iter().skip(11) {
+                    if field.len() >= 5
+                        && field.chars().nth(2) == Some(':')
+                        && field.chars().nth(4) == Some(':')
+                    {
+                        // TAG:TYPE:VALUE simplistic parse
+                        let tag = &field[0..2];
+                        let value = &field[5..];
+                        tags.insert(tag.to_string(), value.to_string());
+                    } else if field.len() >= 5 && field.chars().nth(2) == Some(':') {
+                        // TAG:B:TYPE,LIST  (we'll just store raw)
+                        let tag = &field[0..2];
+                        let value = &field[5..];
+                        tags.insert(tag.to_string(), value.to_string());
+                    }
+                }
+                (qname, tags)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_revtag_happy_path_forward_vs_reverse() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}{}", sam_header(), sam_body_with_tags()).unwrap();
+
+        let outfile = NamedTempFile::new().expect("temp sam output");
+
+        let exit = revtag(
+            Some(infile.path().to_path_buf()),
+            Some(outfile.path().to_path_buf()),
+            vec!["QT".into(), "MN".into()], // rev
+            vec!["BC".into(), "SQ".into()], // revcomp
+            1,
+        )
+        .expect("revtag should succeed");
+        assert_eq!(exit, 0);
+
+        let output_contents = std::fs::read_to_string(outfile.path()).unwrap();
+        let parsed = parse_sam_tags(&output_contents);
+        assert_eq!(parsed.len(), 2);
+
+        // Find forward and reverse records
+        let mut fwd = None;
+        let mut rev_rec = None;
+        for (q, t) in parsed {
+            if q == "fwd" {
+                fwd = Some(t);
+            } else if q == "rev" {
+                rev_rec = Some(t);
+            }
+        }
+        let fwd = fwd.expect("forward record present");
+        let rev_rec = rev_rec.expect("reverse record present");
+
+        assert_eq!(fwd.get("MN").unwrap(), "HELLO");
+        assert_eq!(fwd.get("BC").unwrap(), "ATCG");
+        // MN reversed WORLD -> DLROW
+        assert_eq!(rev_rec.get("MN").unwrap(), "DLROW");
+        // BC revcomp of GATT -> AATC
+        assert_eq!(rev_rec.get("BC").unwrap(), "AATC");
+    }
+
+    #[test]
+    fn test_revtag_threads_two() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}{}", sam_header(), sam_body_with_tags()).unwrap();
+        let outfile = NamedTempFile::new().expect("temp sam output");
+
+        let exit = revtag(
+            Some(infile.path().to_path_buf()),
+            Some(outfile.path().to_path_buf()),
+            vec!["MN".into()],
+            vec!["BC".into()],
+            2,
+        )
+        .expect("revtag should succeed with threads=2");
+        assert_eq!(exit, 0);
+        let output_contents = std::fs::read_to_string(outfile.path()).unwrap();
+        assert!(output_contents.contains("rev"));
+        assert!(output_contents.contains("MN:Z:DLROW"));
+    }
+
+    #[test]
+    fn test_revtag_output_identical_across_threads() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_header()).unwrap();
+        for i in 0..5_000 {
+            let flag = if i % 2 == 0 { 0 } else { 16 };
+            writeln!(
+                infile,
+                "r{i}\t{flag}\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:B:C,{},2,3\tBC:Z:GATT",
+                i % 900 + 1,
+                i % 200
+            )
+            .unwrap();
+        }
+        let tmpdir = tempfile::tempdir().unwrap();
+
+        let outputs: Vec<Vec<u8>> = [1, 2, 4]
+            .into_iter()
+            .map(|threads| {
+                let bam_out = tmpdir.path().join(format!("out.{threads}.bam"));
+                revtag(
+                    Some(infile.path().to_path_buf()),
+                    Some(bam_out.clone()),
+                    vec!["QT".into()],
+                    vec!["BC".into()],
+                    threads,
+                )
+                .expect("revtag should succeed");
+                std::fs::read(bam_out).unwrap()
+            })
+            .collect();
+
+        assert!(outputs.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[test]
+    fn test_run_batched_matches_sequential() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_header()).unwrap();
+        for i in 0..100 {
+            let flag = if i % 3 == 0 { 16 } else { 0 };
+            writeln!(
+                infile,
+                "r{i}\t{flag}\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\tFFFF\tMN:Z:AB{i}\tBC:Z:GATT",
+                i + 1
+            )
+            .unwrap();
+        }
+        let tmpdir = tempfile::tempdir().unwrap();
+        let plan = Plan::new(&["MN".to_string()], &["BC".to_string()]).unwrap();
+
+        let outputs: Vec<String> = [
+            (1, 1024, None),
+            (2, 1, None),
+            (2, 7, None),
+            (3, 1024, None),
+            (2, 1024, Some(4096)),
+            (2, 1024, Some(1)),
+        ]
+        .into_iter()
+        .map(|(threads, batch_size, max_memory)| {
+            let sam_out = tmpdir
+                .path()
+                .join(format!("out.{threads}.{batch_size}.{max_memory:?}.sam"));
+            let options = Options {
+                threads,
+                batch_size,
+                max_memory,
+                ..Options::default()
+            };
+            run(
+                Some(infile.path()),
+                Some(sam_out.as_path()),
+                &plan,
+                &options,
+            )
+            .expect("run should succeed");
+            std::fs::read_to_string(sam_out).unwrap()
+        })
+        .collect();
+
+        assert!(outputs[0].contains("r3\t16") && outputs[0].contains("MN:Z:3BA"));
+        assert!(outputs.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[test]
+    fn test_transform_iter() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}{}", sam_header(), sam_body_with_tags()).unwrap();
+        let mut reader = Reader::from_path(infile.path()).expect("read SAM input");
+        let plan = Plan::new(&["QT".to_string()], &["BC".to_string()]).unwrap();
+
+        let records: Vec<Record> = transform_iter(reader.records(), &plan)
+            .collect::<Result<_, _>>()
+            .expect("transform records");
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].aux(b"BC").unwrap(), Aux::String("ATCG"));
+        assert_eq!(records[1].aux(b"BC").unwrap(), Aux::String("AATC"));
+        match records[1].aux(b"QT").unwrap() {
+            Aux::ArrayU8(values) => assert_eq!(values.iter().collect::<Vec<_>>(), [3, 2, 1]),
+            other => panic!("unexpected QT value {other:?}"),
+        }
+
+        let options = Options {
+            strict: true,
+            ..Options::default()
+        };
+        let strict_plan = Plan::new(&["XX".to_string()], &[]).unwrap();
+        let mut iter = transform_iter(records.into_iter().map(Ok::<_, String>), &strict_plan)
+            .with_options(options);
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.unsupported().types().is_empty());
+    }
+
+    #[test]
+    fn test_run_strict_fails_on_missing_tag() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}{}", sam_header(), sam_body_with_tags()).unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let sam_out = tmpdir.path().join("out.sam");
+
+        for threads in [1, 2] {
+            let options = Options {
+                threads,
+                strict: true,
+                ..Options::default()
+            };
+            let plan = Plan::new(&["QT".to_string()], &["BC".to_string()]).unwrap();
+            run(
+                Some(infile.path()),
+                Some(sam_out.as_path()),
+                &plan,
+                &options,
+            )
+            .expect("every planned tag is present");
+
+            let typo = Plan::new(&["Qt".to_string()], &[]).unwrap();
+            let error = run(
+                Some(infile.path()),
+                Some(sam_out.as_path()),
+                &typo,
+                &options,
+            )
+            .expect_err("Qt is missing from the reverse record");
+            assert_eq!(error.to_string(), "Record rev is missing required tag Qt");
+        }
+    }
+
+    #[test]
+    fn test_run_required_tags_checks_every_record() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}{}", sam_header(), sam_body_with_tags()).unwrap();
+        writeln!(
+            infile,
+            "bare\t0\tchr1\t3\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:B:C,1"
+        )
+        .unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let sam_out = tmpdir.path().join("out.sam");
+        let plan = Plan::new(&["QT".to_string()], &[]).unwrap();
+
+        for threads in [1, 2] {
+            let options = Options {
+                threads,
+                required_tags: vec![*b"QT"],
+                ..Options::default()
+            };
+            run(
+                Some(infile.path()),
+                Some(sam_out.as_path()),
+                &plan,
+                &options,
+            )
+            .expect("every record carries QT");
+
+            let options = Options {
+                threads,
+                required_tags: vec![*b"QT", *b"BC"],
+                ..Options::default()
+            };
+            let error = run(
+                Some(infile.path()),
+                Some(sam_out.as_path()),
+                &plan,
+                &options,
+            )
+            .expect_err("the forward record bare lacks BC");
+            assert_eq!(error.to_string(), "Record bare is missing required tag BC");
+        }
+    }
+
+    #[test]
+    fn test_run_pair_fails_on_lone_tag() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_header()).unwrap();
+        writeln!(
+            infile,
+            "fwd\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD"
+        )
+        .unwrap();
+        writeln!(
+            infile,
+            "both\t16\tchr1\t2\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\tQT:Z:ABCD"
+        )
+        .unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let sam_out = tmpdir.path().join("out.sam");
+        let plan = Plan::default().with_pairs(&["BC:QT".to_string()]).unwrap();
+
+        for threads in [1, 2] {
+            let options = Options {
+                threads,
+                ..Options::default()
+            };
+            run(
+                Some(infile.path()),
+                Some(sam_out.as_path()),
+                &plan,
+                &options,
+            )
+            .expect("forward records need not carry both tags");
+            let tags = parse_sam_tags(&std::fs::read_to_string(&sam_out).unwrap());
+            assert_eq!(tags[1].1["BC"], "CGTT");
+            assert_eq!(tags[1].1["QT"], "DCBA");
+        }
+
+        writeln!(
+            infile,
+            "lonely\t16\tchr1\t3\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD"
+        )
+        .unwrap();
+        for threads in [1, 2] {
+            let options = Options {
+                threads,
+                ..Options::default()
+            };
+            let error = run(
+                Some(infile.path()),
+                Some(sam_out.as_path()),
+                &plan,
+                &options,
+            )
+            .expect_err("lonely carries QT without BC");
+            assert_eq!(
+                error.to_string(),
+                "Record lonely has tag QT without its companion tag BC"
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_strand_from_tag() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_header()).unwrap();
+        writeln!(
+            infile,
+            "minus\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF\tXS:A:-\tMN:Z:AB"
+        )
+        .unwrap();
+        writeln!(
+            infile,
+            "plus\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF\tXS:A:+\tMN:Z:AB"
+        )
+        .unwrap();
+        writeln!(
+            infile,
+            "flag\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tMN:Z:AB"
+        )
+        .unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let plan = Plan::new(&["MN".to_string()], &[]).unwrap();
+
+        for threads in [1, 2] {
+            let sam_out = tmpdir.path().join(format!("out.{threads}.sam"));
+            let options = Options {
+                threads,
+                strand_tag: Some(*b"XS"),
+                ..Options::default()
+            };
+            run(
+                Some(infile.path()),
+                Some(sam_out.as_path()),
+                &plan,
+                &options,
+            )
+            .expect("run should succeed");
+
+            let tags = parse_sam_tags(&std::fs::read_to_string(sam_out).unwrap());
+            let values: Vec<&str> = tags.iter().map(|(_, t)| t["MN"].as_str()).collect();
+            assert_eq!(values, vec!["BA", "AB", "AB"]);
+        }
+    }
+
+    #[test]
+    fn test_run_strand_source_per_tag() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_header()).unwrap();
+        // Flags: 0x1 paired, 0x10 reverse, 0x20 mate reverse.
+        writeln!(
+            infile,
+            "both\t49\tchr1\t1\t60\t4M\t=\t1\t0\tACGT\tFFFF\tMN:Z:AB\tMB:Z:AAC"
+        )
+        .unwrap();
+        writeln!(
+            infile,
+            "mate\t33\tchr1\t1\t60\t4M\t=\t1\t0\tACGT\tFFFF\tMN:Z:AB\tMB:Z:AAC"
+        )
+        .unwrap();
+        writeln!(
+            infile,
+            "read\t17\tchr1\t1\t60\t4M\t=\t1\t0\tACGT\tFFFF\tMN:Z:AB\tMB:Z:AAC"
+        )
+        .unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let plan = Plan::parse(
+            &["MN".to_string()],
+            &[],
+            &["MB:revcomp:strand=mate".to_string()],
+        )
+        .unwrap();
+
+        for threads in [1, 2] {
+            let sam_out = tmpdir.path().join(format!("out.{threads}.sam"));
+            let options = Options {
+                threads,
+                ..Options::default()
+            };
+            run(
+                Some(infile.path()),
+                Some(sam_out.as_path()),
+                &plan,
+                &options,
+            )
+            .expect("run should succeed");
+
+            let tags = parse_sam_tags(&std::fs::read_to_string(sam_out).unwrap());
+            let values: Vec<(&str, &str)> = tags
+                .iter()
+                .map(|(_, t)| (t["MN"].as_str(), t["MB"].as_str()))
+                .collect();
+            assert_eq!(values, vec![("BA", "GTT"), ("AB", "GTT"), ("BA", "AAC")]);
+        }
+    }
+
+    #[test]
+    fn test_run_preflight_fails_without_planned_tags() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}{}", sam_header(), sam_body_with_tags()).unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let sam_out = tmpdir.path().join("out.sam");
+        let options = Options {
+            preflight: Some(10),
+            ..Options::default()
+        };
+
+        let plan = Plan::new(&["QX".to_string()], &[]).unwrap();
+        let err = run(
+            Some(infile.path()),
+            Some(sam_out.as_path()),
+            &plan,
+            &options,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("None of the planned tags (QX)"));
+        assert!(err.to_string().contains("first 2 records"));
+        assert!(!sam_out.exists());
+
+        let plan = Plan::new(&["QX".to_string(), "QT".to_string()], &[]).unwrap();
+        run(
+            Some(infile.path()),
+            Some(sam_out.as_path()),
+            &plan,
+            &options,
+        )
+        .expect("run should succeed");
+
+        let err = run(None, Some(sam_out.as_path()), &plan, &options).unwrap_err();
+        assert!(err.to_string().contains("needs an input file"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_writes_progress_events() {
+        use std::os::fd::IntoRawFd;
+
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}{}", sam_header(), sam_body_with_tags()).unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let plan = Plan::new(&["QT".to_string()], &[]).unwrap();
+
+        for threads in [1, 2] {
+            let events = tmpdir.path().join(format!("events.{threads}.jsonl"));
+            let sam_out = tmpdir.path().join(format!("out.{threads}.sam"));
+            let options = Options {
+                threads,
+                progress_fd: Some(File::create(&events).unwrap().into_raw_fd()),
+                ..Options::default()
+            };
+            run(
+                Some(infile.path()),
+                Some(sam_out.as_path()),
+                &plan,
+                &options,
+            )
+            .expect("run should succeed");
+
+            assert_eq!(
+                std::fs::read_to_string(&events).unwrap(),
+                "{\"event\":\"start\",\"bytes_total\":null}\n{\"event\":\"finish\",\"records\":2}\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_rejects_zero_batch_size() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_header()).unwrap();
+        let options = Options {
+            batch_size: 0,
+            ..Options::default()
+        };
+        assert!(run(Some(infile.path()), None, &Plan::default(), &options).is_err());
+    }
+
+    #[test]
+    fn test_revtag_empty_input() {
+        let mut infile = NamedTempFile::new().expect("empty sam input");
+        write!(infile, "{}", sam_header()).unwrap(); // header only
+        let outfile = NamedTempFile::new().expect("empty sam output");
+
+        let exit = revtag(
+            Some(infile.path().to_path_buf()),
+            Some(outfile.path().to_path_buf()),
+            vec!["QT".into()],
+            vec!["BC".into()],
+            1,
+        )
+        .expect("revtag should succeed on empty input");
+        assert_eq!(exit, 0);
+        let output_contents = std::fs::read_to_string(outfile.path()).unwrap();
+        assert!(output_contents.lines().all(|l| l.starts_with('@')));
+    }
+
+    #[test]
+    fn test_revtag_output_bam_format() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}{}", sam_header(), sam_body_with_tags()).unwrap();
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let bam_out = tmpdir.path().join("out.bam");
+
+        let exit = revtag(
+            Some(infile.path().to_path_buf()),
+            Some(bam_out.clone()),
+            vec!["QT".into(), "MN".into()],
+            vec!["BC".into()],
+            1,
+        )
+        .expect("revtag should succeed producing BAM");
+        assert_eq!(exit, 0);
+
+        let mut reader = Reader::from_path(&bam_out).expect("read BAM output");
+        let mut rec = Record::new();
+        let mut saw_fwd = false;
+        let mut saw_rev = false;
+        while let Some(Ok(())) = reader.read(&mut rec) {
+            let qname = String::from_utf8_lossy(rec.qname()).to_string();
+            if qname == "fwd" {
+                saw_fwd = true;
+                if let Ok(Aux::String(s)) = rec.aux(b"MN") {
+                    assert_eq!(s, "HELLO");
+                }
+            } else if qname == "rev" {
+                saw_rev = true;
+                if let Ok(Aux::String(s)) = rec.aux(b"MN") {
+                    assert_eq!(s, "DLROW");
+                }
+            }
+        }
+        assert!(saw_fwd && saw_rev);
+    }
+
+    #[test]
+    fn test_run_tees_to_every_output() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}{}", sam_header(), sam_body_with_tags()).unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let sam_out = tmpdir.path().join("out.sam");
+        let bam_out = tmpdir.path().join("tee.bam");
+        let plan = Plan::new(&["MN".to_string()], &[]).unwrap();
+
+        for threads in [1, 2] {
+            let options = Options {
+                threads,
+                tee: vec![bam_out.clone()],
+                ..Options::default()
+            };
+            run(
+                Some(infile.path()),
+                Some(sam_out.as_path()),
+                &plan,
+                &options,
+            )
+            .expect("run should succeed");
+
+            let sam = parse_sam_tags(&std::fs::read_to_string(&sam_out).unwrap());
+            let mut reader = Reader::from_path(&bam_out).expect("read BAM tee");
+            let bam: Vec<(String, String)> = reader
+                .records()
+                .map(|rec| {
+                    let rec = rec.unwrap();
+                    let Ok(Aux::String(mn)) = rec.aux(b"MN") else {
+                        panic!("MN should be a string");
+                    };
+                    (
+                        String::from_utf8_lossy(rec.qname()).into_owned(),
+                        mn.to_string(),
+                    )
+                })
+                .collect();
+            let sam: Vec<(String, String)> = sam
+                .into_iter()
+                .map(|(qname, tags)| (qname, tags["MN"].clone()))
+                .collect();
+            assert_eq!(bam, sam);
+        }
+    }
+
+    #[test]
+    fn test_run_rejects_two_stdout_outputs() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}", sam_header()).unwrap();
+        let options = Options {
+            tee: vec![PathBuf::from("-")],
+            ..Options::default()
+        };
+        let error = run(Some(infile.path()), None, &Plan::default(), &options).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "At most one output may be written to stdout"
+        );
+    }
+
+    /// Returns the core fields and raw data of every record in a BAM file.
+    fn raw_records(path: &Path) -> Vec<((i32, i64, u16, u8), Vec<u8>)> {
+        let mut reader = Reader::from_path(path).expect("read BAM");
+        let mut record = Record::new();
+        let mut records = Vec::new();
+        while let Some(Ok(())) = reader.read(&mut record) {
+            let inner = record.inner();
+            let core = (
+                inner.core.tid,
+                inner.core.pos,
+                inner.core.flag,
+                inner.core.qual,
+            );
+            // SAFETY: htslib keeps `l_data` initialized bytes at `data`.
+            let data = unsafe { std::slice::from_raw_parts(inner.data, inner.l_data as usize) };
+            records.push((core, data.to_vec()));
+        }
+        records
+    }
+
+    #[test]
+    fn test_revtag_bam_to_bam_preserves_raw_records() {
+        let mut infile = NamedTempFile::new().expect("temp sam input");
+        write!(infile, "{}{}", sam_header(), sam_body_with_tags()).unwrap();
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let bam_in = tmpdir.path().join("in.bam");
+        let bam_out = tmpdir.path().join("out.bam");
+
+        revtag(
+            Some(infile.path().to_path_buf()),
+            Some(bam_in.clone()),
+            vec![],
+            vec![],
+            1,
+        )
+        .expect("revtag should produce BAM");
+        revtag(
+            Some(bam_in.clone()),
+            Some(bam_out.clone()),
+            vec![],
+            vec![],
+            1,
+        )
+        .expect("revtag should copy BAM");
+
+        let input = raw_records(&bam_in);
+        assert_eq!(input.len(), 2);
+        assert_eq!(input, raw_records(&bam_out));
+    }
+
+    #[test]
+    fn test_revtag_output_cram_empty_input() {
+        let mut infile = NamedTempFile::new().expect("empty sam input");
+        write!(infile, "{}", sam_header()).unwrap();
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cram_out = tmpdir.path().join("out.cram");
+
+        let exit = revtag(
+            Some(infile.path().to_path_buf()),
+            Some(cram_out.clone()),
+            vec![],
+            vec![],
+            1,
+        )
+        .expect("revtag should succeed for empty input CRAM");
+        assert_eq!(exit, 0);
+        let meta = std::fs::metadata(&cram_out).expect("cram file exists");
+        assert!(meta.len() > 0);
+    }
+
+    #[test]
+    fn test_sendable_keeps_record_errors() {
+        let error: Box<dyn error::Error> = Box::new(MissingTagError {
+            qname: "read1".to_string(),
+            tag: *b"QT",
+        });
+        let error = sendable(error);
+        assert_eq!(
+            error
+                .downcast_ref::<MissingTagError>()
+                .map(|e| e.qname.as_str()),
+            Some("read1")
+        );
+
+        let error = sendable("Something else failed".into());
+        assert_eq!(error.to_string(), "Something else failed");
+    }
+
+    #[test]
+    fn test_resume_reopens_urls() {
+        let url = Path::new("http://127.0.0.1:9/in.sam");
+        let options = Options {
+            retries: 1,
+            retry_delay: std::time::Duration::ZERO,
+            ..Options::default()
+        };
+        let resume = Resume::new(Some(url), &options);
+        assert_eq!(resume.policy.retries, 1);
+
+        // Reopening reaches htslib with the URL, rather than looking for a local file.
+        let error = resume.reopen().unwrap_err();
+        let error = error.downcast::<rust_htslib::errors::Error>().unwrap();
+        assert!(!matches!(
+            *error,
+            rust_htslib::errors::Error::FileNotFound { .. }
+        ));
+    }
+}
@@ -0,0 +1,266 @@
+//! Repairs of malformed auxiliary data seen in the wild, applied before the plan so that it
+//! transforms well-formed values.
+//!
+//! Three malformations are repaired:
+//! - `Z` and `H` strings followed by extra NULs, which would otherwise be read as the start of
+//!   another field; the extra NULs are removed.
+//! - `B` arrays declaring a nonzero count without any elements, so that the declared elements
+//!   overrun the data while the fields after the count are well formed on their own; the count
+//!   is set to zero.
+//! - Quality-like arrays, such as `OQ` or `QT`, written with a wider or signed integer element
+//!   type even though every value fits a byte; they are narrowed to `B:C`.
+use std::error;
+use std::fmt;
+
+use crate::aux::{self, Field};
+use crate::plan::{Plan, Tag};
+
+/// Tags holding base qualities by convention, whose integer arrays are narrowed to `B:C`.
+pub const QUALITY_TAGS: [Tag; 5] = [*b"OQ", *b"QT", *b"CQ", *b"U2", *b"Q2"];
+
+/// Counts of each repair made to auxiliary data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Repairs {
+    /// Strings whose extra trailing NULs were removed.
+    pub trailing_nuls: u64,
+    /// Arrays whose nonzero count declared elements that were missing, set to zero.
+    pub empty_arrays: u64,
+    /// Quality-like arrays narrowed to `B:C`.
+    pub quality_types: u64,
+}
+
+impl Repairs {
+    /// Adds every count from `other` to these counts.
+    pub fn merge(&mut self, other: &Repairs) {
+        self.trailing_nuls += other.trailing_nuls;
+        self.empty_arrays += other.empty_arrays;
+        self.quality_types += other.quality_types;
+    }
+
+    /// Returns true if nothing was repaired.
+    pub fn is_empty(&self) -> bool {
+        *self == Repairs::default()
+    }
+}
+
+impl fmt::Display for Repairs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Repaired {} strings with trailing NULs, {} arrays declaring missing elements, and {} \
+             quality arrays of the wrong element type",
+            self.trailing_nuls, self.empty_arrays, self.quality_types
+        )
+    }
+}
+
+/// Returns true if a tag holds base qualities for the plan: it is one of [`QUALITY_TAGS`] or the
+/// quality tag of one of the plan's companion pairs.
+///
+/// # Arguments
+///
+/// * `plan` - The plan whose companion pairs name quality tags
+/// * `tag` - The tag to test
+///
+/// # Returns
+///
+/// Returns whether the tag holds qualities.
+///
+pub fn is_quality_tag(plan: &Plan, tag: &Tag) -> bool {
+    QUALITY_TAGS.contains(tag) || plan.pairs().iter().any(|pair| pair.quality == *tag)
+}
+
+/// Returns the offset just past a parsed field.
+fn field_end(field: &Field) -> usize {
+    match field.value_type {
+        b'Z' | b'H' => field.value.end + 1,
+        _ => field.value.end,
+    }
+}
+
+/// Returns the declared count and element width of a `B` array whose header starts at `start`,
+/// or None if the field is not a complete array header.
+fn array_header(aux: &[u8], start: usize) -> Option<(usize, usize)> {
+    let header = aux.get(start..start + 8)?;
+    if header[2] != b'B' {
+        return None;
+    }
+    let width = aux::width(header[3])?;
+    let count = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    Some((count as usize, width))
+}
+
+/// Returns the elements of an integer array if every one fits an unsigned byte.
+fn byte_values(elements: &[u8], subtype: u8) -> Option<Vec<u8>> {
+    let width = aux::width(subtype)?;
+    elements
+        .chunks_exact(width)
+        .map(|bytes| {
+            let value = match subtype {
+                b'c' => i64::from(bytes[0] as i8),
+                b's' => i64::from(i16::from_le_bytes([bytes[0], bytes[1]])),
+                b'S' => i64::from(u16::from_le_bytes([bytes[0], bytes[1]])),
+                b'i' => i64::from(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+                b'I' => i64::from(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+                _ => return None,
+            };
+            u8::try_from(value).ok()
+        })
+        .collect()
+}
+
+/// Repairs the malformations described in the [module documentation](self) in place.
+///
+/// Every repair shortens the data or leaves its length unchanged, so the repaired data is
+/// compacted to the front of `aux` and its new length returned.
+///
+/// # Arguments
+///
+/// * `aux` - The raw auxiliary data of a BAM record
+/// * `is_quality` - Whether a tag holds base qualities, e.g. per [`is_quality_tag`]
+/// * `repairs` - Counts of each repair, updated in place
+///
+/// # Returns
+///
+/// Returns the length of the repaired data, or an error if it is malformed in a way that
+/// cannot be repaired.
+///
+pub fn sanitize<F>(
+    aux: &mut [u8],
+    is_quality: F,
+    repairs: &mut Repairs,
+) -> Result<usize, Box<dyn error::Error>>
+where
+    F: Fn(&Tag) -> bool,
+{
+    let mut read = 0;
+    let mut write = 0;
+    let mut after_string = false;
+    while read < aux.len() {
+        if after_string && aux[read] == 0 {
+            read += aux[read..].iter().take_while(|&&b| b == 0).count();
+            repairs.trailing_nuls += 1;
+            after_string = false;
+            continue;
+        }
+
+        if let Some((count, width)) = array_header(aux, read) {
+            let rest = read + 8;
+            let overruns = rest + count * width > aux.len();
+            if count > 0 && overruns && aux::fields(&aux[rest..]).all(|field| field.is_ok()) {
+                aux.copy_within(read..rest - 4, write);
+                aux[write + 4..write + 8].fill(0);
+                write += 8;
+                read = rest;
+                repairs.empty_arrays += 1;
+                after_string = false;
+                continue;
+            }
+        }
+
+        let field = match aux::fields(&aux[read..]).next() {
+            Some(Ok(field)) => field,
+            Some(Err(e)) => return Err(e),
+            None => break,
+        };
+        let end = read + field_end(&field);
+        let elements = read + field.value.start..read + field.value.end;
+        let narrowed = field
+            .subtype
+            .filter(|subtype| *subtype != b'C' && is_quality(&field.tag))
+            .and_then(|subtype| byte_values(&aux[elements], subtype));
+        match narrowed {
+            Some(values) => {
+                let count = (values.len() as u32).to_le_bytes();
+                aux.copy_within(read..read + 2, write);
+                aux[write + 2] = b'B';
+                aux[write + 3] = b'C';
+                aux[write + 4..write + 8].copy_from_slice(&count);
+                aux[write + 8..write + 8 + values.len()].copy_from_slice(&values);
+                write += 8 + values.len();
+                repairs.quality_types += 1;
+            }
+            None => {
+                aux.copy_within(read..end, write);
+                write += end - read;
+            }
+        }
+        after_string = matches!(field.value_type, b'Z' | b'H');
+        read = end;
+    }
+    Ok(write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sanitizes a copy of `aux`, returning the repaired data and the repairs made.
+    fn sanitized(aux: &[u8]) -> (Vec<u8>, Repairs) {
+        let mut aux = aux.to_vec();
+        let mut repairs = Repairs::default();
+        let length = sanitize(&mut aux, |tag| QUALITY_TAGS.contains(tag), &mut repairs).unwrap();
+        aux.truncate(length);
+        (aux, repairs)
+    }
+
+    #[test]
+    fn test_well_formed_data_is_unchanged() {
+        let aux = b"BCZACGT\0NMC\x03XQBs\x02\x00\x00\x00\x01\x00\x02\x00";
+        assert_eq!(sanitized(aux), (aux.to_vec(), Repairs::default()));
+    }
+
+    #[test]
+    fn test_trailing_nuls() {
+        let (aux, repairs) = sanitized(b"BCZACGT\0\0\0NMC\x03XYZ\0\0");
+        assert_eq!(aux, b"BCZACGT\0NMC\x03XYZ\0");
+        assert_eq!(repairs.trailing_nuls, 2);
+    }
+
+    #[test]
+    fn test_empty_arrays() {
+        let (aux, repairs) = sanitized(b"XQBs\x05\x00\x00\x00NMC\x03");
+        assert_eq!(aux, b"XQBs\x00\x00\x00\x00NMC\x03");
+        assert_eq!(repairs.empty_arrays, 1);
+
+        let (aux, repairs) = sanitized(b"XQBC\x02\x00\x00\x00");
+        assert_eq!(aux, b"XQBC\x00\x00\x00\x00");
+        assert_eq!(repairs.empty_arrays, 1);
+    }
+
+    #[test]
+    fn test_quality_types() {
+        let (aux, repairs) = sanitized(b"OQBs\x02\x00\x00\x00\x1e\x00\x14\x00NMC\x03");
+        assert_eq!(aux, b"OQBC\x02\x00\x00\x00\x1e\x14NMC\x03");
+        assert_eq!(repairs.quality_types, 1);
+
+        // Values that do not fit a byte, and tags that do not hold qualities, are kept.
+        let aux = b"OQBs\x01\x00\x00\x00\x00\x01XQBs\x01\x00\x00\x00\x1e\x00";
+        assert_eq!(sanitized(aux), (aux.to_vec(), Repairs::default()));
+    }
+
+    #[test]
+    fn test_unrepairable_data() {
+        let mut aux = b"BCZACGT".to_vec();
+        assert!(sanitize(&mut aux, |_| false, &mut Repairs::default()).is_err());
+    }
+
+    #[test]
+    fn test_repairs_display() {
+        let mut repairs = Repairs {
+            trailing_nuls: 1,
+            ..Repairs::default()
+        };
+        repairs.merge(&Repairs {
+            trailing_nuls: 1,
+            quality_types: 3,
+            ..Repairs::default()
+        });
+        assert_eq!(
+            repairs.to_string(),
+            "Repaired 2 strings with trailing NULs, 0 arrays declaring missing elements, and 3 \
+             quality arrays of the wrong element type"
+        );
+        assert!(!repairs.is_empty());
+    }
+}
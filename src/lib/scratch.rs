@@ -0,0 +1,94 @@
+//! Scratch directories for modes that buffer records beyond memory.
+use log::*;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Distinguishes scratch directories created by one process.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// A uniquely named directory for spilled records, removed with its contents when dropped.
+#[derive(Debug)]
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    /// Creates a scratch directory under `parent`, or under `TMPDIR` (the system temporary
+    /// directory) when None.
+    ///
+    /// # Arguments
+    ///
+    /// * `parent` - The directory to create the scratch directory in, e.g. from `--tmpdir`
+    ///
+    /// # Returns
+    ///
+    /// Returns the scratch directory, or an error if it cannot be created.
+    ///
+    pub fn create(parent: Option<&Path>) -> io::Result<Self> {
+        let parent = parent.map_or_else(std::env::temp_dir, Path::to_path_buf);
+        loop {
+            let name = format!(
+                "revtag-{}-{}",
+                process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = parent.join(name);
+            match fs::create_dir(&path) {
+                Ok(()) => {
+                    debug!("Scratch directory: {path:?}");
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("Could not create a scratch directory in {parent:?}: {e}"),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Returns the path of the scratch directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!("Could not remove scratch directory {:?}: {e}", self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scratch_dir_is_removed_on_drop() {
+        let parent = tempfile::tempdir().unwrap();
+        let first = ScratchDir::create(Some(parent.path())).unwrap();
+        let second = ScratchDir::create(Some(parent.path())).unwrap();
+        assert_ne!(first.path(), second.path());
+        assert!(first.path().starts_with(parent.path()));
+
+        fs::write(first.path().join("spill.bam"), b"records").unwrap();
+        let path = first.path().to_path_buf();
+        drop(first);
+        assert!(!path.exists());
+        assert!(second.path().is_dir());
+    }
+
+    #[test]
+    fn test_scratch_dir_requires_parent() {
+        let parent = tempfile::tempdir().unwrap();
+        let missing = parent.path().join("missing");
+        assert!(ScratchDir::create(Some(&missing)).is_err());
+    }
+}
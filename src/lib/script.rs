@@ -0,0 +1,426 @@
+//! An embedded Rhai scripting hook for site-specific tag conventions.
+//!
+//! A script defines `fn transform(record)`, called for every reverse strand record before the
+//! plan is applied. `record` is a map with:
+//!
+//! - `flag`: the SAM flag as an integer
+//! - `qname`: the read name as a string
+//! - `tags`: a map from each planned tag present on the record to its value, with `Z`/`H`
+//!   values as strings and `B` arrays as arrays of numbers
+//!
+//! The return value decides what happens to the record:
+//!
+//! - `false` vetoes the transform and the record is written unchanged
+//! - `true` or no value applies the plan as usual
+//! - a map from tag names to replacement values sets those tags to the returned values instead
+//!   of transforming them, and applies the plan to every other planned tag
+//!
+//! Replacement values keep the type of the existing tag. New tags are written as `Z` strings,
+//! `B:i` integer arrays, or `B:f` float arrays depending on the returned value.
+use rhai::{AST, Array, Dynamic, Engine, Map, Scope};
+use rust_htslib::bam::Record;
+use rust_htslib::bam::record::Aux;
+use std::error;
+use std::path::Path;
+
+use crate::ops::apply;
+use crate::plan::{Plan, Tag};
+
+/// The name of the function every script must define.
+const ENTRYPOINT: &str = "transform";
+
+/// A replacement tag value computed by a script.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Replacement {
+    /// A string value.
+    String(String),
+    /// An array of integer values.
+    Ints(Vec<i64>),
+    /// An array of floating point values.
+    Floats(Vec<f64>),
+}
+
+/// The decision a script makes about a single record.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    /// Leave the record unchanged.
+    Veto,
+    /// Apply the plan, except to the replaced tags which are set to the given values.
+    Transform(Vec<(Tag, Replacement)>),
+}
+
+/// A compiled script with its own evaluation engine.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+/// Converts a tag value into a script value, if its type is supported.
+fn to_dynamic(aux: Aux) -> Option<Dynamic> {
+    macro_rules! array {
+        ($arr:expr, $convert:expr) => {
+            Some(Dynamic::from_array(
+                $arr.iter().map($convert).map(Dynamic::from).collect(),
+            ))
+        };
+    }
+
+    match aux {
+        Aux::String(s) | Aux::HexByteArray(s) => Some(Dynamic::from(s.to_string())),
+        Aux::ArrayU8(arr) => array!(arr, i64::from),
+        Aux::ArrayU16(arr) => array!(arr, i64::from),
+        Aux::ArrayU32(arr) => array!(arr, i64::from),
+        Aux::ArrayI8(arr) => array!(arr, i64::from),
+        Aux::ArrayI16(arr) => array!(arr, i64::from),
+        Aux::ArrayI32(arr) => array!(arr, i64::from),
+        Aux::ArrayFloat(arr) => array!(arr, f64::from),
+        _ => None,
+    }
+}
+
+/// Converts a script value into a replacement tag value.
+fn to_replacement(tag: &str, value: Dynamic) -> Result<Replacement, Box<dyn error::Error>> {
+    if value.is_string() {
+        return Ok(Replacement::String(value.into_string()?));
+    }
+    if value.is_array() {
+        let values: Array = value.into_array()?;
+        if values.iter().all(|v| v.is_int()) {
+            return Ok(Replacement::Ints(
+                values
+                    .iter()
+                    .map(|v| v.as_int())
+                    .collect::<Result<_, _>>()?,
+            ));
+        }
+        if values.iter().all(|v| v.is_int() || v.is_float()) {
+            return Ok(Replacement::Floats(
+                values
+                    .iter()
+                    .map(|v| v.as_float().or_else(|_| v.as_int().map(|i| i as f64)))
+                    .collect::<Result<_, _>>()?,
+            ));
+        }
+    }
+    Err(format!("Script replacement for {tag} must be a string or an array of numbers").into())
+}
+
+/// Converts integers into a narrower element type, failing on out-of-range values.
+fn narrow<T: TryFrom<i64>>(tag: &Tag, values: &[i64]) -> Result<Vec<T>, Box<dyn error::Error>> {
+    values
+        .iter()
+        .map(|&v| {
+            T::try_from(v).map_err(|_| {
+                format!(
+                    "Script replacement value {v} is out of range for {}",
+                    String::from_utf8_lossy(tag)
+                )
+                .into()
+            })
+        })
+        .collect()
+}
+
+/// The type of an existing tag value, detached from the record it was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    String,
+    Hex,
+    U8,
+    U16,
+    U32,
+    I8,
+    I16,
+    I32,
+    Float,
+    Other,
+}
+
+/// Returns the type of the value stored under `tag`, or None if the tag is missing.
+fn kind(record: &Record, tag: &Tag) -> Option<Kind> {
+    record.aux(tag).ok().map(|aux| match aux {
+        Aux::String(_) => Kind::String,
+        Aux::HexByteArray(_) => Kind::Hex,
+        Aux::ArrayU8(_) => Kind::U8,
+        Aux::ArrayU16(_) => Kind::U16,
+        Aux::ArrayU32(_) => Kind::U32,
+        Aux::ArrayI8(_) => Kind::I8,
+        Aux::ArrayI16(_) => Kind::I16,
+        Aux::ArrayI32(_) => Kind::I32,
+        Aux::ArrayFloat(_) => Kind::Float,
+        _ => Kind::Other,
+    })
+}
+
+/// Sets a tag to a replacement value, keeping the type of any existing value.
+fn replace(
+    record: &mut Record,
+    tag: &Tag,
+    value: Replacement,
+) -> Result<(), Box<dyn error::Error>> {
+    let existing = kind(record, tag);
+
+    macro_rules! push {
+        ($aux:expr) => {{
+            if existing.is_some() {
+                record.remove_aux(tag)?;
+            }
+            record.push_aux(tag, $aux)?;
+        }};
+    }
+
+    macro_rules! push_ints {
+        ($variant:ident, $ty:ty, $values:expr) => {{
+            let values: Vec<$ty> = narrow(tag, &$values)?;
+            push!(Aux::$variant((&values[..]).into()));
+        }};
+    }
+
+    match (existing, value) {
+        (None | Some(Kind::String), Replacement::String(s)) => push!(Aux::String(&s)),
+        (Some(Kind::Hex), Replacement::String(s)) => push!(Aux::HexByteArray(&s)),
+        (Some(Kind::U8), Replacement::Ints(v)) => push_ints!(ArrayU8, u8, v),
+        (Some(Kind::U16), Replacement::Ints(v)) => push_ints!(ArrayU16, u16, v),
+        (Some(Kind::U32), Replacement::Ints(v)) => push_ints!(ArrayU32, u32, v),
+        (Some(Kind::I8), Replacement::Ints(v)) => push_ints!(ArrayI8, i8, v),
+        (Some(Kind::I16), Replacement::Ints(v)) => push_ints!(ArrayI16, i16, v),
+        (None | Some(Kind::I32), Replacement::Ints(v)) => push_ints!(ArrayI32, i32, v),
+        (None | Some(Kind::Float), Replacement::Floats(v)) => {
+            let values: Vec<f32> = v.iter().map(|&x| x as f32).collect();
+            push!(Aux::ArrayFloat((&values[..]).into()));
+        }
+        (Some(Kind::Float), Replacement::Ints(v)) => {
+            let values: Vec<f32> = v.iter().map(|&x| x as f32).collect();
+            push!(Aux::ArrayFloat((&values[..]).into()));
+        }
+        _ => {
+            return Err(format!(
+                "Script replacement for {} does not match the existing tag type",
+                String::from_utf8_lossy(tag)
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+impl Script {
+    /// Compiles a script from source text.
+    ///
+    /// # Returns
+    ///
+    /// Returns the script, or an error if it fails to compile or lacks a `transform` function.
+    ///
+    pub fn compile(source: &str) -> Result<Self, Box<dyn error::Error>> {
+        let engine = Engine::new();
+        let ast = engine.compile(source)?;
+        Self::new(engine, ast)
+    }
+
+    /// Compiles a script from a `.rhai` file.
+    ///
+    /// # Returns
+    ///
+    /// Returns the script, or an error if it cannot be read, fails to compile, or lacks a
+    /// `transform` function.
+    ///
+    pub fn load(path: &Path) -> Result<Self, Box<dyn error::Error>> {
+        let engine = Engine::new();
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| format!("Invalid script {}: {e}", path.display()))?;
+        Self::new(engine, ast)
+    }
+
+    fn new(engine: Engine, ast: AST) -> Result<Self, Box<dyn error::Error>> {
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == ENTRYPOINT && f.params.len() == 1)
+        {
+            return Err(format!("Script must define `fn {ENTRYPOINT}(record)`").into());
+        }
+        Ok(Self { engine, ast })
+    }
+
+    /// Asks the script what to do with a record.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The record to expose to the script
+    /// * `plan` - The plan whose tags are exposed to the script
+    ///
+    /// # Returns
+    ///
+    /// Returns the script's verdict, or an error if the script fails or returns a bad value.
+    ///
+    pub fn evaluate(&self, record: &Record, plan: &Plan) -> Result<Verdict, Box<dyn error::Error>> {
+        let mut tags = Map::new();
+        for spec in plan.specs() {
+            if let Some(value) = record.aux(&spec.tag).ok().and_then(to_dynamic) {
+                tags.insert(String::from_utf8_lossy(&spec.tag).as_ref().into(), value);
+            }
+        }
+
+        let mut fields = Map::new();
+        fields.insert("flag".into(), Dynamic::from(i64::from(record.flags())));
+        fields.insert(
+            "qname".into(),
+            Dynamic::from(String::from_utf8_lossy(record.qname()).to_string()),
+        );
+        fields.insert("tags".into(), Dynamic::from_map(tags));
+
+        let result: Dynamic = self.engine.call_fn(
+            &mut Scope::new(),
+            &self.ast,
+            ENTRYPOINT,
+            (Dynamic::from_map(fields),),
+        )?;
+
+        if result.is_unit() {
+            return Ok(Verdict::Transform(Vec::new()));
+        }
+        if let Ok(apply) = result.as_bool() {
+            return Ok(if apply {
+                Verdict::Transform(Vec::new())
+            } else {
+                Verdict::Veto
+            });
+        }
+        if result.is_map() {
+            let mut replacements = Vec::new();
+            for (name, value) in result.cast::<Map>() {
+                let tag: Tag = name
+                    .as_bytes()
+                    .try_into()
+                    .map_err(|_| format!("Script returned an invalid tag name: {name}"))?;
+                replacements.push((tag, to_replacement(&name, value)?));
+            }
+            return Ok(Verdict::Transform(replacements));
+        }
+
+        Err(format!(
+            "Script `{ENTRYPOINT}` must return a bool, a map, or nothing, not {}",
+            result.type_name()
+        )
+        .into())
+    }
+
+    /// Applies the plan to a record as directed by the script.
+    ///
+    /// # Returns
+    ///
+    /// Returns true if the record was transformed, false if the script vetoed it.
+    ///
+    pub fn apply(&self, record: &mut Record, plan: &Plan) -> Result<bool, Box<dyn error::Error>> {
+        match self.evaluate(record, plan)? {
+            Verdict::Veto => Ok(false),
+            Verdict::Transform(replacements) if replacements.is_empty() => {
+                apply(record, plan)?;
+                Ok(true)
+            }
+            Verdict::Transform(replacements) => {
+                let remaining = Plan::from_specs(
+                    plan.specs()
+                        .iter()
+                        .filter(|spec| replacements.iter().all(|(tag, _)| *tag != spec.tag))
+                        .copied()
+                        .collect(),
+                )
+                .with_nonsequence(plan.nonsequence());
+                apply(record, &remaining)?;
+                for (tag, value) in replacements {
+                    replace(record, &tag, value)?;
+                }
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_record() -> Record {
+        let mut record = Record::new();
+        record.set_qname(b"read1");
+        record.set_reverse();
+        record.push_aux(b"BC", Aux::String("ATCG")).unwrap();
+        record
+            .push_aux(b"QT", Aux::ArrayU8((&[1u8, 2, 3][..]).into()))
+            .unwrap();
+        record
+    }
+
+    fn plan() -> Plan {
+        Plan::new(&["QT".to_string()], &["BC".to_string()]).unwrap()
+    }
+
+    #[test]
+    fn test_script_requires_entrypoint() {
+        assert!(Script::compile("fn other(record) { true }").is_err());
+    }
+
+    #[test]
+    fn test_script_unit_applies_plan() {
+        let script = Script::compile("fn transform(record) { }").unwrap();
+        let mut record = create_test_record();
+        assert!(script.apply(&mut record, &plan()).unwrap());
+        assert!(matches!(record.aux(b"BC"), Ok(Aux::String("CGAT"))));
+    }
+
+    #[test]
+    fn test_script_veto() {
+        let script =
+            Script::compile(r#"fn transform(record) { record.qname != "read1" }"#).unwrap();
+        let mut record = create_test_record();
+        assert!(!script.apply(&mut record, &plan()).unwrap());
+        assert!(matches!(record.aux(b"BC"), Ok(Aux::String("ATCG"))));
+    }
+
+    #[test]
+    fn test_script_sees_flag_and_tags() {
+        let script = Script::compile(
+            r#"fn transform(record) { record.flag == 16 && record.tags.BC == "ATCG" && record.tags.QT[2] == 3 }"#,
+        )
+        .unwrap();
+        let mut record = create_test_record();
+        assert!(script.apply(&mut record, &plan()).unwrap());
+    }
+
+    #[test]
+    fn test_script_replacements() {
+        let script =
+            Script::compile(r#"fn transform(record) { #{ QT: [9, 8], XX: "new" } }"#).unwrap();
+        let mut record = create_test_record();
+        assert!(script.apply(&mut record, &plan()).unwrap());
+
+        // BC is still transformed by the plan, QT is replaced with the same type, XX is added.
+        assert!(matches!(record.aux(b"BC"), Ok(Aux::String("CGAT"))));
+        if let Ok(Aux::ArrayU8(arr)) = record.aux(b"QT") {
+            assert_eq!(arr.iter().collect::<Vec<u8>>(), vec![9, 8]);
+        } else {
+            panic!("Expected ArrayU8");
+        }
+        assert!(matches!(record.aux(b"XX"), Ok(Aux::String("new"))));
+    }
+
+    #[test]
+    fn test_script_replacement_out_of_range() {
+        let script = Script::compile(r#"fn transform(record) { #{ QT: [256] } }"#).unwrap();
+        let mut record = create_test_record();
+        let err = script.apply(&mut record, &plan()).unwrap_err();
+        assert!(
+            err.to_string().contains("out of range"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn test_script_replacement_type_mismatch() {
+        let script = Script::compile(r#"fn transform(record) { #{ BC: [1, 2] } }"#).unwrap();
+        let mut record = create_test_record();
+        assert!(script.apply(&mut record, &plan()).is_err());
+    }
+}
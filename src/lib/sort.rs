@@ -0,0 +1,379 @@
+//! Validation of the input's sort order against the order a run expects.
+//!
+//! The order declared in the `@HD` header line is checked before any record is read, and the
+//! records themselves are checked as they stream past, so an input whose header omits or
+//! misstates its order still fails at the first record out of place rather than producing
+//! subtly wrong output. Outputs whose records were re-expressed in their sequencing
+//! orientation get an `@HD` line that no longer claims a coordinate order, per
+//! [`reoriented_header`], and outputs reverted to unaligned records also lose their `@SQ`
+//! lines, per [`unaligned_header`].
+use std::cmp::Ordering;
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+/// A sort order an input can be expected to have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    /// By reference sequence and position, with unplaced records last, as `SO:coordinate`.
+    Coordinate,
+    /// By read name, as `SO:queryname`, either lexicographically as Picard sorts or naturally,
+    /// comparing runs of digits by value, as `samtools sort -n` does.
+    Queryname,
+}
+
+impl SortOrder {
+    /// Parses a sort order as accepted by `--expected-sort-order` and written in `@HD SO`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "coordinate" => Some(SortOrder::Coordinate),
+            "queryname" => Some(SortOrder::Queryname),
+            _ => None,
+        }
+    }
+
+    /// Returns the sort order as accepted by `--expected-sort-order`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortOrder::Coordinate => "coordinate",
+            SortOrder::Queryname => "queryname",
+        }
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_name(s).ok_or_else(|| {
+            format!("Unknown sort order `{s}`; expected one of coordinate or queryname")
+        })
+    }
+}
+
+impl fmt::Display for SortOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// An error for an input whose header or records contradict the expected sort order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UnsortedError {
+    /// The `@HD` line declares another known sort order.
+    Header {
+        /// The expected sort order.
+        expected: SortOrder,
+        /// The sort order the header declares.
+        declared: SortOrder,
+    },
+    /// A record sorts before the record preceding it.
+    Record {
+        /// The expected sort order.
+        expected: SortOrder,
+        /// The name of the record out of place.
+        qname: String,
+        /// The name of the record preceding it.
+        previous: String,
+    },
+}
+
+impl fmt::Display for UnsortedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnsortedError::Header { expected, declared } => write!(
+                f,
+                "The input header declares SO:{declared}, but the input is expected to be sorted \
+                 by {expected}"
+            ),
+            UnsortedError::Record {
+                expected,
+                qname,
+                previous,
+            } => write!(
+                f,
+                "The input is expected to be sorted by {expected}, but record {qname} follows \
+                 record {previous}"
+            ),
+        }
+    }
+}
+
+impl error::Error for UnsortedError {}
+
+/// Returns the sort order declared by the `SO` field of a header's `@HD` line, or None if it is
+/// missing or is neither `coordinate` nor `queryname`.
+pub(crate) fn declared_order(header: &[u8]) -> Option<SortOrder> {
+    let line = header
+        .split(|&b| b == b'\n')
+        .find(|line| line.starts_with(b"@HD\t"))?;
+    let order = line
+        .split(|&b| b == b'\t')
+        .find_map(|field| field.strip_prefix(b"SO:"))?;
+    SortOrder::from_name(std::str::from_utf8(order).ok()?.trim_end_matches('\r'))
+}
+
+/// Fails if a header declares a known sort order other than the expected one. Headers declaring
+/// `unsorted`, `unknown`, or no order pass, leaving the records to be checked.
+///
+/// # Arguments
+///
+/// * `header` - The SAM header text
+/// * `expected` - The sort order the input is expected to have
+///
+/// # Returns
+///
+/// Returns an error naming both orders if they differ.
+///
+pub fn check_header(header: &[u8], expected: SortOrder) -> Result<(), UnsortedError> {
+    match declared_order(header) {
+        Some(declared) if declared != expected => Err(UnsortedError::Header { expected, declared }),
+        _ => Ok(()),
+    }
+}
+
+/// Returns header text whose `@HD` line no longer claims an order derived from coordinates,
+/// for outputs whose records were re-expressed in their sequencing orientation.
+///
+/// `SO:coordinate` becomes `SO:unsorted`, dropping any `SS` sub-sort refining it, and
+/// `GO:reference` becomes `GO:none`. Orders by read name still hold and are kept, as are all
+/// other lines and fields.
+///
+/// # Arguments
+///
+/// * `header` - The SAM header text
+///
+/// # Returns
+///
+/// Returns the updated header text.
+///
+pub fn reoriented_header(header: &[u8]) -> Vec<u8> {
+    let mut text = Vec::with_capacity(header.len());
+    for (i, line) in header.split(|&b| b == b'\n').enumerate() {
+        if i > 0 {
+            text.push(b'\n');
+        }
+        if !line.starts_with(b"@HD\t") {
+            text.extend_from_slice(line);
+            continue;
+        }
+        let coordinate = line
+            .split(|&b| b == b'\t')
+            .any(|field| field == b"SO:coordinate");
+        let fields = line.split(|&b| b == b'\t').filter_map(|field| match field {
+            b"SO:coordinate" => Some(b"SO:unsorted".as_slice()),
+            b"GO:reference" => Some(b"GO:none".as_slice()),
+            field if coordinate && field.starts_with(b"SS:") => None,
+            field => Some(field),
+        });
+        for (j, field) in fields.enumerate() {
+            if j > 0 {
+                text.push(b'\t');
+            }
+            text.extend_from_slice(field);
+        }
+    }
+    text
+}
+
+/// Returns header text for outputs reverted to unaligned records: the `@SQ` lines are removed,
+/// and the `@HD` line is updated as [`reoriented_header`] does.
+///
+/// # Arguments
+///
+/// * `header` - The SAM header text
+///
+/// # Returns
+///
+/// Returns the updated header text.
+///
+pub fn unaligned_header(header: &[u8]) -> Vec<u8> {
+    let text = reoriented_header(header);
+    let mut unaligned = Vec::with_capacity(text.len());
+    for line in text.split_inclusive(|&b| b == b'\n') {
+        if !line.starts_with(b"@SQ\t") {
+            unaligned.extend_from_slice(line);
+        }
+    }
+    unaligned
+}
+
+/// Compares read names naturally, as `samtools sort -n` does: runs of digits compare by their
+/// value, and everything else byte by byte.
+pub fn natural_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            let digits = |name: &[u8], start: usize| {
+                let end = start
+                    + name[start..]
+                        .iter()
+                        .take_while(|b| b.is_ascii_digit())
+                        .count();
+                let zeros = name[start..end].iter().take_while(|&&b| b == b'0').count();
+                (start + zeros, end)
+            };
+            let (start_a, end_a) = digits(a, i);
+            let (start_b, end_b) = digits(b, j);
+            let (run_a, run_b) = (&a[start_a..end_a], &b[start_b..end_b]);
+            let ordering = run_a.len().cmp(&run_b.len()).then_with(|| run_a.cmp(run_b));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            (i, j) = (end_a, end_b);
+        } else if a[i] != b[j] {
+            return a[i].cmp(&b[j]);
+        } else {
+            i += 1;
+            j += 1;
+        }
+    }
+    (a.len() - i).cmp(&(b.len() - j))
+}
+
+/// Checks that records stream past in the expected sort order.
+#[derive(Clone, Debug)]
+pub struct SortCheck {
+    expected: SortOrder,
+    previous: Option<(u32, i64, Vec<u8>)>,
+    lexicographic: bool,
+    natural: bool,
+}
+
+impl SortCheck {
+    /// Creates a check of records against a sort order.
+    pub fn new(expected: SortOrder) -> Self {
+        Self {
+            expected,
+            previous: None,
+            lexicographic: true,
+            natural: true,
+        }
+    }
+
+    /// Checks the next record of the input.
+    ///
+    /// Read names pass as sorted by queryname if every name so far sorts at or after the one
+    /// before it under either the lexicographic or the natural order.
+    ///
+    /// # Arguments
+    ///
+    /// * `tid` - The record's reference sequence index, or -1 if it is unplaced
+    /// * `pos` - The record's 0-based position
+    /// * `qname` - The record's name
+    ///
+    /// # Returns
+    ///
+    /// Returns an error naming the record and the one before it if it is out of order.
+    ///
+    pub fn check(&mut self, tid: i32, pos: i64, qname: &[u8]) -> Result<(), UnsortedError> {
+        // Unplaced records, with a negative index, sort after every placed one.
+        let tid = u32::try_from(tid).unwrap_or(u32::MAX);
+        if let Some((previous_tid, previous_pos, previous_qname)) = &self.previous {
+            let sorted = match self.expected {
+                SortOrder::Coordinate => (*previous_tid, *previous_pos) <= (tid, pos),
+                SortOrder::Queryname => {
+                    self.lexicographic &= previous_qname.as_slice() <= qname;
+                    self.natural &= natural_cmp(previous_qname, qname) != Ordering::Greater;
+                    self.lexicographic || self.natural
+                }
+            };
+            if !sorted {
+                return Err(UnsortedError::Record {
+                    expected: self.expected,
+                    qname: String::from_utf8_lossy(qname).into_owned(),
+                    previous: String::from_utf8_lossy(previous_qname).into_owned(),
+                });
+            }
+        }
+        match &mut self.previous {
+            Some((previous_tid, previous_pos, previous_qname)) => {
+                (*previous_tid, *previous_pos) = (tid, pos);
+                previous_qname.clear();
+                previous_qname.extend_from_slice(qname);
+            }
+            None => self.previous = Some((tid, pos, qname.to_vec())),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_header() {
+        let header = b"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n";
+        assert!(check_header(header, SortOrder::Coordinate).is_ok());
+        assert_eq!(
+            check_header(header, SortOrder::Queryname)
+                .unwrap_err()
+                .to_string(),
+            "The input header declares SO:coordinate, but the input is expected to be sorted by \
+             queryname"
+        );
+        assert!(check_header(b"@HD\tVN:1.6\tSO:unsorted\n", SortOrder::Queryname).is_ok());
+        assert!(check_header(b"@SQ\tSN:chr1\tLN:1000\n", SortOrder::Queryname).is_ok());
+    }
+
+    #[test]
+    fn test_reoriented_header() {
+        let header = b"@HD\tVN:1.6\tSO:coordinate\tSS:coordinate:TC\tGO:reference\n\
+                       @SQ\tSN:chr1\tLN:1000\n";
+        assert_eq!(
+            reoriented_header(header),
+            b"@HD\tVN:1.6\tSO:unsorted\tGO:none\n@SQ\tSN:chr1\tLN:1000\n"
+        );
+        let queryname = b"@HD\tVN:1.6\tSO:queryname\tSS:queryname:natural\n";
+        assert_eq!(reoriented_header(queryname), queryname);
+        assert_eq!(reoriented_header(b""), b"");
+    }
+
+    #[test]
+    fn test_unaligned_header() {
+        let header = b"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n\
+                       @SQ\tSN:chr2\tLN:500\n@RG\tID:A\n";
+        assert_eq!(
+            unaligned_header(header),
+            b"@HD\tVN:1.6\tSO:unsorted\n@RG\tID:A\n"
+        );
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        assert_eq!(natural_cmp(b"read2", b"read10"), Ordering::Less);
+        assert_eq!(natural_cmp(b"read10", b"read010"), Ordering::Equal);
+        assert_eq!(natural_cmp(b"a:1:5", b"a:1:12"), Ordering::Less);
+        assert_eq!(natural_cmp(b"readB", b"readA"), Ordering::Greater);
+        assert_eq!(natural_cmp(b"read", b"read1"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_coordinate_check() {
+        let mut check = SortCheck::new(SortOrder::Coordinate);
+        assert!(check.check(0, 10, b"a").is_ok());
+        assert!(check.check(0, 10, b"b").is_ok());
+        assert!(check.check(1, 5, b"c").is_ok());
+        assert!(check.check(-1, -1, b"d").is_ok());
+        assert_eq!(
+            check.check(0, 20, b"e").unwrap_err().to_string(),
+            "The input is expected to be sorted by coordinate, but record e follows record d"
+        );
+    }
+
+    #[test]
+    fn test_queryname_check() {
+        let mut natural = SortCheck::new(SortOrder::Queryname);
+        for qname in [b"r2".as_slice(), b"r2", b"r10", b"r11"] {
+            assert!(natural.check(0, 0, qname).is_ok());
+        }
+        assert!(natural.check(0, 0, b"r3").is_err());
+
+        let mut lexicographic = SortCheck::new(SortOrder::Queryname);
+        for qname in [b"r10".as_slice(), b"r2", b"r20"] {
+            assert!(lexicographic.check(0, 0, qname).is_ok());
+        }
+        assert!(lexicographic.check(0, 0, b"r1").is_err());
+    }
+}
@@ -0,0 +1,227 @@
+//! Outputs whose header is replaced, byte for byte, with the input's header text, for
+//! downstream checks that diff headers and would flag htslib's rebuild of them.
+use rust_htslib::bgzf;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, PipeWriter, Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+use crate::options::OutputFormat;
+
+/// The magic number starting a decompressed BAM stream.
+const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
+
+/// Copies a SAM stream, replacing its header lines with `header`.
+///
+/// # Arguments
+///
+/// * `input` - The SAM stream as written by htslib
+/// * `output` - Where to write the spliced stream
+/// * `header` - The header text to write in place of the stream's
+///
+/// # Returns
+///
+/// Returns an error if the stream cannot be read or written.
+///
+pub fn splice_sam<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    header: &[u8],
+) -> io::Result<()> {
+    output.write_all(header)?;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.starts_with(b"@") {
+            output.write_all(&line)?;
+            break;
+        }
+    }
+    io::copy(input, output)?;
+    Ok(())
+}
+
+/// Copies a decompressed BAM stream, replacing the header text before its reference sequences
+/// with `header`. The reference sequences and records are copied unchanged.
+///
+/// # Arguments
+///
+/// * `input` - The decompressed BAM stream as written by htslib
+/// * `output` - Where to write the spliced stream, before compression
+/// * `header` - The header text to write in place of the stream's
+///
+/// # Returns
+///
+/// Returns an error if the stream is not BAM or cannot be read or written.
+///
+pub fn splice_bam<R: Read, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    header: &[u8],
+) -> io::Result<()> {
+    let mut magic = [0u8; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != BAM_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The output is not a BAM stream",
+        ));
+    }
+    let mut l_text = [0u8; 4];
+    input.read_exact(&mut l_text)?;
+    io::copy(
+        &mut input.by_ref().take(u32::from_le_bytes(l_text).into()),
+        &mut io::sink(),
+    )?;
+
+    let l_text = u32::try_from(header.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "The header is too long"))?;
+    output.write_all(BAM_MAGIC)?;
+    output.write_all(&l_text.to_le_bytes())?;
+    output.write_all(header)?;
+    io::copy(input, output)?;
+    Ok(())
+}
+
+/// An output fed through a pipe, whose header is replaced with given text on a separate thread
+/// as records are written, since htslib writers only write the header they parse and rebuild.
+///
+/// Open the writer on [`HeaderSplice::path`] in the format given, close it, then call
+/// [`HeaderSplice::finish`] to wait for the output to be complete. BAM outputs are decompressed
+/// and compressed again, which costs a little throughput.
+#[derive(Debug)]
+pub struct HeaderSplice {
+    path: PathBuf,
+    writer: PipeWriter,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl HeaderSplice {
+    /// Creates the pipe feeding an output.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - The output file, or None for stdout
+    /// * `format` - The format the output is written in, SAM or BAM
+    /// * `header` - The header text to write in place of htslib's
+    ///
+    /// # Returns
+    ///
+    /// Returns the pipe, or an error if the format is CRAM or the pipe cannot be created.
+    ///
+    pub fn create(
+        output: Option<&Path>,
+        format: OutputFormat,
+        header: Vec<u8>,
+    ) -> io::Result<Self> {
+        let (reader, writer) = io::pipe()?;
+        let path = PathBuf::from(format!("/dev/fd/{}", writer.as_raw_fd()));
+        let output = output.map(Path::to_path_buf);
+        let thread = match format {
+            OutputFormat::Sam => thread::spawn(move || {
+                let mut input = BufReader::new(reader);
+                match output {
+                    Some(path) => {
+                        let mut file = BufWriter::new(File::create(path)?);
+                        splice_sam(&mut input, &mut file, &header)?;
+                        file.flush()
+                    }
+                    None => {
+                        let mut stdout = io::stdout().lock();
+                        splice_sam(&mut input, &mut stdout, &header)?;
+                        stdout.flush()
+                    }
+                }
+            }),
+            OutputFormat::Bam => {
+                let Some(output) = output else {
+                    return Err(io::Error::other("Spliced BAM outputs must be files"));
+                };
+                thread::spawn(move || {
+                    let source = PathBuf::from(format!("/dev/fd/{}", reader.as_raw_fd()));
+                    let mut input = bgzf::Reader::from_path(&source).map_err(io::Error::other)?;
+                    let mut file = bgzf::Writer::from_path(&output).map_err(io::Error::other)?;
+                    splice_bam(&mut input, &mut file, &header)?;
+                    file.flush()
+                })
+            }
+            OutputFormat::Cram => {
+                return Err(io::Error::other(
+                    "Preserving the input header is not supported for CRAM outputs",
+                ));
+            }
+        };
+        Ok(Self {
+            path,
+            writer,
+            thread,
+        })
+    }
+
+    /// Returns the path to open the writer on, a `/dev/fd` path for the write end of the pipe.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Closes the pipe and waits for the output to be written; every writer opened on
+    /// [`HeaderSplice::path`] must be closed first.
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the output could not be written.
+    ///
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.writer);
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("The header splice thread panicked")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_splice_sam() {
+        let mut input: &[u8] = b"@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\nread1\t0\n@notheader\n";
+        let mut output = Vec::new();
+        splice_sam(
+            &mut input,
+            &mut output,
+            b"@SQ\tSN:chr1\tLN:1000\n@HD\tVN:1.6\n",
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            b"@SQ\tSN:chr1\tLN:1000\n@HD\tVN:1.6\nread1\t0\n@notheader\n"
+        );
+
+        let mut empty: &[u8] = b"@HD\tVN:1.6\n";
+        let mut output = Vec::new();
+        splice_sam(&mut empty, &mut output, b"@CO\tkept\n").unwrap();
+        assert_eq!(output, b"@CO\tkept\n");
+    }
+
+    #[test]
+    fn test_splice_bam() {
+        let mut input = BAM_MAGIC.to_vec();
+        input.extend(3u32.to_le_bytes());
+        input.extend(b"@HD");
+        input.extend(b"REFS RECORDS");
+        let mut output = Vec::new();
+        splice_bam(&mut &input[..], &mut output, b"@CO\tx\n").unwrap();
+
+        let mut expected = BAM_MAGIC.to_vec();
+        expected.extend(6u32.to_le_bytes());
+        expected.extend(b"@CO\tx\n");
+        expected.extend(b"REFS RECORDS");
+        assert_eq!(output, expected);
+
+        assert!(splice_bam(&mut &b"SAM\x01"[..], &mut Vec::new(), b"").is_err());
+    }
+}
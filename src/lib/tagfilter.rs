@@ -0,0 +1,288 @@
+//! Filters on tag values, as given to `--tag-filter`, restricting the plan to records whose tag
+//! matches a pattern or compares to a value.
+//!
+//! A filter names a tag, an operator, and a value, without spaces around the operator:
+//!
+//! ```text
+//! RG=~^lane1\.
+//! CB!~-1$
+//! NM<=2
+//! XS==-
+//! ```
+//!
+//! `=~` and `!~` search the text of a string or character tag, or the decimal form of an integer
+//! tag, for a regular expression, unanchored unless the pattern anchors itself. `==`, `!=`, `<`,
+//! `<=`, `>`, and `>=` compare an integer tag numerically when the value is an integer, and a
+//! string or character tag as text otherwise. A record lacking the tag, or holding a value of
+//! another type, passes no filter.
+use regex::Regex;
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::condition::{Comparison, RecordFields, TagValue};
+use crate::plan::{Tag, parse_tag};
+
+/// The operators a filter accepts, longest first so that `<=` is not read as `<`.
+const OPERATORS: [&str; 8] = ["=~", "!~", "==", "!=", "<=", ">=", "<", ">"];
+
+/// What a filter tests a tag value for.
+#[derive(Clone, Debug)]
+enum Test {
+    Matches(Regex),
+    NotMatches(Regex),
+    /// A comparison with the value as text, and as an integer if it is one.
+    Compare(Comparison, String, Option<i64>),
+}
+
+/// A filter on the value of one tag.
+#[derive(Clone, Debug)]
+pub struct TagFilter {
+    source: String,
+    tag: Tag,
+    test: Test,
+}
+
+impl TagFilter {
+    /// Parses a filter such as `RG=~^lane1` or `NM<=2`.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The filter to parse
+    ///
+    /// # Returns
+    ///
+    /// Returns the filter, or an error describing why the tag, operator, or pattern is invalid.
+    ///
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let (tag, rest) = match (source.get(..2), source.get(2..)) {
+            (Some(tag), Some(rest)) => (tag, rest),
+            _ => return Err(format!("The filter {source:?} does not start with a tag")),
+        };
+        let tag = parse_tag(tag).map_err(|e| e.to_string())?;
+        let Some(operator) = OPERATORS.into_iter().find(|op| rest.starts_with(op)) else {
+            return Err(format!(
+                "The filter {source:?} needs one of {} after its tag",
+                OPERATORS.join(", ")
+            ));
+        };
+        let value = &rest[operator.len()..];
+        let regex = || Regex::new(value).map_err(|e| format!("Invalid pattern {value:?}: {e}"));
+        let test = match operator {
+            "=~" => Test::Matches(regex()?),
+            "!~" => Test::NotMatches(regex()?),
+            _ => {
+                let comparison = match operator {
+                    "==" => Comparison::Eq,
+                    "!=" => Comparison::Ne,
+                    "<=" => Comparison::Le,
+                    ">=" => Comparison::Ge,
+                    "<" => Comparison::Lt,
+                    _ => Comparison::Gt,
+                };
+                Test::Compare(comparison, value.to_string(), value.parse().ok())
+            }
+        };
+        Ok(Self {
+            source: source.to_string(),
+            tag,
+            test,
+        })
+    }
+
+    /// Returns the filter as supplied.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns the tag the filter tests.
+    pub fn tag(&self) -> Tag {
+        self.tag
+    }
+
+    /// Returns true if a record's tag passes the filter.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The record to test
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the record passes, false if it lacks the tag, or an error if its
+    /// auxiliary data is malformed.
+    ///
+    pub fn matches<R: RecordFields + ?Sized>(
+        &self,
+        record: &R,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        let text = match (record.tag(&self.tag)?, &self.test) {
+            (Some(TagValue::Int(value)), Test::Compare(comparison, _, Some(expected))) => {
+                return Ok(comparison.holds(value.cmp(expected)));
+            }
+            (Some(TagValue::Int(value)), _) => value.to_string(),
+            (Some(TagValue::Str(value)), _) => value,
+            (Some(TagValue::Other) | None, _) => return Ok(false),
+        };
+        Ok(match &self.test {
+            Test::Matches(regex) => regex.is_match(&text),
+            Test::NotMatches(regex) => !regex.is_match(&text),
+            Test::Compare(comparison, expected, _) => comparison.holds(text.as_str().cmp(expected)),
+        })
+    }
+}
+
+impl PartialEq for TagFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for TagFilter {}
+
+impl fmt::Display for TagFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for TagFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+/// Returns true if a record passes every filter, as `--tag-filter` given more than once
+/// requires.
+///
+/// # Arguments
+///
+/// * `filters` - The filters, all of which must pass
+/// * `record` - The record to test
+///
+/// # Returns
+///
+/// Returns whether the record passes, or an error if its auxiliary data is malformed.
+///
+pub fn passes_all<R: RecordFields + ?Sized>(
+    filters: &[TagFilter],
+    record: &R,
+) -> Result<bool, Box<dyn error::Error>> {
+    for filter in filters {
+        if !filter.matches(record)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestRecord {
+        tags: Vec<(Tag, TagValue)>,
+    }
+
+    impl RecordFields for TestRecord {
+        fn flag(&self) -> u16 {
+            0x10
+        }
+
+        fn mapq(&self) -> u8 {
+            60
+        }
+
+        fn pos(&self) -> i64 {
+            1
+        }
+
+        fn tlen(&self) -> i64 {
+            0
+        }
+
+        fn seq_len(&self) -> usize {
+            4
+        }
+
+        fn tag(&self, tag: &Tag) -> Result<Option<TagValue>, Box<dyn error::Error>> {
+            Ok(self
+                .tags
+                .iter()
+                .find(|(name, _)| name == tag)
+                .map(|(_, value)| value.clone()))
+        }
+    }
+
+    fn record() -> TestRecord {
+        TestRecord {
+            tags: vec![
+                (*b"RG", TagValue::Str("lane1.A".to_string())),
+                (*b"NM", TagValue::Int(2)),
+                (*b"XF", TagValue::Other),
+            ],
+        }
+    }
+
+    fn matches(source: &str) -> bool {
+        TagFilter::parse(source)
+            .unwrap()
+            .matches(&record())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_patterns() {
+        assert!(matches("RG=~lane1.*"));
+        assert!(matches("RG=~^lane1\\.A$"));
+        assert!(!matches("RG=~^lane2"));
+        assert!(matches("RG!~lane2"));
+        assert!(matches("NM=~^2$"));
+        assert!(!matches("XF=~.*"));
+        assert!(!matches("BC=~.*"));
+        assert!(!matches("BC!~.*"));
+    }
+
+    #[test]
+    fn test_comparisons() {
+        assert!(matches("NM<=2"));
+        assert!(matches("NM<3"));
+        assert!(!matches("NM>2"));
+        assert!(matches("NM!=10"));
+        assert!(matches("NM>=-1"));
+        assert!(matches("RG==lane1.A"));
+        assert!(matches("RG>lane1"));
+        assert!(!matches("RG<lane1"));
+        assert!(!matches("NM==two"));
+        assert!(!matches("XF==1"));
+    }
+
+    #[test]
+    fn test_passes_all() {
+        let filters: Vec<TagFilter> = ["RG=~lane1", "NM<=2"]
+            .iter()
+            .map(|source| source.parse().unwrap())
+            .collect();
+        assert!(passes_all(&filters, &record()).unwrap());
+        let filters = vec![TagFilter::parse("NM>5").unwrap()];
+        assert!(!passes_all(&filters, &record()).unwrap());
+        assert!(passes_all(&[], &record()).unwrap());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let filter = TagFilter::parse("RG=~lane1").unwrap();
+        assert_eq!(filter.tag(), *b"RG");
+        assert_eq!(filter.to_string(), "RG=~lane1");
+        assert!(TagFilter::parse("R").is_err());
+        assert!(TagFilter::parse("RG").is_err());
+        assert!(TagFilter::parse("RG:lane1").is_err());
+        assert!(TagFilter::parse("1G==x").is_err());
+        assert!(
+            TagFilter::parse("RG=~(")
+                .unwrap_err()
+                .starts_with("Invalid pattern")
+        );
+    }
+}
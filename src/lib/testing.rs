@@ -0,0 +1,244 @@
+//! Builders of synthetic records and headers for tests of crates embedding revtag.
+//!
+//! Tests of a tag transform need little more than a reverse strand record carrying a few aux
+//! tags and a header naming its reference. These helpers build them without fixture files, so
+//! downstream crates need not copy the private helpers of revtag's own tests. They require the
+//! `test-utils` feature, which is meant for `[dev-dependencies]`.
+use rust_htslib::bam::header::HeaderRecord;
+use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+use rust_htslib::bam::{Header, HeaderView, Record};
+
+use crate::plan::Tag;
+
+/// The SAM text of the header built by [`minimal_header`].
+pub const SAM_HEADER: &str = "@HD\tVN:1.6\tSO:unknown\n@SQ\tSN:chr1\tLN:1000\n";
+
+/// Returns a header with a single 1,000 base reference sequence named `chr1`.
+pub fn minimal_header() -> Header {
+    let mut header = Header::new();
+    header.push_record(
+        HeaderRecord::new(b"HD")
+            .push_tag(b"VN", "1.6")
+            .push_tag(b"SO", "unknown"),
+    );
+    header.push_record(
+        HeaderRecord::new(b"SQ")
+            .push_tag(b"SN", "chr1")
+            .push_tag(b"LN", 1000),
+    );
+    header
+}
+
+/// Returns a view of [`minimal_header`], as readers and writers expect.
+pub fn minimal_header_view() -> HeaderView {
+    HeaderView::from_header(&minimal_header())
+}
+
+/// An aux value owned by a [`RecordBuilder`] until the record is built.
+#[derive(Clone, Debug, PartialEq)]
+enum AuxValue {
+    Char(u8),
+    Int(i32),
+    Float(f32),
+    String(String),
+    ArrayU8(Vec<u8>),
+    ArrayI16(Vec<i16>),
+    ArrayU16(Vec<u16>),
+    ArrayI32(Vec<i32>),
+    ArrayFloat(Vec<f32>),
+}
+
+/// Builds a synthetic record aligned to `chr1` of [`minimal_header`], carrying arbitrary aux
+/// tags.
+///
+/// Records are forward strand, mapping quality 60, at position 0, with the sequence `ACGT`
+/// and qualities of 30 unless set otherwise. The CIGAR is all matches, one per base.
+#[derive(Clone, Debug)]
+pub struct RecordBuilder {
+    qname: Vec<u8>,
+    flags: u16,
+    pos: i64,
+    mapq: u8,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+    aux: Vec<(Tag, AuxValue)>,
+}
+
+impl RecordBuilder {
+    /// Starts a record named `qname`.
+    pub fn new(qname: &str) -> Self {
+        Self {
+            qname: qname.as_bytes().to_vec(),
+            flags: 0,
+            pos: 0,
+            mapq: 60,
+            seq: b"ACGT".to_vec(),
+            qual: vec![30; 4],
+            aux: Vec::new(),
+        }
+    }
+
+    /// Sets or clears the reverse strand flag, 0x10.
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.flags = if reverse {
+            self.flags | 0x10
+        } else {
+            self.flags & !0x10
+        };
+        self
+    }
+
+    /// Sets the SAM flags, replacing any set before.
+    pub fn flags(mut self, flags: u16) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Sets the 0-based position.
+    pub fn pos(mut self, pos: i64) -> Self {
+        self.pos = pos;
+        self
+    }
+
+    /// Sets the mapping quality.
+    pub fn mapq(mut self, mapq: u8) -> Self {
+        self.mapq = mapq;
+        self
+    }
+
+    /// Sets the sequence, with qualities of 30 for each base.
+    pub fn seq(mut self, seq: &[u8]) -> Self {
+        self.qual = vec![30; seq.len()];
+        self.seq = seq.to_vec();
+        self
+    }
+
+    /// Sets the qualities, as Phred scores rather than ASCII.
+    pub fn qual(mut self, qual: &[u8]) -> Self {
+        self.qual = qual.to_vec();
+        self
+    }
+
+    /// Adds an `A` character tag.
+    pub fn char_tag(self, tag: &Tag, value: u8) -> Self {
+        self.tag(tag, AuxValue::Char(value))
+    }
+
+    /// Adds an integer tag.
+    pub fn int_tag(self, tag: &Tag, value: i32) -> Self {
+        self.tag(tag, AuxValue::Int(value))
+    }
+
+    /// Adds a `f` float tag.
+    pub fn float_tag(self, tag: &Tag, value: f32) -> Self {
+        self.tag(tag, AuxValue::Float(value))
+    }
+
+    /// Adds a `Z` string tag.
+    pub fn string_tag(self, tag: &Tag, value: &str) -> Self {
+        self.tag(tag, AuxValue::String(value.to_string()))
+    }
+
+    /// Adds a `B:C` array tag.
+    pub fn u8_array_tag(self, tag: &Tag, values: &[u8]) -> Self {
+        self.tag(tag, AuxValue::ArrayU8(values.to_vec()))
+    }
+
+    /// Adds a `B:s` array tag.
+    pub fn i16_array_tag(self, tag: &Tag, values: &[i16]) -> Self {
+        self.tag(tag, AuxValue::ArrayI16(values.to_vec()))
+    }
+
+    /// Adds a `B:S` array tag.
+    pub fn u16_array_tag(self, tag: &Tag, values: &[u16]) -> Self {
+        self.tag(tag, AuxValue::ArrayU16(values.to_vec()))
+    }
+
+    /// Adds a `B:i` array tag.
+    pub fn i32_array_tag(self, tag: &Tag, values: &[i32]) -> Self {
+        self.tag(tag, AuxValue::ArrayI32(values.to_vec()))
+    }
+
+    /// Adds a `B:f` array tag.
+    pub fn f32_array_tag(self, tag: &Tag, values: &[f32]) -> Self {
+        self.tag(tag, AuxValue::ArrayFloat(values.to_vec()))
+    }
+
+    /// Adds a tag, in order after those added before.
+    fn tag(mut self, tag: &Tag, value: AuxValue) -> Self {
+        self.aux.push((*tag, value));
+        self
+    }
+
+    /// Builds the record.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the same tag was added twice, which htslib refuses.
+    ///
+    pub fn build(&self) -> Record {
+        let mut record = Record::new();
+        let cigar = CigarString(vec![Cigar::Match(self.seq.len() as u32)]);
+        record.set(&self.qname, Some(&cigar), &self.seq, &self.qual);
+        record.set_flags(self.flags);
+        record.set_tid(0);
+        record.set_pos(self.pos);
+        record.set_mapq(self.mapq);
+        record.set_mtid(-1);
+        record.set_mpos(-1);
+        for (tag, value) in &self.aux {
+            let value = match value {
+                AuxValue::Char(value) => Aux::Char(*value),
+                AuxValue::Int(value) => Aux::I32(*value),
+                AuxValue::Float(value) => Aux::Float(*value),
+                AuxValue::String(value) => Aux::String(value),
+                AuxValue::ArrayU8(values) => Aux::ArrayU8(values.as_slice().into()),
+                AuxValue::ArrayI16(values) => Aux::ArrayI16(values.as_slice().into()),
+                AuxValue::ArrayU16(values) => Aux::ArrayU16(values.as_slice().into()),
+                AuxValue::ArrayI32(values) => Aux::ArrayI32(values.as_slice().into()),
+                AuxValue::ArrayFloat(values) => Aux::ArrayFloat(values.as_slice().into()),
+            };
+            record
+                .push_aux(tag, value)
+                .unwrap_or_else(|e| panic!("Cannot add tag {}: {e}", String::from_utf8_lossy(tag)));
+        }
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::Plan;
+
+    #[test]
+    fn test_record_builder() {
+        let record = RecordBuilder::new("read1")
+            .reverse(true)
+            .seq(b"ACGTA")
+            .string_tag(b"BC", "AACG")
+            .u8_array_tag(b"QT", &[1, 2, 3])
+            .build();
+        assert_eq!(record.qname(), b"read1");
+        assert!(record.is_reverse());
+        assert_eq!(record.seq().as_bytes(), b"ACGTA");
+        assert_eq!(record.cigar().to_string(), "5M");
+
+        let mut record = record;
+        let plan = Plan::parse(&["QT".to_string()], &["BC".to_string()], &[]).unwrap();
+        crate::ops::apply(&mut record, &plan).unwrap();
+        assert_eq!(record.aux(b"BC").unwrap(), Aux::String("CGTT"));
+    }
+
+    #[test]
+    fn test_minimal_header() {
+        let view = minimal_header_view();
+        assert_eq!(view.target_names(), vec![&b"chr1"[..]]);
+        assert_eq!(view.target_len(0), Some(1000));
+        assert!(
+            SAM_HEADER
+                .as_bytes()
+                .starts_with(&minimal_header().to_bytes())
+        );
+    }
+}
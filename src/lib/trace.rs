@@ -0,0 +1,172 @@
+//! Instrumentation of the read, transform, and write stages with `tracing` spans.
+//!
+//! With the `tracing` feature, each stage of a batch, or of a record when running on one thread,
+//! is covered by a `DEBUG` span named after the stage. Its fields count the records the stage
+//! handled and, for the transform stage, how many were transformed, so subscribers get
+//! structured timing data and counters. Without the feature, these spans compile to nothing.
+//!
+//! Independently of the feature, spans given a [`StageTimes`] add the wall time they cover to
+//! it, for the per-stage breakdown of `--profile`.
+use log::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A stage records pass through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Stage {
+    /// Reading records from the input.
+    Read,
+    /// Applying the plan to records.
+    Transform,
+    /// Writing records to the outputs.
+    Write,
+}
+
+impl Stage {
+    /// Returns the stage as named in the profile, as a gerund.
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Read => "reading",
+            Stage::Transform => "transforming",
+            Stage::Write => "writing",
+        }
+    }
+}
+
+/// Wall time spent in each stage, summed across threads.
+///
+/// When records are transformed on their own thread, transforming overlaps reading and writing,
+/// so the stages may add up to more than the run's wall time.
+#[derive(Debug, Default)]
+pub(crate) struct StageTimes {
+    nanos: [AtomicU64; 3],
+}
+
+impl StageTimes {
+    /// Adds wall time spent in a stage.
+    pub(crate) fn add(&self, stage: Stage, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.nanos[stage as usize].fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Returns the wall time spent in a stage so far.
+    pub(crate) fn elapsed(&self, stage: Stage) -> Duration {
+        Duration::from_nanos(self.nanos[stage as usize].load(Ordering::Relaxed))
+    }
+
+    /// Returns a line breaking a run's wall time down by stage, each with its share of it.
+    pub(crate) fn summary(&self, total: Duration) -> String {
+        let stages = [Stage::Read, Stage::Transform, Stage::Write].map(|stage| {
+            let elapsed = self.elapsed(stage);
+            let share = 100.0 * elapsed.as_secs_f64() / total.as_secs_f64().max(f64::EPSILON);
+            format!(
+                "{} {:.3}s ({share:.0}%)",
+                stage.name(),
+                elapsed.as_secs_f64()
+            )
+        });
+        format!(
+            "Wall time {:.3}s: {}",
+            total.as_secs_f64(),
+            stages.join(", ")
+        )
+    }
+
+    /// Logs the breakdown of a run's wall time by stage, per [`StageTimes::summary`].
+    pub(crate) fn report(&self, total: Duration) {
+        info!("{}", self.summary(total));
+    }
+}
+
+/// Runs `f`, adding its wall time to a stage of `times` if given.
+pub(crate) fn timed<T>(times: Option<&StageTimes>, stage: Stage, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = f();
+    if let Some(times) = times {
+        times.add(stage, started.elapsed());
+    }
+    result
+}
+
+/// A span covering one stage, closed when dropped.
+pub(crate) struct StageSpan<'a> {
+    #[cfg(feature = "tracing")]
+    span: tracing::span::EnteredSpan,
+    timer: Option<(&'a StageTimes, Stage, Instant)>,
+}
+
+impl<'a> StageSpan<'a> {
+    /// Enters a span for a stage, whose counts are recorded later, adding the wall time it
+    /// covers to `times` if given.
+    pub(crate) fn enter(stage: Stage, times: Option<&'a StageTimes>) -> Self {
+        let timer = times.map(|times| (times, stage, Instant::now()));
+        #[cfg(feature = "tracing")]
+        {
+            use tracing::field::Empty;
+            let span = match stage {
+                Stage::Read => tracing::debug_span!("read", records = Empty),
+                Stage::Transform => {
+                    tracing::debug_span!("transform", records = Empty, transformed = Empty)
+                }
+                Stage::Write => tracing::debug_span!("write", records = Empty),
+            };
+            Self {
+                span: span.entered(),
+                timer,
+            }
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            Self { timer }
+        }
+    }
+
+    /// Records the number of records the stage handled.
+    pub(crate) fn records(&self, records: usize) {
+        #[cfg(feature = "tracing")]
+        self.span.record("records", records);
+        #[cfg(not(feature = "tracing"))]
+        let _ = records;
+    }
+
+    /// Records the number of records the transform stage transformed.
+    pub(crate) fn transformed(&self, transformed: usize) {
+        #[cfg(feature = "tracing")]
+        self.span.record("transformed", transformed);
+        #[cfg(not(feature = "tracing"))]
+        let _ = transformed;
+    }
+}
+
+impl Drop for StageSpan<'_> {
+    fn drop(&mut self) {
+        if let Some((times, stage, started)) = self.timer {
+            times.add(stage, started.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stage_times() {
+        let times = StageTimes::default();
+        times.add(Stage::Read, Duration::from_millis(500));
+        times.add(Stage::Read, Duration::from_millis(250));
+        times.add(Stage::Write, Duration::from_millis(1000));
+        drop(StageSpan::enter(Stage::Transform, Some(&times)));
+        assert_eq!(times.elapsed(Stage::Read), Duration::from_millis(750));
+        assert!(times.elapsed(Stage::Transform) < Duration::from_secs(1));
+
+        let times = StageTimes::default();
+        times.add(Stage::Read, Duration::from_millis(500));
+        times.add(Stage::Write, Duration::from_millis(1500));
+        assert_eq!(
+            times.summary(Duration::from_secs(2)),
+            "Wall time 2.000s: reading 0.500s (25%), transforming 0.000s (0%), writing 1.500s \
+             (75%)"
+        );
+    }
+}
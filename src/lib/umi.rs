@@ -0,0 +1,132 @@
+//! UMIs carried in read names instead of, or as well as, the `RX` tag, reverse complemented
+//! with the tags of reverse strand records.
+use regex::bytes::Regex;
+use std::fmt;
+use std::ops::Range;
+
+use crate::dna;
+
+/// The default pattern locating a UMI in a read name: a trailing run of bases after the last
+/// `_` or `:`, as written by UMI-tools and bcl-convert, with dual UMIs joined by `-` or `+`.
+pub const DEFAULT_PATTERN: &str = r"[_:]([ACGTNacgtn]+(?:[-+][ACGTNacgtn]+)*)$";
+
+/// A pattern locating the UMI segment of read names.
+///
+/// The UMI is the capture group named `umi` or, without one, the first capture group.
+#[derive(Clone, Debug)]
+pub struct QnameUmi {
+    regex: Regex,
+    group: usize,
+}
+
+impl QnameUmi {
+    /// Compiles a pattern locating the UMI segment of read names.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - A regular expression with a capture group around the UMI
+    ///
+    /// # Returns
+    ///
+    /// Returns the pattern, or an error if it is invalid or captures nothing.
+    ///
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+        let group = match regex.capture_names().position(|name| name == Some("umi")) {
+            Some(group) => group,
+            None if regex.captures_len() > 1 => 1,
+            None => {
+                return Err(format!(
+                    "The pattern {pattern:?} has no capture group around the UMI"
+                ));
+            }
+        };
+        Ok(Self { regex, group })
+    }
+
+    /// Returns the pattern as supplied.
+    pub fn pattern(&self) -> &str {
+        self.regex.as_str()
+    }
+
+    /// Returns the byte range of the UMI within a read name, or None if the name carries none.
+    pub fn locate(&self, qname: &[u8]) -> Option<Range<usize>> {
+        self.regex
+            .captures(qname)
+            .and_then(|captures| captures.get(self.group))
+            .map(|umi| umi.range())
+    }
+
+    /// Returns the read name with its UMI reverse complemented, or None if it carries none.
+    /// Dual UMIs are reverse complemented as a whole, as `RX` is, so `AAA-CCG` becomes `CGG-TTT`.
+    ///
+    /// # Arguments
+    ///
+    /// * `qname` - The read name
+    ///
+    /// # Returns
+    ///
+    /// Returns the new read name, or None if the pattern does not match.
+    ///
+    pub fn reverse_complement(&self, qname: &[u8]) -> Option<Vec<u8>> {
+        let range = self.locate(qname)?;
+        let mut fixed = qname.to_vec();
+        dna::revcomp_in_place(&mut fixed[range]);
+        Some(fixed)
+    }
+}
+
+impl Default for QnameUmi {
+    fn default() -> Self {
+        Self::new(DEFAULT_PATTERN).expect("the default UMI pattern is valid")
+    }
+}
+
+impl PartialEq for QnameUmi {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern() == other.pattern()
+    }
+}
+
+impl Eq for QnameUmi {}
+
+impl fmt::Display for QnameUmi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.pattern())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_pattern() {
+        let umi = QnameUmi::default();
+        assert_eq!(
+            umi.reverse_complement(b"read1_AAACCG"),
+            Some(b"read1_CGGTTT".to_vec())
+        );
+        assert_eq!(
+            umi.reverse_complement(b"M1:7:FC:1:1:10:20:AAC+GGT"),
+            Some(b"M1:7:FC:1:1:10:20:ACC+GTT".to_vec())
+        );
+        assert_eq!(
+            umi.reverse_complement(b"read1_AAA-CCG"),
+            Some(b"read1_CGG-TTT".to_vec())
+        );
+        assert_eq!(umi.reverse_complement(b"read1"), None);
+    }
+
+    #[test]
+    fn test_custom_pattern() {
+        let umi = QnameUmi::new(r"^(?P<umi>[ACGT]+)\.").unwrap();
+        assert_eq!(umi.locate(b"AACG.read1"), Some(0..4));
+        assert_eq!(
+            umi.reverse_complement(b"AACG.read1"),
+            Some(b"CGTT.read1".to_vec())
+        );
+        assert!(QnameUmi::new(r"_[ACGT]+$").is_err());
+        assert!(QnameUmi::new(r"_([ACGT+$").is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//! Validation of a barcode tag against a whitelist of known barcodes, comparing how many reverse
+//! strand records match before and after the transform as evidence that the orientation fix is
+//! correct: a fix in the right direction raises the match rate, and one in the wrong direction
+//! lowers it.
+use std::collections::HashSet;
+use std::error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::aux;
+use crate::plan::Tag;
+
+/// A set of known barcodes and the tag holding each record's barcode.
+#[derive(Clone, Debug)]
+pub struct Whitelist {
+    barcodes: HashSet<Vec<u8>>,
+    tag: Tag,
+}
+
+impl Whitelist {
+    /// Builds a whitelist from barcodes, compared without regard to case.
+    ///
+    /// # Arguments
+    ///
+    /// * `barcodes` - The known barcodes
+    /// * `tag` - The `Z` tag holding each record's barcode
+    ///
+    /// # Returns
+    ///
+    /// Returns the whitelist.
+    ///
+    pub fn new<I, B>(barcodes: I, tag: Tag) -> Self
+    where
+        I: IntoIterator<Item = B>,
+        B: AsRef<[u8]>,
+    {
+        let barcodes = barcodes
+            .into_iter()
+            .map(|barcode| barcode.as_ref().to_ascii_uppercase())
+            .collect();
+        Self { barcodes, tag }
+    }
+
+    /// Reads a whitelist holding one barcode per line, as the first whitespace-separated field,
+    /// skipping blank lines and lines starting with `#`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The whitelist file
+    /// * `tag` - The `Z` tag holding each record's barcode
+    ///
+    /// # Returns
+    ///
+    /// Returns the whitelist, or an error naming the file if it cannot be read or holds no
+    /// barcodes.
+    ///
+    pub fn read(path: &Path, tag: Tag) -> io::Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read whitelist {}: {e}", path.display()),
+            )
+        })?;
+        let barcodes = text
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_whitespace().next());
+        let whitelist = Self::new(barcodes, tag);
+        if whitelist.barcodes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Whitelist {} holds no barcodes", path.display()),
+            ));
+        }
+        Ok(whitelist)
+    }
+
+    /// Returns the tag holding each record's barcode.
+    pub fn tag(&self) -> Tag {
+        self.tag
+    }
+
+    /// Returns true if a barcode is on the whitelist, without regard to case.
+    pub fn contains(&self, barcode: &[u8]) -> bool {
+        self.barcodes.contains(&barcode.to_ascii_uppercase())
+    }
+
+    /// Returns whether the barcode in a raw auxiliary data block is on the whitelist.
+    ///
+    /// # Arguments
+    ///
+    /// * `aux` - The raw auxiliary data of a BAM record
+    ///
+    /// # Returns
+    ///
+    /// Returns whether the barcode matches, None if the tag is missing or is not a `Z` string,
+    /// or an error if the data is malformed.
+    ///
+    pub fn matches(&self, aux: &[u8]) -> Result<Option<bool>, Box<dyn error::Error>> {
+        Ok(match aux::find(aux, &self.tag)? {
+            Some(field) if field.value_type == b'Z' => Some(self.contains(&aux[field.value])),
+            _ => None,
+        })
+    }
+}
+
+/// Counts of reverse strand records whose barcode matched the whitelist before and after the
+/// transform.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MatchRates {
+    /// Reverse strand records carrying the barcode tag before the transform.
+    pub records: u64,
+    /// Those records whose barcode matched before the transform.
+    pub before: u64,
+    /// Those records whose barcode matched after the transform.
+    pub after: u64,
+}
+
+impl MatchRates {
+    /// Counts a record by whether its barcode matched before and after the transform.
+    pub fn record(&mut self, before: bool, after: bool) {
+        self.records += 1;
+        self.before += u64::from(before);
+        self.after += u64::from(after);
+    }
+
+    /// Adds every count from `other` to these counts.
+    pub fn merge(&mut self, other: &MatchRates) {
+        self.records += other.records;
+        self.before += other.before;
+        self.after += other.after;
+    }
+
+    /// Returns true if the transform left fewer records matching than it found, suggesting the
+    /// chosen orientation fix is wrong for this data.
+    pub fn worsened(&self) -> bool {
+        self.after < self.before
+    }
+
+    /// Describes the match rates of the barcodes in a tag.
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - The tag holding each record's barcode
+    ///
+    /// # Returns
+    ///
+    /// Returns a line such as `Whitelist matches of BC on 200 reverse strand records: 12
+    /// (6.0%) before the transform, 190 (95.0%) after`.
+    ///
+    pub fn summary(&self, tag: &Tag) -> String {
+        let percent = |count: u64| {
+            if self.records == 0 {
+                0.0
+            } else {
+                count as f64 * 100.0 / self.records as f64
+            }
+        };
+        format!(
+            "Whitelist matches of {} on {} reverse strand records: {} ({:.1}%) before the \
+             transform, {} ({:.1}%) after",
+            String::from_utf8_lossy(tag),
+            self.records,
+            self.before,
+            percent(self.before),
+            self.after,
+            percent(self.after)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let whitelist = Whitelist::new(["AACG", "ttga"], *b"BC");
+        assert_eq!(whitelist.matches(b"BCZAACG\0").unwrap(), Some(true));
+        assert_eq!(whitelist.matches(b"NMC\x03BCZTTGA\0").unwrap(), Some(true));
+        assert_eq!(whitelist.matches(b"BCZCGTT\0").unwrap(), Some(false));
+        assert_eq!(whitelist.matches(b"BCC\x03").unwrap(), None);
+        assert_eq!(whitelist.matches(b"NMC\x03").unwrap(), None);
+        assert!(whitelist.contains(b"aacg"));
+        assert!(!whitelist.contains(b"CGTT"));
+    }
+
+    #[test]
+    fn test_read() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("whitelist.txt");
+        fs::write(&path, "# 10x whitelist\nAACG\n\nTTGA\textra\n").unwrap();
+        let whitelist = Whitelist::read(&path, *b"CB").unwrap();
+        assert_eq!(whitelist.tag(), *b"CB");
+        assert_eq!(whitelist.matches(b"CBZTTGA\0").unwrap(), Some(true));
+
+        fs::write(&path, "# nothing\n").unwrap();
+        assert!(Whitelist::read(&path, *b"CB").is_err());
+    }
+
+    #[test]
+    fn test_match_rates() {
+        let mut rates = MatchRates::default();
+        rates.record(false, true);
+        rates.record(true, true);
+        let mut other = MatchRates::default();
+        other.record(false, false);
+        other.record(false, true);
+        rates.merge(&other);
+        assert_eq!(
+            rates.summary(b"BC"),
+            "Whitelist matches of BC on 4 reverse strand records: 1 (25.0%) before the \
+             transform, 3 (75.0%) after"
+        );
+        assert!(!rates.worsened());
+        assert!(
+            MatchRates {
+                records: 2,
+                before: 2,
+                after: 0
+            }
+            .worsened()
+        );
+        assert_eq!(
+            MatchRates::default().summary(b"BC"),
+            "Whitelist matches of BC on 0 reverse strand records: 0 (0.0%) before the \
+             transform, 0 (0.0%) after"
+        );
+    }
+}
@@ -1,28 +1,119 @@
 //! Reverse (and complement) array-like SAM tags  for reverse alignments.
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::OnceLock;
+use std::time::Duration;
 
-use anyhow::{Error, Result};
+use anyhow::{Error, anyhow};
 use env_logger::Env;
 use structopt::StructOpt;
 
-use revtaglib::revtag;
+use revtaglib::batch::{read_manifest, run_batch};
+use revtaglib::condition::Condition;
+use revtaglib::config::effective_config;
+use revtaglib::diagnostic::{Callout, Style, paint, use_color};
+use revtaglib::failure::{Category, Failure};
+#[cfg(feature = "remote")]
+use revtaglib::io::auto_reference_env;
+use revtaglib::memory::{format_mib, parse_size, peak_rss};
+#[cfg(feature = "htslib")]
+use revtaglib::merge::merge_shards;
+#[cfg(all(feature = "noodles", not(feature = "htslib")))]
+use revtaglib::noodles::run;
+use revtaglib::options::{MateTag, expand_argsfiles, parse_threads, read_tag_file};
+use revtaglib::plan::{ParseError, parse_tag};
+use revtaglib::quality::QualityBins;
+#[cfg(feature = "htslib")]
+use revtaglib::report::dry_run;
+use revtaglib::retry::parse_delay;
+#[cfg(feature = "htslib")]
+use revtaglib::run;
+use revtaglib::sort::SortOrder;
+use revtaglib::tagfilter::TagFilter;
+use revtaglib::umi::QnameUmi;
+use revtaglib::{
+    DuplicateTags, MissingSeqPolicy, NonSequence, Options, OutputFormat, PairMismatch, Plan,
+    Preset, QcFailAction, QcFailFilter, ReadOfPair, UnmappedPolicy, build,
+};
 
 #[derive(Clone, Debug, StructOpt)]
 #[structopt(
     setting = structopt::clap::AppSettings::ColoredHelp,
     setting = structopt::clap::AppSettings::DeriveDisplayOrder,
     rename_all = "kebab-case",
-    about
+    about,
+    after_help = "EXIT STATUS:\n    0    Success\n    1    The run failed, e.g. unreadable input or malformed records\n    2    An argument is invalid, e.g. a tag specification, a condition, or an existing output without --force-overwrite\n\nARGUMENT FILES:\n    @FILE    Reads further arguments from FILE, one per line; lines starting with # are skipped"
 )]
 struct Opt {
     /// Input SAM/BAM/CRAM file or stream [default: /dev/stdin]
     #[structopt(short = "i", long = "--input", parse(from_os_str))]
     input: Option<PathBuf>,
 
-    /// Output SAM/BAM/CRAM file or stream [default: /dev/stdout]
+    /// Output SAM/BAM/CRAM files or streams, each in the format implied by its extension and
+    /// written in a single pass [default: /dev/stdout]
     #[structopt(short = "o", long = "--output", parse(from_os_str))]
-    output: Option<PathBuf>,
+    output: Vec<PathBuf>,
+
+    /// Replace output files that already exist, which are otherwise refused so a rerun cannot
+    /// clobber a file another step is still reading
+    #[structopt(long = "--force-overwrite")]
+    force_overwrite: bool,
+
+    /// Process every input listed in this file, one per line as the input and its output
+    /// separated by a tab, instead of --input and --output
+    #[structopt(
+        long = "--manifest",
+        parse(from_os_str),
+        conflicts_with_all = &["input", "output", "barcode-fastq", "audit-tsv", "contig-tsv"]
+    )]
+    manifest: Option<PathBuf>,
+
+    /// Files of a --manifest processed at once, each with its own --threads; for many small
+    /// inputs, per-file parallelism beats per-record parallelism [default: 1]
+    #[structopt(long = "--jobs", requires = "manifest")]
+    jobs: Option<usize>,
+
+    /// Once every file of a --manifest succeeds, merge their outputs, such as shards of one input
+    /// split by region, into this file; coordinate sorted outputs are merged in coordinate order
+    /// and others are concatenated in manifest order
+    #[cfg(feature = "htslib")]
+    #[structopt(long = "--merge-output", parse(from_os_str), requires = "manifest")]
+    merge_output: Option<PathBuf>,
+
+    /// Write the MD5 checksum of each output file, computed as it is written, to a sidecar named
+    /// like the output with .md5 appended, in the format md5sum -c reads
+    #[structopt(long = "--write-md5")]
+    write_md5: bool,
+
+    /// Flush the output files and their directory entries to storage before exiting
+    /// successfully, so a crash right after cannot leave truncated outputs on networked
+    /// filesystems
+    #[structopt(long = "--fsync")]
+    fsync: bool,
+
+    /// Reference FASTA for CRAM input and output
+    #[structopt(long = "--reference", parse(from_os_str))]
+    reference: Option<PathBuf>,
+
+    /// FASTA index (.fai) of --reference, when it is not beside the FASTA
+    #[structopt(long = "--reference-index", parse(from_os_str), requires = "reference")]
+    reference_index: Option<PathBuf>,
+
+    /// Format of the first output, overriding its extension; needed for FIFOs, /dev/fd paths
+    /// from process substitution, and BAM or CRAM on stdout
+    #[structopt(long = "--output-format", possible_values = &["sam", "bam", "cram"])]
+    output_format: Option<OutputFormat>,
+
+    /// Discard read names in CRAM outputs, which readers regenerate, except where needed to
+    /// pair mates
+    #[structopt(long = "--cram-lossy-names")]
+    cram_lossy_names: bool,
+
+    /// Bin base qualities in CRAM outputs: illumina for the Illumina 8-level scheme, or
+    /// LOW:VALUE pairs with ascending lower bounds, e.g. 10:15,20:22,30:33
+    #[structopt(long = "--cram-qual-bins", value_name = "SCHEME")]
+    cram_qual_bins: Option<QualityBins>,
 
     /// SAM tags with array values to reverse
     #[structopt(long = "--rev")]
@@ -32,18 +123,475 @@ struct Opt {
     #[structopt(long = "--revcomp")]
     revcomp: Vec<String>,
 
-    /// Extra threads for BAM/CRAM compression/decompression
-    #[structopt(short = "t", long = "--threads", default_value = "1")]
+    /// SAM tags paired with an operation, e.g. QT:rev, BC:revcomp, or CS:revcs for a SOLiD
+    /// colorspace read, optionally followed by the strand deciding whether the tag is
+    /// transformed: strand=read (the default), strand=mate, strand=always, or strand=TAG for a
+    /// tag holding + or -, e.g. MB:revcomp:strand=mate
+    #[structopt(long = "--tag")]
+    tag: Vec<String>,
+
+    /// Files listing a tag and its operation on each line, separated by a tab, e.g. QT<TAB>rev,
+    /// applied after any --tag
+    #[structopt(long = "--tag-file", parse(from_os_str))]
+    tag_file: Vec<PathBuf>,
+
+    /// Companion SEQ:QUAL tag pairs, e.g. BC:QT, whose sequence is reverse complemented and
+    /// qualities reversed together; a record carrying only one of a pair is an error
+    #[structopt(long = "--pair")]
+    pair: Vec<String>,
+
+    /// SAM tags holding a matrix packed row-major into a B array, as TAG:ROWSxCOLS, whose rows
+    /// are reversed while each row keeps its order; ROWS may be * for any number, and COLS may
+    /// name an integer tag holding the number of columns, e.g. SG:*x4 or SG:*xNC
+    #[structopt(long = "--matrix")]
+    matrix: Vec<String>,
+
+    /// Named sets of well-known tags, each with the operation it needs: oq reverses the OQ
+    /// original qualities; e2u2 transforms the E2 secondary bases and U2 qualities as a pair;
+    /// colorspace reverses the SOLiD CS read, re-deriving its primer base, and CQ qualities;
+    /// bismark reverses the XM methylation calls and checks the XR and XG conversions
+    #[structopt(
+        long = "--preset",
+        possible_values = &["oq", "e2u2", "colorspace", "bismark"]
+    )]
+    preset: Vec<Preset>,
+
+    /// How to handle --pair tags whose values differ in length, which suggests upstream
+    /// corruption
+    #[structopt(
+        long = "--on-pair-length-mismatch",
+        default_value = "error",
+        possible_values = &["error", "warn"]
+    )]
+    on_pair_length_mismatch: PairMismatch,
+
+    /// Extra threads for BAM/CRAM compression/decompression; 0 or auto uses the available CPUs,
+    /// up to 16
+    #[structopt(
+        short = "t",
+        long = "--threads",
+        default_value = "1",
+        parse(try_from_str = parse_threads)
+    )]
     threads: usize,
+
+    /// How to handle --revcomp tags holding values that are not sequences, e.g. 16-bit arrays
+    #[structopt(
+        long = "--on-revcomp-nonsequence",
+        default_value = "skip",
+        possible_values = &["error", "reverse-only", "skip"]
+    )]
+    on_revcomp_nonsequence: NonSequence,
+
+    /// How to handle a planned tag carried more than once by one record, which the SAM
+    /// specification forbids: transform the first occurrence only, every occurrence, or fail
+    #[structopt(
+        long = "--on-duplicate-tag",
+        default_value = "first",
+        possible_values = &["first", "all", "error"]
+    )]
+    on_duplicate_tag: DuplicateTags,
+
+    /// SAM tag holding + or - that decides each record's strand instead of the reverse flag,
+    /// e.g. XS for unmapped BAMs
+    #[structopt(long = "--strand-from-tag")]
+    strand_from_tag: Option<String>,
+
+    /// Decide each record's strand from the /A or /B duplex strand suffix of its MI tag instead
+    /// of the reverse flag, transforming /B records, for duplex consensus BAMs
+    #[structopt(long = "--strand-from-mi", conflicts_with = "strand-from-tag")]
+    strand_from_mi: bool,
+
+    /// Condition reverse strand records must also meet to be transformed, e.g.
+    /// 'mapq >= 20 && !flag.secondary && has(BC)'
+    #[structopt(long = "--if")]
+    condition: Option<String>,
+
+    /// Filter on a tag value reverse strand records must also pass to be transformed, e.g.
+    /// 'RG=~^lane1' or 'NM<=2'; =~ and !~ take a regular expression, and ==, !=, <, <=, >, and
+    /// >= compare; may be repeated, and records must pass every filter
+    #[structopt(long = "--tag-filter")]
+    tag_filter: Vec<String>,
+
+    /// Minimum mapping quality of reverse strand records to transform; records below it, whose
+    /// strand is unreliable, pass through untouched
+    #[structopt(long = "--min-mapq")]
+    min_mapq: Option<u8>,
+
+    /// Transform only the first or second read of pairs, for library preps that mis-orient the
+    /// tags of one mate
+    #[structopt(long = "--read", possible_values = &["1", "2"])]
+    read: Option<ReadOfPair>,
+
+    /// Propagate a tag from one mate to the other records of its name after the fix, as TAG to
+    /// copy it or TAG:OP (e.g. BC:revcomp) to re-orient it where the mates' strands differ;
+    /// records of a name must be adjacent, as in queryname-grouped input
+    #[structopt(long = "--pair-fix")]
+    pair_fix: Vec<String>,
+
+    /// The mate whose primary record --pair-fix propagates tags from
+    #[structopt(long = "--pair-fix-source", possible_values = &["1", "2"], default_value = "1")]
+    pair_fix_source: ReadOfPair,
+
+    /// How to handle records flagged both unmapped (0x4) and reverse strand (0x10), whose
+    /// strand is meaningless: transform them like other reverse strand records, skip them, or
+    /// fail
+    #[structopt(
+        long = "--unmapped-policy",
+        default_value = "transform",
+        possible_values = &["transform", "skip", "error"]
+    )]
+    unmapped_policy: UnmappedPolicy,
+
+    /// How to handle records with SEQ `*`, such as secondary alignments carrying tags copied
+    /// from their primary: transform them by their own strand, or skip them
+    #[structopt(
+        long = "--on-missing-seq",
+        default_value = "transform",
+        possible_values = &["transform", "skip"]
+    )]
+    on_missing_seq: MissingSeqPolicy,
+
+    /// Leave records flagged QC-fail (0x200) untouched, or drop them with --qcfail-action drop
+    #[structopt(long = "--exclude-qcfail", conflicts_with = "only-qcfail")]
+    exclude_qcfail: bool,
+
+    /// Transform only records flagged QC-fail (0x200), leaving passing records untouched, or
+    /// dropping them with --qcfail-action drop
+    #[structopt(long = "--only-qcfail")]
+    only_qcfail: bool,
+
+    /// What to do with records left out by --exclude-qcfail or --only-qcfail: pass them through
+    /// untouched, or drop them from the output
+    #[structopt(
+        long = "--qcfail-action",
+        default_value = "skip",
+        possible_values = &["skip", "drop"]
+    )]
+    qcfail_action: QcFailAction,
+
+    /// Fail on the first reverse strand record lacking any of the tags to transform
+    #[structopt(long = "--strict")]
+    strict: bool,
+
+    /// Tolerate this many records failing to transform, such as under --strict or
+    /// --require-tags, each logged with its read name and position and left out of the output,
+    /// before aborting on the next
+    #[structopt(long = "--max-errors", default_value = "0")]
+    max_errors: usize,
+
+    /// Reopen a remote input, such as an s3:// or https:// URL, up to this many times after it
+    /// fails to open or read, e.g. on a timeout, server error, or reset connection, resuming
+    /// after the last record read
+    #[structopt(long = "--retries", default_value = "0")]
+    retries: u32,
+
+    /// Seconds to wait before the first retry of a remote input, doubling with each later retry
+    /// up to five minutes
+    #[structopt(
+        long = "--retry-delay",
+        default_value = "1",
+        parse(try_from_str = parse_delay)
+    )]
+    retry_delay: Duration,
+
+    /// Repair malformed aux tags seen in the wild before transforming: trailing NULs in Z tags,
+    /// B arrays declaring elements they lack, and quality arrays such as OQ of a wider type than
+    /// B:C; each repair is counted in the summary at the end of the run
+    #[structopt(long = "--sanitize")]
+    sanitize: bool,
+
+    /// Write byte-identical output across reruns with the same inputs and thread count, e.g. to
+    /// pin outputs by checksum: the @PG record gets a deterministic ID unique within the input
+    /// header and a command line without the program's install path
+    #[structopt(long = "--reproducible")]
+    reproducible: bool,
+
+    /// The ID of the @PG record written, e.g. a versioned step name; defaults to the program
+    /// name, and must not already be in the input header
+    #[structopt(long = "--pg-id", value_name = "ID")]
+    pg_id: Option<String>,
+
+    /// The program name (PN) of the @PG record written, and the default of --pg-id
+    #[structopt(
+        long = "--pg-program-name",
+        value_name = "NAME",
+        default_value = "revtag"
+    )]
+    pg_program_name: String,
+
+    /// Copy the input header text byte for byte, appending only the @PG record, instead of
+    /// rebuilding it, for downstream header-diff checks; SAM and BAM outputs only
+    #[structopt(long = "--preserve-header")]
+    preserve_header: bool,
+
+    /// Before the run, scan this many records from the start of the input and abort if none
+    /// carries any tag to transform, which usually means a typo in a tag name
+    #[structopt(long = "--preflight")]
+    preflight: Option<usize>,
+
+    /// Fail early unless the input is sorted in this order, per its @HD SO field and the records
+    /// themselves as they stream, instead of producing subtly wrong output
+    #[structopt(
+        long = "--expected-sort-order",
+        possible_values = &["coordinate", "queryname"]
+    )]
+    expected_sort_order: Option<SortOrder>,
+
+    /// SAM tags every record must carry, e.g. QT,BC; the run fails on the first record lacking one
+    #[structopt(long = "--require-tags", use_delimiter = true)]
+    require_tags: Vec<String>,
+
+    /// Also reverse complement SEQ, reverse QUAL and CIGAR, and clear the reverse flag of
+    /// reverse strand records, restoring their sequencing orientation
+    #[structopt(long = "--restore-original-orientation")]
+    restore_original_orientation: bool,
+
+    /// Revert every record to an unaligned record for re-alignment after transforming it:
+    /// restore reverse strand records to their sequencing orientation, clear alignment fields
+    /// and NM, UQ, PG, MD, MQ, SA, MC, and AS tags, drop secondary and supplementary records,
+    /// and remove @SQ header lines
+    #[structopt(long = "--revert-to-ubam", conflicts_with = "audit-tsv")]
+    revert_to_ubam: bool,
+
+    /// Stamp each transformed record with a tag recording the orientation it was treated as,
+    /// `-` for reverse strand records and `+` for records transformed only by a per-tag strand
+    /// source; the tag defaults to os, e.g. os:A:-
+    #[structopt(long = "--annotate-strand", value_name = "TAG")]
+    annotate_strand: Option<Option<String>>,
+
+    /// Also reverse complement the UMI carried at the end of the read name of reverse strand
+    /// records, after the last _ or :, e.g. read1_AAACCG becomes read1_CGGTTT
+    #[structopt(long = "--fix-qname-umi")]
+    fix_qname_umi: bool,
+
+    /// Locate the read name UMI reverse complemented by --fix-qname-umi with this regular
+    /// expression instead; the UMI is its group named umi, or else its first group
+    #[structopt(long = "--qname-umi-regex", value_name = "REGEX")]
+    qname_umi_regex: Option<String>,
+
+    /// Also write each record's barcode and its qualities, as transformed, to this FASTQ named
+    /// by read name; BGZF compressed when named .gz
+    #[structopt(long = "--barcode-fastq", parse(from_os_str))]
+    barcode_fastq: Option<PathBuf>,
+
+    /// SAM tag holding the barcode written to --barcode-fastq
+    #[structopt(long = "--barcode-tag", default_value = "BC")]
+    barcode_tag: String,
+
+    /// SAM tag holding the barcode qualities written to --barcode-fastq
+    #[structopt(long = "--barcode-qual-tag", default_value = "QT")]
+    barcode_qual_tag: String,
+
+    /// File of known barcodes, one per line; the fraction of reverse strand records whose
+    /// --whitelist-tag matches it is reported before and after the transform
+    #[structopt(long = "--whitelist", parse(from_os_str))]
+    whitelist: Option<PathBuf>,
+
+    /// SAM tag holding the barcode matched against --whitelist
+    #[structopt(long = "--whitelist-tag", default_value = "BC")]
+    whitelist_tag: String,
+
+    /// Sample records from the start of the input to decide which other tags to reverse or
+    /// reverse complement, by --whitelist matches, agreement with SEQ and QUAL, and trends along
+    /// the read, and log each decision before applying it; stdin is copied to --tmpdir first
+    #[structopt(long = "--auto-orient")]
+    auto_orient: bool,
+
+    /// Number of records --auto-orient samples from the start of the input
+    #[structopt(long = "--auto-orient-records", default_value = "10000")]
+    auto_orient_records: usize,
+
+    /// Also list every modified record's name, contig, position, flag, and modified tags in this
+    /// tab-separated table; BGZF compressed when named .gz
+    #[structopt(long = "--audit-tsv", parse(from_os_str))]
+    audit_tsv: Option<PathBuf>,
+
+    /// Also count the records, reverse strand records, and transformed records on each contig in
+    /// this tab-separated table, to spot contigs that stand out, e.g. already fixed by an
+    /// earlier partial run
+    #[structopt(long = "--contig-tsv", parse(from_os_str))]
+    contig_tsv: Option<PathBuf>,
+
+    /// Print the tag plan and options resolved from all flags and their defaults as JSON, then
+    /// exit without reading any input
+    #[structopt(long = "--print-config")]
+    print_config: bool,
+
+    /// Print the version, git commit, linked htslib version, enabled features, and target of
+    /// this build as JSON, for provenance tracking, then exit
+    #[structopt(long = "--version-json")]
+    version_json: bool,
+
+    /// Report how many records and which tags would be modified, without writing any output
+    #[cfg(feature = "htslib")]
+    #[structopt(long = "--dry-run")]
+    dry_run: bool,
+
+    /// Directory for scratch files of modes that buffer records beyond memory, removed when the
+    /// run ends [default: $TMPDIR]
+    #[structopt(long = "--tmpdir", parse(from_os_str))]
+    tmpdir: Option<PathBuf>,
+
+    /// Write newline-delimited JSON progress events to this inherited file descriptor, e.g. 3,
+    /// for programs wrapping revtag; logs stay on stderr
+    #[structopt(long = "--progress-fd")]
+    progress_fd: Option<i32>,
+
+    /// Log the wall time spent reading and decompressing, transforming, and writing and
+    /// compressing records at the end of the run
+    #[structopt(long = "--profile")]
+    profile: bool,
+
+    /// On failure, also write the error's category, message, offending record when known, and
+    /// exit status as JSON to this file, for workflow engines
+    #[structopt(long = "--error-json", parse(from_os_str))]
+    error_json: Option<PathBuf>,
+
+    /// Records per batch handed between the read, transform, and write threads
+    #[structopt(long = "--batch-size", default_value = "1024")]
+    batch_size: usize,
+
+    /// Upper bound on memory for records buffered between threads, e.g. 512M or 2G; batches of
+    /// long reads shrink to fit
+    #[structopt(long = "--max-memory", parse(try_from_str = parse_size))]
+    max_memory: Option<usize>,
+
+    /// WASM plugin modules to apply as custom tag operations
+    #[cfg(feature = "plugins")]
+    #[structopt(long = "--plugin", parse(from_os_str))]
+    plugin: Vec<PathBuf>,
+
+    /// Rhai script that can veto or override the transform of each record
+    #[cfg(feature = "scripting")]
+    #[structopt(long = "--script", parse(from_os_str))]
+    script: Option<PathBuf>,
+
+    /// Fetch CRAM reference sequences by MD5 checksum when no local copy is found, caching them
+    /// in REF_CACHE
+    #[cfg(feature = "remote")]
+    #[structopt(long = "--auto-reference")]
+    auto_reference: bool,
+
+    /// URL template, with %s for the MD5 checksum, to fetch references from with
+    /// --auto-reference instead of the EBI CRAM reference registry
+    #[cfg(feature = "remote")]
+    #[structopt(long = "--reference-mirror", requires = "auto-reference")]
+    reference_mirror: Option<String>,
+}
+
+/// Exit status when the run fails after its arguments were accepted.
+const EXIT_FAILURE: i32 = 1;
+
+/// Exit status when a tag name or specification is invalid.
+const EXIT_USAGE: i32 = 2;
+
+/// The file `--error-json` reports failures to, set once the arguments are parsed.
+static ERROR_JSON: OnceLock<PathBuf> = OnceLock::new();
+
+/// Reports an error and its causes on stderr, then exits with `code`.
+fn exit_with(error: Error, code: i32) -> ! {
+    exit_with_callout(error, code, None)
+}
+
+/// Reports an invalid tag argument on stderr, underlining the part at fault, then exits.
+fn exit_with_parse_error(error: ParseError) -> ! {
+    let callout = Callout::from(&error);
+    exit_with_callout(
+        Error::new(error).context("Invalid tag arguments"),
+        EXIT_USAGE,
+        Some(callout),
+    )
+}
+
+/// Reports an error and its causes on stderr, followed by a callout of the argument at fault
+/// if given, then exits with `code`.
+fn exit_with_callout(error: Error, code: i32, callout: Option<Callout>) -> ! {
+    let category = if code == EXIT_USAGE {
+        Category::Usage
+    } else {
+        Category::Failure
+    };
+    let failure = Failure {
+        category,
+        message: format!("{error:#}"),
+        record: None,
+        exit_code: code,
+    };
+    exit_with_failure(error, failure, callout.as_ref())
+}
+
+/// Reports a failed run on stderr and, with `--error-json`, as JSON, then exits.
+///
+/// The library error is inspected before it is wrapped, so record checks report their record.
+fn exit_with_run_error(error: Box<dyn std::error::Error>, source: &str) -> ! {
+    let wrapped = anyhow!("{error}").context(format!("Failed to process {source}"));
+    let failure = Failure::from_error(error.as_ref(), format!("{wrapped:#}"), EXIT_FAILURE);
+    exit_with_failure(wrapped, failure, None)
+}
+
+/// Reports an error on stderr and writes `failure` to the `--error-json` file, if any, then
+/// exits with its status.
+///
+/// The error is colored, as is any callout beneath it, when stderr is a terminal.
+fn exit_with_failure(error: Error, failure: Failure, callout: Option<&Callout>) -> ! {
+    let color = use_color();
+    let label = paint("Error", Style::Error, color);
+    eprintln!("{label}: {error:?}");
+    if let Some(callout) = callout {
+        eprintln!("\n{}", callout.render(color));
+    }
+    let written = ERROR_JSON
+        .get()
+        .map(|path| (path, fs::write(path, failure.to_json())));
+    if let Some((path, Err(e))) = written {
+        eprintln!("{label}: Failed to write {}: {e}", path.display());
+    }
+    process::exit(failure.exit_code)
 }
 
 /// Main binary entrypoint.
 #[cfg(not(tarpaulin_include))]
-fn main() -> Result<(), Error> {
+fn main() {
     let env = Env::default().default_filter_or("info");
-    let opt = Opt::from_args();
+    let version = build::long_version();
+    let args = match expand_argsfiles(std::env::args_os()) {
+        Ok(args) => args,
+        Err(e) => exit_with(Error::new(e).context("Invalid arguments"), EXIT_USAGE),
+    };
+    let opt = Opt::from_clap(
+        &Opt::clap()
+            .long_version(version.as_str())
+            .get_matches_from(args),
+    );
 
     env_logger::Builder::from_env(env).init();
+    if opt.version_json {
+        print!("{}", build::version_json());
+        process::exit(0)
+    }
+    if let Some(path) = &opt.error_json {
+        let _ = ERROR_JSON.set(path.clone());
+    }
+
+    #[cfg(feature = "remote")]
+    if opt.auto_reference {
+        match auto_reference_env(opt.reference_mirror.as_deref(), |name| {
+            std::env::var(name).ok()
+        }) {
+            Ok(vars) => {
+                for (name, value) in vars {
+                    log::info!("Setting {name}={value}");
+                    // SAFETY: no other threads have been started yet.
+                    unsafe { std::env::set_var(name, value) };
+                }
+            }
+            Err(e) => exit_with(
+                anyhow!("{e}").context("Invalid --auto-reference settings"),
+                EXIT_USAGE,
+            ),
+        }
+    }
 
     // Convert "-" to None for stdin/stdout
     let input = opt.input.and_then(|p| {
@@ -54,16 +602,318 @@ fn main() -> Result<(), Error> {
         }
     });
 
-    let output = opt.output.and_then(|p| {
+    let mut outputs = opt.output.into_iter();
+    let output = outputs.next().and_then(|p| {
         if p.to_str() == Some("-") {
             None
         } else {
             Some(p)
         }
     });
+    let tee: Vec<PathBuf> = outputs.collect();
+
+    let mut tags = opt.tag;
+    for path in &opt.tag_file {
+        match read_tag_file(path) {
+            Ok(specs) => tags.extend(specs),
+            Err(e) => exit_with(anyhow!(e), EXIT_USAGE),
+        }
+    }
+    let plan = match Plan::parse(&opt.rev, &opt.revcomp, &tags)
+        .and_then(|plan| plan.with_pairs(&opt.pair))
+        .and_then(|plan| plan.with_matrices(&opt.matrix))
+        .and_then(|plan| {
+            opt.preset
+                .iter()
+                .try_fold(plan, |plan, preset| plan.with_preset(*preset))
+        }) {
+        Ok(plan) => plan
+            .with_nonsequence(opt.on_revcomp_nonsequence)
+            .with_pair_mismatch(opt.on_pair_length_mismatch)
+            .with_duplicate_tags(opt.on_duplicate_tag),
+        Err(e) => exit_with_parse_error(e),
+    };
+
+    let pair_fix = match opt
+        .pair_fix
+        .iter()
+        .map(|token| MateTag::parse(token))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(tags) => tags,
+        Err(e) => exit_with_parse_error(e),
+    };
+
+    let required_tags = match opt
+        .require_tags
+        .iter()
+        .map(|tag| parse_tag(tag))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(tags) => tags,
+        Err(e) => exit_with_parse_error(e),
+    };
+
+    let strand_annotation = match opt
+        .annotate_strand
+        .as_ref()
+        .map(|tag| parse_tag(tag.as_deref().unwrap_or("os")))
+        .transpose()
+    {
+        Ok(tag) => tag,
+        Err(e) => exit_with_parse_error(e),
+    };
+
+    for (flag, value) in [
+        ("--pg-id", opt.pg_id.as_deref()),
+        ("--pg-program-name", Some(opt.pg_program_name.as_str())),
+    ] {
+        if value.is_some_and(|value| value.is_empty() || value.contains(['\t', '\n', '\r'])) {
+            exit_with(
+                anyhow!("{flag} must be non-empty and hold no tabs or newlines"),
+                EXIT_USAGE,
+            )
+        }
+    }
+
+    let qname_umi = match (&opt.qname_umi_regex, opt.fix_qname_umi) {
+        (Some(pattern), _) => match QnameUmi::new(pattern) {
+            Ok(umi) => Some(umi),
+            Err(e) => exit_with(
+                anyhow!("{e}").context("Invalid --qname-umi-regex"),
+                EXIT_USAGE,
+            ),
+        },
+        (None, true) => Some(QnameUmi::default()),
+        (None, false) => None,
+    };
+
+    let qcfail = match (opt.exclude_qcfail, opt.only_qcfail) {
+        (true, _) => Some(QcFailFilter::Exclude),
+        (_, true) => Some(QcFailFilter::Only),
+        _ if opt.qcfail_action == QcFailAction::Drop => exit_with(
+            anyhow!("--qcfail-action drop requires --exclude-qcfail or --only-qcfail"),
+            EXIT_USAGE,
+        ),
+        _ => None,
+    };
+
+    let strand_tag = match opt.strand_from_tag.as_deref().map(parse_tag).transpose() {
+        Ok(tag) => tag,
+        Err(e) => exit_with_parse_error(e),
+    };
+
+    let condition = match opt.condition.as_deref().map(Condition::parse).transpose() {
+        Ok(condition) => condition,
+        Err(e) => {
+            let callout = Callout::for_condition(opt.condition.as_deref().unwrap_or_default(), &e);
+            exit_with_callout(
+                Error::new(e).context("Invalid --if condition"),
+                EXIT_USAGE,
+                Some(callout),
+            )
+        }
+    };
+
+    let tag_filters = match opt
+        .tag_filter
+        .iter()
+        .map(|source| TagFilter::parse(source))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(filters) => filters,
+        Err(e) => exit_with(anyhow!("{e}").context("Invalid --tag-filter"), EXIT_USAGE),
+    };
+
+    let (barcode_tag, barcode_quality_tag) = match (
+        parse_tag(&opt.barcode_tag),
+        parse_tag(&opt.barcode_qual_tag),
+    ) {
+        (Ok(barcode), Ok(quality)) => (barcode, quality),
+        (Err(e), _) | (_, Err(e)) => exit_with_parse_error(e),
+    };
+
+    let whitelist_tag = match parse_tag(&opt.whitelist_tag) {
+        Ok(tag) => tag,
+        Err(e) => exit_with_parse_error(e),
+    };
+
+    let options = Options {
+        threads: opt.threads,
+        batch_size: opt.batch_size,
+        max_memory: opt.max_memory,
+        strand_tag,
+        strand_from_mi: opt.strand_from_mi,
+        condition,
+        tag_filters,
+        min_mapq: opt.min_mapq,
+        read: opt.read,
+        pair_fix,
+        pair_fix_source: opt.pair_fix_source,
+        unmapped: opt.unmapped_policy,
+        missing_seq: opt.on_missing_seq,
+        qcfail,
+        qcfail_action: opt.qcfail_action,
+        restore_orientation: opt.restore_original_orientation,
+        revert_to_ubam: opt.revert_to_ubam,
+        strand_annotation,
+        qname_umi,
+        strict: opt.strict,
+        max_errors: opt.max_errors,
+        retries: opt.retries,
+        retry_delay: opt.retry_delay,
+        sanitize: opt.sanitize,
+        reproducible: opt.reproducible,
+        pg_id: opt.pg_id,
+        pg_program_name: Some(opt.pg_program_name),
+        preserve_header: opt.preserve_header,
+        preflight: opt.preflight,
+        expected_sort_order: opt.expected_sort_order,
+        progress_fd: opt.progress_fd,
+        profile: opt.profile,
+        required_tags,
+        reference: opt.reference,
+        reference_index: opt.reference_index,
+        output_format: opt.output_format,
+        cram_lossy_names: opt.cram_lossy_names,
+        cram_quality_bins: opt.cram_qual_bins,
+        tmpdir: opt.tmpdir,
+        tee,
+        write_md5: opt.write_md5,
+        fsync: opt.fsync,
+        barcode_fastq: opt.barcode_fastq,
+        barcode_tag,
+        barcode_quality_tag,
+        whitelist: opt.whitelist,
+        whitelist_tag,
+        auto_orient: opt.auto_orient.then_some(opt.auto_orient_records),
+        audit_tsv: opt.audit_tsv,
+        contig_tsv: opt.contig_tsv,
+        #[cfg(feature = "plugins")]
+        plugins: opt.plugin,
+        #[cfg(feature = "scripting")]
+        script: opt.script,
+        ..Options::default()
+    };
+
+    if opt.print_config {
+        print!(
+            "{}",
+            effective_config(input.as_deref(), output.as_deref(), &plan, &options)
+        );
+        process::exit(0)
+    }
+
+    if let Some(manifest) = &opt.manifest {
+        #[cfg(feature = "htslib")]
+        if opt.dry_run {
+            exit_with(
+                anyhow!("--dry-run cannot be combined with --manifest"),
+                EXIT_USAGE,
+            )
+        }
+        let entries = match read_manifest(manifest) {
+            Ok(entries) => entries,
+            Err(e) => exit_with(anyhow!("{e}").context("Invalid --manifest"), EXIT_USAGE),
+        };
+        if !opt.force_overwrite {
+            let outputs = entries.iter().map(|entry| &entry.output);
+            #[cfg(feature = "htslib")]
+            let outputs = outputs.chain(&opt.merge_output);
+            refuse_existing_output(outputs);
+        }
+        let summary = run_batch(&entries, opt.jobs.unwrap_or(1), |entry| {
+            run(Some(&entry.input), Some(&entry.output), &plan, &options).map(|_| ())
+        });
+        report_peak_memory(options.max_memory);
+        if !summary.is_success() {
+            exit_with(anyhow!("{summary}"), EXIT_FAILURE)
+        }
+        log::info!("{summary}");
+        #[cfg(feature = "htslib")]
+        if let Some(merged) = &opt.merge_output {
+            let shards: Vec<_> = entries.iter().map(|entry| entry.output.clone()).collect();
+            if let Err(e) = merge_shards(&shards, merged, &options) {
+                exit_with(
+                    anyhow!("{e}").context(format!("Failed to merge into {}", merged.display())),
+                    EXIT_FAILURE,
+                )
+            }
+        }
+        process::exit(0)
+    }
+
+    let source = match &input {
+        Some(path) => format!("{}", path.display()),
+        None => "stdin".to_string(),
+    };
+
+    #[cfg(feature = "htslib")]
+    if opt.dry_run {
+        match dry_run(input.as_deref(), &plan, &options) {
+            Ok(report) => {
+                print!("{report}");
+                process::exit(0)
+            }
+            Err(error) => exit_with_run_error(error, &source),
+        }
+    }
+
+    if !opt.force_overwrite {
+        refuse_existing_output(
+            output
+                .iter()
+                .chain(&options.tee)
+                .chain(&options.barcode_fastq)
+                .chain(&options.audit_tsv)
+                .chain(&options.contig_tsv),
+        );
+    }
+
+    match run(input.as_deref(), output.as_deref(), &plan, &options) {
+        Ok(exit_code) => {
+            report_peak_memory(options.max_memory);
+            process::exit(exit_code)
+        }
+        Err(error) => exit_with_run_error(error, &source),
+    }
+}
+
+/// Returns the first of `paths` that is an existing regular file, which the run would overwrite.
+///
+/// FIFOs, devices, and paths under /dev, such as /dev/stdout or /dev/fd/63 from process
+/// substitution, are streams the caller set up to be written, so they are never refused.
+fn existing_output<'a>(paths: impl IntoIterator<Item = &'a PathBuf>) -> Option<&'a Path> {
+    paths
+        .into_iter()
+        .map(PathBuf::as_path)
+        .filter(|path| !path.starts_with("/dev"))
+        .find(|path| fs::metadata(path).is_ok_and(|metadata| metadata.is_file()))
+}
+
+/// Exits with a usage error if any of `paths` is an existing output file, per [`existing_output`].
+fn refuse_existing_output<'a>(paths: impl IntoIterator<Item = &'a PathBuf>) {
+    if let Some(path) = existing_output(paths) {
+        exit_with(
+            anyhow!(
+                "Output {} already exists; pass --force-overwrite to replace it",
+                path.display()
+            ),
+            EXIT_USAGE,
+        )
+    }
+}
 
-    match revtag(input, output, opt.rev, opt.revcomp, opt.threads) {
-        Ok(exit_code) => process::exit(exit_code),
-        Err(except) => panic!("{}", except),
+/// Logs the peak resident memory of the run, warning if it exceeded `--max-memory`.
+fn report_peak_memory(max_memory: Option<usize>) {
+    let Some(peak) = peak_rss() else {
+        return;
+    };
+    log::info!("Peak memory: {}", format_mib(peak));
+    if max_memory.is_some_and(|max_memory| peak > max_memory as u64) {
+        log::warn!(
+            "Peak memory exceeded --max-memory; buffers inside htslib, such as those for \
+             compression threads, are not bounded by it"
+        );
     }
 }
@@ -1,6 +1,7 @@
 #[cfg(test)]
 mod tests {
     use assert_cmd::cmd::Command;
+    use predicates::prelude::*;
     use std::fs;
     use std::path::Path;
     use tempfile::NamedTempFile;
@@ -32,6 +33,7 @@ mod tests {
             .arg("tests/input.sam")
             .arg("--output")
             .arg(&output_path)
+            .arg("--force-overwrite")
             .arg("--rev")
             .arg("QT")
             .assert()
@@ -64,6 +66,7 @@ mod tests {
             .arg("tests/input.sam")
             .arg("--output")
             .arg(&output_path)
+            .arg("--force-overwrite")
             .arg("--rev")
             .arg("BC")
             .assert()
@@ -95,6 +98,7 @@ mod tests {
             .arg("tests/input.sam")
             .arg("--output")
             .arg(&output_path)
+            .arg("--force-overwrite")
             .arg("--revcomp")
             .arg("BC")
             .assert()
@@ -126,6 +130,7 @@ mod tests {
             .arg("tests/input.sam")
             .arg("--output")
             .arg(&output_path)
+            .arg("--force-overwrite")
             .arg("--rev")
             .arg("QT")
             .arg("--rev")
@@ -144,6 +149,2102 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_tag_specs() -> Result<(), Box<dyn std::error::Error>> {
+        let output = NamedTempFile::new().expect("Cannot create temporary file!");
+        let output_path = output.path();
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--output")
+            .arg(&output_path)
+            .arg("--force-overwrite")
+            .arg("--tag")
+            .arg("QT:rev")
+            .arg("--tag")
+            .arg("BC:rev")
+            .assert()
+            .success();
+
+        let content = fs::read_to_string(output_path)?;
+        let lines: Vec<&str> = content.lines().collect();
+        let read2_line = lines.iter().find(|l| l.starts_with("read2\t")).unwrap();
+        assert_eq!(get_tag_value(read2_line, "QT").unwrap(), "QT:Z:EFGH");
+        assert_eq!(get_tag_value(read2_line, "BC").unwrap(), "BC:Z:TAGG");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reproducible_output() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let run = |input: &Path, output: &Path| -> Result<(), Box<dyn std::error::Error>> {
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--input")
+                .arg(input)
+                .arg("--output")
+                .arg(output)
+                .arg("--force-overwrite")
+                .arg("--rev")
+                .arg("QT")
+                .arg("--reproducible")
+                .assert()
+                .success();
+            Ok(())
+        };
+
+        let first = tmpdir.path().join("first.bam");
+        let second = tmpdir.path().join("second.bam");
+        run(Path::new("tests/input.sam"), &first)?;
+        fs::copy(&first, &second)?;
+        run(Path::new("tests/input.sam"), &first)?;
+        assert_eq!(fs::read(&first)?, fs::read(&second)?);
+
+        let rerun = tmpdir.path().join("rerun.sam");
+        run(&first, &rerun)?;
+        let content = fs::read_to_string(&rerun)?;
+        let ids: Vec<&str> = content
+            .lines()
+            .filter(|l| l.starts_with("@PG"))
+            .filter_map(|l| l.split('\t').find(|field| field.starts_with("ID:")))
+            .collect();
+        assert_eq!(ids, vec!["ID:bwa", "ID:revtag", "ID:revtag.1"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_json() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--version-json")
+            .assert()
+            .success()
+            .stdout(predicates::str::contains(format!(
+                "\"version\": \"{}\"",
+                env!("CARGO_PKG_VERSION")
+            )))
+            .stdout(predicates::str::contains("\"git_hash\": "))
+            .stdout(predicates::str::contains("\"htslib_version\": \""))
+            .stdout(predicates::str::contains("\"target\": \""));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_print_config() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/does-not-exist.sam")
+            .arg("--rev")
+            .arg("QT")
+            .arg("--tag")
+            .arg("BC:revcomp:strand=mate")
+            .arg("--threads")
+            .arg("2")
+            .arg("--print-config")
+            .assert()
+            .success()
+            .stdout(predicates::str::contains(
+                "\"input\": \"tests/does-not-exist.sam\"",
+            ))
+            .stdout(predicates::str::contains("\"output\": \"-\""))
+            .stdout(predicates::str::contains("\"operation\": \"revcomp\""))
+            .stdout(predicates::str::contains("\"strand\": \"mate\""))
+            .stdout(predicates::str::contains("\"threads\": 2,"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preset_oq() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             read1\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tOQ:Z:ABCG\n",
+        )?;
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--preset")
+            .arg("oq")
+            .assert()
+            .success();
+
+        let content = fs::read_to_string(&output)?;
+        let line = content.lines().find(|l| l.starts_with("read1\t")).unwrap();
+        assert_eq!(get_tag_value(line, "OQ").unwrap(), "OQ:Z:GCBA");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_matrix() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             read1\t16\tchr1\t1\t60\t3M\t*\t0\t0\tACG\tFFF\tSG:B:S,1,2,3,4,5,6\n",
+        )?;
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--matrix")
+            .arg("SG:3x2")
+            .assert()
+            .success();
+
+        let content = fs::read_to_string(&output)?;
+        let line = content.lines().find(|l| l.starts_with("read1\t")).unwrap();
+        assert_eq!(get_tag_value(line, "SG").unwrap(), "SG:B:S,5,6,3,4,1,2");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preset_bismark() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        let header = "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n";
+        let record = "read1\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tXM:Z:z..Z\tXR:Z:CT";
+        let run = |xg: &str| -> Result<_, Box<dyn std::error::Error>> {
+            fs::write(&input, format!("{header}{record}\tXG:Z:{xg}\n"))?;
+            Ok(Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg(&output)
+                .arg("--force-overwrite")
+                .arg("--preset")
+                .arg("bismark")
+                .assert())
+        };
+
+        run("GA")?.success();
+        let content = fs::read_to_string(&output)?;
+        let line = content.lines().find(|l| l.starts_with("read1\t")).unwrap();
+        assert_eq!(get_tag_value(line, "XM").unwrap(), "XM:Z:Z..z");
+        assert_eq!(get_tag_value(line, "XG").unwrap(), "XG:Z:GA");
+
+        run("AG")?
+            .failure()
+            .stderr(predicates::str::contains("expected one of CT, GA"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_tag_spec() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--tag")
+            .arg("QT:flip")
+            .assert()
+            .code(2)
+            .stderr(predicates::str::contains("Invalid tag arguments"))
+            .stderr(predicates::str::contains("Unknown operation"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_arguments_are_underlined() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--tag")
+            .arg("QT:flip")
+            .assert()
+            .code(2)
+            .stderr(predicates::str::contains("    QT:flip\n       ^^^^\n"))
+            .stderr(predicates::str::contains("\x1b[").not());
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--if")
+            .arg("mapq ~ 20")
+            .assert()
+            .code(2)
+            .stderr(predicates::str::contains("    mapq ~ 20\n         ^\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_strand_source() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--tag")
+            .arg("QT:rev:strand=both")
+            .assert()
+            .code(2)
+            .stderr(predicates::str::contains("Unknown strand source"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_input_reports_cleanly() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/does-not-exist.sam")
+            .arg("--rev")
+            .arg("QT")
+            .assert()
+            .code(1)
+            .stderr(predicates::str::contains(
+                "Failed to process tests/does-not-exist.sam",
+            ))
+            .stderr(predicates::str::contains("panicked").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_reports_missing_tag() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--rev")
+            .arg("Qt")
+            .arg("--strict")
+            .assert()
+            .code(1)
+            .stderr(predicates::str::contains(
+                "Record read2 is missing required tag Qt",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_errors() -> Result<(), Box<dyn std::error::Error>> {
+        let sam = concat!(
+            "@HD\tVN:1.6\tSO:unknown\n",
+            "@SQ\tSN:chr1\tLN:1000\n",
+            "a\t16\tchr1\t5\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n",
+            "b\t16\tchr1\t6\t60\t4M\t*\t0\t0\tACGT\tFFFF\n",
+            "c\t16\tchr1\t7\t60\t4M\t*\t0\t0\tACGT\tFFFF\n",
+        );
+        let run = |max_errors: &str| -> Result<_, Box<dyn std::error::Error>> {
+            Ok(Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--rev")
+                .arg("QT")
+                .arg("--strict")
+                .arg("--max-errors")
+                .arg(max_errors)
+                .write_stdin(sam)
+                .assert())
+        };
+
+        let output = run("2")?
+            .success()
+            .stderr(predicate::str::contains(
+                "Skipping read b at chr1:6 after error 1 of at most 2",
+            ))
+            .stderr(predicate::str::contains(
+                "Skipped 2 records that failed to transform",
+            ))
+            .get_output()
+            .stdout
+            .clone();
+        let output = String::from_utf8(output)?;
+        assert!(output.contains("QT:Z:DCBA"));
+        assert!(!output.contains("\nb\t"));
+        assert!(!output.contains("\nc\t"));
+
+        run("1")?.failure().stderr(predicate::str::contains(
+            "more than the 1 allowed; the last was read c at chr1:7",
+        ));
+        run("0")?.failure().stderr(predicate::str::contains(
+            "Record b is missing required tag QT",
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_require_tags() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--output")
+            .arg(NamedTempFile::new()?.path())
+            .arg("--force-overwrite")
+            .arg("--require-tags")
+            .arg("RG,NM")
+            .assert()
+            .success();
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--require-tags")
+            .arg("NM,BC")
+            .assert()
+            .code(1)
+            .stderr(predicates::str::contains(
+                "Record read3 is missing required tag BC",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unmapped_policy() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             read1\t20\tchr1\t1\t0\t*\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n",
+        )?;
+        let run = |policy: &str| -> Result<_, Box<dyn std::error::Error>> {
+            Ok(Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg(&output)
+                .arg("--force-overwrite")
+                .arg("--rev")
+                .arg("QT")
+                .arg("--unmapped-policy")
+                .arg(policy)
+                .assert())
+        };
+
+        run("transform")?.success();
+        assert!(fs::read_to_string(&output)?.contains("QT:Z:DCBA"));
+        run("skip")?.success();
+        assert!(fs::read_to_string(&output)?.contains("QT:Z:ABCD"));
+        run("error")?
+            .code(1)
+            .stderr(predicates::str::contains("unmapped and reverse strand"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_missing_seq() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             read1\t256\tchr1\t1\t0\t4M\t*\t0\t0\t*\t*\tQT:Z:ABCD\tSA:Z:chr1,100,-,4M,60,0;\n\
+             read2\t272\tchr1\t1\t0\t4M\t*\t0\t0\t*\t*\tQT:Z:ABCD\tSA:Z:chr1,100,+,4M,60,0;\n",
+        )?;
+        let run = |policy: &str| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg(&output)
+                .arg("--force-overwrite")
+                .arg("--rev")
+                .arg("QT")
+                .arg("--on-missing-seq")
+                .arg(policy)
+                .assert()
+                .success();
+            Ok(fs::read_to_string(&output)?
+                .lines()
+                .filter(|line| !line.starts_with('@'))
+                .map(|line| get_tag_value(line, "QT").unwrap())
+                .collect())
+        };
+
+        assert_eq!(run("transform")?, vec!["QT:Z:ABCD", "QT:Z:DCBA"]);
+        assert_eq!(run("skip")?, vec!["QT:Z:ABCD", "QT:Z:ABCD"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_annotate_strand() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             read1\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n\
+             read2\t32\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\n\
+             read3\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n",
+        )?;
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--rev")
+            .arg("QT")
+            .arg("--tag")
+            .arg("BC:revcomp:strand=mate")
+            .arg("--annotate-strand")
+            .assert()
+            .success();
+
+        let annotations: Vec<Option<String>> = fs::read_to_string(&output)?
+            .lines()
+            .filter(|line| !line.starts_with('@'))
+            .map(|line| get_tag_value(line, "os"))
+            .collect();
+        assert_eq!(
+            annotations,
+            vec![Some("os:A:-".to_string()), Some("os:A:+".to_string()), None]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fix_qname_umi() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             read1_AAACCG\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n\
+             read2_AAACCG\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n",
+        )?;
+        let run = |args: &[&str]| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg(&output)
+                .arg("--force-overwrite")
+                .arg("--rev")
+                .arg("QT")
+                .args(args)
+                .assert()
+                .success();
+            Ok(fs::read_to_string(&output)?
+                .lines()
+                .filter(|line| !line.starts_with('@'))
+                .map(|line| line.split('\t').next().unwrap().to_string())
+                .collect())
+        };
+
+        assert_eq!(
+            run(&["--fix-qname-umi"])?,
+            vec!["read1_CGGTTT", "read2_AAACCG"]
+        );
+        assert_eq!(
+            run(&["--qname-umi-regex", "_(?P<umi>AAA)"])?,
+            vec!["read1_TTTCCG", "read2_AAACCG"]
+        );
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--rev")
+            .arg("QT")
+            .arg("--qname-umi-regex")
+            .arg("_[ACGT]+$")
+            .assert()
+            .code(2)
+            .stderr(predicates::str::contains("Invalid --qname-umi-regex"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_header() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        let header = "@SQ\tSN:chr1\tLN:1000\tM5:0123456789abcdef0123456789abcdef\n\
+                      @HD\tVN:1.6\tSO:unsorted\n\
+                      @CO\tfree text:\twith a tab\n";
+        fs::write(
+            &input,
+            format!("{header}read1\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n"),
+        )?;
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--rev")
+            .arg("QT")
+            .arg("--preserve-header")
+            .assert()
+            .success();
+
+        let contents = fs::read_to_string(&output)?;
+        assert!(contents.starts_with(header));
+        let rest = &contents[header.len()..];
+        assert!(rest.starts_with("@PG\tID:revtag\t"));
+        let records: Vec<&str> = rest.lines().skip(1).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            get_tag_value(records[0], "QT"),
+            Some("QT:Z:DCBA".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pg_id_and_program_name() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n@PG\tID:bwa\tPN:bwa\n",
+        )?;
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--rev")
+            .arg("QT")
+            .arg("--pg-id")
+            .arg("step3.v2")
+            .arg("--pg-program-name")
+            .arg("tag-fixer")
+            .assert()
+            .success();
+        let contents = fs::read_to_string(&output)?;
+        assert!(contents.contains("@PG\tID:step3.v2\tPN:tag-fixer\t"));
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--force-overwrite")
+            .arg("--rev")
+            .arg("QT")
+            .arg("--pg-id")
+            .arg("bwa")
+            .assert()
+            .code(1)
+            .stderr(predicates::str::contains(
+                "The @PG ID bwa is already in the input header",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_chain() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             read1\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tXB:Z:AACG\n\
+             read2\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tXB:Z:AACG\n",
+        )?;
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--tag")
+            .arg("XB:comp,rev,rename=BC")
+            .assert()
+            .success();
+
+        let lines: Vec<String> = fs::read_to_string(&output)?
+            .lines()
+            .filter(|line| !line.starts_with('@'))
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            get_tag_value(&lines[0], "BC"),
+            Some("BC:Z:CGTT".to_string())
+        );
+        assert_eq!(get_tag_value(&lines[0], "XB"), None);
+        assert_eq!(
+            get_tag_value(&lines[1], "XB"),
+            Some("XB:Z:AACG".to_string())
+        );
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--force-overwrite")
+            .arg("--tag")
+            .arg("XB:rename=BC,rev")
+            .assert()
+            .failure()
+            .code(2)
+            .stderr(predicate::str::contains("Cannot chain"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_qcfail() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             pass\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n\
+             fail\t528\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n",
+        )?;
+        let run = |args: &[&str]| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg(&output)
+                .arg("--force-overwrite")
+                .arg("--rev")
+                .arg("QT")
+                .args(args)
+                .assert()
+                .success();
+            Ok(fs::read_to_string(&output)?
+                .lines()
+                .filter(|line| !line.starts_with('@'))
+                .map(|line| {
+                    let name = line.split('\t').next().unwrap_or_default();
+                    format!("{name} {}", get_tag_value(line, "QT").unwrap_or_default())
+                })
+                .collect())
+        };
+
+        assert_eq!(
+            run(&["--exclude-qcfail"])?,
+            vec!["pass QT:Z:DCBA", "fail QT:Z:ABCD"]
+        );
+        assert_eq!(
+            run(&["--only-qcfail"])?,
+            vec!["pass QT:Z:ABCD", "fail QT:Z:DCBA"]
+        );
+        assert_eq!(
+            run(&["--exclude-qcfail", "--qcfail-action", "drop"])?,
+            vec!["pass QT:Z:DCBA"]
+        );
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--force-overwrite")
+            .arg("--qcfail-action")
+            .arg("drop")
+            .assert()
+            .failure()
+            .code(2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cram_lossy_output() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let reference = tmpdir.path().join("ref.fa");
+        let input = tmpdir.path().join("in.sam");
+        let cram = tmpdir.path().join("out.cram");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(&reference, format!(">chr1\n{}\n", "ACGT".repeat(250)))?;
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             original_read_name\t16\tchr1\t1\t60\t5M\t*\t0\t0\tACGTA\t#+5?I\tQT:Z:ABCDE\n",
+        )?;
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&cram)
+            .arg("--reference")
+            .arg(&reference)
+            .arg("--rev")
+            .arg("QT")
+            .arg("--cram-lossy-names")
+            .arg("--cram-qual-bins")
+            .arg("illumina")
+            .assert()
+            .success();
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&cram)
+            .arg("--output")
+            .arg(&output)
+            .arg("--reference")
+            .arg(&reference)
+            .assert()
+            .success();
+
+        let sam = fs::read_to_string(&output)?;
+        let fields: Vec<&str> = sam
+            .lines()
+            .find(|line| !line.starts_with('@'))
+            .unwrap_or_default()
+            .split('\t')
+            .collect();
+        assert_ne!(fields[0], "original_read_name");
+        assert_eq!(fields[10], "'07BI");
+        assert!(sam.contains("QT:Z:EDCBA"));
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--force-overwrite")
+            .arg("--cram-qual-bins")
+            .arg("illumina")
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("need a CRAM output"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_tsv() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        let audit = tmpdir.path().join("audit.tsv");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             fwd\t0\tchr1\t5\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\tBC:Z:AACG\n\
+             rev\t16\tchr1\t9\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\tBC:Z:AACG\n\
+             pal\t16\tchr1\t12\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:AA\tBC:Z:ACGT\n",
+        )?;
+        for threads in ["1", "2"] {
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg(&output)
+                .arg("--force-overwrite")
+                .arg("--threads")
+                .arg(threads)
+                .arg("--rev")
+                .arg("QT")
+                .arg("--revcomp")
+                .arg("BC")
+                .arg("--audit-tsv")
+                .arg(&audit)
+                .assert()
+                .success();
+
+            assert_eq!(
+                fs::read_to_string(&audit)?,
+                "qname\tcontig\tposition\tflag\ttags\n\
+                 rev\tchr1\t9\t16\tQT,BC\n\
+                 pal\tchr1\t12\t16\t*\n"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sanitize() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             fwd\t0\tchr1\t5\t60\t2M\t*\t0\t0\tAC\tFF\tOQ:B:s,30,20\n\
+             rev\t16\tchr1\t9\t60\t2M\t*\t0\t0\tAC\tFF\tOQ:B:s,30,20\tXS:B:s,30,20\n\
+             big\t16\tchr1\t12\t60\t2M\t*\t0\t0\tAC\tFF\tOQ:B:s,300,20\n",
+        )?;
+        for threads in ["1", "2"] {
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg(&output)
+                .arg("--force-overwrite")
+                .arg("--threads")
+                .arg(threads)
+                .arg("--rev")
+                .arg("OQ")
+                .arg("--sanitize")
+                .assert()
+                .success()
+                .stderr(predicate::str::contains(
+                    "Repaired 0 strings with trailing NULs, 0 arrays declaring missing elements, \
+                     and 2 quality arrays of the wrong element type",
+                ));
+
+            let sam = fs::read_to_string(&output)?;
+            assert!(sam.contains("fwd\t0\tchr1\t5\t60\t2M\t*\t0\t0\tAC\tFF\tOQ:B:C,30,20\n"));
+            assert!(sam.contains("\tOQ:B:C,20,30\tXS:B:s,30,20\n"));
+            assert!(sam.contains("\tOQ:B:s,20,300\n"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.bam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             rev\t16\tchr1\t9\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\n",
+        )?;
+        for threads in ["1", "2"] {
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg(&output)
+                .arg("--force-overwrite")
+                .arg("--threads")
+                .arg(threads)
+                .arg("--revcomp")
+                .arg("BC")
+                .arg("--profile")
+                .assert()
+                .success()
+                .stderr(predicate::str::is_match(
+                    r"Wall time [0-9.]+s: reading [0-9.]+s \([0-9]+%\), transforming [0-9.]+s \([0-9]+%\), writing [0-9.]+s \([0-9]+%\)",
+                )?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expected_sort_order() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        let records = "b\t16\tchr1\t9\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\n\
+                       a\t16\tchr1\t5\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\n";
+        let run = |order: &str| -> Result<_, Box<dyn std::error::Error>> {
+            let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME"))?;
+            cmd.arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg(&output)
+                .arg("--force-overwrite")
+                .arg("--revcomp")
+                .arg("BC")
+                .arg("--expected-sort-order")
+                .arg(order);
+            Ok(cmd.assert())
+        };
+
+        fs::write(
+            &input,
+            format!("@HD\tVN:1.6\tSO:unsorted\n@SQ\tSN:chr1\tLN:1000\n{records}"),
+        )?;
+        run("queryname")?.success();
+        run("coordinate")?
+            .failure()
+            .stderr(predicate::str::contains(
+                "expected to be sorted by coordinate, but record a follows record b",
+            ));
+
+        fs::write(
+            &input,
+            format!("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n{records}"),
+        )?;
+        run("queryname")?.failure().stderr(predicate::str::contains(
+            "declares SO:coordinate, but the input is expected to be sorted by queryname",
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_orientation_header() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\tSO:coordinate\tGO:reference\n@SQ\tSN:chr1\tLN:1000\n\
+             rev\t16\tchr1\t9\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\n",
+        )?;
+        for preserve in [false, true] {
+            let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME"))?;
+            cmd.arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg(&output)
+                .arg("--force-overwrite")
+                .arg("--revcomp")
+                .arg("BC")
+                .arg("--restore-original-orientation");
+            if preserve {
+                cmd.arg("--preserve-header");
+            }
+            cmd.assert().success();
+
+            let sam = fs::read_to_string(&output)?;
+            assert!(sam.starts_with("@HD\tVN:1.6\tSO:unsorted\tGO:none\n"));
+            assert!(sam.contains("rev\t0\tchr1\t9\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:CGTT"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_file() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        let tags = tmpdir.path().join("tags.txt");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             rev\t16\tchr1\t9\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\tBC:Z:AACG\n",
+        )?;
+        fs::write(&tags, "# tag\toperation\nQT\trev\nBC\trevcomp\n")?;
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--tag-file")
+            .arg(&tags)
+            .assert()
+            .success();
+        let sam = fs::read_to_string(&output)?;
+        assert!(sam.contains("QT:Z:DCBA\tBC:Z:CGTT"));
+
+        fs::write(&tags, "QT rev\n")?;
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--force-overwrite")
+            .arg("--tag-file")
+            .arg(&tags)
+            .assert()
+            .code(2)
+            .stderr(predicate::str::contains("is not TAG<TAB>OPERATION"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pair_fix() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        let audit = tmpdir.path().join("audit.tsv");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\tSO:queryname\n@SQ\tSN:chr1\tLN:1000\n\
+             a\t99\tchr1\t1\t60\t4M\t=\t9\t12\tACGT\tFFFF\tBC:Z:AACG\tRX:Z:GGT\n\
+             a\t147\tchr1\t9\t60\t4M\t=\t1\t-12\tACGT\tFFFF\tBC:Z:NNNN\n\
+             b\t163\tchr1\t3\t60\t4M\t=\t7\t8\tACGT\tFFFF\tBC:Z:TTTT\n\
+             b\t83\tchr1\t7\t60\t4M\t=\t3\t-8\tACGT\tFFFF\tBC:Z:GGCA\n",
+        )?;
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--revcomp")
+            .arg("BC")
+            .arg("--pair-fix")
+            .arg("BC:revcomp")
+            .arg("--pair-fix")
+            .arg("RX")
+            .arg("--audit-tsv")
+            .arg(&audit)
+            .assert()
+            .success();
+
+        let sam = fs::read_to_string(&output)?;
+        assert!(
+            sam.contains("a\t99\tchr1\t1\t60\t4M\t=\t9\t12\tACGT\tFFFF\tBC:Z:AACG\tRX:Z:GGT\n")
+        );
+        assert!(
+            sam.contains("a\t147\tchr1\t9\t60\t4M\t=\t1\t-12\tACGT\tFFFF\tBC:Z:CGTT\tRX:Z:GGT\n")
+        );
+        // The first read of b is transformed by the plan, and its mate takes the value back.
+        assert!(sam.contains("b\t83\tchr1\t7\t60\t4M\t=\t3\t-8\tACGT\tFFFF\tBC:Z:TGCC\n"));
+        assert!(sam.contains("b\t163\tchr1\t3\t60\t4M\t=\t7\t8\tACGT\tFFFF\tBC:Z:GGCA\n"));
+        // Values propagated onto a mate are listed with those its own transform modified.
+        assert_eq!(
+            fs::read_to_string(&audit)?,
+            "qname\tcontig\tposition\tflag\ttags\n\
+             a\tchr1\t9\t147\tBC,RX\n\
+             b\tchr1\t3\t163\tBC\n\
+             b\tchr1\t7\t83\tBC\n"
+        );
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--pair-fix")
+            .arg("BC:rename")
+            .assert()
+            .code(2)
+            .stderr(predicate::str::contains("Unknown operation"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strand_from_mi() -> Result<(), Box<dyn std::error::Error>> {
+        let sam = concat!(
+            "@HD\tVN:1.6\tSO:unknown\n",
+            "@SQ\tSN:chr1\tLN:1000\n",
+            "a\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tMI:Z:7/A\tad:B:s,1,2,3\n",
+            "b\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tMI:Z:7/B\tad:B:s,1,2,3\n",
+            "c\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tMI:Z:8/A\tad:B:s,1,2,3\n",
+        );
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--rev")
+            .arg("ad")
+            .arg("--strand-from-mi")
+            .write_stdin(sam)
+            .output()?;
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout)?;
+        let line = |name: &str| stdout.lines().find(|l| l.starts_with(name)).unwrap();
+        assert_eq!(
+            get_tag_value(line("a\t"), "ad").as_deref(),
+            Some("ad:B:s,1,2,3")
+        );
+        assert_eq!(
+            get_tag_value(line("b\t"), "ad").as_deref(),
+            Some("ad:B:s,3,2,1")
+        );
+        assert_eq!(
+            get_tag_value(line("c\t"), "ad").as_deref(),
+            Some("ad:B:s,1,2,3")
+        );
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--rev")
+            .arg("ad")
+            .arg("--strand-from-mi")
+            .arg("--strand-from-tag")
+            .arg("XS")
+            .write_stdin(sam)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("cannot be used with"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_revert_to_ubam() -> Result<(), Box<dyn std::error::Error>> {
+        let sam = concat!(
+            "@HD\tVN:1.6\tSO:coordinate\n",
+            "@SQ\tSN:chr1\tLN:1000\n",
+            "@RG\tID:A\n",
+            "a\t83\tchr1\t9\t60\t1S3M\t=\t1\t-12\tAACG\tABCD\tNM:i:1\tBC:Z:AACG\tRG:Z:A\n",
+            "a\t2131\tchr1\t20\t60\t4M\t=\t1\t0\tAACG\tABCD\tBC:Z:AACG\n",
+            "a\t163\tchr1\t1\t60\t4M\t=\t9\t12\tACGT\tFFFF\tMC:Z:1S3M\tBC:Z:AACG\n",
+        );
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--revcomp")
+            .arg("BC")
+            .arg("--revert-to-ubam")
+            .write_stdin(sam)
+            .output()?;
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.starts_with("@HD\tVN:1.6\tSO:unsorted\n@RG\tID:A\n@PG"));
+        assert!(!stdout.contains("@SQ"));
+        let records: Vec<&str> = stdout.lines().filter(|l| !l.starts_with('@')).collect();
+        assert_eq!(
+            records,
+            [
+                "a\t77\t*\t0\t0\t*\t*\t0\t0\tCGTT\tDCBA\tBC:Z:CGTT\tRG:Z:A",
+                "a\t141\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_whitelist() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let whitelist = tmpdir.path().join("whitelist.txt");
+        fs::write(&whitelist, "CGTT\nGGGA\n")?;
+        let sam = concat!(
+            "@HD\tVN:1.6\tSO:unknown\n",
+            "@SQ\tSN:chr1\tLN:1000\n",
+            "a\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\n",
+            "b\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:TCCC\n",
+            "c\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\n",
+        );
+        let run = |threads: &str| -> Result<_, Box<dyn std::error::Error>> {
+            Ok(Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--revcomp")
+                .arg("BC")
+                .arg("--whitelist")
+                .arg(&whitelist)
+                .arg("--threads")
+                .arg(threads)
+                .write_stdin(sam)
+                .assert())
+        };
+        for threads in ["1", "2"] {
+            run(threads)?.success().stderr(predicate::str::contains(
+                "Whitelist matches of BC on 2 reverse strand records: 0 (0.0%) before the \
+                 transform, 2 (100.0%) after",
+            ));
+        }
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--rev")
+            .arg("BC")
+            .arg("--whitelist")
+            .arg(&whitelist)
+            .write_stdin(sam.replace("AACG", "CGTT"))
+            .assert()
+            .success()
+            .stderr(predicate::str::contains(
+                "Fewer reverse strand barcodes match",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_orient() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            concat!(
+                "@HD\tVN:1.6\tSO:unknown\n",
+                "@SQ\tSN:chr1\tLN:1000\n",
+                "a\t0\tchr1\t1\t60\t6M\t*\t0\t0\tACGTAC\t*\tXQ:Z:IIH%##\n",
+                "b\t16\tchr1\t1\t60\t6M\t*\t0\t0\tACGTAC\t*\tXQ:Z:IIG$##\n",
+                "c\t0\tchr1\t1\t60\t6M\t*\t0\t0\tACGTAC\t*\tXQ:Z:IHI###\n",
+            ),
+        )?;
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--auto-orient")
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("XQ:rev from trend along the read"));
+        let content = fs::read_to_string(&output)?;
+        assert!(content.contains("XQ:Z:IIH%##"));
+        assert!(content.contains("XQ:Z:##$GII"));
+
+        // Stdin is read twice through a copy in a scratch directory under --tmpdir, which is
+        // removed when the run ends.
+        let scratch = tmpdir.path().join("scratch");
+        fs::create_dir(&scratch)?;
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--auto-orient")
+            .arg("--tmpdir")
+            .arg(&scratch)
+            .write_stdin(fs::read_to_string(&input)?)
+            .output()?;
+        assert!(output.status.success());
+        assert!(String::from_utf8(output.stdout)?.contains("XQ:Z:##$GII"));
+        assert_eq!(fs::read_dir(&scratch)?.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_filter() -> Result<(), Box<dyn std::error::Error>> {
+        let sam = concat!(
+            "@HD\tVN:1.6\tSO:unknown\n",
+            "@SQ\tSN:chr1\tLN:1000\n",
+            "a\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tRG:Z:lane1.A\tNM:i:1\tQT:Z:ABCD\n",
+            "b\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tRG:Z:lane2.A\tNM:i:1\tQT:Z:EFGH\n",
+            "c\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tRG:Z:lane1.B\tNM:i:3\tQT:Z:IJKL\n",
+            "d\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:MNOP\n",
+        );
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--rev")
+            .arg("QT")
+            .arg("--tag-filter")
+            .arg("RG=~^lane1\\.")
+            .arg("--tag-filter")
+            .arg("NM<=2")
+            .write_stdin(sam)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let output = String::from_utf8(output)?;
+        assert!(output.contains("QT:Z:DCBA"));
+        assert!(output.contains("QT:Z:EFGH"));
+        assert!(output.contains("QT:Z:IJKL"));
+        assert!(output.contains("QT:Z:MNOP"));
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--rev")
+            .arg("QT")
+            .arg("--tag-filter")
+            .arg("RG=~(")
+            .write_stdin(sam)
+            .assert()
+            .code(2)
+            .stderr(predicate::str::contains("Invalid --tag-filter"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_contig_tsv() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let contigs = tmpdir.path().join("contigs.tsv");
+        let sam = concat!(
+            "@HD\tVN:1.6\tSO:unknown\n",
+            "@SQ\tSN:chr1\tLN:1000\n",
+            "@SQ\tSN:chr2\tLN:1000\n",
+            "@SQ\tSN:chr3\tLN:1000\n",
+            "a\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n",
+            "b\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n",
+            "c\t16\tchr2\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\n",
+            "d\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF\n",
+        );
+        for threads in ["1", "2"] {
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--rev")
+                .arg("QT")
+                .arg("--contig-tsv")
+                .arg(&contigs)
+                .arg("--threads")
+                .arg(threads)
+                .write_stdin(sam)
+                .assert()
+                .success();
+            assert_eq!(
+                fs::read_to_string(&contigs)?,
+                "contig\trecords\treverse\ttransformed\n\
+                 chr1\t2\t1\t1\n\
+                 chr2\t1\t1\t1\n\
+                 chr3\t0\t0\t0\n\
+                 *\t1\t0\t0\n"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retries() -> Result<(), Box<dyn std::error::Error>> {
+        // Nothing listens on the discard port, so every attempt to open the input fails.
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("-i")
+            .arg("http://127.0.0.1:9/in.bam")
+            .arg("--rev")
+            .arg("QT")
+            .arg("--retries")
+            .arg("2")
+            .arg("--retry-delay")
+            .arg("0")
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "Opening http://127.0.0.1:9/in.bam failed",
+            ))
+            .stderr(predicate::str::contains("(2 of 2)"));
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--rev")
+            .arg("QT")
+            .arg("--retries")
+            .arg("3")
+            .write_stdin("@HD\tVN:1.6\n")
+            .assert()
+            .success();
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--rev")
+            .arg("QT")
+            .arg("--retry-delay")
+            .arg("-1")
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("expected a number of seconds"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_output() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let header = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n";
+        let shards = [
+            (
+                "a",
+                "a1\t16\tchr1\t5\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n\
+                 a2\t0\tchr2\t3\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n",
+            ),
+            (
+                "b",
+                "b1\t16\tchr1\t9\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n",
+            ),
+        ];
+        let mut lines = String::new();
+        for (name, records) in shards {
+            let input = tmpdir.path().join(format!("{name}.sam"));
+            fs::write(&input, format!("{header}{records}"))?;
+            let output = tmpdir.path().join(format!("{name}.out.sam"));
+            lines.push_str(&format!("{}\t{}\n", input.display(), output.display()));
+        }
+        let manifest = tmpdir.path().join("manifest.tsv");
+        fs::write(&manifest, &lines)?;
+        let merged = tmpdir.path().join("merged.sam");
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--manifest")
+            .arg(&manifest)
+            .arg("--rev")
+            .arg("QT")
+            .arg("--merge-output")
+            .arg(&merged)
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("Merged 3 records from 2 shards"));
+
+        let text = fs::read_to_string(&merged)?;
+        let names: Vec<_> = text
+            .lines()
+            .filter(|line| !line.starts_with('@'))
+            .map(|line| line.split('\t').next().unwrap())
+            .collect();
+        assert_eq!(names, ["a1", "b1", "a2"]);
+        assert_eq!(text.matches("QT:Z:DCBA").count(), 2);
+        assert!(text.starts_with("@HD\tVN:1.6\tSO:coordinate"));
+
+        for name in ["a", "b"] {
+            fs::remove_file(tmpdir.path().join(format!("{name}.out.sam")))?;
+        }
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--manifest")
+            .arg(&manifest)
+            .arg("--rev")
+            .arg("QT")
+            .arg("--merge-output")
+            .arg(&merged)
+            .assert()
+            .code(2)
+            .stderr(predicate::str::contains("merged.sam already exists"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_rejects_shared_tables() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let manifest = tmpdir.path().join("manifest.tsv");
+        fs::write(&manifest, "a.sam\ta.out.sam\nb.sam\tb.out.sam\n")?;
+
+        // Every entry would write the same table, so the tables are refused with a manifest.
+        for flag in ["--audit-tsv", "--contig-tsv"] {
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--manifest")
+                .arg(&manifest)
+                .arg("--rev")
+                .arg("QT")
+                .arg(flag)
+                .arg(tmpdir.path().join("table.tsv"))
+                .assert()
+                .failure()
+                .stderr(predicate::str::contains("cannot be used with"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_output_write_md5() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let mut lines = String::new();
+        for name in ["a", "b"] {
+            let input = tmpdir.path().join(format!("{name}.sam"));
+            fs::write(
+                &input,
+                format!(
+                    "@HD\tVN:1.6\tSO:unsorted\n@SQ\tSN:chr1\tLN:1000\n\
+                     {name}1\t16\tchr1\t5\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n"
+                ),
+            )?;
+            let output = tmpdir.path().join(format!("{name}.out.sam"));
+            lines.push_str(&format!("{}\t{}\n", input.display(), output.display()));
+        }
+        let manifest = tmpdir.path().join("manifest.tsv");
+        fs::write(&manifest, &lines)?;
+        let merged = tmpdir.path().join("merged.sam");
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--manifest")
+            .arg(&manifest)
+            .arg("--rev")
+            .arg("QT")
+            .arg("--merge-output")
+            .arg(&merged)
+            .arg("--write-md5")
+            .arg("--fsync")
+            .assert()
+            .success();
+
+        let sidecar = fs::read_to_string(tmpdir.path().join("merged.sam.md5"))?;
+        let (digest, file) = sidecar.trim_end().split_once("  ").unwrap();
+        assert_eq!(file, "merged.sam");
+        assert_eq!(digest.len(), 32);
+        assert!(fs::read_to_string(&merged)?.contains("b1\t16"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_conversion() -> Result<(), Box<dyn std::error::Error>> {
+        let sam = concat!(
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n",
+            "read1\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tXB:Z:AACG\tNM:i:0\n",
+            "read2\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tXB:Z:AACG\tNM:i:0\n",
+        );
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--tag")
+            .arg("XB:revcomp,as=B:C")
+            .write_stdin(sam)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let output = String::from_utf8(output)?;
+        let lines: Vec<&str> = output
+            .lines()
+            .filter(|line| !line.starts_with('@'))
+            .collect();
+        assert!(lines[0].ends_with("\tXB:B:C,67,71,84,84\tNM:i:0"));
+        assert!(lines[1].ends_with("\tXB:Z:AACG\tNM:i:0"));
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--tag")
+            .arg("XB:as=Z,as=B:C")
+            .write_stdin(sam)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("the tag is already converted"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_duplicate_tag() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.sam");
+        let output = tmpdir.path().join("out.sam");
+        fs::write(
+            &input,
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             read1\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABC\tQT:Z:DEF\n",
+        )?;
+        let run = |policy: &str| -> Result<_, Box<dyn std::error::Error>> {
+            Ok(Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg(&output)
+                .arg("--force-overwrite")
+                .arg("--rev")
+                .arg("QT")
+                .arg("--on-duplicate-tag")
+                .arg(policy)
+                .assert())
+        };
+
+        run("all")?.success();
+        let content = fs::read_to_string(&output)?;
+        assert!(content.contains("QT:Z:CBA\tQT:Z:FED"));
+
+        run("error")?
+            .code(1)
+            .stderr(predicates::str::contains("Tag QT occurs more than once"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_argsfile() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let argsfile = tmpdir.path().join("args.txt");
+        fs::write(&argsfile, "# barcodes\n--tag\nBC:flip\n")?;
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg(format!("@{}", argsfile.display()))
+            .assert()
+            .code(2)
+            .stderr(predicates::str::contains("Unknown operation"));
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg(format!("@{}", tmpdir.path().join("missing.txt").display()))
+            .assert()
+            .code(2)
+            .stderr(predicates::str::contains("Failed to read arguments file"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_json() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let errors = tmpdir.path().join("errors.json");
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--require-tags")
+            .arg("NM,BC")
+            .arg("--error-json")
+            .arg(&errors)
+            .assert()
+            .code(1);
+        let report = fs::read_to_string(&errors)?;
+        assert!(report.contains("\"category\": \"record\""));
+        assert!(report.contains("\"qname\": \"read3\""));
+        assert!(report.contains("\"exit_code\": 1"));
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--tag")
+            .arg("QT:flip")
+            .arg("--error-json")
+            .arg(&errors)
+            .assert()
+            .code(2);
+        let report = fs::read_to_string(&errors)?;
+        assert!(report.contains("\"category\": \"usage\""));
+        assert!(report.contains("\"record\": null"));
+
+        // Record errors keep their record when raised on the transform thread.
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--require-tags")
+            .arg("NM,BC")
+            .arg("--threads")
+            .arg("2")
+            .arg("--error-json")
+            .arg(&errors)
+            .assert()
+            .code(1);
+        let report = fs::read_to_string(&errors)?;
+        assert!(report.contains("\"category\": \"record\""));
+        assert!(report.contains("\"qname\": \"read3\""));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_revcomp_nonsequence_error() -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--revcomp")
+            .arg("s2")
+            .arg("--on-revcomp-nonsequence")
+            .arg("error")
+            .assert()
+            .code(1)
+            .stderr(predicates::str::contains(
+                "Tag s2 holds a value of type f that is not a sequence",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strand_from_tag() -> Result<(), Box<dyn std::error::Error>> {
+        let sam = concat!(
+            "@HD\tVN:1.6\tSO:unknown\n",
+            "minus\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF\tXS:A:-\tQT:Z:ABCD\n",
+            "plus\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF\tXS:A:+\tQT:Z:ABCD\n",
+        );
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--rev")
+            .arg("QT")
+            .arg("--strand-from-tag")
+            .arg("XS")
+            .write_stdin(sam)
+            .output()?;
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout)?;
+        let minus = stdout.lines().find(|l| l.starts_with("minus")).unwrap();
+        let plus = stdout.lines().find(|l| l.starts_with("plus")).unwrap();
+        assert_eq!(get_tag_value(minus, "QT").as_deref(), Some("QT:Z:DCBA"));
+        assert_eq!(get_tag_value(plus, "QT").as_deref(), Some("QT:Z:ABCD"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_if_condition() -> Result<(), Box<dyn std::error::Error>> {
+        let sam = concat!(
+            "@HD\tVN:1.6\tSO:unknown\n",
+            "@SQ\tSN:chr1\tLN:1000\n",
+            "unique\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n",
+            "multi\t16\tchr1\t1\t3\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n",
+            "secondary\t272\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tQT:Z:ABCD\n",
+        );
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--rev")
+            .arg("QT")
+            .arg("--if")
+            .arg("mapq >= 20 && !flag.secondary")
+            .write_stdin(sam)
+            .output()?;
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout)?;
+        let qt = |name: &str| {
+            let line = stdout.lines().find(|l| l.starts_with(name)).unwrap();
+            get_tag_value(line, "QT")
+        };
+        assert_eq!(qt("unique").as_deref(), Some("QT:Z:DCBA"));
+        assert_eq!(qt("multi").as_deref(), Some("QT:Z:ABCD"));
+        assert_eq!(qt("secondary").as_deref(), Some("QT:Z:ABCD"));
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--rev")
+            .arg("QT")
+            .arg("--if")
+            .arg("mapq >=")
+            .write_stdin(sam)
+            .assert()
+            .code(2)
+            .stderr(predicates::str::contains("Invalid condition at column 8"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fastq_with_tag_comments() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let input = tmpdir.path().join("in.fq");
+        let output = tmpdir.path().join("out.fq");
+        fs::write(
+            &input,
+            "@minus\tXS:A:-\tQT:Z:ABCD\nACGT\n+\nIIII\n@plus\tXS:A:+\tQT:Z:ABCD\nACGT\n+\nIIII\n",
+        )?;
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--rev")
+            .arg("QT")
+            .arg("--strand-from-tag")
+            .arg("XS")
+            .assert()
+            .success();
+
+        assert_eq!(
+            fs::read_to_string(&output)?,
+            "@minus\tXS:A:-\tQT:Z:DCBA\nACGT\n+\nIIII\n@plus\tXS:A:+\tQT:Z:ABCD\nACGT\n+\nIIII\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pair_requires_both_tags() -> Result<(), Box<dyn std::error::Error>> {
+        let sam = concat!(
+            "@HD\tVN:1.6\tSO:unknown\n",
+            "@SQ\tSN:chr1\tLN:1000\n",
+            "both\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\tQT:Z:ABCD\n",
+            "lonely\t16\tchr1\t2\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\n",
+        );
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--pair")
+            .arg("BC:QT")
+            .write_stdin(sam)
+            .assert()
+            .failure()
+            .code(1)
+            .stderr(predicate::str::contains(
+                "Record lonely has tag BC without its companion tag QT",
+            ));
+
+        let short = concat!(
+            "@HD\tVN:1.6\tSO:unknown\n",
+            "@SQ\tSN:chr1\tLN:1000\n",
+            "short\t16\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\tQT:Z:ABC\n",
+        );
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--pair")
+            .arg("BC:QT")
+            .write_stdin(short)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "Record short has tag BC of length 4 but companion tag QT of length 3",
+            ));
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--pair")
+            .arg("BC:QT")
+            .arg("--on-pair-length-mismatch")
+            .arg("warn")
+            .write_stdin(short)
+            .output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        let record = stdout.lines().find(|l| l.starts_with("short")).unwrap();
+        assert_eq!(get_tag_value(record, "QT").as_deref(), Some("QT:Z:CBA"));
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--pair")
+            .arg("BC")
+            .write_stdin(sam)
+            .assert()
+            .failure()
+            .code(2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_force_overwrite() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let output = tmpdir.path().join("out.sam");
+        fs::write(&output, "in use")?;
+        let run = || -> Result<Command, Box<dyn std::error::Error>> {
+            let mut command = Command::cargo_bin(env!("CARGO_PKG_NAME"))?;
+            command
+                .arg("--input")
+                .arg("tests/input.sam")
+                .arg("--output")
+                .arg(&output)
+                .arg("--rev")
+                .arg("QT");
+            Ok(command)
+        };
+
+        run()?
+            .assert()
+            .code(2)
+            .stderr(predicates::str::contains("already exists"));
+        assert_eq!(fs::read_to_string(&output)?, "in use");
+
+        run()?.arg("--force-overwrite").assert().success();
+        assert_eq!(count_sam_records(&output), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_jobs() -> Result<(), Box<dyn std::error::Error>> {
+        let input = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/input.sam");
+        let tmpdir = tempfile::tempdir()?;
+        let manifest = tmpdir.path().join("manifest.tsv");
+        let outputs: Vec<_> = (1..=3)
+            .map(|i| tmpdir.path().join(format!("out{i}.sam")))
+            .collect();
+        let mut lines = String::from("# input\toutput\n");
+        for output in &outputs {
+            lines.push_str(&format!("{}\t{}\n", input.display(), output.display()));
+        }
+        fs::write(&manifest, &lines)?;
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--manifest")
+            .arg(&manifest)
+            .arg("--jobs")
+            .arg("2")
+            .arg("--rev")
+            .arg("QT")
+            .assert()
+            .success()
+            .stderr(predicates::str::contains(
+                "Processed 3 files: 3 succeeded, 0 failed",
+            ));
+        for output in &outputs {
+            assert_eq!(count_sam_records(output), 4);
+        }
+
+        lines.push_str(&format!(
+            "{}\t{}\n",
+            tmpdir.path().join("missing.sam").display(),
+            tmpdir.path().join("out4.sam").display()
+        ));
+        fs::write(&manifest, &lines)?;
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--manifest")
+            .arg(&manifest)
+            .arg("--jobs")
+            .arg("2")
+            .arg("--rev")
+            .arg("QT")
+            .arg("--force-overwrite")
+            .assert()
+            .code(1)
+            .stderr(predicates::str::contains(
+                "Processed 4 files: 3 succeeded, 1 failed",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_md5() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let bam = tmpdir.path().join("out.bam");
+        let sam = tmpdir.path().join("out.sam");
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--output")
+            .arg(&bam)
+            .arg("--output")
+            .arg(&sam)
+            .arg("--rev")
+            .arg("QT")
+            .arg("--write-md5")
+            .arg("--fsync")
+            .assert()
+            .success();
+
+        assert_eq!(fs::read(&bam)?[..2], [0x1f, 0x8b]);
+        assert_eq!(count_sam_records(&sam), 4);
+        for (output, name) in [(&bam, "out.bam"), (&sam, "out.sam")] {
+            let sidecar = fs::read_to_string(tmpdir.path().join(format!("{name}.md5")))?;
+            let (digest, file) = sidecar.trim_end().split_once("  ").unwrap();
+            assert_eq!(file, name);
+            assert_eq!(digest.len(), 32);
+            assert!(!fs::read(output)?.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_format_overrides_extension() -> Result<(), Box<dyn std::error::Error>> {
+        let input = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/input.sam");
+        let tmpdir = tempfile::tempdir()?;
+        let output = tmpdir.path().join("out");
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&output)
+            .arg("--output-format")
+            .arg("bam")
+            .arg("--rev")
+            .arg("QT")
+            .assert()
+            .success();
+
+        // BAM output is BGZF, which begins with the gzip magic bytes.
+        assert_eq!(fs::read(&output)?[..2], [0x1f, 0x8b]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_format_on_stdout() -> Result<(), Box<dyn std::error::Error>> {
+        let input = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/input.sam");
+
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output-format")
+            .arg("bam")
+            .arg("--rev")
+            .arg("QT")
+            .output()?;
+        assert!(output.status.success());
+        assert_eq!(output.stdout[..2], [0x1f, 0x8b]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_outputs() -> Result<(), Box<dyn std::error::Error>> {
+        let input = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/input.sam");
+        let tmpdir = tempfile::tempdir()?;
+        let sam_out = tmpdir.path().join("out.sam");
+
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg(&input)
+            .arg("--output")
+            .arg(&sam_out)
+            .arg("--output")
+            .arg("-")
+            .arg("--rev")
+            .arg("QT")
+            .output()?;
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout)?;
+        assert_eq!(fs::read_to_string(&sam_out)?, stdout);
+        assert_eq!(count_sam_records(&sam_out), count_sam_records(&input));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_barcode_fastq() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let barcodes = tmpdir.path().join("barcodes.fq");
+        let sam = concat!(
+            "@HD\tVN:1.6\tSO:unknown\n",
+            "@SQ\tSN:chr1\tLN:1000\n",
+            "fwd\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\tQT:Z:ABCD\n",
+            "rev\t16\tchr1\t2\t60\t4M\t*\t0\t0\tACGT\tFFFF\tBC:Z:AACG\tQT:Z:ABCD\n",
+            "none\t16\tchr1\t3\t60\t4M\t*\t0\t0\tACGT\tFFFF\n",
+        );
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--rev")
+            .arg("QT")
+            .arg("--revcomp")
+            .arg("BC")
+            .arg("--barcode-fastq")
+            .arg(&barcodes)
+            .write_stdin(sam)
+            .assert()
+            .success();
+
+        assert_eq!(
+            fs::read_to_string(&barcodes)?,
+            "@fwd\nAACG\n+\nABCD\n@rev\nCGTT\n+\nDCBA\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_identical_across_threads() -> Result<(), Box<dyn std::error::Error>> {
+        let input = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/input.sam");
+        let mut outputs = Vec::new();
+
+        // Each run writes to the same relative path so the @PG command lines match.
+        for threads in ["1", "4"] {
+            let tmpdir = tempfile::tempdir()?;
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .current_dir(tmpdir.path())
+                .arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg("out.bam")
+                .arg("--threads")
+                .arg(threads)
+                .arg("--rev")
+                .arg("QT")
+                .assert()
+                .success();
+            outputs.push(fs::read(tmpdir.path().join("out.bam"))?);
+        }
+
+        assert_eq!(outputs[0], outputs[1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_annotated_output_identical_across_threads() -> Result<(), Box<dyn std::error::Error>> {
+        let input = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/input.sam");
+        let mut outputs = Vec::new();
+
+        // Restoring orientation with no planned tags still transforms reverse strand records, so
+        // both paths must stamp them alike.
+        for threads in ["1", "2"] {
+            let tmpdir = tempfile::tempdir()?;
+            Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+                .current_dir(tmpdir.path())
+                .arg("--input")
+                .arg(&input)
+                .arg("--output")
+                .arg("out.sam")
+                .arg("--threads")
+                .arg(threads)
+                .arg("--restore-original-orientation")
+                .arg("--annotate-strand")
+                .assert()
+                .success();
+            outputs.push(fs::read_to_string(tmpdir.path().join("out.sam"))?);
+        }
+
+        assert_eq!(outputs[0], outputs[1]);
+        assert!(outputs[0].contains("os:A:-"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_writes_no_output() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let output_path = tmpdir.path().join("out.bam");
+
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .arg("--input")
+            .arg("tests/input.sam")
+            .arg("--output")
+            .arg(&output_path)
+            .arg("--tag")
+            .arg("QT:rev")
+            .arg("--dry-run")
+            .assert()
+            .success()
+            .stdout(predicates::str::contains("Records read: 4"))
+            .stdout(predicates::str::contains(
+                "Records that would be modified: 2",
+            ))
+            .stdout(predicates::str::contains("Example records: read2, read4"));
+
+        assert!(!output_path.exists());
+
+        Ok(())
+    }
+
     #[test]
     fn test_stdin_stdout() -> Result<(), Box<dyn std::error::Error>> {
         Command::cargo_bin(env!("CARGO_PKG_NAME"))?
@@ -182,6 +2283,7 @@ mod tests {
             .arg("tests/input.sam")
             .arg("--output")
             .arg(&output_path)
+            .arg("--force-overwrite")
             .assert()
             .success();
 
@@ -201,6 +2303,7 @@ mod tests {
             .arg("tests/input.sam")
             .arg("--output")
             .arg(&output_path)
+            .arg("--force-overwrite")
             .arg("--rev")
             .arg("QT")
             .assert()